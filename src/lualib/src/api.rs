@@ -1,14 +1,20 @@
 //! Lua API
 
-use std::rc::Rc;
+use std::{any::Any, cell::RefCell, ffi::c_void, rc::Rc};
 
 use crate::{
+    chunk::CompiledChunk,
+    limits::MAX_UPVAL,
     luaD, luaG, luaV, luaZ,
-    object::{Closure, TValue},
-    state::{LuaState, PanicFunction},
-    LuaFloat, LuaInteger, LuaRustFunction, Reader, LUA_REGISTRYINDEX, LUA_RIDX_GLOBALS,
+    luaconf::LUAI_MAXSTACK,
+    object::{number2str, str2number, Closure, LClosure, TValue, UpVal, UserData, UserDataRef},
+    opcodes::OpCode,
+    state::{LuaState, PanicFunction, CIST_LUA},
+    LuaFloat, LuaHook, LuaInteger, LuaRustFunction, Reader, LUA_REGISTRYINDEX, LUA_RIDX_GLOBALS,
 };
 
+pub use crate::debug::{get_info, get_local, get_stack, set_local, HookEvent, LuaDebug};
+
 #[derive(Debug, PartialEq)]
 pub enum LuaError {
     /// error during error handling
@@ -17,6 +23,18 @@ pub enum LuaError {
     RuntimeError,
     /// error during parsing of the source code
     SyntaxError,
+    /// a file could not be opened or read
+    FileError,
+    /// the memory limit set with [`set_memory_limit`] was exceeded
+    MemoryError,
+    /// `os.exit` was called with this exit code
+    Exit(i32),
+}
+
+/// Destroys the state, calling the `__gc` finalizers of all the objects
+/// registered for finalization. This is what dropping the state does.
+pub fn close(state: LuaState) {
+    drop(state);
 }
 
 pub fn at_panic(state: &mut LuaState, panic: PanicFunction) -> Option<PanicFunction> {
@@ -25,14 +43,22 @@ pub fn at_panic(state: &mut LuaState, panic: PanicFunction) -> Option<PanicFunct
     old
 }
 
+/// Converts the Lua value at the given index to a string. The value must be
+/// a string or a number; otherwise, returns None. If the value is a number,
+/// then it also changes the actual value in the stack to a string.
 pub fn to_string(state: &mut LuaState, idx: isize) -> Option<String> {
-    // TODO convert in stack
-    match state.index2adr(idx) {
-        TValue::String(s) => Some(s.as_ref().clone()),
-        TValue::Float(n) => Some(format!("{}", n)),
-        TValue::Integer(n) => Some(format!("{}", n)),
-        _ => None,
+    let s = match state.index2adr(idx) {
+        TValue::String(s) => return Some(s.as_ref().clone()),
+        TValue::Float(n) => number2str(n),
+        TValue::Integer(n) => format!("{}", n),
+        _ => return None,
+    };
+    if idx > LUA_REGISTRYINDEX {
+        // convert in stack
+        let value = TValue::from(s.as_str());
+        state.set_index(idx, value);
     }
+    Some(s)
 }
 
 struct CallData {
@@ -45,6 +71,9 @@ fn f_call(state: &mut LuaState, c: &CallData) -> Result<i32, LuaError> {
     Ok(0)
 }
 
+/// Calls a function in protected mode. If `errfunc` is not 0, it is the stack
+/// index of a message handler, called with the error object when an error occurs,
+/// before the stack is unwound. Its return value becomes the error object.
 pub fn pcall(
     state: &mut LuaState,
     nargs: usize,
@@ -58,34 +87,196 @@ pub fn pcall(
             func: (state.stack.len() - (nargs + 1)) as u32,
             nresults,
         };
-        (c, errfunc)
+        let func = if errfunc == 0 {
+            0
+        } else {
+            debug_assert!(state.is_index_valid(errfunc as isize));
+            state.base_ci[state.ci].func + errfunc as usize
+        };
+        (c, func)
     };
-    let status = luaD::pcall(state, f_call, &c, c.func as usize, func as usize)?;
+    let status = luaD::pcall(state, f_call, &c, c.func as usize, func)?;
     state.adjust_results(nresults);
     Ok(status)
 }
 
+/// Loads a Lua chunk without running it. If there are no errors, pushes the
+/// compiled chunk as a Lua function. Otherwise, pushes an error message.
+/// `mode` controls whether the chunk can be text or binary ("t", "b" or "bt").
+/// None is equivalent to "bt".
 pub fn load<T>(
     state: &mut LuaState,
     reader: Reader<T>,
     data: T,
     name: Option<&str>,
+    mode: Option<&str>,
 ) -> Result<i32, LuaError> {
     let zio = luaZ::Zio::new(reader, data);
-    let res = luaD::protected_parser(state, zio, name.unwrap_or("?"));
+    let res = luaD::protected_parser(state, zio, name.unwrap_or("?"), mode);
     if res.is_ok() {
-        if let TValue::Function(clref) = state.stack.last().unwrap() {
-            if let Closure::Lua(lcl) = &mut *clref.borrow_mut() {
-                if lcl.upvalues.len() >= 1 {
-                    // does it have one upvalue?
-                    let gt = state.get_global_table();
-                    // set global table as 1st upvalue of 'lcl' (may be LUA_ENV)
-                    lcl.upvalues[0].value = gt.clone();
-                }
+        set_env(state);
+    }
+    res
+}
+
+/// Pushes a chunk compiled with [`crate::luaL::compile`] as a Lua function,
+/// as [`load`] does. The chunk may have been compiled by another state.
+pub fn load_compiled(state: &mut LuaState, chunk: &CompiledChunk) {
+    let proto = chunk.to_proto();
+    let nup = proto.upvalues.len();
+    let cl = TValue::from(Closure::Lua(LClosure::new(proto, nup)));
+    state.link_object(&cl);
+    state.stack.push(cl);
+    set_env(state);
+}
+
+/// Sets the global table as the first upvalue of the loaded chunk on the top
+/// of the stack
+fn set_env(state: &mut LuaState) {
+    if let TValue::Function(clref) = state.stack.last().unwrap() {
+        if let Closure::Lua(lcl) = &mut *clref.borrow_mut() {
+            if lcl.upvalues.len() >= 1 {
+                // does it have one upvalue?
+                let gt = state.get_global_table();
+                // set global table as 1st upvalue of 'lcl' (may be LUA_ENV)
+                *lcl.upvalues[0].borrow_mut() = UpVal::Closed(gt.clone());
             }
         }
     }
-    res
+}
+
+/// Pushes the value of upvalue `n` of the closure at index `funcindex` onto
+/// the stack and returns its name (an empty string for Rust functions).
+/// Returns None and pushes nothing if there is no such upvalue.
+pub fn get_upvalue(state: &mut LuaState, funcindex: isize, n: usize) -> Option<String> {
+    let clref = match state.index2adr(funcindex) {
+        TValue::Function(clref) => clref,
+        _ => return None,
+    };
+    let cl = clref.borrow();
+    if n == 0 || n > cl.get_nupvalues() {
+        return None;
+    }
+    let name = match &*cl {
+        Closure::Rust(_) => String::new(),
+        Closure::Lua(cl) => cl.proto.upvalues[n - 1].name.clone(),
+    };
+    let value = state.get_upval_value(cl.borrow_upval(n - 1));
+    state.stack.push(value);
+    Some(name)
+}
+
+/// Returns a unique identifier for the upvalue numbered `n` of the closure
+/// at index `funcindex`. Closures sharing an upvalue get the same identifier.
+/// Returns None if there is no such upvalue.
+pub fn upvalue_id(state: &mut LuaState, funcindex: isize, n: usize) -> Option<*const c_void> {
+    let TValue::Function(clref) = state.index2adr(funcindex) else {
+        return None;
+    };
+    let cl = clref.borrow();
+    if n == 0 || n > cl.get_nupvalues() {
+        return None;
+    }
+    Some(Rc::as_ptr(cl.borrow_upval(n - 1)) as *const c_void)
+}
+
+/// Makes the `n1`-th upvalue of the closure at index `funcindex1` refer to
+/// the `n2`-th upvalue of the closure at index `funcindex2`. Both closures
+/// can be Lua or Rust functions.
+pub fn upvalue_join(state: &mut LuaState, funcindex1: isize, n1: usize, funcindex2: isize, n2: usize) {
+    let (TValue::Function(f1), TValue::Function(f2)) =
+        (state.index2adr(funcindex1), state.index2adr(funcindex2))
+    else {
+        unreachable!("function expected");
+    };
+    let uv = f2.borrow().borrow_upval(n2 - 1).clone();
+    let mut f1 = f1.borrow_mut();
+    match &mut *f1 {
+        Closure::Lua(cl) => cl.upvalues[n1 - 1] = uv,
+        Closure::Rust(cl) => cl.upvalues[n1 - 1] = uv,
+    }
+}
+
+/// Sets the debugging hook function. `mask` specifies on which events the
+/// hook will be called: it is formed by a bitwise or of the constants
+/// LUA_MASKCALL, LUA_MASKRET, LUA_MASKLINE and LUA_MASKCOUNT. `count` is
+/// only meaningful when the mask includes LUA_MASKCOUNT. A None hook or a
+/// zero mask turns off the hooks.
+///
+/// The hook receives a `LuaDebug` record with the event and, for line events,
+/// the current line. Use `get_info` with this record to get more information.
+/// While the hook is running, other hooks are disabled. The hook can raise an
+/// error by pushing a message and returning Err.
+pub fn set_hook(state: &mut LuaState, func: Option<LuaHook>, mask: usize, count: usize) {
+    let (func, mask) = if func.is_none() || mask == 0 {
+        (None, 0) // turn off hooks
+    } else {
+        (func, mask)
+    };
+    state.hook = func;
+    state.basehookcount = count;
+    state.hookcount = count;
+    state.hookmask = mask;
+    if state.base_ci[state.ci].call_status & CIST_LUA != 0 {
+        state.oldpc = state.base_ci[state.ci].saved_pc;
+    }
+}
+
+/// Returns the current hook function.
+pub fn get_hook(state: &LuaState) -> Option<LuaHook> {
+    state.hook
+}
+
+/// Returns the current hook mask.
+pub fn get_hook_mask(state: &LuaState) -> usize {
+    state.hookmask
+}
+
+/// Returns the current hook count.
+pub fn get_hook_count(state: &LuaState) -> usize {
+    state.basehookcount
+}
+
+/// Pushes onto the stack the Lua value associated with the full userdata
+/// at the given index.
+pub fn get_user_value(state: &mut LuaState, idx: isize) {
+    let TValue::UserData(ud) = state.index2adr(idx) else {
+        unreachable!("full userdata expected");
+    };
+    let value = ud.borrow().uservalue.clone();
+    state.stack.push(value);
+}
+
+/// Pops a value from the stack and sets it as the new value associated
+/// to the full userdata at the given index.
+pub fn set_user_value(state: &mut LuaState, idx: isize) {
+    let TValue::UserData(ud) = state.index2adr(idx) else {
+        unreachable!("full userdata expected");
+    };
+    let value = state.stack.pop().unwrap();
+    ud.borrow_mut().uservalue = value;
+}
+
+/// Pops a value from the stack and sets it as the new value of upvalue `n`
+/// of the closure at index `funcindex`. Returns the name of the upvalue
+/// (an empty string for Rust functions), or None and pops nothing if there
+/// is no such upvalue.
+pub fn set_upvalue(state: &mut LuaState, funcindex: isize, n: usize) -> Option<String> {
+    let clref = match state.index2adr(funcindex) {
+        TValue::Function(clref) => clref,
+        _ => return None,
+    };
+    let cl = clref.borrow();
+    if n == 0 || n > cl.get_nupvalues() {
+        return None;
+    }
+    let value = state.stack.pop().unwrap();
+    let name = match &*cl {
+        Closure::Rust(_) => String::new(),
+        Closure::Lua(cl) => cl.proto.upvalues[n - 1].name.clone(),
+    };
+    state.set_upval_value(cl.borrow_upval(n - 1), value);
+    Some(name)
 }
 
 /// Returns the index of the top element in the stack.
@@ -100,7 +291,7 @@ pub fn get_global(s: &mut LuaState, name: &str) {
     let gt = s.get_global_table();
     let top = s.stack.len();
     s.stack.push(TValue::from(name));
-    LuaState::get_tablev2(&mut s.stack, &gt, &TValue::from(name), Some(top));
+    s.get_tablev2(&gt, &TValue::from(name), Some(top));
 }
 
 /// Pops a value from the stack and sets it as the new value of global name.
@@ -115,7 +306,7 @@ pub fn set_global(state: &mut LuaState, name: &str) {
 pub fn get_field(s: &mut LuaState, index: isize, name: &str) {
     let t = s.index2adr(index).clone();
     let key = TValue::from(name);
-    LuaState::get_tablev2(&mut s.stack, &t, &key, None);
+    s.get_tablev2(&t, &key, None);
 }
 
 /// Pushes a copy of the element at the given index onto the stack.
@@ -178,9 +369,11 @@ pub fn to_integer(s: &mut LuaState, index: isize) -> Option<LuaInteger> {
     s.index2adr(index).into_integer().ok()
 }
 
+/// Converts the Lua value at the given index to a boolean value.
+/// Like all tests in Lua, returns true for any Lua value different from false and nil;
+/// otherwise it returns false.
 pub fn to_boolean(s: &mut LuaState, index: isize) -> bool {
-    // TODO convert in stack
-    s.index2adr(index).is_false()
+    !s.index2adr(index).is_false()
 }
 
 /// Returns true if the value at the given index is a number or a string convertible to a number, and false otherwise.
@@ -222,18 +415,32 @@ pub fn is_function(s: &mut LuaState, index: isize) -> bool {
     s.index2adr(index).is_function()
 }
 
+/// Returns true if the value at the given index is a Rust function, and false otherwise.
+pub fn is_rust_function(s: &mut LuaState, index: isize) -> bool {
+    match s.index2adr(index) {
+        TValue::Function(cl) => matches!(&*cl.borrow(), Closure::Rust(_)),
+        _ => false,
+    }
+}
+
 /// Returns true if the value at the given index is a table, and false otherwise.
 pub fn is_table(s: &mut LuaState, index: isize) -> bool {
     s.index2adr(index).is_table()
 }
 
-pub fn to_pointer(s: &mut LuaState, index: isize) -> *const TValue {
+/// Converts the value at the given index to a generic pointer. The value can
+/// be a userdata, a table or a function; otherwise, returns a null pointer.
+/// Different objects will give different pointers. Typically this function
+/// is used only for hashing and debug information.
+pub fn to_pointer(s: &mut LuaState, index: isize) -> *const c_void {
     s.index2adr(index).to_pointer()
 }
 
 pub fn concat(state: &mut LuaState, n: usize) -> Result<(), LuaError> {
     if n >= 2 {
+        let top = state.stack.len();
         luaV::concat(state, n)?;
+        state.stack.truncate(top - n + 1);
     } else if n == 0 {
         // push empty string
         state.push_string("");
@@ -249,11 +456,51 @@ pub fn create_table(state: &mut LuaState) {
     state.create_table();
 }
 
-pub(crate) fn _replace(_state: &mut LuaState, _lua_environindex: isize) {
-    todo!()
+/// Pushes onto the stack a new full userdata wrapping `data`.
+pub fn new_userdata<T: Any>(state: &mut LuaState, data: T) {
+    let ud = TValue::UserData(Rc::new(RefCell::new(UserData::new(Box::new(data)))));
+    state.link_object(&ud);
+    state.stack.push(ud);
 }
 
-pub(crate) fn set_metatable(state: &mut LuaState, obj_index: i32) {
+/// If the value at the given index is a full userdata, returns a reference to it.
+/// Otherwise, returns None.
+pub fn to_userdata(state: &mut LuaState, idx: isize) -> Option<UserDataRef> {
+    match state.index2adr(idx) {
+        TValue::UserData(udref) => Some(udref),
+        _ => None,
+    }
+}
+
+/// Pushes a light userdata onto the stack. A light userdata is a plain pointer
+/// that Lua neither owns nor dereferences. Two light userdata are equal when
+/// they hold the same address.
+pub fn push_light_userdata(state: &mut LuaState, p: *const c_void) {
+    state.stack.push(TValue::LightUserData(p));
+}
+
+/// Returns true if the value at the given index is a userdata (either full or light), and false otherwise.
+pub fn is_userdata(state: &mut LuaState, idx: isize) -> bool {
+    matches!(
+        state.index2adr(idx),
+        TValue::UserData(_) | TValue::LightUserData(_)
+    )
+}
+
+/// Returns true if the value at the given index is a light userdata, and false otherwise.
+pub fn is_light_userdata(state: &mut LuaState, idx: isize) -> bool {
+    matches!(state.index2adr(idx), TValue::LightUserData(_))
+}
+
+/// Returns true if the value at the given index is a thread, and false otherwise.
+pub fn is_thread(state: &mut LuaState, idx: isize) -> bool {
+    matches!(state.index2adr(idx), TValue::Thread())
+}
+
+/// Pops a table or nil from the stack and sets it as the new metatable for
+/// the value at the given index. Values other than tables and userdata
+/// share one metatable per type.
+pub fn set_metatable(state: &mut LuaState, obj_index: i32) {
     let mt = state.stack.pop().unwrap();
     let mt = if mt.is_nil() {
         None
@@ -270,12 +517,14 @@ pub(crate) fn set_metatable(state: &mut LuaState, obj_index: i32) {
         obj_index
     };
     let obj = state.index2adr(obj_index as isize);
-    match obj {
+    match &obj {
         TValue::Table(tref) => {
             tref.borrow_mut().metatable = mt;
+            state.check_finalizer(&obj);
         }
         TValue::UserData(udref) => {
             udref.borrow_mut().metatable = mt;
+            state.check_finalizer(&obj);
         }
         _ => {
             let obj_type = obj.get_type_name().to_owned();
@@ -284,32 +533,306 @@ pub(crate) fn set_metatable(state: &mut LuaState, obj_index: i32) {
     }
 }
 
-pub(crate) fn raw_get_i(state: &mut LuaState, idx: isize, n: usize) {
-    let o = state.index2adr(idx as isize);
+/// Pushes onto the stack the value t[k], where t is the value at the given
+/// index and k is the value at the top of the stack. Pops the key from the
+/// stack, putting the resulting value in its place. As in Lua, this function
+/// may trigger a metamethod for the "index" event.
+pub fn get_table(state: &mut LuaState, idx: isize) -> Result<(), LuaError> {
+    let t = state.index2adr(idx);
+    let key = state.stack.pop().unwrap();
+    state.get_tablev(&t, &key, None)
+}
+
+/// Does the equivalent to t[k] = v, where t is the value at the given index,
+/// v is the value at the top of the stack, and k is the value just below the top.
+/// Pops both the key and the value from the stack. As in Lua, this function
+/// may trigger a metamethod for the "newindex" event.
+pub fn set_table(state: &mut LuaState, idx: isize) -> Result<(), LuaError> {
+    let t = state.index2adr(idx);
+    let value = state.stack.pop().unwrap();
+    let key = state.stack.pop().unwrap();
+    state.set_table(&t, key, value)
+}
+
+/// Pushes onto the stack the value t[n], where t is the value at the given index.
+/// As in Lua, this function may trigger a metamethod for the "index" event.
+pub fn get_i(state: &mut LuaState, idx: isize, n: LuaInteger) -> Result<(), LuaError> {
+    let t = state.index2adr(idx);
+    state.get_tablev(&t, &TValue::Integer(n), None)
+}
+
+/// Does the equivalent to t[n] = v, where t is the value at the given index
+/// and v is the value at the top of the stack. Pops the value from the stack.
+/// As in Lua, this function may trigger a metamethod for the "newindex" event.
+pub fn set_i(state: &mut LuaState, idx: isize, n: LuaInteger) -> Result<(), LuaError> {
+    let t = state.index2adr(idx);
+    let value = state.stack.pop().unwrap();
+    state.set_table(&t, TValue::Integer(n), value)
+}
+
+/// Returns the length of the value at the given index and pushes it onto
+/// the stack. It is equivalent to the '#' operator in Lua and may trigger
+/// a metamethod for the "length" event.
+pub fn len(state: &mut LuaState, idx: isize) -> Result<(), LuaError> {
+    let value = state.index2adr(idx);
+    let len = luaV::obj_len(state, &value)?;
+    state.stack.push(len);
+    Ok(())
+}
+
+/// Comparison operators for [`compare`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompareOp {
+    /// compares for equality (==)
+    Eq,
+    /// compares for less than (<)
+    Lt,
+    /// compares for less or equal (<=)
+    Le,
+}
+
+/// Arithmetic and bitwise operators for [`arith`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArithOp {
+    /// performs addition (+)
+    Add,
+    /// performs subtraction (-)
+    Sub,
+    /// performs multiplication (*)
+    Mul,
+    /// performs modulo (%)
+    Mod,
+    /// performs exponentiation (^)
+    Pow,
+    /// performs float division (/)
+    Div,
+    /// performs floor division (//)
+    IDiv,
+    /// performs bitwise AND (&)
+    BAnd,
+    /// performs bitwise OR (|)
+    BOr,
+    /// performs bitwise exclusive OR (~)
+    BXor,
+    /// performs left shift (<<)
+    Shl,
+    /// performs right shift (>>)
+    Shr,
+    /// performs mathematical negation (unary -)
+    Unm,
+    /// performs bitwise NOT (unary ~)
+    BNot,
+}
+
+/// Performs an arithmetic or bitwise operation over the two values (or one,
+/// in the case of negations) at the top of the stack, with the value at the
+/// top being the second operand, pops these values, and pushes the result of
+/// the operation. The function follows the semantics of the corresponding
+/// Lua operator (that is, it may call metamethods).
+pub fn arith(state: &mut LuaState, op: ArithOp) -> Result<(), LuaError> {
+    let (opcode, event) = match op {
+        ArithOp::Add => (OpCode::Add, "__add"),
+        ArithOp::Sub => (OpCode::Sub, "__sub"),
+        ArithOp::Mul => (OpCode::Mul, "__mul"),
+        ArithOp::Mod => (OpCode::Mod, "__mod"),
+        ArithOp::Pow => (OpCode::Pow, "__pow"),
+        ArithOp::Div => (OpCode::Div, "__div"),
+        ArithOp::IDiv => (OpCode::IntegerDiv, "__idiv"),
+        ArithOp::BAnd => (OpCode::BinaryAnd, "__band"),
+        ArithOp::BOr => (OpCode::BinaryOr, "__bor"),
+        ArithOp::BXor => (OpCode::BinaryXor, "__bxor"),
+        ArithOp::Shl => (OpCode::Shl, "__shl"),
+        ArithOp::Shr => (OpCode::Shr, "__shr"),
+        ArithOp::Unm => (OpCode::UnaryMinus, "__unm"),
+        ArithOp::BNot => (OpCode::BinaryNot, "__bnot"),
+    };
+    if matches!(op, ArithOp::Unm | ArithOp::BNot) {
+        // add fake 2nd operand
+        state.push_value(-1);
+    }
+    let top = state.stack.len();
+    let p1 = state.stack[top - 2].clone();
+    let p2 = state.stack[top - 1].clone();
+    let res = luaV::obj_arith(state, opcode, event, &p1, &p2)?;
+    state.stack.truncate(top - 2);
+    state.stack.push(res);
+    Ok(())
+}
+
+/// Compares two Lua values. Returns true if the value at index `index1`
+/// satisfies `op` when compared with the value at index `index2`, following
+/// the semantics of the corresponding Lua operator (that is, it may call
+/// metamethods). Otherwise returns false. Also returns false if any of the
+/// indices is not valid.
+pub fn compare(
+    state: &mut LuaState,
+    index1: isize,
+    index2: isize,
+    op: CompareOp,
+) -> Result<bool, LuaError> {
+    if !state.is_index_valid(index1) || !state.is_index_valid(index2) {
+        return Ok(false);
+    }
+    let o1 = state.index2adr(index1);
+    let o2 = state.index2adr(index2);
+    match op {
+        CompareOp::Eq => Ok(luaV::raw_equal_obj(&o1, &o2)),
+        CompareOp::Lt => luaV::less_than(state, o1, o2),
+        CompareOp::Le => luaV::less_equal(state, o1, o2),
+    }
+}
+
+/// Pushes onto the stack the value t[n], where t is the table at the given
+/// index. The access is raw, that is, it does not invoke the __index metamethod.
+pub fn raw_get_i(state: &mut LuaState, idx: isize, n: LuaInteger) {
+    raw_get_key(state, idx, &TValue::Integer(n));
+}
+
+/// Does the equivalent of t[n] = v, where t is the table at the given index
+/// and v is the value at the top of the stack. Pops the value from the stack.
+/// The assignment is raw, that is, it does not invoke the __newindex metamethod.
+pub fn raw_set_i(state: &mut LuaState, idx: isize, n: LuaInteger) {
+    raw_set_key(state, idx, TValue::Integer(n));
+}
+
+/// Pushes onto the stack the value t[k], where t is the table at the given
+/// index and k is the pointer `p` represented as a light userdata. The access
+/// is raw, that is, it does not invoke the __index metamethod.
+pub fn raw_get_p(state: &mut LuaState, idx: isize, p: *const c_void) {
+    raw_get_key(state, idx, &TValue::LightUserData(p));
+}
+
+/// Does the equivalent of t[p] = v, where t is the table at the given index,
+/// p is encoded as a light userdata, and v is the value at the top of the
+/// stack. Pops the value from the stack. The assignment is raw, that is, it
+/// does not invoke the __newindex metamethod.
+pub fn raw_set_p(state: &mut LuaState, idx: isize, p: *const c_void) {
+    raw_set_key(state, idx, TValue::LightUserData(p));
+}
+
+fn raw_get_key(state: &mut LuaState, idx: isize, key: &TValue) {
+    let o = state.index2adr(idx);
     if let TValue::Table(tref) = o {
-        let value = {
-            let mut t = tref.borrow_mut();
-            t.get_num(n as usize).clone()
-        };
+        let value = tref.borrow().get(key).cloned().unwrap_or_default();
         state.stack.push(value);
     } else {
         unreachable!()
     }
 }
 
+fn raw_set_key(state: &mut LuaState, idx: isize, key: TValue) {
+    let o = state.index2adr(idx);
+    let value = state.stack.pop().unwrap();
+    if let TValue::Table(tref) = o {
+        tref.borrow_mut().set(key, value);
+    } else {
+        unreachable!()
+    }
+}
+
+/// Options for [`gc`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GcOption {
+    /// stops the automatic collections
+    Stop,
+    /// restarts the automatic collections
+    Restart,
+    /// performs a full collection
+    Collect,
+    /// performs a collection step. Collections are not incremental, so a step
+    /// is a full collection
+    Step,
+    /// checks whether the automatic collections are running
+    IsRunning,
+    /// returns the current amount of memory (in Kbytes) in use by Lua
+    Count,
+    /// returns the remainder of dividing the current amount of bytes of
+    /// memory in use by Lua by 1024
+    CountB,
+    /// sets `data` as the new value for the pause of the collector and
+    /// returns the previous value
+    SetPause,
+    /// sets `data` as the new value for the step multiplier of the collector
+    /// and returns the previous value
+    SetStepMul,
+}
+
+/// Controls the cycle collector. Tables, closures and userdata are freed as
+/// soon as they are not referenced anymore. The collector frees the cycles of
+/// objects unreachable from the stack and the registry, and runs automatically
+/// when enough objects were created since the last collection. The `__gc`
+/// finalizers of the unreachable objects are called at the end of a
+/// collection, and an error in a finalizer is returned by this function.
+/// Returns 1 for `Step` (the collection is finished) and for `IsRunning` if
+/// the collector is running. Returns 0 otherwise.
+pub fn gc(state: &mut LuaState, what: GcOption, data: usize) -> Result<usize, LuaError> {
+    let res = match what {
+        GcOption::Stop => {
+            state.g.gc.running = false;
+            0
+        }
+        GcOption::Restart => {
+            state.g.gc.running = true;
+            0
+        }
+        GcOption::Collect => {
+            state.full_gc()?;
+            0
+        }
+        GcOption::Step => {
+            state.full_gc()?;
+            1
+        }
+        GcOption::IsRunning => state.g.gc.running as usize,
+        GcOption::Count => state.memory_count() >> 10,
+        GcOption::CountB => state.memory_count() & 0x3ff,
+        GcOption::SetPause => std::mem::replace(&mut state.g.gc.pause, data),
+        GcOption::SetStepMul => std::mem::replace(&mut state.g.gc.stepmul, data),
+    };
+    Ok(res)
+}
+
+/// Returns the memory used by the state, in bytes: its stack, its tables,
+/// closures, userdata, strings and function prototypes. This is an estimate
+/// as there is no allocator hook.
+pub fn memory_used(state: &LuaState) -> usize {
+    state.memory_estimate()
+}
+
+/// Sets the maximum memory the state may use, in bytes, as measured by
+/// [`memory_used`]. Once it is exceeded, the allocations done by the scripts
+/// raise a "not enough memory" error ([`LuaError::MemoryError`]) that can be
+/// caught with `pcall`. `None` removes the limit. Returns the previous limit.
+pub fn set_memory_limit(state: &mut LuaState, limit: Option<usize>) -> Option<usize> {
+    state.g.gc.estimate = state.memory_estimate();
+    state.g.gc.debt = 0;
+    std::mem::replace(&mut state.g.gc.limit, limit)
+}
+
+/// Pushes a new Rust closure onto the stack. The `nupval` values on top of the
+/// stack are popped and become the closure upvalues, reachable from the function
+/// with the pseudo-indices returned by [`upvalue_index`].
 pub fn push_rust_function(state: &mut LuaState, func: LuaRustFunction, nupval: usize) {
     if nupval == 0 {
         state.push_rust_function(func);
     } else {
-        todo!()
+        debug_assert!(nupval <= MAX_UPVAL);
+        state.push_rust_closure(func, nupval);
     }
 }
 
+/// Returns the pseudo-index that refers to the i-th upvalue (starting at 1)
+/// of the running Rust function.
+pub fn upvalue_index(i: usize) -> isize {
+    LUA_REGISTRYINDEX - i as isize
+}
+
+/// Accepts any index, or 0, and sets the stack top to this index.
+/// If the new top is larger than the old one, then the new elements are filled with nil.
 pub fn set_top(s: &mut LuaState, idx: i32) {
     if idx >= 0 {
-        while s.stack.len() < s.base_ci[s.ci].base + idx as usize {
-            s.push_nil();
-        }
+        let newlen = s.base_ci[s.ci].func + 1 + idx as usize;
+        s.stack.resize(newlen, TValue::Nil);
     } else {
         let newlen = s.stack.len() + 1 - (-idx) as usize;
         s.stack.resize(newlen, TValue::Nil);
@@ -335,29 +858,134 @@ pub fn next(s: &mut LuaState, idx: i32) -> bool {
 }
 
 pub fn push_global_table(state: &mut LuaState) {
-    raw_get_i(state, LUA_REGISTRYINDEX, LUA_RIDX_GLOBALS);
+    raw_get_i(state, LUA_REGISTRYINDEX, LUA_RIDX_GLOBALS as LuaInteger);
 }
 
 pub fn raw_get(s: &mut LuaState, idx: i32) {
     let t = s.index2adr(idx as isize);
     debug_assert!(t.is_table());
     if let TValue::Table(tref) = &t {
-        let mut t = tref.borrow_mut();
-        let key = s.stack.pop().unwrap();
-        let value = t.get(&key).cloned().unwrap_or(TValue::Nil);
+        let t = tref.borrow();
+        let key = s.stack.last().unwrap();
+        let value = t.get(key).cloned().unwrap_or(TValue::Nil);
         // replace key with result
         let len = s.stack.len();
         s.stack[len - 1] = value;
     }
 }
 
+/// Does the equivalent to t[k] = v, where t is the value at the given index,
+/// v is the value at the top of the stack, and k is the value just below the top.
+/// Pops both the key and the value from the stack. The assignment is raw
+/// (does not invoke metamethods). Raises an error if the key is nil or NaN.
+pub fn raw_set(s: &mut LuaState, idx: isize) -> Result<(), LuaError> {
+    let t = s.index2adr(idx);
+    debug_assert!(t.is_table());
+    let len = s.stack.len();
+    match &s.stack[len - 2] {
+        TValue::Nil => return s.run_error("index is nil"),
+        TValue::Float(f) if f.is_nan() => return s.run_error("index is NaN"),
+        _ => (),
+    }
+    let value = s.stack.pop().unwrap();
+    let key = s.stack.pop().unwrap();
+    if let TValue::Table(tref) = t {
+        tref.borrow_mut().set(key, value);
+    }
+    Ok(())
+}
+
+/// Returns true if the two values in indices `index1` and `index2` are
+/// primitively equal (that is, without calling the __eq metamethod).
+/// Otherwise returns false. Also returns false if any of the indices is not valid.
+pub fn raw_equal(s: &mut LuaState, index1: isize, index2: isize) -> bool {
+    s.is_index_valid(index1)
+        && s.is_index_valid(index2)
+        && luaV::raw_equal_obj(&s.index2adr(index1), &s.index2adr(index2))
+}
+
+/// Returns the raw "length" of the value at the given index: for strings,
+/// this is the string length; for tables, this is the result of the length
+/// operator ('#') with no metamethods. For other values, it is 0.
+pub fn raw_len(s: &mut LuaState, idx: isize) -> usize {
+    match s.index2adr(idx) {
+        TValue::String(s) => s.len(),
+        TValue::Table(tref) => tref.borrow().len(),
+        _ => 0,
+    }
+}
+
+/// Basic types of Lua values, as returned by [`type_of`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LuaType {
+    /// non-valid (but acceptable) index
+    None,
+    Nil,
+    Boolean,
+    LightUserData,
+    Number,
+    String,
+    Table,
+    Function,
+    UserData,
+    Thread,
+}
+
+impl LuaType {
+    /// Returns the name of the type, as returned by the Lua function `type`
+    pub fn name(&self) -> &'static str {
+        match self {
+            LuaType::None => "no value",
+            LuaType::Nil => "nil",
+            LuaType::Boolean => "boolean",
+            LuaType::LightUserData | LuaType::UserData => "userdata",
+            LuaType::Number => "number",
+            LuaType::String => "string",
+            LuaType::Table => "table",
+            LuaType::Function => "function",
+            LuaType::Thread => "thread",
+        }
+    }
+}
+
+/// Returns the type of the value in the given valid index,
+/// or [`LuaType::None`] for a non-valid (but acceptable) index.
+pub fn type_of(s: &mut LuaState, idx: isize) -> LuaType {
+    if !s.is_index_valid(idx) {
+        return LuaType::None;
+    }
+    match s.index2adr(idx) {
+        TValue::Nil => LuaType::Nil,
+        TValue::Boolean(_) => LuaType::Boolean,
+        TValue::LightUserData(_) => LuaType::LightUserData,
+        TValue::Float(_) | TValue::Integer(_) => LuaType::Number,
+        TValue::String(_) => LuaType::String,
+        TValue::Table(_) => LuaType::Table,
+        TValue::Function(_) => LuaType::Function,
+        TValue::UserData(_) => LuaType::UserData,
+        TValue::Thread() => LuaType::Thread,
+    }
+}
+
+/// Returns the name of the type encoded by the value `t`.
+pub fn type_name(_s: &mut LuaState, t: LuaType) -> &'static str {
+    t.name()
+}
+
+/// Exchanges values between different threads of the same state. This
+/// function pops `n` values from the stack `from`, and pushes them onto the
+/// stack `to`.
+pub fn xmove(from: &mut LuaState, to: &mut LuaState, n: usize) {
+    debug_assert!(n <= get_top(from));
+    let start = from.stack.len() - n;
+    to.stack.extend(from.stack.drain(start..));
+}
+
 pub fn get_meta_table(s: &mut LuaState, objindex: i32) -> bool {
     let obj = s.index2adr(objindex as isize);
     let mt = match obj {
         TValue::Table(tref) => tref.borrow().metatable.clone(),
-        TValue::UserData(_) => {
-            todo!()
-        }
+        TValue::UserData(udref) => udref.borrow().metatable.clone(),
         _ => {
             // get global type metatable
             let objtype = obj.get_type_name();
@@ -378,17 +1006,95 @@ pub fn get_meta_table(s: &mut LuaState, objindex: i32) -> bool {
 /// This function cannot be called with a pseudo-index,
 /// because a pseudo-index is not an actual stack position.
 pub fn remove(s: &mut LuaState, idx: isize) {
-    debug_assert!(idx < s.stack.len() as isize);
-    debug_assert!(idx >= -(s.stack.len() as isize));
+    debug_assert!(idx != 0 && idx > LUA_REGISTRYINDEX);
     // convert to absolute index
     let idx = if idx < 0 {
         s.stack.len() as isize + idx
     } else {
-        idx
+        s.base_ci[s.ci].func as isize + idx
     };
     s.stack.remove(idx as usize);
 }
 
+/// Moves the top element into the given valid index, shifting up the elements
+/// above this index to open space. This function cannot be called with a
+/// pseudo-index, because a pseudo-index is not an actual stack position.
+pub fn insert(s: &mut LuaState, idx: isize) {
+    rotate(s, idx, 1);
+}
+
+/// Moves the top element into the given valid index without shifting any
+/// element (therefore replacing the value at that given index), and then
+/// pops the top element.
+pub fn replace(s: &mut LuaState, idx: isize) {
+    copy(s, -1, idx);
+    s.pop_stack(1);
+}
+
+/// Rotates the stack elements between the valid index `idx` and the top of
+/// the stack. The elements are rotated `n` positions in the direction of the
+/// top, for a positive `n`, or `-n` positions in the direction of the bottom,
+/// for a negative `n`. The absolute value of `n` must not be greater than the
+/// size of the slice being rotated. This function cannot be called with a
+/// pseudo-index, because a pseudo-index is not an actual stack position.
+pub fn rotate(s: &mut LuaState, idx: isize, n: isize) {
+    debug_assert!(idx != 0 && idx > LUA_REGISTRYINDEX);
+    let start = s.base_ci[s.ci].func + abs_index(s, idx) as usize;
+    let slice = &mut s.stack[start..];
+    debug_assert!(n.unsigned_abs() <= slice.len());
+    if n >= 0 {
+        slice.rotate_right(n as usize);
+    } else {
+        slice.rotate_left((-n) as usize);
+    }
+}
+
+/// Copies the element at index `fromidx` into the valid index `toidx`,
+/// replacing the value at that position. Values at other positions are not
+/// affected.
+pub fn copy(s: &mut LuaState, fromidx: isize, toidx: isize) {
+    let value = s.index2adr(fromidx);
+    s.set_index(toidx, value);
+}
+
+/// Ensures that the stack has space for at least `n` extra slots (that is,
+/// that you can safely push up to `n` values into it). Returns false if it
+/// cannot fulfill the request, because it would cause the stack to be larger
+/// than a fixed maximum size or because it cannot allocate memory for the
+/// extra space. This function never shrinks the stack.
+pub fn check_stack(s: &mut LuaState, n: usize) -> bool {
+    let top = s.stack.len();
+    if top + n > LUAI_MAXSTACK {
+        return false;
+    }
+    if s.grow_stack(n).is_err() {
+        // drop the memory error
+        s.stack.truncate(top);
+        s.g.gc.memerr = false;
+        return false;
+    }
+    let ci = &mut s.base_ci[s.ci];
+    if ci.top < top + n {
+        // adjust frame top
+        ci.top = top + n;
+    }
+    true
+}
+
+/// Converts the string `value` to a number and pushes that number onto the
+/// stack. Returns the length of the string plus one if the conversion
+/// succeeds, or 0 otherwise, in which case nothing is pushed. The string may
+/// have leading and trailing spaces and a sign.
+pub fn string_to_number(s: &mut LuaState, value: &str) -> usize {
+    match str2number(value) {
+        Some(n) => {
+            s.stack.push(n);
+            value.len() + 1
+        }
+        None => 0,
+    }
+}
+
 /// Creates a new empty table and pushes it onto the stack.
 pub fn new_table(s: &mut LuaState) {
     create_table(s);
@@ -417,3 +1123,164 @@ pub fn is_none(s: &mut LuaState, index: isize) -> bool {
 pub fn number_to_integer(v: LuaFloat) -> LuaInteger {
     v as LuaInteger
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{api, luaL, object::TValue, LuaInteger, LuaState};
+
+    use super::{ArithOp, LuaType};
+    #[test]
+    fn rotate_insert_replace() {
+        let mut state = luaL::newstate();
+        for i in 1..=4 {
+            api::push_integer(&mut state, i);
+        }
+        api::rotate(&mut state, 2, 1); // 1 4 2 3
+        api::insert(&mut state, 1); // 3 1 4 2
+        api::push_integer(&mut state, 5);
+        api::replace(&mut state, -3); // 3 1 5 2
+        api::copy(&mut state, 1, 4); // 3 1 5 3
+        let values: Vec<_> = (1..=4)
+            .map(|i| api::to_integer(&mut state, i).unwrap())
+            .collect();
+        assert_eq!(values, [3, 1, 5, 3]);
+        assert_eq!(api::abs_index(&mut state, -1), 4);
+    }
+    #[test]
+    fn arith_and_conversions() {
+        let mut state = luaL::newstate();
+        api::push_integer(&mut state, 7);
+        api::push_integer(&mut state, 3);
+        api::arith(&mut state, ArithOp::IDiv).unwrap();
+        assert_eq!(api::to_integer(&mut state, -1), Some(2));
+        api::arith(&mut state, ArithOp::Unm).unwrap();
+        assert_eq!(api::to_integer(&mut state, -1), Some(-2));
+        assert_eq!(api::to_string(&mut state, -1).as_deref(), Some("-2"));
+        assert_eq!(api::type_of(&mut state, -1), LuaType::String);
+        assert_eq!(api::string_to_number(&mut state, " 0x10 "), 7);
+        assert_eq!(api::to_integer(&mut state, -1), Some(16));
+        assert_eq!(api::string_to_number(&mut state, "1e"), 0);
+        assert_eq!(api::type_of(&mut state, 3), LuaType::None);
+    }
+    #[test]
+    fn to_boolean() {
+        let mut state = luaL::newstate();
+        api::push_nil(&mut state);
+        api::push_boolean(&mut state, false);
+        api::push_boolean(&mut state, true);
+        api::push_integer(&mut state, 0);
+        api::push_literal(&mut state, "");
+        let values: Vec<bool> = (1..=5).map(|i| api::to_boolean(&mut state, i)).collect();
+        assert_eq!(values, [false, false, true, true, true]);
+        // a none index is false
+        assert!(!api::to_boolean(&mut state, 6));
+    }
+    #[test]
+    fn arith_semantics() {
+        let mut state = luaL::newstate();
+        let mut ar = |op: ArithOp, a: TValue, b: TValue| {
+            state.stack.push(a);
+            state.stack.push(b);
+            let res = api::arith(&mut state, op).map(|_| state.stack.pop().unwrap());
+            api::set_top(&mut state, 0);
+            res
+        };
+        let int = TValue::Integer;
+        let float = TValue::Float;
+        let st = |s: &str| TValue::from(s);
+        let (min, max) = (LuaInteger::MIN, LuaInteger::MAX);
+        // floor modulus and floor division
+        assert_eq!(ar(ArithOp::Mod, int(-3), int(2)), Ok(int(1)));
+        assert_eq!(ar(ArithOp::Mod, int(3), int(-2)), Ok(int(-1)));
+        assert_eq!(ar(ArithOp::Mod, int(min), int(-1)), Ok(int(0)));
+        assert_eq!(ar(ArithOp::Mod, float(-3.0), float(2.0)), Ok(float(1.0)));
+        assert_eq!(ar(ArithOp::Mod, float(5.5), float(-2.0)), Ok(float(-0.5)));
+        assert_eq!(ar(ArithOp::IDiv, int(-3), int(2)), Ok(int(-2)));
+        assert_eq!(ar(ArithOp::IDiv, int(3), int(-2)), Ok(int(-2)));
+        assert_eq!(ar(ArithOp::IDiv, int(min), int(-1)), Ok(int(min)));
+        assert_eq!(ar(ArithOp::IDiv, float(-3.0), float(2.0)), Ok(float(-2.0)));
+        assert!(ar(ArithOp::Mod, int(1), int(0)).is_err());
+        assert!(ar(ArithOp::IDiv, int(1), int(0)).is_err());
+        // integer overflows wrap around
+        assert_eq!(ar(ArithOp::Add, int(max), int(1)), Ok(int(min)));
+        assert_eq!(ar(ArithOp::Sub, int(min), int(1)), Ok(int(max)));
+        assert_eq!(ar(ArithOp::Mul, int(max), int(2)), Ok(int(-2)));
+        assert_eq!(ar(ArithOp::Unm, int(min), int(min)), Ok(int(min)));
+        // logical shifts
+        assert_eq!(ar(ArithOp::Shl, int(1), int(63)), Ok(int(min)));
+        assert_eq!(ar(ArithOp::Shl, int(1), int(64)), Ok(int(0)));
+        assert_eq!(ar(ArithOp::Shl, int(4), int(-1)), Ok(int(2)));
+        assert_eq!(ar(ArithOp::Shr, int(-1), int(1)), Ok(int(max)));
+        assert_eq!(ar(ArithOp::Shr, int(-1), int(64)), Ok(int(0)));
+        assert_eq!(ar(ArithOp::Shr, int(2), int(-1)), Ok(int(4)));
+        assert_eq!(ar(ArithOp::Shr, int(1), int(min)), Ok(int(0)));
+        // numeric strings keep their kind
+        assert_eq!(ar(ArithOp::Add, st("10"), int(1)), Ok(int(11)));
+        assert_eq!(ar(ArithOp::Add, st("10.0"), int(1)), Ok(float(11.0)));
+        assert_eq!(ar(ArithOp::Mod, st("-3"), int(2)), Ok(int(1)));
+        assert_eq!(ar(ArithOp::BAnd, st("0x13"), int(3)), Ok(int(3)));
+        assert!(ar(ArithOp::IDiv, st("7"), st("0")).is_err());
+    }
+    #[test]
+    fn table_access() {
+        let mut state = luaL::newstate();
+        api::new_table(&mut state);
+        api::push_string(&mut state, "x");
+        api::raw_set_i(&mut state, 1, 1);
+        api::push_string(&mut state, "k");
+        api::push_integer(&mut state, 42);
+        api::set_table(&mut state, 1).unwrap();
+        api::push_string(&mut state, "k");
+        api::get_table(&mut state, 1).unwrap();
+        assert_eq!(api::to_integer(&mut state, -1), Some(42));
+        api::raw_get_i(&mut state, 1, 1);
+        assert_eq!(api::to_string(&mut state, -1).as_deref(), Some("x"));
+        assert_eq!(api::raw_len(&mut state, 1), 1);
+        assert!(api::check_stack(&mut state, 100));
+        assert!(!api::check_stack(&mut state, usize::MAX / 2));
+    }
+    #[test]
+    fn stack_overflow() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "local function f(n) return 1 + f(n + 1) end ok, err = pcall(f, 1)",
+        )
+        .unwrap();
+        api::get_global(&mut state, "ok");
+        assert!(!api::to_boolean(&mut state, -1));
+        api::get_global(&mut state, "err");
+        assert!(api::to_string(&mut state, -1).unwrap().ends_with("stack overflow"));
+    }
+    fn counter(s: &mut LuaState) -> Result<i32, ()> {
+        let n = api::to_integer(s, api::upvalue_index(1)).unwrap() + 1;
+        api::push_integer(s, n);
+        api::copy(s, -1, api::upvalue_index(1));
+        Ok(1)
+    }
+    #[test]
+    fn rust_upvalues() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        api::push_integer(&mut state, 10);
+        api::push_rust_function(&mut state, counter, 1);
+        api::set_global(&mut state, "counter");
+        luaL::dostring(&mut state, "counter() z = counter()").unwrap();
+        api::get_global(&mut state, "z");
+        assert_eq!(api::to_integer(&mut state, -1), Some(12));
+        api::get_global(&mut state, "counter");
+        assert_eq!(api::get_upvalue(&mut state, -1, 1).as_deref(), Some(""));
+        assert_eq!(api::to_integer(&mut state, -1), Some(12));
+        assert!(api::get_upvalue(&mut state, -2, 2).is_none());
+        assert!(api::is_none(&mut state, api::upvalue_index(1)));
+        // share the counter with a Lua closure
+        luaL::dostring(&mut state, "local n = 0 function get() return n end").unwrap();
+        api::get_global(&mut state, "get");
+        api::upvalue_join(&mut state, -1, 1, -3, 1);
+        assert_eq!(api::upvalue_id(&mut state, -1, 1), api::upvalue_id(&mut state, -3, 1));
+        luaL::dostring(&mut state, "counter() z = get()").unwrap();
+        api::get_global(&mut state, "z");
+        assert_eq!(api::to_integer(&mut state, -1), Some(13));
+    }
+}
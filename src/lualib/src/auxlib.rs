@@ -1,9 +1,18 @@
 //! Auxiliary functions for building Lua libraries
 
+use std::{
+    cell::RefCell,
+    io::Read,
+    rc::{Rc, Weak},
+};
+
 use crate::{
-    api::{self, LuaError},
-    luaH::TableRef,
-    object::TValue,
+    api::{self, LuaDebug, LuaError, LuaType},
+    chunk::CompiledChunk,
+    fs::OpenMode,
+    luaG,
+    luaH::{Table, TableRef},
+    object::{chunk_id, TValue, UserDataRef},
     state::LuaState,
     LuaFloat, LuaInteger, LuaRustFunction, LUA_MULTRET, LUA_REGISTRYINDEX,
 };
@@ -35,14 +44,93 @@ pub fn newstate() -> LuaState {
     state
 }
 
+/// Loads a string as a Lua chunk named `name`. `mode` works as in [`api::load`].
+pub fn loadbufferx(
+    state: &mut LuaState,
+    buff: &str,
+    name: &str,
+    mode: Option<&str>,
+) -> Result<i32, LuaError> {
+    api::load(state, get_s, buff, Some(name), mode)
+}
+
+pub fn loadbuffer(state: &mut LuaState, buff: &str, name: &str) -> Result<i32, LuaError> {
+    loadbufferx(state, buff, name, None)
+}
+
 pub fn loadstring(state: &mut LuaState, s: &str) -> Result<i32, LuaError> {
-    api::load(state, get_s, s, Some(s))
+    loadbuffer(state, s, s)
+}
+
+/// Compiles a string as a Lua chunk named `name` without loading it. The
+/// compiled chunk can be loaded in any state with [`api::load_compiled`].
+/// On error, pushes the error message.
+pub fn compile(state: &mut LuaState, buff: &str, name: &str) -> Result<CompiledChunk, LuaError> {
+    loadbuffer(state, buff, name)?;
+    match state.stack.pop() {
+        Some(TValue::Function(cl)) => Ok(CompiledChunk::new(cl.borrow().get_proto())),
+        _ => unreachable!(),
+    }
 }
 
 pub fn dostring(state: &mut LuaState, s: &str) -> Result<i32, LuaError> {
     loadstring(state, s).and_then(|_| api::pcall(state, 0, LUA_MULTRET, 0))
 }
 
+/// Loads a file as a Lua chunk and pushes the compiled chunk as a function.
+/// Reads the standard input if `filename` is None.
+/// The first line of the file is ignored if it starts with a `#`.
+/// `mode` works as in [`api::load`].
+pub fn loadfilex(
+    state: &mut LuaState,
+    filename: Option<&str>,
+    mode: Option<&str>,
+) -> Result<i32, LuaError> {
+    let (chunkname, content) = match filename {
+        Some(fname) => {
+            let content = state.g.fs.open(fname, OpenMode::from_c_mode("r")).and_then(|mut f| {
+                let mut buf = Vec::new();
+                f.read_to_end(&mut buf).map(|_| buf)
+            });
+            (format!("@{}", fname), content)
+        }
+        None => {
+            let mut buf = Vec::new();
+            let content = std::io::stdin().read_to_end(&mut buf).map(|_| buf);
+            ("=stdin".to_owned(), content)
+        }
+    };
+    let content = match content {
+        Ok(content) => String::from_utf8_lossy(&content).into_owned(),
+        Err(e) => {
+            let what = if filename.is_some() { "open" } else { "read" };
+            state.push_string(&format!(
+                "cannot {} {}: {}",
+                what,
+                &chunkname[1..],
+                os_error_message(&e)
+            ));
+            return Err(LuaError::FileError);
+        }
+    };
+    // skip the first line if it is a comment (unix exec. file),
+    // keeping the newline so that line numbers are not changed
+    let code = if content.starts_with('#') {
+        &content[content.find('\n').unwrap_or(content.len())..]
+    } else {
+        &content[..]
+    };
+    api::load(state, get_s, code, Some(&chunkname), mode)
+}
+
+pub fn loadfile(state: &mut LuaState, filename: Option<&str>) -> Result<i32, LuaError> {
+    loadfilex(state, filename, None)
+}
+
+pub fn dofile(state: &mut LuaState, filename: Option<&str>) -> Result<i32, LuaError> {
+    loadfile(state, filename).and_then(|_| api::pcall(state, 0, LUA_MULTRET, 0))
+}
+
 pub fn error(state: &mut LuaState, msg: &str) -> Result<(), LuaError> {
     lwhere(state, 1);
     state.push_string(msg);
@@ -50,12 +138,86 @@ pub fn error(state: &mut LuaState, msg: &str) -> Result<(), LuaError> {
     api::error(state)
 }
 
-fn lwhere(_state: &mut LuaState, _arg: i32) {
-    todo!();
+/// Pushes onto the stack a string identifying the current position
+/// of the control at level `level` in the call stack.
+/// Level 0 is the running function, level 1 is the function that
+/// called the running function, etc.
+pub fn lwhere(state: &mut LuaState, level: usize) {
+    match luaG::current_position(state, level) {
+        Some((source, line)) => state.push_string(&format!("{}:{}: ", chunk_id(&source), line)),
+        // else, no information available...
+        None => state.push_literal(""),
+    }
 }
 
-pub fn typename(s: &LuaState, index: isize) -> String {
-    s.index2adr(index).get_type_name().to_owned()
+/// size of the first part of the stack
+const LEVELS1: usize = 10;
+/// size of the second part of the stack
+const LEVELS2: usize = 11;
+
+/// Returns a name for the function described by `ar`
+fn func_name(state: &mut LuaState, ar: &mut LuaDebug) -> String {
+    api::get_info(state, "f", ar); // push function
+    let func = state.stack.pop().unwrap();
+    if let Some(name) = global_func_name(state, &func) {
+        // try first a global name
+        format!("function '{}'", name)
+    } else if !ar.namewhat.is_empty() {
+        // is there a name from code?
+        format!("{} '{}'", ar.namewhat, ar.name.as_deref().unwrap_or("?")) // use it
+    } else if ar.what == "main" {
+        "main chunk".to_owned()
+    } else if ar.what != "C" {
+        // for Lua functions, use <file:line>
+        format!("function <{}:{}>", ar.short_src, ar.linedefined)
+    } else {
+        // nothing left...
+        "?".to_owned()
+    }
+}
+
+/// Creates and pushes a traceback of the stack, starting at `level`.
+/// If `msg` is not None it is appended at the beginning of the traceback.
+pub fn traceback(state: &mut LuaState, msg: Option<&str>, level: usize) {
+    let mut tb = String::new();
+    if let Some(msg) = msg {
+        tb.push_str(msg);
+        tb.push('\n');
+    }
+    tb.push_str("stack traceback:");
+    let last = state.ci; // number of levels
+    let mut n1 = if last.saturating_sub(level) > LEVELS1 + LEVELS2 {
+        Some(LEVELS1)
+    } else {
+        None
+    };
+    let mut level = level;
+    while let Some(mut ar) = api::get_stack(state, level) {
+        level += 1;
+        if n1 == Some(0) {
+            // too many levels?
+            tb.push_str("\n\t...");
+            level = last - LEVELS2; // and skip to last ones
+            n1 = None;
+            continue;
+        }
+        n1 = n1.map(|n| n - 1);
+        api::get_info(state, "Slnt", &mut ar);
+        tb.push_str(&format!("\n\t{}:", ar.short_src));
+        if ar.currentline > 0 {
+            tb.push_str(&format!("{}:", ar.currentline));
+        }
+        tb.push_str(" in ");
+        tb.push_str(&func_name(state, &mut ar));
+        if ar.istailcall {
+            tb.push_str("\n\t(...tail calls...)");
+        }
+    }
+    state.push_string(&tb);
+}
+
+pub fn typename(s: &mut LuaState, index: isize) -> String {
+    api::type_of(s, index).name().to_owned()
 }
 
 pub fn check_number(s: &mut LuaState, index: isize) -> Result<LuaFloat, ()> {
@@ -86,10 +248,14 @@ pub fn check_boolean(s: &mut LuaState, index: isize) -> Result<bool, ()> {
 
 pub fn check_integer(s: &mut LuaState, index: isize) -> Result<LuaInteger, ()> {
     match api::to_integer(s, index) {
-        None => {
+        None if api::is_number(s, index) => {
             arg_error(s, index, "number has no integer representation").map_err(|_| ())?;
             unreachable!()
         }
+        None => {
+            type_error(s, index, "number").map_err(|_| ())?;
+            unreachable!()
+        }
         Some(value) => Ok(value),
     }
 }
@@ -114,34 +280,170 @@ pub fn check_table(s: &mut LuaState, index: isize) -> Result<TableRef, ()> {
     }
 }
 
+/// Checks whether the function argument `index` has type `t`
+pub fn check_type(s: &mut LuaState, index: isize, t: LuaType) -> Result<(), ()> {
+    if api::type_of(s, index) != t {
+        type_error(s, index, t.name()).map_err(|_| ())?;
+    }
+    Ok(())
+}
+
+/// Checks whether the function argument `narg` is a userdata of the type `tname`
+/// (see [`new_metatable`]) and returns it, or returns None.
+pub fn test_udata(s: &mut LuaState, narg: isize, tname: &str) -> Option<UserDataRef> {
+    let udref = api::to_userdata(s, narg)?;
+    let mt = udref.borrow().metatable.clone()?;
+    get_meta_table(s, tname); // get correct metatable
+    let expected = s.stack.pop().unwrap();
+    match expected {
+        TValue::Table(expected) if Rc::ptr_eq(&mt, &expected) => Some(udref),
+        _ => None,
+    }
+}
+
+/// Checks whether the function argument `narg` is a userdata of the type `tname`
+/// (see [`new_metatable`]) and returns it.
+pub fn check_udata(s: &mut LuaState, narg: isize, tname: &str) -> Result<UserDataRef, ()> {
+    match test_udata(s, narg, tname) {
+        Some(udref) => Ok(udref),
+        None => {
+            let _ = type_error(s, narg, tname);
+            Err(())
+        }
+    }
+}
+
 pub(crate) fn type_error(
     s: &mut LuaState,
     index: isize,
     expected_type: &str,
 ) -> Result<(), LuaError> {
-    let value = s.index2adr(index);
-    let tname = value.get_type_name();
+    let tname = if get_meta_field(s, index as i32, "__name") {
+        // use the given type name
+        match s.stack.pop().unwrap() {
+            TValue::String(name) => name.to_string(),
+            _ => typename(s, index),
+        }
+    } else {
+        typename(s, index) // standard name
+    };
     let msg = format!("{} expected, got {}", expected_type, tname);
-    s.push_string(&msg);
     arg_error(s, index, &msg)
 }
 
-pub(crate) fn arg_error(
+/// Searches `package.loaded` for a field holding the running function and
+/// returns its qualified name, without the "_G." prefix for global functions.
+fn global_func_name(s: &mut LuaState, func: &TValue) -> Option<String> {
+    api::get_field(s, LUA_REGISTRYINDEX, "_LOADED");
+    let loaded = s.stack.pop().unwrap();
+    let TValue::Table(loaded) = loaded else {
+        return None;
+    };
+    let mut names = Vec::new();
+    for (modname, module) in loaded.borrow().pairs() {
+        let (TValue::String(modname), TValue::Table(module)) = (modname, module) else {
+            continue;
+        };
+        for (key, value) in module.borrow().pairs() {
+            match key {
+                TValue::String(key) if value == func => {
+                    names.push(if modname.as_str() == "_G" {
+                        key.to_string()
+                    } else {
+                        format!("{}.{}", modname, key)
+                    })
+                }
+                _ => (),
+            }
+        }
+    }
+    // several names are possible. use the shortest one
+    names.into_iter().min_by(|a, b| a.len().cmp(&b.len()).then(a.cmp(b)))
+}
+
+/// Raises an error reporting a problem with argument `narg` of the Rust
+/// function that called it, using a standard message that includes
+/// `extra_msg` as a comment: `bad argument #narg to 'funcname' (extra_msg)`
+pub fn arg_error(
     state: &mut LuaState,
     narg: isize,
     extra_msg: &str,
 ) -> Result<(), LuaError> {
-    // TODO
-    state.push_string(&format!("bad argument #{} ({})", narg, extra_msg));
-    Err(LuaError::RuntimeError)
+    if state.ci == 0 {
+        // no stack frame?
+        return error(state, &format!("bad argument #{} ({})", narg, extra_msg));
+    }
+    let mut narg = narg;
+    let name = match luaG::get_func_name(state, state.ci) {
+        Some(("method", name)) => {
+            narg -= 1; // do not count 'self'
+            if narg == 0 {
+                // error is in the self argument itself?
+                return error(
+                    state,
+                    &format!("calling '{}' on bad self ({})", name, extra_msg),
+                );
+            }
+            Some(name)
+        }
+        Some((_, name)) => Some(name),
+        None => None,
+    };
+    let name = name
+        .or_else(|| {
+            let func = state.stack[state.base_ci[state.ci].func].clone();
+            global_func_name(state, &func)
+        })
+        .unwrap_or_else(|| "?".to_owned());
+    error(
+        state,
+        &format!("bad argument #{} to '{}' ({})", narg, name, extra_msg),
+    )
+}
+
+/// Checks whether the function argument `narg` is a string and searches for
+/// this string in `list`. Returns the index in the list where the string was found.
+/// Raises an error if the argument is not a string or if the string cannot be found.
+/// If `def` is not None, it is used as a default value when there is no argument
+/// `narg` or when this argument is nil.
+pub fn check_option(
+    s: &mut LuaState,
+    narg: isize,
+    def: Option<&str>,
+    list: &[&str],
+) -> Result<usize, ()> {
+    let name = match def {
+        Some(def) if api::is_none_or_nil(s, narg) => def.to_owned(),
+        _ => check_string(s, narg)?,
+    };
+    match list.iter().position(|&opt| opt == name) {
+        Some(i) => Ok(i),
+        None => {
+            arg_error(s, narg, &format!("invalid option '{}'", name)).map_err(|_| ())?;
+            unreachable!()
+        }
+    }
 }
 
-pub fn opt_integer(state: &mut LuaState, narg: i32) -> Option<LuaInteger> {
-    check_integer(state, narg as isize).ok()
+/// If the function argument `narg` is an integer (or convertible to an integer),
+/// returns this integer. If this argument is absent or is nil, returns `def`.
+/// Otherwise, raises an error.
+pub fn opt_integer(state: &mut LuaState, narg: isize, def: LuaInteger) -> Result<LuaInteger, ()> {
+    if api::is_none_or_nil(state, narg) {
+        Ok(def)
+    } else {
+        check_integer(state, narg)
+    }
 }
 
-pub fn opt_number(state: &mut LuaState, narg: i32) -> Option<LuaFloat> {
-    check_number(state, narg as isize).ok()
+/// If the function argument `narg` is a number, returns this number.
+/// If this argument is absent or is nil, returns `def`. Otherwise, raises an error.
+pub fn opt_number(state: &mut LuaState, narg: isize, def: LuaFloat) -> Result<LuaFloat, ()> {
+    if api::is_none_or_nil(state, narg) {
+        Ok(def)
+    } else {
+        check_number(state, narg)
+    }
 }
 
 pub fn opt_boolean(state: &mut LuaState, narg: i32) -> Option<bool> {
@@ -152,8 +454,14 @@ pub fn opt_table(state: &mut LuaState, narg: i32) -> Option<TableRef> {
     check_table(state, narg as isize).ok()
 }
 
-pub fn opt_string(state: &mut LuaState, narg: i32) -> Option<String> {
-    check_string(state, narg as isize).ok()
+/// If the function argument `narg` is a string, returns this string.
+/// If this argument is absent or is nil, returns `def`. Otherwise, raises an error.
+pub fn opt_string(state: &mut LuaState, narg: isize, def: &str) -> Result<String, ()> {
+    if api::is_none_or_nil(state, narg) {
+        Ok(def.to_owned())
+    } else {
+        check_string(state, narg)
+    }
 }
 
 pub fn obj_len(state: &mut LuaState, idx: i32) -> usize {
@@ -165,6 +473,22 @@ pub fn obj_len(state: &mut LuaState, idx: i32) -> usize {
     }
 }
 
+/// Returns the "length" of the value at the given index as a number;
+/// it is equivalent to the '#' operator in Lua. Raises an error if the
+/// result of the operation is not an integer.
+pub fn len(s: &mut LuaState, idx: isize) -> Result<LuaInteger, LuaError> {
+    api::len(s, idx)?;
+    let len = api::to_integer(s, -1);
+    api::pop(s, 1); // remove object
+    match len {
+        Some(len) => Ok(len),
+        None => {
+            error(s, "object length is not an integer")?;
+            unreachable!()
+        }
+    }
+}
+
 /// set functions from list 'l' into table at top - 'nup'; each
 /// function gets the 'nup' elements at the top as upvalues.
 /// Returns with only the table at the stack.
@@ -197,6 +521,55 @@ pub fn get_meta_field(s: &mut LuaState, obj: i32, event: &str) -> bool {
     }
 }
 
+/// Calls a metamethod. If the object at index `obj` has a metatable and this
+/// metatable has a field `event`, calls this field passing the object as its
+/// only argument, pushes the value returned by the call and returns true.
+/// If there is no metatable or no metamethod, returns false (without pushing
+/// any value on the stack).
+pub fn call_meta(s: &mut LuaState, obj: isize, event: &str) -> Result<bool, LuaError> {
+    let obj = api::abs_index(s, obj);
+    if !get_meta_field(s, obj as i32, event) {
+        // no metafield?
+        return Ok(false);
+    }
+    api::push_value(s, obj);
+    api::call(s, 1, 1)?;
+    Ok(true)
+}
+
+/// Converts any Lua value at the given index to a string in a reasonable
+/// format, honouring the `__tostring` and `__name` metafields. The resulting
+/// string is pushed onto the stack and also returned by the function.
+pub fn to_lstring(s: &mut LuaState, idx: isize) -> Result<String, LuaError> {
+    if call_meta(s, idx, "__tostring")? {
+        // metafield?
+        if !api::is_string(s, -1) {
+            error(s, "'__tostring' must return a string")?;
+        }
+    } else {
+        let value = s.index2adr(idx);
+        match value {
+            TValue::Nil | TValue::Boolean(_) | TValue::Integer(_) | TValue::Float(_) => {
+                s.push_string(&value.to_string())
+            }
+            TValue::String(_) => api::push_value(s, idx),
+            _ => {
+                let kind = if get_meta_field(s, idx as i32, "__name") {
+                    // try name
+                    match s.stack.pop().unwrap() {
+                        TValue::String(name) => name.to_string(),
+                        _ => typename(s, idx),
+                    }
+                } else {
+                    typename(s, idx)
+                };
+                s.push_string(&format!("{}: {:?}", kind, value.to_pointer()));
+            }
+        }
+    }
+    Ok(api::to_string(s, -1).unwrap())
+}
+
 /// Creates a new table and registers there the functions in list `funcs`
 pub fn new_lib(state: &mut LuaState, funcs: &[LibReg]) {
     api::create_table(state);
@@ -208,7 +581,7 @@ pub fn new_lib(state: &mut LuaState, funcs: &[LibReg]) {
 /// adds it to the registry with key tname, and returns true.
 /// In both cases pushes onto the stack the final value associated with tname
 /// in the registry.
-pub(crate) fn new_metatable(s: &mut LuaState, tname: &str) -> bool {
+pub fn new_metatable(s: &mut LuaState, tname: &str) -> bool {
     get_meta_table(s, tname); // try to get metatable
     if !api::is_nil(s, -1) {
         // name already in use?
@@ -216,6 +589,8 @@ pub(crate) fn new_metatable(s: &mut LuaState, tname: &str) -> bool {
     } else {
         api::pop(s, 1);
         api::new_table(s); // create metatable
+        api::push_string(s, tname);
+        api::set_field(s, -2, "__name"); // metatable.__name = tname
         api::push_value(s, -1);
         api::set_field(s, LUA_REGISTRYINDEX, tname); // registry.name = metatable
         true
@@ -227,6 +602,13 @@ fn get_meta_table(s: &mut LuaState, tname: &str) {
     api::get_field(s, LUA_REGISTRYINDEX, tname);
 }
 
+/// Sets the metatable of the object at the top of the stack as the metatable
+/// associated with name tname in the registry (see [`new_metatable`]).
+pub fn set_metatable(s: &mut LuaState, tname: &str) {
+    get_meta_table(s, tname);
+    api::set_metatable(s, -2);
+}
+
 /// Calls function openf with string modname as an argument
 /// and sets the call result in package.loaded[modname],
 /// as if that function has been called through require.
@@ -277,3 +659,285 @@ pub fn check_any(s: &mut LuaState, index: isize) -> Result<(), LuaError> {
         arg_error(s, index, "value expected")
     }
 }
+
+/// Grows the stack size to top + `space` elements, raising an error if the
+/// stack cannot grow to that size. `msg` is an additional text to go into
+/// the error message.
+pub fn check_stack(s: &mut LuaState, space: usize, msg: Option<&str>) -> Result<(), LuaError> {
+    if api::check_stack(s, space) {
+        return Ok(());
+    }
+    match msg {
+        Some(msg) => error(s, &format!("stack overflow ({})", msg)),
+        None => error(s, "stack overflow"),
+    }
+}
+
+/// Reference returned by [`reference`] for a nil value
+pub const LUA_REFNIL: i32 = -1;
+/// Value that is never returned by [`reference`]: it can be used to mark
+/// a variable that holds no reference
+pub const LUA_NOREF: i32 = -2;
+
+/// Index of the free list of references in a reference table
+const FREELIST: LuaInteger = 0;
+
+/// Creates and returns a reference, in the table at index `t`, for the object
+/// at the top of the stack (and pops the object). The reference is a unique
+/// integer key: as long as it is not released with [`unreference`], the
+/// object can be retrieved with `api::raw_get_i(s, t, r)`. If the object is
+/// nil, returns [`LUA_REFNIL`]. Released references are reused.
+pub fn reference(s: &mut LuaState, t: isize) -> i32 {
+    if api::is_nil(s, -1) {
+        api::pop(s, 1); // remove it from stack
+        return LUA_REFNIL;
+    }
+    let t = api::abs_index(s, t);
+    api::raw_get_i(s, t, FREELIST); // get first free element
+    let r = api::to_integer(s, -1).unwrap_or(0); // ref = t[freelist]
+    api::pop(s, 1); // remove it from stack
+    let r = if r != 0 {
+        // any free element?
+        api::raw_get_i(s, t, r); // remove it from list
+        api::raw_set_i(s, t, FREELIST); // (t[freelist] = t[ref])
+        r
+    } else {
+        // no free elements: get a new reference
+        api::raw_len(s, t) as LuaInteger + 1
+    };
+    api::raw_set_i(s, t, r);
+    r as i32
+}
+
+/// Releases reference `r` from the table at index `t`. The entry is removed
+/// from the table, so that the referred object can be collected. The
+/// reference `r` is also freed to be used again. If `r` is [`LUA_NOREF`] or
+/// [`LUA_REFNIL`], does nothing.
+pub fn unreference(s: &mut LuaState, t: isize, r: i32) {
+    if r >= 0 {
+        let t = api::abs_index(s, t);
+        // keep the referred object on the stack while the table is updated:
+        // dropping it may release other references of the same table
+        api::raw_get_i(s, t, r as LuaInteger);
+        api::raw_get_i(s, t, FREELIST);
+        api::raw_set_i(s, t, r as LuaInteger); // t[ref] = t[freelist]
+        api::push_integer(s, r as LuaInteger);
+        api::raw_set_i(s, t, FREELIST); // t[freelist] = ref
+        api::pop(s, 1); // release the object
+    }
+}
+
+/// A value kept in the registry of a state, so that Rust code can hold it
+/// across calls. The reference is released when the key is dropped. The key
+/// does not keep the state's registry alive.
+pub struct RegistryKey {
+    registry: Weak<RefCell<Table>>,
+    reference: i32,
+}
+
+impl RegistryKey {
+    /// Pops the value at the top of the stack and stores it in the registry
+    pub fn new(s: &mut LuaState) -> Self {
+        let reference = reference(s, LUA_REGISTRYINDEX);
+        let TValue::Table(registry) = s.index2adr(LUA_REGISTRYINDEX) else {
+            unreachable!()
+        };
+        Self {
+            registry: Rc::downgrade(&registry),
+            reference,
+        }
+    }
+    /// Returns the reference of the value in the registry
+    pub fn id(&self) -> i32 {
+        self.reference
+    }
+    /// Returns true if the key was created by the state `s`
+    pub fn owned_by(&self, s: &mut LuaState) -> bool {
+        match s.index2adr(LUA_REGISTRYINDEX) {
+            TValue::Table(registry) => Weak::ptr_eq(&Rc::downgrade(&registry), &self.registry),
+            _ => false,
+        }
+    }
+    /// Pushes the value onto the stack. Raises an error if the key was
+    /// created by another state.
+    pub fn push(&self, s: &mut LuaState) -> Result<(), LuaError> {
+        if !self.owned_by(s) {
+            return error(s, "registry key used with a different state");
+        }
+        if self.reference == LUA_REFNIL {
+            api::push_nil(s);
+        } else {
+            api::raw_get_i(s, LUA_REGISTRYINDEX, self.reference as LuaInteger);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for RegistryKey {
+    fn drop(&mut self) {
+        if self.reference < 0 {
+            return;
+        }
+        let Some(registry) = self.registry.upgrade() else {
+            // the state is gone
+            return;
+        };
+        // same as unreference, without a state. The referred object is
+        // dropped once the registry is released, as it may hold other keys.
+        // The registry is already borrowed when this key is dropped by an
+        // update of the registry itself: the reference is then leaked.
+        let old = match registry.try_borrow_mut() {
+            Ok(mut registry) => {
+                let r = TValue::Integer(self.reference as LuaInteger);
+                let old = registry.get_num(self.reference as usize).clone();
+                let free = registry.get_num(FREELIST as usize).clone();
+                registry.set(r.clone(), free);
+                registry.set_num(FREELIST as usize, r);
+                old
+            }
+            Err(_) => return,
+        };
+        drop(old);
+    }
+}
+
+/// Returns the text of an operating system error, without the error code
+/// appended by rust (same as C `strerror`)
+pub(crate) fn os_error_message(e: &std::io::Error) -> String {
+    let msg = e.to_string();
+    match msg.rfind(" (os error ") {
+        Some(pos) => msg[..pos].to_owned(),
+        None => msg,
+    }
+}
+
+/// This function produces the return values for file-related functions
+/// in the standard library (io.open, os.rename, file:seek, etc.).
+pub fn file_result(s: &mut LuaState, res: std::io::Result<()>, fname: Option<&str>) -> i32 {
+    match res {
+        Ok(()) => {
+            api::push_boolean(s, true);
+            1
+        }
+        Err(e) => {
+            api::push_nil(s);
+            let msg = os_error_message(&e);
+            match fname {
+                Some(fname) => api::push_string(s, &format!("{}: {}", fname, msg)),
+                None => api::push_string(s, &msg),
+            }
+            api::push_integer(s, e.raw_os_error().unwrap_or(0) as LuaInteger);
+            3
+        }
+    }
+}
+
+/// This function produces the return values for process-related functions
+/// in the standard library (os.execute and io.close).
+pub fn exec_result(s: &mut LuaState, res: std::io::Result<std::process::ExitStatus>) -> i32 {
+    let status = match res {
+        Ok(status) => status,
+        Err(e) => return file_result(s, Err(e), None),
+    };
+    let (what, code) = match status.code() {
+        Some(code) => ("exit", code),
+        None => ("signal", exit_signal(&status)),
+    };
+    if what == "exit" && code == 0 {
+        api::push_boolean(s, true);
+    } else {
+        api::push_nil(s);
+    }
+    api::push_string(s, what);
+    api::push_integer(s, code as LuaInteger);
+    3 // return true/nil,what,code
+}
+
+#[cfg(unix)]
+fn exit_signal(status: &std::process::ExitStatus) -> i32 {
+    use std::os::unix::process::ExitStatusExt;
+    status.signal().unwrap_or(0)
+}
+
+#[cfg(not(unix))]
+fn exit_signal(_status: &std::process::ExitStatus) -> i32 {
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{api, luaL, LuaInteger, LUA_REGISTRYINDEX};
+
+    use super::{RegistryKey, LUA_REFNIL};
+    #[test]
+    fn references() {
+        let mut state = luaL::newstate();
+        api::push_nil(&mut state);
+        assert_eq!(luaL::reference(&mut state, LUA_REGISTRYINDEX), LUA_REFNIL);
+        api::push_string(&mut state, "a");
+        let a = luaL::reference(&mut state, LUA_REGISTRYINDEX);
+        api::push_string(&mut state, "b");
+        let b = luaL::reference(&mut state, LUA_REGISTRYINDEX);
+        assert!(a > 0 && b > 0 && a != b);
+        assert_eq!(api::get_top(&mut state), 0);
+        luaL::unreference(&mut state, LUA_REGISTRYINDEX, a);
+        api::raw_get_i(&mut state, LUA_REGISTRYINDEX, b as LuaInteger);
+        assert_eq!(api::to_string(&mut state, -1).as_deref(), Some("b"));
+        // the released reference is reused
+        api::push_string(&mut state, "c");
+        assert_eq!(luaL::reference(&mut state, LUA_REGISTRYINDEX), a);
+        api::raw_get_i(&mut state, LUA_REGISTRYINDEX, a as LuaInteger);
+        assert_eq!(api::to_string(&mut state, -1).as_deref(), Some("c"));
+        // predefined values are untouched
+        luaL::dostring(&mut state, "x = 1").unwrap();
+        api::get_global(&mut state, "x");
+        assert_eq!(api::to_integer(&mut state, -1), Some(1));
+    }
+    #[test]
+    fn registry_key() {
+        let mut state = luaL::newstate();
+        luaL::dostring(&mut state, "function f() return 42 end").unwrap();
+        api::get_global(&mut state, "f");
+        let key = RegistryKey::new(&mut state);
+        let id = key.id();
+        luaL::dostring(&mut state, "f = nil").unwrap();
+        key.push(&mut state).unwrap();
+        api::call(&mut state, 0, 1).unwrap();
+        assert_eq!(api::to_integer(&mut state, -1), Some(42));
+        let mut other = luaL::newstate();
+        assert!(!key.owned_by(&mut other));
+        assert!(key.push(&mut other).is_err());
+        drop(key);
+        api::push_boolean(&mut state, true);
+        assert_eq!(RegistryKey::new(&mut state).id(), id);
+    }
+    #[test]
+    fn nested_registry_keys() {
+        struct Holder {
+            _key: RegistryKey,
+        }
+        let mut state = luaL::newstate();
+        api::push_string(&mut state, "inner");
+        let inner = RegistryKey::new(&mut state);
+        let inner_id = inner.id();
+        api::new_userdata(&mut state, Holder { _key: inner });
+        let outer = RegistryKey::new(&mut state);
+        // dropping the userdata drops the key it holds
+        drop(outer);
+        api::push_boolean(&mut state, true);
+        let a = RegistryKey::new(&mut state);
+        api::push_boolean(&mut state, true);
+        let b = RegistryKey::new(&mut state);
+        assert!(a.id() == inner_id || b.id() == inner_id);
+        // same thing when the reference is released through the stack API
+        api::push_string(&mut state, "inner");
+        let inner = RegistryKey::new(&mut state);
+        api::new_userdata(&mut state, Holder { _key: inner });
+        let r = luaL::reference(&mut state, LUA_REGISTRYINDEX);
+        luaL::unreference(&mut state, LUA_REGISTRYINDEX, r);
+        assert_eq!(api::get_top(&mut state), 0);
+        // a key can outlive its state
+        drop(state);
+        drop(a);
+    }
+}
@@ -0,0 +1,207 @@
+//! Compiled chunks shared between states
+//!
+//! A [`CompiledChunk`] is an immutable copy of the prototypes of a compiled
+//! chunk that does not reference any state. It is compiled once with
+//! [`crate::luaL::compile`] and can be loaded in any number of states with
+//! [`crate::api::load_compiled`], including states running on other threads.
+//! The bytecode, the line information, the local variables and the upvalues
+//! descriptions are shared by the chunk and the functions loaded from it. The
+//! constants are converted to Lua values each time the chunk is loaded.
+
+use std::{rc::Rc, sync::Arc};
+
+use crate::{
+    limits::Instruction,
+    object::{LocVar, Proto, TValue},
+    parser::UpValDesc,
+    LuaFloat, LuaInteger,
+};
+
+/// constant of a compiled function
+enum Constant {
+    Nil,
+    Boolean(bool),
+    Integer(LuaInteger),
+    Float(LuaFloat),
+    String(String),
+}
+
+impl From<&TValue> for Constant {
+    fn from(value: &TValue) -> Self {
+        match value {
+            TValue::Nil => Constant::Nil,
+            TValue::Boolean(b) => Constant::Boolean(*b),
+            TValue::Integer(i) => Constant::Integer(*i),
+            TValue::Float(f) => Constant::Float(*f),
+            TValue::String(s) => Constant::String(s.to_string()),
+            // the parser only generates nil, boolean, number and string constants
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl From<&Constant> for TValue {
+    fn from(value: &Constant) -> Self {
+        match value {
+            Constant::Nil => TValue::Nil,
+            Constant::Boolean(b) => TValue::Boolean(*b),
+            Constant::Integer(i) => TValue::Integer(*i),
+            Constant::Float(f) => TValue::Float(*f),
+            Constant::String(s) => TValue::from(s.as_str()),
+        }
+    }
+}
+
+/// function prototype of a compiled chunk
+struct ChunkProto {
+    k: Vec<Constant>,
+    code: Arc<Vec<Instruction>>,
+    p: Vec<ChunkProto>,
+    lineinfo: Arc<Vec<usize>>,
+    locvars: Arc<Vec<LocVar>>,
+    upvalues: Arc<Vec<UpValDesc>>,
+    source: String,
+    linedefined: usize,
+    lastlinedefined: usize,
+    numparams: usize,
+    is_vararg: bool,
+    maxstacksize: usize,
+}
+
+impl From<&Proto> for ChunkProto {
+    fn from(p: &Proto) -> Self {
+        Self {
+            k: p.k.iter().map(Constant::from).collect(),
+            code: Arc::clone(&p.code),
+            p: p.p.iter().map(|p| ChunkProto::from(&**p)).collect(),
+            lineinfo: Arc::clone(&p.lineinfo),
+            locvars: Arc::clone(&p.locvars),
+            upvalues: Arc::clone(&p.upvalues),
+            source: p.source.clone(),
+            linedefined: p.linedefined,
+            lastlinedefined: p.lastlinedefined,
+            numparams: p.numparams,
+            is_vararg: p.is_vararg,
+            maxstacksize: p.maxstacksize,
+        }
+    }
+}
+
+impl From<&ChunkProto> for Proto {
+    fn from(p: &ChunkProto) -> Self {
+        Self {
+            k: p.k.iter().map(TValue::from).collect(),
+            code: Arc::clone(&p.code),
+            p: p.p.iter().map(|p| Rc::new(Proto::from(p))).collect(),
+            lineinfo: Arc::clone(&p.lineinfo),
+            locvars: Arc::clone(&p.locvars),
+            upvalues: Arc::clone(&p.upvalues),
+            source: p.source.clone(),
+            linedefined: p.linedefined,
+            lastlinedefined: p.lastlinedefined,
+            numparams: p.numparams,
+            is_vararg: p.is_vararg,
+            maxstacksize: p.maxstacksize,
+        }
+    }
+}
+
+/// A compiled Lua chunk that can be loaded in any state. It is cheap to clone
+/// and can be sent to other threads.
+#[derive(Clone)]
+pub struct CompiledChunk {
+    main: Arc<ChunkProto>,
+}
+
+impl CompiledChunk {
+    /// Returns the compiled chunk of the main function of a chunk
+    pub(crate) fn new(main: &Proto) -> Self {
+        Self {
+            main: Arc::new(ChunkProto::from(main)),
+        }
+    }
+
+    /// Returns the prototype of the main function, with its constants
+    /// converted to Lua values
+    pub(crate) fn to_proto(&self) -> Rc<Proto> {
+        Rc::new(Proto::from(&*self.main))
+    }
+
+    /// Returns the name of the chunk
+    pub fn name(&self) -> &str {
+        &self.main.source
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        api, luaL,
+        object::{Closure, TValue},
+        CompiledChunk,
+    };
+    #[test]
+    fn send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<CompiledChunk>();
+    }
+    #[test]
+    fn load_in_states() {
+        let mut state = luaL::newstate();
+        let chunk = luaL::compile(
+            &mut state,
+            "local n = ... function f(x) return x .. 'b' end z = f(n or 'a')",
+            "=chunk",
+        )
+        .unwrap();
+        assert_eq!(api::get_top(&mut state), 0);
+        assert_eq!(chunk.name(), "=chunk");
+        let thread_chunk = chunk.clone();
+        let res = std::thread::spawn(move || {
+            let mut state = luaL::newstate();
+            api::load_compiled(&mut state, &thread_chunk);
+            api::call(&mut state, 0, 0).unwrap();
+            api::get_global(&mut state, "z");
+            api::to_string(&mut state, -1)
+        })
+        .join()
+        .unwrap();
+        assert_eq!(res.as_deref(), Some("ab"));
+        for i in 0..2 {
+            let mut state = luaL::newstate();
+            api::load_compiled(&mut state, &chunk);
+            api::push_integer(&mut state, i);
+            api::call(&mut state, 1, 0).unwrap();
+            api::get_global(&mut state, "z");
+            assert_eq!(state.stack.last().unwrap(), &TValue::from(format!("{}b", i)));
+        }
+    }
+    #[test]
+    fn shared_code() {
+        let mut state = luaL::newstate();
+        let chunk = luaL::compile(&mut state, "return function() return 1 end", "=chunk").unwrap();
+        api::load_compiled(&mut state, &chunk);
+        api::call(&mut state, 0, 1).unwrap();
+        let TValue::Function(cl) = state.stack.pop().unwrap() else {
+            unreachable!()
+        };
+        let Closure::Lua(cl) = &*cl.borrow() else {
+            unreachable!()
+        };
+        let main = &chunk.main;
+        assert!(Arc::ptr_eq(&cl.proto.code, &main.p[0].code));
+        assert!(Arc::ptr_eq(&cl.proto.lineinfo, &main.p[0].lineinfo));
+        assert!(Arc::ptr_eq(&cl.proto.upvalues, &main.p[0].upvalues));
+    }
+    #[test]
+    fn compile_error() {
+        let mut state = luaL::newstate();
+        assert!(luaL::compile(&mut state, "x = ", "=chunk").is_err());
+        assert_eq!(
+            state.stack.last().unwrap(),
+            &TValue::from("chunk:1: unexpected symbol")
+        );
+    }
+}
@@ -1,10 +1,12 @@
 //! Code generator for Lua
 
+use std::sync::Arc;
+
 use crate::{
     api::LuaError,
     lex::LexState,
     limits::MAX_LUA_STACK,
-    object::TValue,
+    object::{str2number, TValue},
     opcodes::{
         create_abc, create_abx, create_ax, get_arg_a, get_arg_b, get_arg_c, get_arg_sbx,
         get_opcode, is_reg_constant, rk_as_k, set_arg_a, set_arg_b, set_arg_c, set_arg_sbx, OpCode,
@@ -126,8 +128,8 @@ fn code<T>(lex: &mut LexState<T>, state: &mut LuaState, o: u32) -> Result<u32, L
     let line = lex.lastline;
     let proto = lex.borrow_mut_proto(state, None);
     let pc = proto.next_pc() as u32;
-    proto.code.push(o);
-    proto.lineinfo.push(line);
+    Arc::make_mut(&mut proto.code).push(o);
+    Arc::make_mut(&mut proto.lineinfo).push(line);
     Ok(pc)
 }
 
@@ -379,9 +381,7 @@ fn get_jump_control<'a, T>(
     state: &'a mut LuaState,
     pc: i32,
 ) -> &'a mut u32 {
-    let i = lex.get_code(state, pc as usize - 1);
-    let op = get_opcode(i);
-    if pc >= 1 && op.is_test() {
+    if pc >= 1 && get_opcode(lex.get_code(state, pc as usize - 1)).is_test() {
         lex.borrow_mut_code(state, pc as usize - 1)
     } else {
         lex.borrow_mut_code(state, pc as usize)
@@ -667,12 +667,13 @@ pub(crate) fn nil<T>(
                 let previous = lex.borrow_mut_code(state, pc as usize - 1);
                 if get_opcode(*previous) == OpCode::LoadNil {
                     let pfrom = get_arg_a(*previous);
-                    let pto = get_arg_b(*previous);
-                    if pfrom < from && from < pto + 1 {
+                    let pl = pfrom + get_arg_b(*previous); // last register set by previous
+                    let l = (from as i32 + n - 1) as u32; // last register to set
+                    if (pfrom <= from && from <= pl + 1) || (from <= pfrom && pfrom <= l + 1) {
                         // can connect both?
-                        if from as i32 + n + 1 > pto as i32 {
-                            set_arg_b(previous, (from as i32 + n) as u32 - 1);
-                        }
+                        let from = from.min(pfrom);
+                        set_arg_a(previous, from);
+                        set_arg_b(previous, l.max(pl) - from);
                         return Ok(());
                     }
                 }
@@ -685,7 +686,7 @@ pub(crate) fn nil<T>(
         state,
         OpCode::LoadNil as u32,
         from as i32,
-        from as i32 + n - 1,
+        n - 1,
         0,
     )?;
     Ok(())
@@ -751,7 +752,7 @@ fn free_reg<T>(lex: &mut LexState<T>, reg: u32) {
 pub(crate) fn fix_line<T>(lex: &mut LexState<T>, state: &mut LuaState, line: usize) {
     let proto = lex.borrow_mut_proto(state, None);
     let pc = proto.next_pc() as usize;
-    proto.lineinfo[pc - 1] = line;
+    Arc::make_mut(&mut proto.lineinfo)[pc - 1] = line;
 }
 
 /// Apply prefix operation 'op' to expression 'e'
@@ -932,7 +933,7 @@ fn jump_on_cond<T>(
             let ie = proto.code[exp.info as usize];
             if get_opcode(ie) == OpCode::Not {
                 // remove previous OP_NOT
-                proto.code.pop();
+                Arc::make_mut(&mut proto.code).pop();
                 return cond_jump(
                     lex,
                     state,
@@ -1026,9 +1027,13 @@ pub(crate) fn pos_fix<T>(
         }
         BinaryOp::Concat => {
             exp2val(lex, state, exp2)?;
-            let i2 = lex.get_code(state, exp2.info as usize);
-            if exp2.k == ExpressionKind::Relocable && get_opcode(i2) == OpCode::Concat {
-                debug_assert!(exp1.info as u32 == get_arg_b(i2) - 1);
+            // only a relocable expression has its instruction in 'info'
+            if exp2.k == ExpressionKind::Relocable
+                && get_opcode(lex.get_code(state, exp2.info as usize)) == OpCode::Concat
+            {
+                debug_assert!(
+                    exp1.info as u32 == get_arg_b(lex.get_code(state, exp2.info as usize)) - 1
+                );
                 free_exp(lex, exp1);
                 set_arg_b(
                     lex.borrow_mut_code(state, exp2.info as usize),
@@ -1176,6 +1181,7 @@ fn const_folding(op: OpCode, exp1: &mut ExpressionDesc, exp2: &mut ExpressionDes
 }
 
 pub(crate) fn arith(op: OpCode, v1: &TValue, v2: &TValue) -> TValue {
+    let (v1, v2) = (&to_numeral(v1), &to_numeral(v2));
     match op {
         OpCode::BinaryAnd
         | OpCode::BinaryOr
@@ -1210,30 +1216,95 @@ pub(crate) fn arith(op: OpCode, v1: &TValue, v2: &TValue) -> TValue {
     todo!()
 }
 
+/// Strings are converted to the number they represent, following the
+/// rules of the lexer: "10" is an integer, "10.0" a float
+pub(crate) fn to_numeral(v: &TValue) -> TValue {
+    match v {
+        TValue::String(s) => str2number(s).unwrap_or_else(|| v.clone()),
+        _ => v.clone(),
+    }
+}
+
+/// Integer operations wrap around on overflow
 fn int_arith(op: OpCode, i1: LuaInteger, i2: LuaInteger) -> LuaInteger {
     match op {
-        OpCode::Add => i1 + i2,
-        OpCode::Sub => i1 - i2,
-        OpCode::Mul => i1 * i2,
-        OpCode::Mod => i1 % i2,
-        OpCode::IntegerDiv => i1 / i2,
+        OpCode::Add => i1.wrapping_add(i2),
+        OpCode::Sub => i1.wrapping_sub(i2),
+        OpCode::Mul => i1.wrapping_mul(i2),
+        OpCode::Mod => int_mod(i1, i2),
+        OpCode::IntegerDiv => int_div(i1, i2),
         OpCode::BinaryAnd => i1 & i2,
         OpCode::BinaryOr => i1 | i2,
         OpCode::BinaryXor => i1 ^ i2,
-        OpCode::Shl => i1 << i2,
-        OpCode::Shr => i1 >> i2,
-        OpCode::UnaryMinus => -i1,
+        OpCode::Shl => shift_left(i1, i2),
+        OpCode::Shr => shift_left(i1, i2.wrapping_neg()),
+        OpCode::UnaryMinus => i1.wrapping_neg(),
         OpCode::BinaryNot => !i1,
         _ => 0,
     }
 }
 
+/// Integer division, rounding the quotient towards minus infinity.
+/// The callers raise an error for a zero divisor.
+fn int_div(m: LuaInteger, n: LuaInteger) -> LuaInteger {
+    if n == -1 {
+        // avoid overflow with MIN // -1
+        return m.wrapping_neg();
+    }
+    let q = m / n; // perform C division
+    if (m ^ n) < 0 && m % n != 0 {
+        // 'm/n' would be negative non-integer: correct result for different rounding
+        q - 1
+    } else {
+        q
+    }
+}
+
+/// Integer modulus, with the sign of the divisor.
+/// The callers raise an error for a zero divisor.
+fn int_mod(m: LuaInteger, n: LuaInteger) -> LuaInteger {
+    if n == -1 {
+        // avoid overflow with MIN % -1
+        return 0;
+    }
+    let r = m % n;
+    if r != 0 && (r ^ n) < 0 {
+        // 'm/n' would be non-integer negative: correct result
+        r + n
+    } else {
+        r
+    }
+}
+
+/// Shifts `x` left by `y` bits (right if `y` is negative). Vacated bits are
+/// filled with zeros: shifting by 64 bits or more gives 0.
+fn shift_left(x: LuaInteger, y: LuaInteger) -> LuaInteger {
+    if y <= -64 || y >= 64 {
+        0
+    } else if y < 0 {
+        ((x as u64) >> -y) as LuaInteger
+    } else {
+        ((x as u64) << y) as LuaInteger
+    }
+}
+
+/// Float modulus, with the sign of the divisor
+fn num_mod(a: LuaFloat, b: LuaFloat) -> LuaFloat {
+    let m = a % b;
+    if (m > 0.0 && b < 0.0) || (m < 0.0 && b > 0.0) {
+        // 'm' and 'b' have different signs: correct result
+        m + b
+    } else {
+        m
+    }
+}
+
 fn num_arith(op: OpCode, i1: LuaFloat, i2: LuaFloat) -> LuaFloat {
     match op {
         OpCode::Add => i1 + i2,
         OpCode::Sub => i1 - i2,
         OpCode::Mul => i1 * i2,
-        OpCode::Mod => i1 % i2,
+        OpCode::Mod => num_mod(i1, i2),
         OpCode::Div => i1 / i2,
         OpCode::Pow => i1.powf(i2),
         OpCode::IntegerDiv => (i1 / i2).floor(),
@@ -1,22 +1,582 @@
 //! Debug Interface
 
 use crate::{
-    object::{Closure, StkId, TValue},
-    state::LuaState,
-    LuaError,
+    func::get_local_name,
+    ldo::CallId,
+    limits::LUA_ENV,
+    object::{chunk_id, Closure, Proto, StkId, TValue},
+    opcodes::{
+        get_arg_a, get_arg_ax, get_arg_b, get_arg_bx, get_arg_c, get_arg_sbx, get_opcode,
+        rk_is_k, OpCode, BIT_RK,
+    },
+    state::{LuaState, CIST_FIN, CIST_HOOKED, CIST_LUA, CIST_TAIL},
+    LuaError, LuaInteger, LUA_MASKCOUNT, LUA_MASKLINE,
 };
 
-pub(crate) fn error_msg(_state: &mut LuaState) -> Result<(), LuaError> {
-    todo!()
+/// Event that triggered a hook
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HookEvent {
+    #[default]
+    Call,
+    Return,
+    Line,
+    Count,
+    TailCall,
 }
 
-pub(crate) fn concat_error(state: &mut LuaState, p1: isize, p2: isize) -> Result<(), LuaError> {
-    let perr = if state.index2adr(p1).is_string() || state.index2adr(p1).is_float() {
-        p2
+impl HookEvent {
+    /// name of the event as given to `debug.sethook` hooks
+    pub fn name(&self) -> &'static str {
+        match self {
+            HookEvent::Call => "call",
+            HookEvent::Return => "return",
+            HookEvent::Line => "line",
+            HookEvent::Count => "count",
+            HookEvent::TailCall => "tail call",
+        }
+    }
+}
+
+/// Information about a function or an activation record.
+/// Obtained from [`get_stack`] and filled by [`get_info`].
+#[derive(Clone, Debug, Default)]
+pub struct LuaDebug {
+    /// event that triggered the hook (hooks only)
+    pub event: HookEvent,
+    /// a reasonable name for the function ('n')
+    pub name: Option<String>,
+    /// "global", "local", "method", "field", "upvalue", "metamethod", ... or "" ('n')
+    pub namewhat: &'static str,
+    /// "Lua" for a Lua function, "C" for a Rust function, "main" for the main part of a chunk ('S')
+    pub what: &'static str,
+    /// the source of the chunk that created the function ('S')
+    pub source: String,
+    /// a "printable" version of `source`, to be used in error messages ('S')
+    pub short_src: String,
+    /// the current line where the given function is executing, or -1 ('l')
+    pub currentline: i32,
+    /// the line number where the definition of the function starts ('S')
+    pub linedefined: i32,
+    /// the line number where the definition of the function ends ('S')
+    pub lastlinedefined: i32,
+    /// number of upvalues of the function ('u')
+    pub nups: usize,
+    /// number of fixed parameters of the function ('u')
+    pub nparams: usize,
+    /// whether the function is a vararg function ('u')
+    pub isvararg: bool,
+    /// whether this function invocation was called by a tail call ('t')
+    pub istailcall: bool,
+    /// active function
+    pub(crate) i_ci: Option<CallId>,
+}
+
+/// Gets information about the interpreter runtime stack.
+/// Level 0 is the current running function, whereas level n+1 is the
+/// function that has called level n. Returns None when called with a
+/// level greater than the stack depth.
+pub fn get_stack(state: &LuaState, level: usize) -> Option<LuaDebug> {
+    if level >= state.ci {
+        // level 0 of the call stack is the host
+        return None;
+    }
+    Some(LuaDebug {
+        i_ci: Some(state.ci - level),
+        ..LuaDebug::default()
+    })
+}
+
+/// Fills the fields of `ar` selected by `what` ('n', 'S', 'l', 't', 'u').
+/// Option 'f' pushes the function and 'L' pushes a table whose keys are the
+/// valid lines of the function. When `what` starts with '>', the function
+/// is popped from the stack instead of being taken from `ar`.
+/// Returns false for an invalid option.
+pub fn get_info(state: &mut LuaState, what: &str, ar: &mut LuaDebug) -> bool {
+    let (what, func, ci) = match what.strip_prefix('>') {
+        Some(what) => {
+            let func = state.stack.pop().unwrap();
+            debug_assert!(func.is_function(), "function expected");
+            (what, func, None)
+        }
+        None => {
+            let ci = ar.i_ci.unwrap();
+            (what, state.stack[state.base_ci[ci].func].clone(), Some(ci))
+        }
+    };
+    let cl = match &func {
+        TValue::Function(cl) => Some(cl.clone()),
+        _ => None,
+    };
+    let mut status = true;
+    for option in what.chars() {
+        match option {
+            'S' => func_info(ar, &func),
+            'l' => {
+                ar.currentline = match ci {
+                    Some(ci) if state.base_ci[ci].call_status & CIST_LUA != 0 => {
+                        current_line(state, ci) as i32
+                    }
+                    _ => -1,
+                }
+            }
+            'u' => match cl.as_ref().map(|cl| cl.borrow().clone()) {
+                Some(Closure::Lua(lcl)) => {
+                    let p = &lcl.proto;
+                    ar.nups = lcl.upvalues.len();
+                    ar.isvararg = p.is_vararg;
+                    ar.nparams = p.numparams;
+                }
+                Some(Closure::Rust(rcl)) => {
+                    ar.nups = rcl.upvalues.len();
+                    ar.isvararg = true;
+                    ar.nparams = 0;
+                }
+                None => {
+                    ar.nups = 0;
+                    ar.isvararg = true;
+                    ar.nparams = 0;
+                }
+            },
+            't' => {
+                ar.istailcall = ci.is_some_and(|ci| state.base_ci[ci].call_status & CIST_TAIL != 0);
+            }
+            'n' => match ci.and_then(|ci| get_func_name(state, ci)) {
+                Some((namewhat, name)) => {
+                    ar.namewhat = namewhat;
+                    ar.name = Some(name);
+                }
+                None => {
+                    ar.namewhat = ""; // not found
+                    ar.name = None;
+                }
+            },
+            'L' | 'f' => (), // handled below
+            _ => status = false, // invalid option
+        }
+    }
+    if what.contains('f') {
+        state.stack.push(func.clone());
+    }
+    if what.contains('L') {
+        collect_valid_lines(state, &func);
+    }
+    status
+}
+
+fn func_info(ar: &mut LuaDebug, func: &TValue) {
+    let proto = match func {
+        TValue::Function(cl) => match &*cl.borrow() {
+            Closure::Lua(lcl) => Some(lcl.proto.clone()),
+            Closure::Rust(_) => None,
+        },
+        _ => None,
+    };
+    match proto {
+        Some(p) => {
+            ar.source = p.source.clone();
+            ar.linedefined = p.linedefined as i32;
+            ar.lastlinedefined = p.lastlinedefined as i32;
+            ar.what = if ar.linedefined == 0 { "main" } else { "Lua" };
+        }
+        None => {
+            ar.source = "=[C]".to_owned();
+            ar.linedefined = -1;
+            ar.lastlinedefined = -1;
+            ar.what = "C";
+        }
+    }
+    ar.short_src = chunk_id(&ar.source);
+}
+
+/// Pushes a table whose keys are the lines with code of a Lua function, or nil
+fn collect_valid_lines(state: &mut LuaState, func: &TValue) {
+    let proto = match func {
+        TValue::Function(cl) => match &*cl.borrow() {
+            Closure::Lua(lcl) => Some(lcl.proto.clone()),
+            Closure::Rust(_) => None,
+        },
+        _ => None,
+    };
+    match proto {
+        Some(proto) => {
+            let t = TValue::new_table();
+            if let TValue::Table(tref) = &t {
+                let mut tref = tref.borrow_mut();
+                for &line in proto.lineinfo.iter() {
+                    tref.set(TValue::Integer(line as LuaInteger), TValue::Boolean(true));
+                }
+            }
+            state.link_object(&t);
+            state.stack.push(t);
+        }
+        None => state.stack.push(TValue::Nil),
+    }
+}
+
+/// Returns the line being executed by the Lua function running at call info `ci`
+fn current_line(state: &LuaState, ci: CallId) -> usize {
+    let ci = &state.base_ci[ci];
+    let p = &state.get_lua_closure_proto(ci.func);
+    p.lineinfo.get(ci.saved_pc.saturating_sub(1)).copied().unwrap_or(0)
+}
+
+/// Returns the name of the vararg parameter `n` of the Lua function running at `ci`
+/// and its stack position
+fn find_vararg(state: &LuaState, ci: CallId, n: usize) -> Option<(&'static str, StkId)> {
+    let ci = &state.base_ci[ci];
+    let nparams = state.get_lua_closure_proto(ci.func).numparams;
+    if n > ci.base - ci.func - nparams - 1 {
+        None // no such vararg
+    } else {
+        Some(("(*vararg)", ci.func + nparams + n)) // generic name for any vararg
+    }
+}
+
+/// Returns the name and the stack position of the local variable `n`
+/// of the function running at `ci`
+fn find_local(state: &LuaState, ci: CallId, n: i32) -> Option<(String, StkId)> {
+    let cinfo = &state.base_ci[ci];
+    let is_lua = cinfo.call_status & CIST_LUA != 0;
+    let base = if is_lua {
+        if n < 0 {
+            // access to vararg values?
+            return find_vararg(state, ci, n.unsigned_abs() as usize)
+                .map(|(name, pos)| (name.to_owned(), pos));
+        }
+        let p = &state.get_lua_closure_proto(cinfo.func);
+        if let Some(name) = get_local_name(p, n as usize, cinfo.saved_pc.saturating_sub(1)) {
+            return Some((name.to_owned(), cinfo.base + n as usize - 1));
+        }
+        cinfo.base
+    } else {
+        cinfo.func + 1
+    };
+    // no 'standard' name?
+    let limit = if ci == state.ci {
+        state.stack.len()
+    } else {
+        state.base_ci[ci + 1].func
+    };
+    if n > 0 && limit - base >= n as usize {
+        // is 'n' inside 'ci' stack?
+        let name = if is_lua { "(*temporary)" } else { "(*C temporary)" };
+        Some((name.to_owned(), base + n as usize - 1))
+    } else {
+        None // no name
+    }
+}
+
+/// Gets information about a local variable of a given activation record.
+/// Pushes the variable's value onto the stack and returns its name.
+/// Negative `n` access the vararg values.
+/// When `ar` is None, returns the name of the parameter `n` of the function
+/// on top of the stack, without pushing anything.
+/// Returns None (and pushes nothing) when the index is greater than the
+/// number of active local variables.
+pub fn get_local(state: &mut LuaState, ar: Option<&LuaDebug>, n: i32) -> Option<String> {
+    match ar {
+        None => {
+            // information about non-active function?
+            match state.stack.last() {
+                Some(TValue::Function(cl)) => match &*cl.borrow() {
+                    Closure::Lua(lcl) if n > 0 => {
+                        // is a Lua function? returns its parameter names
+                        get_local_name(&lcl.proto, n as usize, 0).map(str::to_owned)
+                    }
+                    _ => None,
+                },
+                _ => None,
+            }
+        }
+        Some(ar) => {
+            let (name, pos) = find_local(state, ar.i_ci.unwrap(), n)?;
+            state.stack.push(state.stack[pos].clone());
+            Some(name)
+        }
+    }
+}
+
+/// Sets the value of a local variable of a given activation record to the
+/// value on top of the stack and pops it. Returns the name of the variable,
+/// or None (and pops nothing) when the index is greater than the number
+/// of active local variables.
+pub fn set_local(state: &mut LuaState, ar: &LuaDebug, n: i32) -> Option<String> {
+    let (name, pos) = find_local(state, ar.i_ci.unwrap(), n)?;
+    let value = state.stack.pop().unwrap();
+    state.stack[pos] = value;
+    Some(name)
+}
+
+/// Raises the error whose object is on top of the stack. If a message handler
+/// is set, it is called at the error point, before the stack is unwound,
+/// and its result replaces the error object.
+/// Calls the count and line hooks before the execution of the current
+/// instruction of a Lua function. The line hook is called when entering a
+/// new function, when jumping back (loop) or when entering a new line.
+pub(crate) fn trace_exec(state: &mut LuaState) -> Result<(), LuaError> {
+    let mask = state.hookmask;
+    let mut counthook = false;
+    if mask & LUA_MASKCOUNT != 0 && state.hookcount > 0 {
+        state.hookcount -= 1;
+        counthook = state.hookcount == 0;
+    }
+    if counthook {
+        state.hookcount = state.basehookcount; // reset count
+        state.dhook(HookEvent::Count, -1)?;
+    }
+    let pc = state.base_ci[state.ci].saved_pc;
+    if mask & LUA_MASKLINE != 0 {
+        let p = state.get_lua_closure_proto(state.base_ci[state.ci].func);
+        let npc = pc - 1;
+        let newline = p.lineinfo.get(npc).copied().unwrap_or(0);
+        if npc == 0
+            || pc <= state.oldpc
+            || p.lineinfo.get(state.oldpc.wrapping_sub(1)) != Some(&newline)
+        {
+            state.dhook(HookEvent::Line, newline as i32)?;
+        }
+    }
+    state.oldpc = pc;
+    Ok(())
+}
+
+pub(crate) fn error_msg(state: &mut LuaState) -> Result<(), LuaError> {
+    if let Some(code) = state.exit_code {
+        // os.exit is not an error: no message handler
+        return Err(LuaError::Exit(code));
+    }
+    if state.errfunc != 0 {
+        // is there an error handling function?
+        let errfunc = state.errfunc;
+        let msg = state.stack.pop().unwrap();
+        state.stack.push(state.stack[errfunc].clone()); // push function
+        state.stack.push(msg); // and its argument
+        // an error in the handler is not handled again
+        state.errfunc = 0;
+        let status = state.dcall(state.stack.len() - 2, 1); // call it
+        state.errfunc = errfunc;
+        if let Some(code) = state.exit_code {
+            // os.exit called by the handler
+            return Err(LuaError::Exit(code));
+        }
+        if status.is_err() {
+            // the error may reach the protected call through Rust functions
+            // that cannot return the error kind
+            state.stack.push(TValue::from("error in error handling"));
+            return Err(LuaError::ErrorHandlerError);
+        }
+    }
+    Err(LuaError::RuntimeError)
+}
+
+/// Adds the position of the running Lua function to an error message
+pub(crate) fn add_info(state: &LuaState, msg: &str) -> String {
+    match current_position(state, 0) {
+        Some((source, line)) => format!("{}:{}: {}", chunk_id(&source), line, msg),
+        None => msg.to_owned(),
+    }
+}
+
+/// Returns the source name and the line currently executed by the function
+/// running at the given call level (level 0 is the current running function).
+/// Returns None for Rust functions or if there is no such level.
+pub(crate) fn current_position(state: &LuaState, level: usize) -> Option<(String, usize)> {
+    if level >= state.ci {
+        // level 0 of the call stack is the host
+        return None;
+    }
+    let ci = &state.base_ci[state.ci - level];
+    match &state.stack[ci.func] {
+        TValue::Function(cl) => match &*cl.borrow() {
+            Closure::Lua(cl) => {
+                let proto = &cl.proto;
+                let pc = ci.saved_pc.saturating_sub(1);
+                let line = proto.lineinfo.get(pc).copied().unwrap_or(0);
+                Some((proto.source.clone(), line))
+            }
+            Closure::Rust(_) => None,
+        },
+        _ => None,
+    }
+}
+
+/// Returns the kind ("global", "local", "method", "field", "upvalue",
+/// "constant", "metamethod", "for iterator" or "hook") and the name of the
+/// function running at call info `ci`, as deduced from the calling instruction.
+/// Returns None if the calling function is not a Lua function.
+pub(crate) fn get_func_name(state: &LuaState, ci: usize) -> Option<(&'static str, String)> {
+    if ci == 0 {
+        // no function at the host level
+        return None;
+    }
+    if state.base_ci[ci - 1].call_status & CIST_FIN != 0 {
+        // was it called by a finalizer?
+        return Some(("metamethod", "__gc".to_owned()));
+    }
+    if state.base_ci[ci].call_status & CIST_TAIL == 0
+        && state.base_ci[ci - 1].call_status & CIST_LUA != 0
+    {
+        // calling function is a known Lua function
+        func_name_from_code(state, ci - 1)
     } else {
-        p1
+        None // no way to determine the name
+    }
+}
+
+fn func_name_from_code(state: &LuaState, ci: usize) -> Option<(&'static str, String)> {
+    let ci = &state.base_ci[ci];
+    if ci.call_status & CIST_HOOKED != 0 {
+        // was it called inside a hook?
+        return Some(("hook", "?".to_owned()));
+    }
+    let p = &state.get_lua_closure_proto(ci.func); // calling function
+    let pc = ci.saved_pc.saturating_sub(1); // calling instruction index
+    let i = p.code[pc];
+    // all instructions but calls can call only through metamethods
+    let tm = match get_opcode(i) {
+        OpCode::Call | OpCode::TailCall => return get_obj_name_from_code(p, pc, get_arg_a(i)),
+        OpCode::TForCall => return Some(("for iterator", "for iterator".to_owned())),
+        OpCode::OpSelf | OpCode::GetTabUp | OpCode::GetTable => "index",
+        OpCode::SetTabUp | OpCode::SetTable => "newindex",
+        OpCode::Add => "add",
+        OpCode::Sub => "sub",
+        OpCode::Mul => "mul",
+        OpCode::Mod => "mod",
+        OpCode::Pow => "pow",
+        OpCode::Div => "div",
+        OpCode::IntegerDiv => "idiv",
+        OpCode::BinaryAnd => "band",
+        OpCode::BinaryOr => "bor",
+        OpCode::BinaryXor => "bxor",
+        OpCode::Shl => "shl",
+        OpCode::Shr => "shr",
+        OpCode::UnaryMinus => "unm",
+        OpCode::BinaryNot => "bnot",
+        OpCode::Len => "len",
+        OpCode::Concat => "concat",
+        OpCode::Eq => "eq",
+        OpCode::Lt => "lt",
+        OpCode::Le => "le",
+        _ => return None, // other instructions cannot call a function
     };
-    type_error(state, perr as StkId, "concatenate")
+    Some(("metamethod", tm.to_owned()))
+}
+
+/// Finds a name for the value stored in register `reg` at instruction `lastpc`
+/// by symbolic execution of the function's code.
+fn get_obj_name_from_code(p: &Proto, lastpc: usize, reg: u32) -> Option<(&'static str, String)> {
+    if let Some(name) = get_local_name(p, reg as usize + 1, lastpc) {
+        // is a local?
+        return Some(("local", name.to_owned()));
+    }
+    // else try symbolic execution
+    let pc = find_set_reg(p, lastpc, reg)?;
+    let i = p.code[pc];
+    match get_opcode(i) {
+        OpCode::Move => {
+            let b = get_arg_b(i); // move from 'b' to 'a'
+            if b < get_arg_a(i) {
+                return get_obj_name_from_code(p, pc, b); // get name for 'b'
+            }
+            None
+        }
+        op @ (OpCode::GetTabUp | OpCode::GetTable) => {
+            let k = get_arg_c(i); // key index
+            let t = get_arg_b(i); // table index
+            // name of indexed variable
+            let vn = if op == OpCode::GetTable {
+                get_local_name(p, t as usize + 1, pc)
+            } else {
+                Some(upval_name(p, t as usize))
+            };
+            let name = k_name(p, pc, k);
+            Some((if vn == Some(LUA_ENV) { "global" } else { "field" }, name))
+        }
+        OpCode::GetUpVal => Some(("upvalue", upval_name(p, get_arg_b(i) as usize).to_owned())),
+        op @ (OpCode::LoadK | OpCode::LoadKx) => {
+            let b = if op == OpCode::LoadK {
+                get_arg_bx(i)
+            } else {
+                get_arg_ax(p.code[pc + 1])
+            };
+            match &p.k[b as usize] {
+                TValue::String(s) => Some(("constant", s.to_string())),
+                _ => None,
+            }
+        }
+        OpCode::OpSelf => Some(("method", k_name(p, pc, get_arg_c(i)))),
+        _ => None, // could not find reasonable name
+    }
+}
+
+fn upval_name(p: &Proto, uv: usize) -> &str {
+    match p.upvalues.get(uv) {
+        Some(desc) if !desc.name.is_empty() => &desc.name,
+        _ => "?",
+    }
+}
+
+/// Finds a name for the key RK(c) used at instruction `pc`
+fn k_name(p: &Proto, pc: usize, c: u32) -> String {
+    if rk_is_k(c) {
+        // is 'c' a constant?
+        if let TValue::String(s) = &p.k[(c & !BIT_RK) as usize] {
+            // literal constant : it is its own name
+            return s.to_string();
+        }
+    } else if let Some(("constant", name)) = get_obj_name_from_code(p, pc, c) {
+        // 'c' is a register holding a constant name
+        return name;
+    }
+    "?".to_owned() // no reasonable name found
+}
+
+/// Returns the index of the last instruction before `lastpc` that
+/// changed register `reg`, or None if it cannot be known.
+fn find_set_reg(p: &Proto, lastpc: usize, reg: u32) -> Option<usize> {
+    let mut setreg = None; // keep last instruction that changed 'reg'
+    let mut jmptarget = 0; // any code before this address is conditional
+    // is code conditional (inside a jump)? then we cannot know who sets that register
+    let filter_pc = |pc: usize, jmptarget: usize| if pc < jmptarget { None } else { Some(pc) };
+    for (pc, &i) in p.code.iter().enumerate().take(lastpc) {
+        let op = get_opcode(i);
+        let a = get_arg_a(i);
+        match op {
+            OpCode::LoadNil => {
+                let b = get_arg_b(i);
+                if a <= reg && reg <= a + b {
+                    // set registers from 'a' to 'a+b'
+                    setreg = filter_pc(pc, jmptarget);
+                }
+            }
+            OpCode::TForCall => {
+                if reg >= a + 2 {
+                    // affect all regs above its base
+                    setreg = filter_pc(pc, jmptarget);
+                }
+            }
+            OpCode::Call | OpCode::TailCall => {
+                if reg >= a {
+                    // affect all registers above base
+                    setreg = filter_pc(pc, jmptarget);
+                }
+            }
+            OpCode::Jmp => {
+                let dest = pc as i32 + 1 + get_arg_sbx(i);
+                // jump is forward and do not skip 'lastpc'?
+                if (pc as i32) < dest && dest <= lastpc as i32 {
+                    jmptarget = jmptarget.max(dest as usize);
+                }
+            }
+            _ => {
+                if op.test_a_mode() && reg == a {
+                    // any instruction that set A
+                    setreg = filter_pc(pc, jmptarget);
+                }
+            }
+        }
+    }
+    setreg
 }
 
 pub(crate) fn type_error(state: &mut LuaState, id: StkId, operation: &str) -> Result<(), LuaError> {
@@ -0,0 +1,477 @@
+//! Virtual filesystem
+//!
+//! All file accesses of the standard library (`io`, `os.remove`, `loadfile`,
+//! `require`...) go through the [`FileSystem`] installed in the global state.
+
+use std::{
+    cell::RefCell,
+    collections::{hash_map::RandomState, HashMap},
+    hash::{BuildHasher, Hasher},
+    io::{self, Cursor, Read, Seek, SeekFrom, Write},
+    path::{Component, Path, PathBuf},
+    rc::Rc,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+/// An open file
+pub trait FileStream: Read + Write + Seek {}
+
+impl<T: Read + Write + Seek> FileStream for T {}
+
+/// How to open a file. Same semantics as [`std::fs::OpenOptions`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OpenMode {
+    pub read: bool,
+    pub write: bool,
+    pub append: bool,
+    pub truncate: bool,
+    pub create: bool,
+    pub create_new: bool,
+}
+
+impl OpenMode {
+    /// Converts a C `fopen` mode ("r", "w+", "ab"...)
+    pub fn from_c_mode(mode: &str) -> Self {
+        let update = mode.contains('+');
+        match mode.chars().next() {
+            Some('w') => Self {
+                read: update,
+                write: true,
+                truncate: true,
+                create: true,
+                ..Default::default()
+            },
+            Some('a') => Self {
+                read: update,
+                write: true,
+                append: true,
+                create: true,
+                ..Default::default()
+            },
+            _ => Self {
+                read: true,
+                write: update,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+/// The files seen by a Lua state
+pub trait FileSystem {
+    fn open(&self, path: &str, mode: OpenMode) -> io::Result<Box<dyn FileStream>>;
+    /// Removes a file (or an empty directory)
+    fn remove(&self, path: &str) -> io::Result<()>;
+    fn rename(&self, from: &str, to: &str) -> io::Result<()>;
+    /// Directory where temporary files are created
+    fn temp_dir(&self) -> String {
+        "/tmp".to_owned()
+    }
+    /// Creates a new empty file with a unique name, opened for reading and writing.
+    /// Returns its path and the file.
+    fn create_temp_file(&self) -> io::Result<(String, Box<dyn FileStream>)> {
+        static COUNTER: AtomicU32 = AtomicU32::new(0);
+        let seed = RandomState::new().build_hasher().finish() as u32;
+        let dir = self.temp_dir();
+        let mode = OpenMode {
+            read: true,
+            write: true,
+            create_new: true,
+            ..Default::default()
+        };
+        let mut last_error = None;
+        for _ in 0..100 {
+            let n = seed.wrapping_add(
+                COUNTER
+                    .fetch_add(1, Ordering::Relaxed)
+                    .wrapping_mul(0x9e37_79b9),
+            );
+            let path = format!("{}/lua_{:08x}", dir.trim_end_matches('/'), n);
+            match self.open(&path, mode) {
+                Ok(file) => return Ok((path, file)),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap())
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn not_found() -> io::Error {
+    io::Error::from_raw_os_error(2) // ENOENT
+}
+
+#[cfg(not(unix))]
+pub(crate) fn not_found() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "No such file or directory")
+}
+
+#[cfg(unix)]
+pub(crate) fn bad_file_descriptor() -> io::Error {
+    io::Error::from_raw_os_error(9) // EBADF
+}
+
+#[cfg(not(unix))]
+pub(crate) fn bad_file_descriptor() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "Bad file descriptor")
+}
+
+#[cfg(unix)]
+fn already_exists() -> io::Error {
+    io::Error::from_raw_os_error(17) // EEXIST
+}
+
+#[cfg(not(unix))]
+fn already_exists() -> io::Error {
+    io::Error::new(io::ErrorKind::AlreadyExists, "File exists")
+}
+
+#[cfg(unix)]
+fn permission_denied() -> io::Error {
+    io::Error::from_raw_os_error(13) // EACCES
+}
+
+#[cfg(not(unix))]
+fn permission_denied() -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, "Permission denied")
+}
+
+/// Access to the host filesystem through `std::fs`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RealFileSystem;
+
+impl FileSystem for RealFileSystem {
+    fn open(&self, path: &str, mode: OpenMode) -> io::Result<Box<dyn FileStream>> {
+        let file = std::fs::OpenOptions::new()
+            .read(mode.read)
+            .write(mode.write)
+            .append(mode.append)
+            .truncate(mode.truncate)
+            .create(mode.create)
+            .create_new(mode.create_new)
+            .open(path)?;
+        Ok(Box::new(file))
+    }
+    fn remove(&self, path: &str) -> io::Result<()> {
+        if Path::new(path).is_dir() {
+            std::fs::remove_dir(path)
+        } else {
+            std::fs::remove_file(path)
+        }
+    }
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        std::fs::rename(from, to)
+    }
+    fn temp_dir(&self) -> String {
+        std::env::temp_dir().to_string_lossy().into_owned()
+    }
+}
+
+/// Resolves `.` and `..` in a path. `..` never goes above the root.
+/// Returns the components of the normalized path.
+fn normalize(path: &str) -> Vec<&str> {
+    let mut components = Vec::new();
+    for c in path.split(['/', '\\']) {
+        match c {
+            "" | "." => (),
+            ".." => {
+                components.pop();
+            }
+            c => components.push(c),
+        }
+    }
+    components
+}
+
+/// Filesystem confined to a directory of the host filesystem.
+/// Paths are resolved lexically: the root directory must not contain
+/// symbolic links pointing outside of it.
+#[derive(Clone, Debug)]
+pub struct ChrootFileSystem {
+    root: PathBuf,
+}
+
+impl ChrootFileSystem {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+    /// Returns the host path of a sandboxed path. Only plain names are
+    /// joined to the root: a drive prefix (`C:` on Windows) would replace it.
+    fn host_path(&self, path: &str) -> io::Result<PathBuf> {
+        let mut res = self.root.clone();
+        for c in normalize(path) {
+            let mut components = Path::new(c).components();
+            match (components.next(), components.next()) {
+                (Some(Component::Normal(c)), None) => res.push(c),
+                _ => return Err(permission_denied()),
+            }
+        }
+        Ok(res)
+    }
+    /// Returns the host path of a sandboxed path that can be removed or
+    /// renamed. The root directory itself cannot.
+    fn host_path_below_root(&self, path: &str) -> io::Result<PathBuf> {
+        if normalize(path).is_empty() {
+            Err(permission_denied())
+        } else {
+            self.host_path(path)
+        }
+    }
+}
+
+impl FileSystem for ChrootFileSystem {
+    fn open(&self, path: &str, mode: OpenMode) -> io::Result<Box<dyn FileStream>> {
+        RealFileSystem.open(&self.host_path(path)?.to_string_lossy(), mode)
+    }
+    fn remove(&self, path: &str) -> io::Result<()> {
+        RealFileSystem.remove(&self.host_path_below_root(path)?.to_string_lossy())
+    }
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        std::fs::rename(
+            self.host_path_below_root(from)?,
+            self.host_path_below_root(to)?,
+        )
+    }
+    fn temp_dir(&self) -> String {
+        "/".to_owned()
+    }
+}
+
+type MemoryFiles = Rc<RefCell<HashMap<String, Vec<u8>>>>;
+
+/// Filesystem stored in memory.
+/// Clones share the same files, so the host can keep a clone to
+/// inspect or modify the files used by a state.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryFileSystem {
+    files: MemoryFiles,
+}
+
+impl MemoryFileSystem {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    fn key(path: &str) -> String {
+        normalize(path).join("/")
+    }
+    /// Creates or replaces a file
+    pub fn insert(&self, path: &str, content: impl Into<Vec<u8>>) {
+        self.files
+            .borrow_mut()
+            .insert(Self::key(path), content.into());
+    }
+    /// Returns a copy of the content of a file
+    pub fn get(&self, path: &str) -> Option<Vec<u8>> {
+        self.files.borrow().get(&Self::key(path)).cloned()
+    }
+}
+
+impl FileSystem for MemoryFileSystem {
+    fn open(&self, path: &str, mode: OpenMode) -> io::Result<Box<dyn FileStream>> {
+        let key = Self::key(path);
+        let mut files = self.files.borrow_mut();
+        let data = match files.get(&key) {
+            Some(_) if mode.create_new => return Err(already_exists()),
+            Some(_) if mode.truncate => Vec::new(),
+            Some(data) => data.clone(),
+            None if mode.create || mode.create_new => Vec::new(),
+            None => return Err(not_found()),
+        };
+        if !files.contains_key(&key) || mode.truncate {
+            files.insert(key.clone(), data.clone());
+        }
+        Ok(Box::new(MemoryFile {
+            files: Rc::clone(&self.files),
+            key,
+            data: Cursor::new(data),
+            mode,
+            dirty: false,
+        }))
+    }
+    fn remove(&self, path: &str) -> io::Result<()> {
+        match self.files.borrow_mut().remove(&Self::key(path)) {
+            Some(_) => Ok(()),
+            None => Err(not_found()),
+        }
+    }
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let mut files = self.files.borrow_mut();
+        match files.remove(&Self::key(from)) {
+            Some(data) => {
+                files.insert(Self::key(to), data);
+                Ok(())
+            }
+            None => Err(not_found()),
+        }
+    }
+}
+
+/// A file of a [`MemoryFileSystem`]. Written data is stored
+/// in the filesystem when the file is flushed or dropped.
+struct MemoryFile {
+    files: MemoryFiles,
+    key: String,
+    data: Cursor<Vec<u8>>,
+    mode: OpenMode,
+    dirty: bool,
+}
+
+impl Read for MemoryFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.mode.read {
+            return Err(bad_file_descriptor());
+        }
+        self.data.read(buf)
+    }
+}
+
+impl Write for MemoryFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.mode.write {
+            return Err(bad_file_descriptor());
+        }
+        if self.mode.append {
+            self.data.seek(SeekFrom::End(0))?;
+        }
+        self.dirty = true;
+        self.data.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        if self.dirty {
+            self.files
+                .borrow_mut()
+                .insert(self.key.clone(), self.data.get_ref().clone());
+            self.dirty = false;
+        }
+        Ok(())
+    }
+}
+
+impl Seek for MemoryFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.data.seek(pos)
+    }
+}
+
+impl Drop for MemoryFile {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{api, fs::MemoryFileSystem, luaL, object::TValue};
+    use std::rc::Rc;
+
+    #[test]
+    fn memory_io() {
+        let fs = MemoryFileSystem::new();
+        fs.insert("data/in.txt", "first\nsecond\n");
+        let mut state = luaL::newstate();
+        state.g.fs = Rc::new(fs.clone());
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "local f = io.open('./data/../data/in.txt')
+            z = f:read('a')
+            f:close()
+            f = io.open('/out.txt', 'w')
+            f:write('written')
+            f:close()
+            f = io.open('out.txt', 'a+')
+            f:write('!')
+            f:seek('set')
+            w = f:read('a')
+            f:close()
+            os.rename('out.txt', 'moved.txt')
+            a, b = io.open('out.txt')",
+        )
+        .unwrap();
+        api::get_global(&mut state, "z");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("first\nsecond\n"));
+        api::get_global(&mut state, "w");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("written!"));
+        api::get_global(&mut state, "b");
+        assert_eq!(
+            state.stack.last().unwrap(),
+            &TValue::from("out.txt: No such file or directory")
+        );
+        assert_eq!(fs.get("moved.txt"), Some(b"written!".to_vec()));
+        assert_eq!(fs.get("out.txt"), None);
+    }
+    #[test]
+    fn memory_dofile() {
+        let fs = MemoryFileSystem::new();
+        fs.insert("lib.lua", "#!/usr/bin/lua\nx = 40\nreturn x + 2");
+        let mut state = luaL::newstate();
+        state.g.fs = Rc::new(fs);
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(&mut state, "z = dofile('lib.lua') + x").unwrap();
+        api::get_global(&mut state, "z");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(82));
+        luaL::dostring(&mut state, "a, b = loadfile('missing.lua')").unwrap();
+        api::get_global(&mut state, "b");
+        assert_eq!(
+            state.stack.last().unwrap(),
+            &TValue::from("cannot open missing.lua: No such file or directory")
+        );
+    }
+    #[test]
+    fn chroot_confinement() {
+        let root = std::env::temp_dir().join(format!("lua_chroot_{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let mut state = luaL::newstate();
+        state.g.fs = Rc::new(super::ChrootFileSystem::new(&root));
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "local f = io.open('/../../escaped.txt', 'w')
+            f:write('inside')
+            f:close()",
+        )
+        .unwrap();
+        let content = std::fs::read_to_string(root.join("escaped.txt")).unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+        assert_eq!(content, "inside");
+    }
+    #[test]
+    fn chroot_prefixed_path() {
+        let root = std::env::temp_dir().join("lua_chroot_prefix");
+        let fs = super::ChrootFileSystem::new(&root);
+        let path = fs.host_path("C:/Windows/../Windows/win.ini");
+        if cfg!(windows) {
+            assert!(path.is_err());
+        } else {
+            assert_eq!(
+                path.unwrap(),
+                root.join("C:").join("Windows").join("win.ini")
+            );
+        }
+        assert!(fs.host_path("/a/b").unwrap().starts_with(&root));
+    }
+    #[test]
+    fn chroot_root_protected() {
+        let root = std::env::temp_dir().join(format!("lua_chroot_root_{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let mut state = luaL::newstate();
+        state.g.fs = Rc::new(super::ChrootFileSystem::new(&root));
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "a = os.remove('/')
+            b = os.remove('..')
+            c = os.remove('sub/..')
+            d = os.rename('/', 'moved')
+            e = os.rename('moved', '.')",
+        )
+        .unwrap();
+        let exists = root.is_dir();
+        std::fs::remove_dir_all(&root).unwrap();
+        assert!(exists);
+        for name in ["a", "b", "c", "d", "e"] {
+            api::get_global(&mut state, name);
+            assert_eq!(state.stack.last().unwrap(), &TValue::Nil);
+        }
+    }
+}
@@ -1,2 +1,19 @@
 //! Auxiliary functions to manipulate prototypes and closures
 
+use crate::object::Proto;
+
+/// Looks for the n-th local variable (starting at 1) active at instruction
+/// `pc` of function `f`. Returns None if there is no such variable.
+pub(crate) fn get_local_name(f: &Proto, local_number: usize, pc: usize) -> Option<&str> {
+    let mut local_number = local_number;
+    for locvar in f.locvars.iter().take_while(|v| v.start_pc <= pc) {
+        if pc < locvar.end_pc {
+            // is variable active?
+            local_number -= 1;
+            if local_number == 0 {
+                return Some(&locvar.name);
+            }
+        }
+    }
+    None // not found
+}
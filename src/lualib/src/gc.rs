@@ -0,0 +1,1101 @@
+//! Cycle collector
+//!
+//! Tables, closures and userdata are reference counted: `Rc` frees them as soon
+//! as they are not referenced anymore, except when they are part of a cycle
+//! (`t.self = t`). The state records every such object it creates. A collection
+//! marks all the objects reachable from the roots (the stack, the registry, the
+//! open upvalues and the basic types metatables). The recorded objects that are
+//! still alive but not marked are only referenced by cycles : their references
+//! are cleared so that `Rc` frees them.
+//!
+//! Objects whose metatable has a `__gc` field when it is set are kept alive by
+//! the state until a collection finds them unreachable. They are then
+//! resurrected until their finalizer has been called, in the reverse order of
+//! their registration. The pending finalizers are all called when the state is
+//! dropped.
+//!
+//! Objects held by Rust code outside of the stack and the registry (a table
+//! popped by a library function calling Lua, a value kept by a userdata) are
+//! roots too. They are found by comparing the reference count of each object
+//! with the number of references to it from the other recorded objects: an
+//! object with more references is referenced from outside.
+//!
+//! Memory accounting: the memory used by the state is measured by walking its
+//! stack, its objects, the strings they reference and the prototypes. As this
+//! is costly, the sizes of the objects, strings and table parts allocated since
+//! the last measure are added to a debt. When a memory limit is set and the
+//! last measure plus the debt exceed it, a full collection is done and the
+//! memory is measured again. If it still exceeds the limit, a "not enough
+//! memory" error is raised. Every full collection measures the memory, and
+//! `collectgarbage("count")` returns the last measure plus the debt.
+
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet, VecDeque},
+    ffi::c_void,
+    mem::{size_of, size_of_val},
+    rc::{Rc, Weak},
+};
+
+use crate::{
+    api::LuaError,
+    luaD,
+    luaH::{Table, TableRef},
+    limits::Instruction,
+    object::{Closure, LocVar, Proto, StkId, TValue, UpVal, UserData},
+    parser::UpValDesc,
+    state::{CallInfo, LuaState, CIST_FIN},
+};
+
+/// minimum number of recorded objects triggering an automatic collection
+const GC_MIN_THRESHOLD: usize = 1024;
+/// default size of the heap after a collection triggering the next one, in
+/// percent of the size of the live heap
+const GC_PAUSE: usize = 200;
+/// default speed of the collector relative to memory allocation, in percent
+const GC_STEPMUL: usize = 200;
+
+/// A collectable object created by the state
+enum GcObject {
+    Table(Weak<RefCell<Table>>),
+    Function(Weak<RefCell<Closure>>),
+    UserData(Weak<RefCell<UserData>>),
+}
+
+impl GcObject {
+    /// Returns the object if it has not been freed yet
+    fn upgrade(&self) -> Option<TValue> {
+        match self {
+            GcObject::Table(t) => t.upgrade().map(TValue::Table),
+            GcObject::Function(cl) => cl.upgrade().map(TValue::Function),
+            GcObject::UserData(ud) => ud.upgrade().map(TValue::UserData),
+        }
+    }
+}
+
+/// State of the cycle collector
+pub(crate) struct GcState {
+    /// every collectable object created since the last collection or still
+    /// alive after it
+    objects: Vec<GcObject>,
+    /// number of recorded objects triggering the next automatic collection
+    threshold: usize,
+    /// whether automatic collections are enabled
+    pub(crate) running: bool,
+    /// number of recorded objects after a collection triggering the next one,
+    /// in percent of the number of live objects
+    pub(crate) pause: usize,
+    /// speed of the collector. Collections are not incremental: only kept for
+    /// `collectgarbage("setstepmul")`
+    pub(crate) stepmul: usize,
+    /// objects with a finalizer, in registration order
+    finobj: Vec<TValue>,
+    /// address of the objects in `finobj`
+    finset: HashSet<*const c_void>,
+    /// unreachable objects whose finalizer must be called, next one first
+    tobefnz: VecDeque<TValue>,
+    /// maximum memory used by the state, in bytes
+    pub(crate) limit: Option<usize>,
+    /// memory used by the state at the last measure, in bytes
+    pub(crate) estimate: usize,
+    /// bytes allocated since the last measure
+    pub(crate) debt: usize,
+    /// whether the error being raised is a memory error
+    pub(crate) memerr: bool,
+}
+
+impl Default for GcState {
+    fn default() -> Self {
+        Self {
+            objects: Vec::new(),
+            threshold: GC_MIN_THRESHOLD,
+            running: true,
+            pause: GC_PAUSE,
+            stepmul: GC_STEPMUL,
+            finobj: Vec::new(),
+            finset: HashSet::new(),
+            tobefnz: VecDeque::new(),
+            limit: None,
+            estimate: 0,
+            debt: 0,
+            memerr: false,
+        }
+    }
+}
+
+impl LuaState {
+    /// Records a new object so that the collector can free it if it ends
+    /// up in an unreachable cycle
+    pub(crate) fn link_object(&mut self, o: &TValue) {
+        let (o, size) = match o {
+            TValue::Table(t) => (GcObject::Table(Rc::downgrade(t)), table_size(&t.borrow())),
+            TValue::Function(cl) => (
+                GcObject::Function(Rc::downgrade(cl)),
+                closure_size(&cl.borrow()),
+            ),
+            TValue::UserData(ud) => (
+                GcObject::UserData(Rc::downgrade(ud)),
+                userdata_size(&ud.borrow()),
+            ),
+            _ => return,
+        };
+        self.g.gc.objects.push(o);
+        self.g.gc.debt += size;
+    }
+
+    /// Registers an object for finalization if its metatable has a `__gc`
+    /// field. Called each time the metatable of a table or a userdata is set.
+    pub(crate) fn check_finalizer(&mut self, o: &TValue) {
+        let gc = &mut self.g.gc;
+        if gc.finset.contains(&o.to_pointer()) || self.get_tm_by_obj(o, "__gc").is_nil() {
+            return; // already registered or no finalizer
+        }
+        self.g.gc.finset.insert(o.to_pointer());
+        self.g.gc.finobj.push(o.clone());
+    }
+
+    /// Runs a collection if enough objects were created since the last one.
+    /// Raises a memory error if the memory limit is exceeded.
+    pub(crate) fn check_gc(&mut self) -> Result<(), LuaError> {
+        if self.g.gc.running && self.g.gc.objects.len() >= self.g.gc.threshold {
+            self.full_gc()?;
+        }
+        self.check_alloc(0)
+    }
+
+    /// Checks that `size` more bytes can be allocated without exceeding the
+    /// memory limit. Otherwise, collects the garbage and measures the memory
+    /// again before raising a memory error.
+    pub(crate) fn check_alloc(&mut self, size: usize) -> Result<(), LuaError> {
+        match self.g.gc.limit {
+            Some(limit) if self.g.gc.estimate + self.g.gc.debt + size > limit => {
+                self.full_gc()?;
+                if self.g.gc.estimate + size > limit {
+                    self.g.gc.memerr = true;
+                    self.push_literal("not enough memory");
+                    return Err(LuaError::MemoryError);
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks the memory limit before a buffer of `len` bytes out of
+    /// `capacity` grows by `additional` bytes. Returns the number of bytes to
+    /// reserve, 0 if the buffer already has room for them.
+    pub(crate) fn check_buffer_growth(
+        &mut self,
+        len: usize,
+        capacity: usize,
+        additional: usize,
+    ) -> Result<usize, LuaError> {
+        let needed = len + additional;
+        if needed <= capacity {
+            return Ok(0);
+        }
+        let size = needed.max(capacity * 2);
+        self.check_alloc(size)?;
+        Ok(size - len)
+    }
+
+    /// Frees all the unreachable cycles, clears the weak tables entries
+    /// referencing unreachable objects and calls the finalizers of the
+    /// unreachable objects. Returns the number of recorded objects that were
+    /// freed by breaking cycles.
+    pub(crate) fn full_gc(&mut self) -> Result<usize, LuaError> {
+        let mut marker = self.mark();
+        // at this point, all strongly accessible objects are marked.
+        // Clear values from weak tables, before checking finalizers
+        marker.clear_weak_values();
+        self.separate_tobefnz(&marker, false);
+        // resurrect the objects to be finalized
+        for o in self.g.gc.tobefnz.iter() {
+            marker.mark_value(o);
+        }
+        marker.converge();
+        marker.clear_weak_keys();
+        // clear values from resurrected weak tables
+        marker.clear_weak_values();
+        let mut garbage = Vec::new();
+        self.g.gc.objects.retain(|o| match o.upgrade() {
+            None => false, // already freed by Rc
+            Some(o) if marker.is_marked(&o) => true,
+            Some(o) => {
+                garbage.push(o);
+                false
+            }
+        });
+        // keep strong references to the garbage while its cycles are broken
+        for o in garbage.iter() {
+            clear_object(o);
+        }
+        let garbage_count = garbage.len();
+        self.g.gc.threshold =
+            GC_MIN_THRESHOLD.max(self.g.gc.objects.len() / 100 * self.g.gc.pause);
+        drop(garbage);
+        self.g.gc.estimate = self.memory_estimate();
+        self.g.gc.debt = 0;
+        self.call_all_pending_finalizers(true)?;
+        Ok(garbage_count)
+    }
+
+    /// Moves the unreachable objects with a finalizer (or all of them if `all`
+    /// is true) to the list of objects to be finalized, the last registered
+    /// one first
+    fn separate_tobefnz(&mut self, marker: &Marker, all: bool) {
+        let gc = &mut self.g.gc;
+        let mut i = gc.finobj.len();
+        while i > 0 {
+            i -= 1;
+            if all || !marker.is_marked(&gc.finobj[i]) {
+                let o = gc.finobj.remove(i);
+                gc.finset.remove(&o.to_pointer());
+                gc.tobefnz.push_back(o);
+            }
+        }
+    }
+
+    /// Calls the finalizers of all the objects to be finalized
+    fn call_all_pending_finalizers(&mut self, propagate_errors: bool) -> Result<(), LuaError> {
+        while let Some(o) = self.g.gc.tobefnz.pop_front() {
+            self.gctm(o, propagate_errors)?;
+        }
+        Ok(())
+    }
+
+    /// Calls the `__gc` metamethod of an object. An error in the finalizer
+    /// is raised again as "error in __gc metamethod" if `propagate_errors`
+    /// is true, ignored otherwise
+    fn gctm(&mut self, o: TValue, propagate_errors: bool) -> Result<(), LuaError> {
+        let tm = self.get_tm_by_obj(&o, "__gc");
+        if tm.is_nil() {
+            return Ok(());
+        }
+        let oldah = self.allowhook;
+        let running = self.g.gc.running;
+        self.allowhook = false; // stop debug hooks during GC metamethod
+        self.g.gc.running = false; // avoid GC steps
+        let top = self.stack.len();
+        self.stack.push(tm);
+        self.stack.push(o);
+        self.base_ci[self.ci].call_status |= CIST_FIN; // will run a finalizer
+        let status = luaD::pcall(self, call_finalizer, top, top, 0);
+        self.base_ci[self.ci].call_status &= !CIST_FIN; // not running a finalizer anymore
+        self.allowhook = oldah;
+        self.g.gc.running = running;
+        match status {
+            Err(e) if propagate_errors => {
+                if e == LuaError::RuntimeError {
+                    // error object is on the top of the stack
+                    let msg = match self.stack.pop() {
+                        Some(TValue::String(msg)) => msg.to_string(),
+                        _ => "no message".to_owned(),
+                    };
+                    self.push_string(&format!("error in __gc metamethod ({})", msg));
+                }
+                Err(e)
+            }
+            _ => {
+                self.stack.truncate(top);
+                Ok(())
+            }
+        }
+    }
+
+    /// Calls the finalizers of all the objects with a finalizer, reachable or
+    /// not. Called when the state is dropped.
+    pub(crate) fn call_all_finalizers(&mut self) {
+        if self.g.gc.finobj.is_empty() && self.g.gc.tobefnz.is_empty() {
+            return;
+        }
+        self.separate_tobefnz(&Marker::default(), true);
+        let _ = self.call_all_pending_finalizers(false);
+    }
+
+    /// Breaks the references between all the objects of the state so that
+    /// the cycles (`_G._G`, `package.loaded`...) do not keep them alive once
+    /// the state is dropped. Called after the last finalizers.
+    pub(crate) fn free_all_objects(&mut self) {
+        self.stack.clear();
+        let objects: Vec<TValue> = std::mem::take(&mut self.g.gc.objects)
+            .iter()
+            .filter_map(GcObject::upgrade)
+            .collect();
+        for o in objects.iter() {
+            clear_object(o);
+        }
+        for mt in std::mem::take(&mut self.g.mt).into_values().flatten() {
+            clear_object(&TValue::Table(mt));
+        }
+        let globals = self.get_global_table();
+        if is_collectable(&globals) {
+            clear_object(&globals);
+        }
+        clear_object(&self.g.registry);
+    }
+
+    /// Returns the memory used by the state at the last measure plus the bytes
+    /// allocated since, without walking the heap
+    pub(crate) fn memory_count(&self) -> usize {
+        self.g.gc.estimate + self.g.gc.debt
+    }
+
+    /// Returns an estimate of the memory used by the state, in bytes: its
+    /// stack, its objects, the strings they reference and the prototypes
+    pub(crate) fn memory_estimate(&self) -> usize {
+        let mut seen = HashSet::new();
+        let mut total = self.stack.capacity() * size_of::<TValue>()
+            + self.base_ci.capacity() * size_of::<CallInfo>();
+        for o in self.stack.iter() {
+            total += value_size(o, &mut seen);
+        }
+        total += value_size(&self.g.registry, &mut seen);
+        total += value_size(&self.get_global_table(), &mut seen);
+        for o in self.g.gc.objects.iter() {
+            if let Some(o) = o.upgrade() {
+                total += value_size(&o, &mut seen);
+            }
+        }
+        for p in self.protos.iter() {
+            // functions being compiled
+            total += proto_size(p, &mut seen);
+        }
+        total
+    }
+
+    /// Marks every object reachable from the roots
+    fn mark(&self) -> Marker {
+        let mut marker = Marker {
+            gray: self.held_outside(),
+            ..Default::default()
+        };
+        for o in self.stack.iter() {
+            marker.mark_value(o);
+        }
+        marker.mark_value(&self.g.registry);
+        for mt in self.g.mt.values().flatten() {
+            marker.gray.push(TValue::from(mt));
+        }
+        for uv in self.open_upval.iter() {
+            if let UpVal::Closed(o) = &*uv.borrow() {
+                marker.mark_value(o);
+            }
+        }
+        marker.gray.push(TValue::from(&self.env));
+        marker.mark_value(&self.envvalue);
+        marker.converge();
+        marker
+    }
+
+    /// Returns the recorded objects referenced from outside the recorded
+    /// objects and the lists of objects with a finalizer
+    fn held_outside(&self) -> Vec<TValue> {
+        let mut inner_refs: HashMap<*const c_void, usize> = HashMap::new();
+        let mut count = |o: &TValue| {
+            if is_collectable(o) {
+                *inner_refs.entry(o.to_pointer()).or_default() += 1;
+            }
+        };
+        // a closed upvalue shared by several closures holds its value once
+        let mut upvals = HashSet::new();
+        for o in self.g.gc.objects.iter().filter_map(GcObject::upgrade) {
+            match &o {
+                TValue::Table(t) => {
+                    let t = t.borrow();
+                    if let Some(mt) = &t.metatable {
+                        count(&TValue::from(mt));
+                    }
+                    for v in t.array.iter() {
+                        count(v);
+                    }
+                    for (k, v) in t.node.iter() {
+                        count(k);
+                        count(v);
+                    }
+                }
+                TValue::Function(cl) => {
+                    let cl = cl.borrow();
+                    for i in 0..cl.get_nupvalues() {
+                        let uv = cl.borrow_upval(i);
+                        if upvals.insert(Rc::as_ptr(uv)) {
+                            if let UpVal::Closed(o) = &*uv.borrow() {
+                                count(o);
+                            }
+                        }
+                    }
+                }
+                TValue::UserData(ud) => {
+                    let ud = ud.borrow();
+                    if let Some(mt) = &ud.metatable {
+                        count(&TValue::from(mt));
+                    }
+                    count(&ud.uservalue);
+                }
+                _ => unreachable!(),
+            }
+        }
+        for o in self.g.gc.finobj.iter().chain(self.g.gc.tobefnz.iter()) {
+            count(o);
+        }
+        self.g
+            .gc
+            .objects
+            .iter()
+            .filter_map(GcObject::upgrade)
+            .filter(|o| {
+                // not counting the reference returned by upgrade
+                let refs = strong_count(o) - 1;
+                refs > inner_refs.get(&o.to_pointer()).copied().unwrap_or(0)
+            })
+            .collect()
+    }
+}
+
+/// Returns the number of references to an object
+fn strong_count(o: &TValue) -> usize {
+    match o {
+        TValue::Table(t) => Rc::strong_count(t),
+        TValue::Function(cl) => Rc::strong_count(cl),
+        TValue::UserData(ud) => Rc::strong_count(ud),
+        _ => unreachable!(),
+    }
+}
+
+/// Returns the size of a table, without the strings it references
+pub(crate) fn table_size(t: &Table) -> usize {
+    size_of::<Table>()
+        + t.array.capacity() * size_of::<TValue>()
+        + t.node.capacity() * 2 * size_of::<TValue>()
+}
+
+fn closure_size(cl: &Closure) -> usize {
+    size_of::<Closure>()
+        + match cl {
+            Closure::Lua(cl) => cl.upvalues.len() * size_of::<UpVal>(),
+            Closure::Rust(cl) => cl.upvalues.len() * size_of::<UpVal>(),
+        }
+}
+
+fn userdata_size(ud: &UserData) -> usize {
+    size_of::<UserData>() + size_of_val(&*ud.data)
+}
+
+/// Returns the size of a string, or of an object and the strings it directly
+/// references. Values already in `seen` are not counted again.
+fn value_size(o: &TValue, seen: &mut HashSet<*const c_void>) -> usize {
+    let ptr = match o {
+        TValue::String(s) => Rc::as_ptr(s) as *const c_void,
+        o if is_collectable(o) => o.to_pointer(),
+        _ => return 0,
+    };
+    if !seen.insert(ptr) {
+        return 0; // already counted
+    }
+    match o {
+        TValue::String(s) => size_of::<String>() + s.capacity(),
+        TValue::Table(t) => {
+            let t = t.borrow();
+            let mut size = table_size(&t);
+            for v in t.array.iter() {
+                size += string_size(v, seen);
+            }
+            for (k, v) in t.node.iter() {
+                size += string_size(k, seen) + string_size(v, seen);
+            }
+            size
+        }
+        TValue::Function(cl) => {
+            let cl = cl.borrow();
+            let mut size = closure_size(&cl);
+            match &*cl {
+                Closure::Rust(cl) => {
+                    for uv in cl.upvalues.iter() {
+                        if let UpVal::Closed(v) = &*uv.borrow() {
+                            size += string_size(v, seen);
+                        }
+                    }
+                }
+                Closure::Lua(cl) => {
+                    if seen.insert(Rc::as_ptr(&cl.proto) as *const c_void) {
+                        size += proto_size(&cl.proto, seen);
+                    }
+                }
+            }
+            size
+        }
+        TValue::UserData(ud) => userdata_size(&ud.borrow()),
+        _ => 0,
+    }
+}
+
+/// Returns the size of a prototype, its constants and its nested functions
+fn proto_size(p: &Proto, seen: &mut HashSet<*const c_void>) -> usize {
+    let mut size = size_of::<Proto>()
+        + p.code.capacity() * size_of::<Instruction>()
+        + p.k.capacity() * size_of::<TValue>()
+        + p.p.capacity() * size_of::<Rc<Proto>>()
+        + p.lineinfo.capacity() * size_of::<usize>()
+        + p.locvars.capacity() * size_of::<LocVar>()
+        + p.upvalues.capacity() * size_of::<UpValDesc>()
+        + p.source.capacity();
+    for k in p.k.iter() {
+        size += string_size(k, seen);
+    }
+    for np in p.p.iter() {
+        if seen.insert(Rc::as_ptr(np) as *const c_void) {
+            size += proto_size(np, seen);
+        }
+    }
+    size
+}
+
+/// Returns the size of `o` if it is a string not already counted
+fn string_size(o: &TValue, seen: &mut HashSet<*const c_void>) -> usize {
+    if let TValue::String(_) = o {
+        value_size(o, seen)
+    } else {
+        0
+    }
+}
+
+/// Calls the finalizer at stack position `func`
+fn call_finalizer(state: &mut LuaState, func: StkId) -> Result<i32, LuaError> {
+    state.dcall_no_yield(func, 0)?;
+    Ok(0)
+}
+
+/// Marking phase of a collection
+#[derive(Default)]
+struct Marker {
+    /// address of every object reachable from the roots
+    marked: HashSet<*const c_void>,
+    /// objects reached but not traversed yet
+    gray: Vec<TValue>,
+    /// traversed weak tables, with their weak keys and weak values flags
+    weak: Vec<(TableRef, bool, bool)>,
+    /// traversed tables with weak keys and strong values
+    ephemerons: Vec<TableRef>,
+}
+
+impl Marker {
+    /// Adds a value to the list of objects to traverse if it is collectable
+    fn mark_value(&mut self, o: &TValue) {
+        if is_collectable(o) {
+            self.gray.push(o.clone());
+        }
+    }
+
+    /// Whether a value was reached. Non collectable values are always reachable
+    fn is_marked(&self, o: &TValue) -> bool {
+        !is_collectable(o) || self.marked.contains(&o.to_pointer())
+    }
+
+    /// Traverses all the reached objects, including the ephemerons values
+    /// whose key is reached
+    fn converge(&mut self) {
+        loop {
+            self.propagate();
+            if !self.converge_ephemerons() {
+                break;
+            }
+        }
+    }
+
+    /// Traverses all the reached objects
+    fn propagate(&mut self) {
+        while let Some(o) = self.gray.pop() {
+            if !self.marked.insert(o.to_pointer()) {
+                continue; // already traversed
+            }
+            match &o {
+                TValue::Table(t) => self.traverse_table(t),
+                TValue::Function(cl) => {
+                    let cl = cl.borrow();
+                    for i in 0..cl.get_nupvalues() {
+                        // open upvalues are in the stack
+                        if let UpVal::Closed(o) = &*cl.borrow_upval(i).borrow() {
+                            self.mark_value(o);
+                        }
+                    }
+                }
+                TValue::UserData(ud) => {
+                    let ud = ud.borrow();
+                    if let Some(mt) = &ud.metatable {
+                        self.gray.push(TValue::from(mt));
+                    }
+                    self.mark_value(&ud.uservalue);
+                }
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    /// Marks the content of a table, except its weak keys and values
+    fn traverse_table(&mut self, tref: &TableRef) {
+        let t = tref.borrow();
+        if let Some(mt) = &t.metatable {
+            self.gray.push(TValue::from(mt));
+        }
+        let (weakkey, weakvalue) = weak_mode(&t);
+        if weakkey || weakvalue {
+            self.weak.push((tref.clone(), weakkey, weakvalue));
+        }
+        if !weakvalue {
+            // keys of the array part are integers
+            for v in t.array.iter() {
+                self.mark_value(v);
+            }
+        }
+        if weakkey && !weakvalue {
+            // values are marked when their key is
+            self.ephemerons.push(tref.clone());
+        } else {
+            for (k, v) in t.node.iter() {
+                if !weakkey {
+                    self.mark_value(k);
+                }
+                if !weakvalue {
+                    self.mark_value(v);
+                }
+            }
+        }
+    }
+
+    /// Marks the values of the ephemerons entries whose key is marked.
+    /// Returns true if some value has been marked.
+    fn converge_ephemerons(&mut self) -> bool {
+        let mut changed = false;
+        for i in 0..self.ephemerons.len() {
+            let t = self.ephemerons[i].clone();
+            for (k, v) in t.borrow().node.iter() {
+                if self.is_marked(k) && !self.is_marked(v) {
+                    self.gray.push(v.clone());
+                    changed = true;
+                }
+            }
+        }
+        changed
+    }
+
+    /// Removes from the tables with weak keys the entries whose key was not
+    /// marked
+    fn clear_weak_keys(&self) {
+        for (t, weakkey, _) in self.weak.iter() {
+            if *weakkey {
+                t.borrow_mut().node.retain(|k, _| self.is_marked(k));
+            }
+        }
+    }
+
+    /// Removes from the tables with weak values the entries whose value was
+    /// not marked
+    fn clear_weak_values(&self) {
+        for (t, _, weakvalue) in self.weak.iter() {
+            if *weakvalue {
+                let t = &mut *t.borrow_mut();
+                for v in t.array.iter_mut().chain(t.node.values_mut()) {
+                    if !self.is_marked(v) {
+                        *v = TValue::Nil;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Returns the weak keys and weak values flags from the `__mode` field of
+/// the table metatable
+fn weak_mode(t: &Table) -> (bool, bool) {
+    let Some(mt) = &t.metatable else {
+        return (false, false);
+    };
+    match mt.borrow().get(&TValue::from("__mode")) {
+        Some(TValue::String(mode)) => (mode.contains('k'), mode.contains('v')),
+        _ => (false, false),
+    }
+}
+
+/// Whether a value is an object that the collector can free
+fn is_collectable(o: &TValue) -> bool {
+    matches!(o, TValue::Table(_) | TValue::Function(_) | TValue::UserData(_))
+}
+
+/// Removes all the references held by an unreachable object
+fn clear_object(o: &TValue) {
+    match o {
+        TValue::Table(t) => {
+            let mut t = t.borrow_mut();
+            t.metatable = None;
+            t.array.clear();
+            t.node.clear();
+        }
+        TValue::Function(cl) => match &mut *cl.borrow_mut() {
+            Closure::Lua(cl) => cl.upvalues.clear(),
+            Closure::Rust(cl) => cl.upvalues.clear(),
+        },
+        TValue::UserData(ud) => {
+            let mut ud = ud.borrow_mut();
+            ud.metatable = None;
+            ud.uservalue = TValue::Nil;
+        }
+        _ => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        rc::Rc,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use crate::{api, luaL, object::TValue, state::LuaState, GcOption, LuaError};
+    #[test]
+    fn self_reference() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(&mut state, "t = {} t.self = t").unwrap();
+        api::get_global(&mut state, "t");
+        let weak = match state.stack.pop().unwrap() {
+            TValue::Table(t) => Rc::downgrade(&t),
+            _ => unreachable!(),
+        };
+        api::gc(&mut state, GcOption::Collect, 0).unwrap();
+        assert!(weak.upgrade().is_some());
+        luaL::dostring(&mut state, "ok = t.self == t t = nil").unwrap();
+        api::gc(&mut state, GcOption::Collect, 0).unwrap();
+        assert!(weak.upgrade().is_none());
+        api::get_global(&mut state, "ok");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Boolean(true));
+    }
+    #[test]
+    fn drop_state() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        api::new_userdata(&mut state, 42);
+        let weak = match state.stack.last().unwrap() {
+            TValue::UserData(ud) => Rc::downgrade(ud),
+            _ => unreachable!(),
+        };
+        api::set_global(&mut state, "u");
+        luaL::dostring(&mut state, "t = {u = u} t.self = t").unwrap();
+        api::close(state);
+        assert!(weak.upgrade().is_none());
+    }
+    #[test]
+    fn closure_cycle() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "local t = {}
+            t.f = function() return t end
+            local a, b = {}, {}
+            a.next, b.prev = b, a
+            g, l = t.f, a",
+        )
+        .unwrap();
+        api::get_global(&mut state, "g");
+        api::get_global(&mut state, "l");
+        let l = Rc::downgrade(match &state.stack.pop().unwrap() {
+            TValue::Table(t) => t,
+            _ => unreachable!(),
+        });
+        let g = Rc::downgrade(match &state.stack.pop().unwrap() {
+            TValue::Function(cl) => cl,
+            _ => unreachable!(),
+        });
+        luaL::dostring(&mut state, "g, l = nil").unwrap();
+        assert_eq!(state.full_gc().unwrap(), 4);
+        assert!(g.upgrade().is_none());
+        assert!(l.upgrade().is_none());
+    }
+    #[test]
+    fn automatic() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "for i = 1, 100000 do
+                local t = {}
+                t.self = t
+            end
+            n = 0
+            for i = 1, 10 do
+                n = n + i
+            end",
+        )
+        .unwrap();
+        assert!(state.g.gc.objects.len() < 10000);
+        api::gc(&mut state, GcOption::Stop, 0).unwrap();
+        assert_eq!(api::gc(&mut state, GcOption::IsRunning, 0).unwrap(), 0);
+        luaL::dostring(&mut state, "for i = 1, 10000 do local t = {} t.self = t end").unwrap();
+        assert!(state.g.gc.objects.len() >= 10000);
+        api::gc(&mut state, GcOption::Restart, 0).unwrap();
+        assert_eq!(api::gc(&mut state, GcOption::Step, 0).unwrap(), 1);
+        assert!(state.g.gc.objects.len() < 1000);
+    }
+    #[test]
+    fn weak_values() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "local cache = setmetatable({}, {__mode = 'v'})
+            local function fill()
+                cache[1] = {}
+                cache.x = function() end
+                cache.s = 'str'
+            end
+            fill()
+            local keep = {}
+            cache.k = keep
+            collectgarbage()
+            n = 0
+            for k in pairs(cache) do n = n + 1 end
+            ok = cache.k == keep and cache.s == 'str' and cache[1] == nil",
+        )
+        .unwrap();
+        api::get_global(&mut state, "n");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(2));
+        api::get_global(&mut state, "ok");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Boolean(true));
+    }
+    #[test]
+    fn weak_keys() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "local t = setmetatable({}, {__mode = 'k'})
+            local all = setmetatable({}, {__mode = 'kv'})
+            local function fill()
+                t[{}] = 1
+                -- ephemerons: values referencing their own key
+                local k = {}
+                t[k] = {ref = k}
+                -- chain of ephemerons ending with an unreachable key
+                local k2 = {}
+                t[k2] = {}
+                t[t[k2]] = k2
+                all[{}] = {}
+                all[1] = {}
+            end
+            fill()
+            local keep = {}
+            t[keep] = {ref = keep}
+            t[1] = {}
+            collectgarbage()
+            n = 0
+            for k in pairs(t) do n = n + 1 end
+            m = next(all)",
+        )
+        .unwrap();
+        api::get_global(&mut state, "n");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(2));
+        api::get_global(&mut state, "m");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Nil);
+    }
+    #[test]
+    fn finalizers() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "local log = {}
+            local mt = {__gc = function(o) log[#log + 1] = o.name end}
+            local a = setmetatable({name = 'a'}, mt)
+            local b = setmetatable({name = 'b'}, mt)
+            setmetatable({name = 'c'}, mt)
+            a = nil
+            collectgarbage()
+            collectgarbage()
+            s = table.concat(log)",
+        )
+        .unwrap();
+        api::get_global(&mut state, "s");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("ca"));
+    }
+    #[test]
+    fn resurrection() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "local t = setmetatable({}, {__gc = function(o) saved = o end})
+            t.self = t
+            t.x = 42
+            t = nil
+            collectgarbage()
+            x = saved.self.x",
+        )
+        .unwrap();
+        api::get_global(&mut state, "x");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(42));
+    }
+    #[test]
+    fn finalizer_error() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "setmetatable({}, {__gc = function() error('boom', 0) end})
+            ok, msg = pcall(collectgarbage)",
+        )
+        .unwrap();
+        api::get_global(&mut state, "msg");
+        assert_eq!(
+            state.stack.last().unwrap(),
+            &TValue::from("error in __gc metamethod (boom)")
+        );
+    }
+    /// calls its argument while holding a table outside of the stack
+    fn hold_table(s: &mut LuaState) -> Result<i32, ()> {
+        api::new_table(s);
+        api::push_string(s, "x");
+        api::set_field(s, -2, "k");
+        let t = s.stack.pop().unwrap();
+        api::push_value(s, 1);
+        api::call(s, 0, 0).map_err(|_| ())?;
+        s.stack.push(t);
+        api::get_field(s, -1, "k");
+        Ok(1)
+    }
+    #[test]
+    fn held_by_rust() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        api::push_rust_function(&mut state, hold_table, 0);
+        api::set_global(&mut state, "hold_table");
+        luaL::dostring(
+            &mut state,
+            "k = hold_table(function()
+                for i = 1, 100000 do
+                    local t = {}
+                    t.self = t
+                end
+            end)",
+        )
+        .unwrap();
+        assert!(state.g.gc.objects.len() < 10000);
+        api::get_global(&mut state, "k");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("x"));
+    }
+    static FINALIZED: AtomicUsize = AtomicUsize::new(0);
+    fn finalize(_s: &mut LuaState) -> Result<i32, ()> {
+        FINALIZED.fetch_add(1, Ordering::SeqCst);
+        Ok(0)
+    }
+    #[test]
+    fn close() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        api::push_rust_function(&mut state, finalize, 0);
+        api::set_global(&mut state, "finalize");
+        luaL::dostring(
+            &mut state,
+            "t = setmetatable({}, {__gc = finalize})
+            setmetatable({}, {__gc = finalize})
+            n = collectgarbage('count')",
+        )
+        .unwrap();
+        api::get_global(&mut state, "n");
+        assert!(matches!(state.stack.last().unwrap(), TValue::Float(n) if *n > 0.0));
+        assert_eq!(FINALIZED.load(Ordering::SeqCst), 0);
+        api::close(state);
+        assert_eq!(FINALIZED.load(Ordering::SeqCst), 2);
+    }
+    #[test]
+    fn count() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "a = collectgarbage('count')
+            t = {} for i = 1, 1000 do t[i] = {} end
+            b = collectgarbage('count')
+            t = nil
+            collectgarbage()
+            c = collectgarbage('count')",
+        )
+        .unwrap();
+        let mut count = |name| {
+            api::get_global(&mut state, name);
+            match state.stack.pop().unwrap() {
+                TValue::Float(n) => n,
+                v => panic!("unexpected count {:?}", v),
+            }
+        };
+        let (a, b, c) = (count("a"), count("b"), count("c"));
+        assert!(b > a + 1000.0 * 32.0 / 1024.0);
+        assert!(c < b);
+    }
+    #[test]
+    fn memory_limit() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        let used = api::memory_used(&state);
+        luaL::dostring(&mut state, "t = {} for i = 1, 1000 do t[i] = 'x' .. i end").unwrap();
+        assert!(api::memory_used(&state) > used + 1000 * 4);
+        assert_eq!(api::set_memory_limit(&mut state, Some(used + 100_000)), None);
+        luaL::dostring(
+            &mut state,
+            "ok, msg = pcall(function()
+                local t = {}
+                for i = 1, 1e6 do t[i] = {} end
+            end)
+            s = 'x'
+            ok2 = pcall(function() for i = 1, 20 do s = s .. s end end)
+            t = nil",
+        )
+        .unwrap();
+        api::get_global(&mut state, "msg");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("not enough memory"));
+        api::get_global(&mut state, "ok2");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Boolean(false));
+        assert_eq!(
+            luaL::dostring(&mut state, "t = {} for i = 1, 1e6 do t[i] = i end"),
+            Err(LuaError::MemoryError)
+        );
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("not enough memory"));
+        api::set_memory_limit(&mut state, None);
+        luaL::dostring(&mut state, "t = {} for i = 1, 1e4 do t[i] = {} end").unwrap();
+    }
+    #[test]
+    fn memory_checked_before_allocation() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "parts = {} for i = 1, 200 do parts[i] = ('x'):rep(1000) end",
+        )
+        .unwrap();
+        let limit = api::memory_used(&state) + 100_000;
+        api::set_memory_limit(&mut state, Some(limit));
+        luaL::dostring(
+            &mut state,
+            "local function f(n) return n > 0 and 1 + f(n - 1) or 0 end
+            local s = parts[1]:rep(30)
+            results = {
+                select(2, pcall(string.rep, 'x', 1e12)),
+                select(2, pcall(table.concat, parts)),
+                select(2, pcall(string.format, '%s%s%s%s', s, s, s, s)),
+                select(2, pcall(f, 1e5)),
+            }",
+        )
+        .unwrap();
+        for i in 1..=4 {
+            api::get_global(&mut state, "results");
+            api::get_i(&mut state, -1, i).unwrap();
+            assert_eq!(
+                state.stack.last().unwrap(),
+                &TValue::from("not enough memory")
+            );
+            api::pop(&mut state, 2);
+        }
+        assert!(api::memory_used(&state) <= limit);
+        api::set_memory_limit(&mut state, None);
+        luaL::dostring(&mut state, "assert(#table.concat(parts) == 200000)").unwrap();
+    }
+}
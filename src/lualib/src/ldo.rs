@@ -3,10 +3,11 @@
 use crate::{
     api::LuaError,
     luaG, luaU, luaY, luaZ,
-    luaconf::LUAI_MAXRCALLS,
-    object::{Closure, ProtoId, StkId, TValue},
-    state::{CallInfo, LuaState, CIST_LUA},
-    LUA_MINSTACK, LUA_SIGNATURE,
+    luaconf::{LUAI_MAXRCALLS, LUAI_MAXSTACK},
+    object::{Closure, Proto, StkId, TValue},
+    opcodes::{get_opcode, OpCode},
+    state::{CallInfo, LuaState, CIST_HOOKED, CIST_LUA, CIST_TAIL, EXTRA_STACK},
+    HookEvent, LuaDebug, LuaInteger, LUA_MASKCALL, LUA_MINSTACK, LUA_SIGNATURE,
 };
 
 /// type of protected functions, to be ran by `runprotected'
@@ -24,28 +25,34 @@ pub(crate) enum PrecallStatus {
 
 pub struct SParser<T> {
     pub z: Option<luaZ::Zio<T>>,
+    /// allowed kinds of chunk ("b", "t" or "bt"). None allows both
+    pub mode: Option<String>,
     pub name: String,
 }
 
 impl<T> SParser<T> {
-    pub fn new(z: luaZ::Zio<T>, name: &str) -> Self {
+    pub fn new(z: luaZ::Zio<T>, name: &str, mode: Option<&str>) -> Self {
         Self {
             z: Some(z),
+            mode: mode.map(str::to_owned),
             name: name.to_owned(),
         }
     }
 }
 
-fn seterrorobj(state: &mut LuaState, errcode: &LuaError) {
-    match errcode {
-        LuaError::ErrorHandlerError => {
-            state.push_string("error in error handling");
+fn seterrorobj(state: &mut LuaState, errcode: &LuaError, old_top: StkId) {
+    state.g.gc.memerr = false;
+    let msg = match errcode {
+        LuaError::ErrorHandlerError => TValue::from("error in error handling"),
+        LuaError::MemoryError => TValue::from("not enough memory"),
+        LuaError::Exit(code) => TValue::Integer(*code as LuaInteger),
+        LuaError::SyntaxError | LuaError::RuntimeError | LuaError::FileError => {
+            // error message on current top
+            state.stack.last().cloned().unwrap_or_default()
         }
-        LuaError::SyntaxError | LuaError::RuntimeError => {
-            let msg = state.stack.last().unwrap().clone();
-            state.stack.push(msg);
-        }
-    }
+    };
+    state.stack.truncate(old_top);
+    state.stack.push(msg);
 }
 
 impl LuaState {
@@ -104,17 +111,25 @@ impl LuaState {
             }
         };
         let cl = self.get_closure_ref(func);
+        let needed = match &*cl.borrow() {
+            Closure::Lua(cl) => cl.proto.maxstacksize,
+            Closure::Rust(_) => LUA_MINSTACK,
+        };
+        if self.stack.len() + needed > LUAI_MAXSTACK {
+            return self.stack_overflow();
+        }
+        self.grow_stack(needed)?;
         let cl = cl.borrow();
         match &*cl {
             Closure::Lua(cl) => {
                 // Lua function. prepare its call
                 let nargs = self.stack.len() - func - 1;
-                let base = if self.protos[cl.proto].is_vararg {
+                let base = if cl.proto.is_vararg {
                     // vararg function
-                    self.adjust_varargs(cl.proto, nargs)
+                    self.adjust_varargs(&cl.proto, nargs)
                 } else {
                     // no varargs
-                    let numparams = self.protos[cl.proto].numparams;
+                    let numparams = cl.proto.numparams;
                     for _ in nargs..numparams {
                         self.stack.push(TValue::Nil);
                     }
@@ -123,7 +138,7 @@ impl LuaState {
                 let ci = CallInfo {
                     func,
                     base,
-                    top: base + self.protos[cl.proto].maxstacksize,
+                    top: base + cl.proto.maxstacksize,
                     nresults,
                     call_status: CIST_LUA,
                     ..Default::default()
@@ -131,8 +146,6 @@ impl LuaState {
                 self.stack.resize(ci.top, TValue::Nil);
                 self.base_ci.push(ci);
                 self.ci += 1;
-                // TODO handle hooks
-                Ok(PrecallStatus::Lua)
             }
             Closure::Rust(cl) => {
                 // this is a Rust function, call it
@@ -144,16 +157,128 @@ impl LuaState {
                 };
                 self.base_ci.push(ci);
                 self.ci += 1;
-                // TODO handle hooks
-                let n = (cl.f)(self).map_err(|_| LuaError::RuntimeError)?; // do the actual call
-                self.poscall(self.stack.len() - n as usize, n as usize);
-                Ok(PrecallStatus::Rust)
+                if self.hookmask & LUA_MASKCALL != 0 {
+                    self.dhook(HookEvent::Call, -1)?;
+                }
+                let n = match (cl.f)(self) {
+                    // do the actual call
+                    Ok(n) => n,
+                    Err(_) => return Err(self.rust_error()),
+                };
+                self.check_gc()?;
+                self.poscall(self.stack.len() - n as usize, n as usize)?;
+                return Ok(PrecallStatus::Rust);
+            }
+        }
+        // release the closure so that the hook can access it
+        drop(cl);
+        if self.hookmask & LUA_MASKCALL != 0 {
+            self.call_hook()?;
+        }
+        Ok(PrecallStatus::Lua)
+    }
+
+    /// Calls the call hook of the Lua function being entered. The event is a
+    /// tail call when the caller is executing a TAILCALL instruction.
+    fn call_hook(&mut self) -> Result<(), LuaError> {
+        let mut event = HookEvent::Call;
+        self.base_ci[self.ci].saved_pc += 1; // hooks assume 'pc' is already incremented
+        let prev = &self.base_ci[self.ci - 1];
+        // the first CallInfo is the host, not a Lua function
+        if self.ci > 1 && prev.call_status & CIST_LUA != 0 {
+            let proto = self.get_lua_closure_proto(prev.func);
+            if get_opcode(proto.code[prev.saved_pc - 1]) == OpCode::TailCall {
+                self.base_ci[self.ci].call_status |= CIST_TAIL;
+                event = HookEvent::TailCall;
             }
         }
+        self.dhook(event, -1)?;
+        self.base_ci[self.ci].saved_pc -= 1;
+        Ok(())
+    }
+
+    /// Raises a "stack overflow" error. The stack may grow a little beyond
+    /// `LUAI_MAXSTACK` while the error is handled.
+    fn stack_overflow(&mut self) -> Result<PrecallStatus, LuaError> {
+        if self.stack.len() > LUAI_MAXSTACK + 2 * LUA_MINSTACK {
+            // error while handling stack error
+            return Err(LuaError::ErrorHandlerError);
+        }
+        self.run_error("stack overflow")?;
+        unreachable!()
+    }
+
+    /// Makes room for `n` more slots on the stack. The memory limit is
+    /// checked before the stack is reallocated.
+    pub(crate) fn grow_stack(&mut self, n: usize) -> Result<(), LuaError> {
+        let capacity = self.stack.capacity();
+        let needed = self.stack.len() + n;
+        if needed > capacity {
+            self.check_alloc(needed.max(capacity * 2) * size_of::<TValue>())?;
+            self.stack.reserve(n);
+            self.g.gc.debt += (self.stack.capacity() - capacity) * size_of::<TValue>();
+        }
+        Ok(())
     }
 
-    pub(crate) fn adjust_varargs(&mut self, proto: ProtoId, nargs: usize) -> usize {
-        let nfix_args = self.protos[proto].numparams;
+    /// Frees the stack space left unused by an error, such as a stack
+    /// overflow, so that it no longer counts against the memory limit
+    pub(crate) fn shrink_stack(&mut self) {
+        let inuse = self.stack.len().max(self.base_ci[self.ci].top);
+        let good_size = inuse + inuse / 8 + 2 * EXTRA_STACK;
+        if self.stack.capacity() > good_size {
+            self.stack.shrink_to(good_size);
+        }
+        self.base_ci.shrink_to(2 * self.base_ci.len());
+    }
+
+    /// Returns the error raised by a Rust function or a hook that failed: an
+    /// exit requested by `os.exit`, a memory error or a runtime error.
+    /// The exit code is kept until the exit reaches the host, as Rust
+    /// functions calling Lua only return `Err(())`.
+    fn rust_error(&mut self) -> LuaError {
+        match self.exit_code {
+            Some(code) => LuaError::Exit(code),
+            None if self.g.gc.memerr => LuaError::MemoryError,
+            None => LuaError::RuntimeError,
+        }
+    }
+
+    /// Calls the debug hook for `event`. `line` is the current line for line
+    /// events, -1 otherwise. Hooks are disabled while a hook is running.
+    pub(crate) fn dhook(&mut self, event: HookEvent, line: i32) -> Result<(), LuaError> {
+        let hook = match self.hook {
+            Some(hook) if self.allowhook => hook,
+            _ => return Ok(()),
+        };
+        let ci = self.ci;
+        let top = self.stack.len();
+        let ci_top = self.base_ci[ci].top;
+        let ar = LuaDebug {
+            event,
+            currentline: line,
+            i_ci: Some(ci),
+            ..Default::default()
+        };
+        if top + LUA_MINSTACK > ci_top {
+            self.base_ci[ci].top = top + LUA_MINSTACK;
+        }
+        self.allowhook = false; // cannot call hooks inside a hook
+        self.base_ci[ci].call_status |= CIST_HOOKED;
+        let status = hook(self, &ar);
+        self.allowhook = true;
+        self.base_ci[ci].call_status &= !CIST_HOOKED;
+        if status.is_err() {
+            // the error message is on the top of the stack
+            return Err(self.rust_error());
+        }
+        self.base_ci[ci].top = ci_top;
+        self.stack.resize(top, TValue::Nil);
+        Ok(())
+    }
+
+    pub(crate) fn adjust_varargs(&mut self, proto: &Proto, nargs: usize) -> usize {
+        let nfix_args = proto.numparams;
         for _ in nargs..nfix_args {
             self.stack.push(TValue::Nil);
         }
@@ -183,18 +308,29 @@ pub fn pcall<T>(
     let old_allowhook;
     let old_ci;
     let old_nny;
+    let old_n_rcalls;
     {
         old_ci = state.ci;
+        old_n_rcalls = state.n_rcalls;
         old_allowhook = state.allowhook;
         old_errfunc = state.errfunc;
         old_nny = state.nny;
         state.errfunc = ef;
     }
-    let status = func(state, u);
+    let mut status = func(state, u);
+    if status.is_err() && old_ci == 0 {
+        // os.exit unwinds up to the host
+        if let Some(code) = state.exit_code.take() {
+            status = Err(LuaError::Exit(code));
+        }
+    }
     if let Err(e) = &status {
         state.close_func(old_top);
-        seterrorobj(state, e);
+        seterrorobj(state, e, old_top);
         state.ci = old_ci;
+        state.base_ci.truncate(old_ci + 1);
+        state.shrink_stack();
+        state.n_rcalls = old_n_rcalls;
         state.allowhook = old_allowhook;
         state.nny = old_nny;
     }
@@ -202,6 +338,19 @@ pub fn pcall<T>(
     status
 }
 
+fn check_mode(state: &mut LuaState, mode: &Option<String>, x: &str) -> Result<(), LuaError> {
+    match mode {
+        Some(mode) if !mode.contains(&x[0..1]) => {
+            state.push_string(&format!(
+                "attempt to load a {} chunk (mode is '{}')",
+                x, mode
+            ));
+            Err(LuaError::SyntaxError)
+        }
+        _ => Ok(()),
+    }
+}
+
 fn f_parser<T>(state: &mut LuaState, parser: &mut SParser<T>) -> Result<i32, LuaError> {
     let c = if let Some(ref mut z) = parser.z {
         z.look_ahead(state) // read first character
@@ -209,12 +358,15 @@ fn f_parser<T>(state: &mut LuaState, parser: &mut SParser<T>) -> Result<i32, Lua
         unreachable!()
     };
     let cl = if c == LUA_SIGNATURE.chars().next() {
+        check_mode(state, &parser.mode, "binary")?;
         luaU::undump
     } else {
+        check_mode(state, &parser.mode, "text")?;
         luaY::parser
     }(state, parser)?;
-    let cl = Closure::Lua(cl);
-    state.stack.push(TValue::from(cl));
+    let cl = TValue::from(Closure::Lua(cl));
+    state.link_object(&cl);
+    state.stack.push(cl);
     Ok(0)
 }
 
@@ -222,9 +374,10 @@ pub fn protected_parser<T>(
     state: &mut LuaState,
     zio: luaZ::Zio<T>,
     chunk_name: &str,
+    mode: Option<&str>,
 ) -> Result<i32, LuaError> {
     state.nny += 1; // cannot yield during parsing
-    let mut p = SParser::new(zio, chunk_name);
+    let mut p = SParser::new(zio, chunk_name, mode);
     let top = state.stack.len();
     let errfunc = state.errfunc;
     let status = pcall(state, f_parser, &mut p, top, errfunc);
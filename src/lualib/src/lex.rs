@@ -1,9 +1,11 @@
 //! Lexical Analyzer
 
+use std::sync::Arc;
+
 use crate::{
     api::LuaError,
-    limits::Instruction,
-    object::{chunk_id, LocVar, Proto},
+    limits::{Instruction, LUA_ENV},
+    object::{chunk_id, str2number, LocVar, Proto, TValue},
     parser::FuncState,
     state::LuaState,
     zio::Zio,
@@ -279,7 +281,7 @@ impl<T> LexState<T> {
             buff: Vec::new(),
             dyd: DynData::default(),
             source: source.to_owned(),
-            envn: "_ENV".to_owned(),
+            envn: LUA_ENV.to_owned(),
             vfs: vec![FuncState::new()],
         }
     }
@@ -363,7 +365,7 @@ impl<T> LexState<T> {
         let idx = self.dyd.actvar[first_local + id];
         let proto = self.borrow_mut_proto(state, None);
         debug_assert!(idx < proto.locvars.len());
-        &mut proto.locvars[idx]
+        &mut Arc::make_mut(&mut proto.locvars)[idx]
     }
 
     /// read next character in the stream
@@ -599,12 +601,10 @@ impl<T> LexState<T> {
         t: Option<u32>,
     ) -> Result<D, LuaError> {
         let chunk_id = chunk_id(&self.source);
-        state.push_string(&format!("{}:{}: {}", &chunk_id, self.linenumber, msg));
-        if let Some(t) = t {
-            state.push_string(&format!("{} near '{}'", msg, self.token_2_txt(t)));
-        }
-        if let Some(panic) = state.g.panic {
-            panic(state);
+        let msg = format!("{}:{}: {}", &chunk_id, self.linenumber, msg);
+        match t {
+            Some(t) => state.push_string(&format!("{} near '{}'", msg, self.token_2_txt(t))),
+            None => state.push_string(&msg),
         }
         Err(LuaError::SyntaxError)
     }
@@ -854,16 +854,10 @@ impl<T> LexState<T> {
             }
         }
         let svalue = self.buff.iter().cloned().collect::<String>();
-        match str2d(&svalue) {
-            // TODO 1.0 should be float
-            Some(val) => {
-                if val.fract() == 0.0 {
-                    Ok(Token::new_integer(val as LuaInteger))
-                } else {
-                    Ok(Token::new_number(val))
-                }
-            }
-            None => {
+        match str2number(&svalue) {
+            Some(TValue::Integer(val)) => Ok(Token::new_integer(val)),
+            Some(TValue::Float(val)) => Ok(Token::new_number(val)),
+            _ => {
                 return self.lex_error::<Token>(
                     state,
                     "malformed number",
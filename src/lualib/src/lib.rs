@@ -1,12 +1,16 @@
 pub mod api;
 pub mod auxlib;
+mod chunk;
 mod code;
 mod debug;
 mod func;
+mod gc;
+pub mod fs;
 mod ldo;
 mod lex;
 mod libs;
 mod limits;
+pub mod lua;
 mod luaconf;
 mod object;
 mod opcodes;
@@ -22,6 +26,7 @@ mod zio;
 pub use api::*;
 pub use auxlib as luaL;
 pub(crate) use code as luaK;
+pub(crate) use gc as luaC;
 pub(crate) use debug as luaG;
 pub(crate) use ldo as luaD;
 pub(crate) use parser as luaY;
@@ -29,12 +34,19 @@ pub(crate) use table as luaH;
 pub(crate) use undump as luaU;
 pub(crate) use vm as luaV;
 pub(crate) use zio as luaZ;
-pub use {object::TValue, state::LuaState};
+pub use lua::{
+    AnyUserData, FromLua, FromLuaMulti, Function, IntoLua, IntoLuaMulti, Lua, Table, Thread, Value,
+    Variadic,
+};
+pub use {chunk::CompiledChunk, object::TValue, state::LuaState};
 
 pub type LuaFloat = f64;
 pub type LuaInteger = i64;
 
 pub type LuaRustFunction = fn(&mut LuaState) -> Result<i32, ()>;
+/// Type for debug hook functions. The hook can raise an error by returning Err.
+/// Hooks cannot yield since coroutines are not supported.
+pub type LuaHook = fn(&mut LuaState, &LuaDebug) -> Result<(), ()>;
 
 /// lua bytecode dump header
 pub(crate) const LUA_SIGNATURE: &str = "\x1BLua";
@@ -48,6 +60,11 @@ pub const LUA_RIDX_GLOBALS: usize = 2;
 // pseudo-indices
 pub const LUA_REGISTRYINDEX: isize = -1000000 - 1000;
 pub const LUA_VERSION: &str = "Lua 5.3.6";
+// event masks for hooks
+pub const LUA_MASKCALL: usize = 1 << 0;
+pub const LUA_MASKRET: usize = 1 << 1;
+pub const LUA_MASKLINE: usize = 1 << 2;
+pub const LUA_MASKCOUNT: usize = 1 << 3;
 
 pub type Reader<T> = fn(&mut LuaState, &T, &mut Vec<char>) -> Result<(), ()>;
 
@@ -61,7 +78,10 @@ macro_rules! debug_print {
 
 #[cfg(test)]
 mod tests {
-    use crate::{api, luaL, object::TValue, LuaError};
+    use crate::{
+        api, luaL, object::TValue, HookEvent, LuaDebug, LuaError, LuaState, LUA_MASKCALL,
+        LUA_MASKCOUNT, LUA_MASKLINE, LUA_MASKRET,
+    };
     #[test]
     fn bitor_const() {
         let mut state = luaL::newstate();
@@ -300,7 +320,7 @@ mod tests {
         let msg = api::to_string(&mut state, -1);
         assert_eq!(
             msg,
-            Some("ia.write('hello'):1 attempt to call a nil value".to_owned())
+            Some("[string \"ia.write('hello')\"]:1: attempt to index a nil value".to_owned())
         );
     }
     #[test]
@@ -486,6 +506,22 @@ mod tests {
         assert_eq!(state.stack.last().unwrap(), &TValue::from("hello world"));
     }
     #[test]
+    fn concat_error() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "local t = {}
+            ok, msg = pcall(function() return t .. 'a' .. 1 end)",
+        )
+        .unwrap();
+        api::get_global(&mut state, "ok");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Boolean(false));
+        api::get_global(&mut state, "msg");
+        let msg = api::to_string(&mut state, -1).unwrap();
+        assert!(msg.ends_with("attempt to concatenate a table value"), "{}", msg);
+    }
+    #[test]
     fn tailcall() {
         let mut state = luaL::newstate();
         luaL::dostring(
@@ -612,7 +648,7 @@ mod tests {
         .unwrap();
 
         api::get_global(&mut state, "z");
-        assert_eq!(state.stack.last().unwrap(), &TValue::Float(15.0));
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(15));
     }
     #[test]
     fn expression() {
@@ -643,10 +679,13 @@ mod tests {
     fn numbers() {
         let mut state = luaL::newstate();
         luaL::open_libs(&mut state).unwrap();
-        luaL::dostring(&mut state, "z=3 + 1E1 + 0xa + 0xB").unwrap();
+        luaL::dostring(&mut state, "z=3 + 1E1 + 0xa + 0xB w=3 + 0xa + 0xB").unwrap();
 
+        // a numeral with an exponent is a float
         api::get_global(&mut state, "z");
-        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(34));
+        assert_eq!(state.stack.last().unwrap(), &TValue::Float(34.0));
+        api::get_global(&mut state, "w");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(24));
     }
     #[test]
     fn goto() {
@@ -671,4 +710,173 @@ mod tests {
         api::get_global(&mut state, "z");
         assert_eq!(state.stack.last().unwrap(), &TValue::Integer(9));
     }
+    #[test]
+    fn shared_upvalues() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "
+        local function counter()
+            local n = 0
+            return function() n = n + 1 return n end, function() return n end
+        end
+        local inc, get = counter()
+        inc()
+        inc()
+        a = get()
+        local fs = {}
+        for i = 1, 3 do
+            fs[i] = function() return i end
+        end
+        b = fs[1]() + fs[2]() * 10 + fs[3]() * 100
+        ",
+        )
+        .unwrap();
+
+        api::get_global(&mut state, "a");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(2));
+        api::get_global(&mut state, "b");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(321));
+    }
+    #[test]
+    fn tail_call() {
+        let mut state = luaL::newstate();
+        luaL::dostring(
+            &mut state,
+            "
+        local function f(x) return x + 1 end
+        local function g(x) return f(x) end
+        local function loop(n) if n == 0 then return g(n) end return loop(n - 1) end
+        z = loop(100000)
+        ",
+        )
+        .unwrap();
+
+        api::get_global(&mut state, "z");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(1));
+    }
+    #[test]
+    fn multiple_assignment() {
+        let mut state = luaL::newstate();
+        luaL::dostring(
+            &mut state,
+            "
+        local a, b = {}, {}
+        a.next, b.prev = b, a
+        local t, i = {1, 2}, 1
+        i, t[i] = i + 1, 20
+        z = (a.next == b and b.prev == a) and i + t[1] + t[2]
+        ",
+        )
+        .unwrap();
+
+        api::get_global(&mut state, "z");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(24));
+    }
+    #[test]
+    fn nil_assignment() {
+        let mut state = luaL::newstate();
+        luaL::dostring(
+            &mut state,
+            "
+        local a, b = 1, 2
+        a = nil
+        local c, d, e = 3
+        z = a == nil and b == 2 and c == 3 and d == nil and e == nil
+        ",
+        )
+        .unwrap();
+
+        api::get_global(&mut state, "z");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Boolean(true));
+    }
+    #[test]
+    fn constants() {
+        let mut state = luaL::newstate();
+        luaL::dostring(&mut state, "a, b, c = 1, 1.0, 1").unwrap();
+        // each chunk runs its own main function
+        luaL::dostring(&mut state, "d = 2").unwrap();
+        api::get_global(&mut state, "a");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(1));
+        api::get_global(&mut state, "b");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Float(1.0));
+        api::get_global(&mut state, "c");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(1));
+        api::get_global(&mut state, "d");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(2));
+    }
+    #[test]
+    fn prototypes_freed() {
+        let mut state = luaL::newstate();
+        luaL::loadstring(&mut state, "return function() return 1 end").unwrap();
+        let proto = match state.stack.last().unwrap() {
+            TValue::Function(cl) => std::rc::Rc::downgrade(cl.borrow().get_proto()),
+            _ => unreachable!(),
+        };
+        api::call(&mut state, 0, 1).unwrap();
+        api::set_global(&mut state, "f");
+        assert!(proto.upgrade().is_none()); // the main function was freed
+        assert!(luaL::loadstring(&mut state, "function g() return 1").is_err());
+        assert!(state.protos.is_empty());
+        luaL::dostring(&mut state, "z = f()").unwrap();
+        api::get_global(&mut state, "z");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(1));
+    }
+    fn trace_hook(state: &mut LuaState, ar: &LuaDebug) -> Result<(), ()> {
+        let mut ar = ar.clone();
+        api::get_info(state, "S", &mut ar);
+        let event = match ar.event {
+            HookEvent::Line => ar.currentline.to_string(),
+            event => format!("{}:{}", event.name(), ar.what),
+        };
+        api::get_global(state, "trace");
+        api::push_string(state, &format!("{} ", event));
+        api::concat(state, 2).map_err(|_| ())?;
+        api::set_global(state, "trace");
+        Ok(())
+    }
+    #[test]
+    fn hooks() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(&mut state, "trace = ''").unwrap();
+        api::set_hook(
+            &mut state,
+            Some(trace_hook),
+            LUA_MASKCALL | LUA_MASKRET | LUA_MASKLINE,
+            0,
+        );
+        luaL::dostring(
+            &mut state,
+            "local function f()
+                return 1
+            end
+            local x = f()",
+        )
+        .unwrap();
+        api::set_hook(&mut state, None, 0, 0);
+        api::get_global(&mut state, "trace");
+        assert_eq!(
+            state.stack.last().unwrap(),
+            &TValue::from("call:main 3 4 call:Lua 2 return:Lua return:main ")
+        );
+    }
+    fn watchdog(state: &mut LuaState, _ar: &LuaDebug) -> Result<(), ()> {
+        api::push_string(state, "timeout");
+        Err(())
+    }
+    #[test]
+    fn count_hook_error() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        api::set_hook(&mut state, Some(watchdog), LUA_MASKCOUNT, 1000);
+        let r = luaL::dostring(&mut state, "while true do end");
+        assert_eq!(r, Err(LuaError::RuntimeError));
+        assert_eq!(api::to_string(&mut state, -1), Some("timeout".to_owned()));
+        api::set_hook(&mut state, None, 0, 0);
+        luaL::dostring(&mut state, "z = 1").unwrap();
+        api::get_global(&mut state, "z");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(1));
+    }
 }
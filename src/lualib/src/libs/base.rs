@@ -1,14 +1,27 @@
 //! Basic library
 
-use crate::{api, lex::str2d, luaL, state::LuaState, LuaRustFunction, LUA_VERSION};
+use std::cell::RefCell;
+
+use crate::{
+    api::{self, GcOption, LuaError, LuaType},
+    lex::str2d,
+    luaL,
+    object::{StkId, TValue},
+    state::LuaState,
+    LuaInteger, LuaFloat, LuaRustFunction, LUA_MULTRET, LUA_VERSION,
+};
 
 use super::LibReg;
 
-const BASE_FUNCS: [LibReg; 21] = [
+const BASE_FUNCS: [LibReg; 22] = [
     LibReg {
         name: "assert",
         func: luab_assert,
     },
+    LibReg {
+        name: "collectgarbage",
+        func: luab_collectgarbage,
+    },
     LibReg {
         name: "dofile",
         func: luab_dofile,
@@ -90,26 +103,234 @@ const BASE_FUNCS: [LibReg; 21] = [
         func: luab_xpcall,
     },
 ];
-pub fn luab_assert(_state: &mut LuaState) -> Result<i32, ()> {
-    todo!();
+pub fn luab_assert(s: &mut LuaState) -> Result<i32, ()> {
+    if api::to_boolean(s, 1) {
+        // condition is true?
+        return Ok(api::get_top(s) as i32); // return all arguments
+    }
+    luaL::check_any(s, 1).map_err(|_| ())?; // there must be a condition
+    api::remove(s, 1); // remove it
+    api::push_literal(s, "assertion failed!"); // default message
+    api::set_top(s, 1); // leave only message (default if no other one)
+    luab_error(s) // call 'error'
 }
-pub fn luab_dofile(_state: &mut LuaState) -> Result<i32, ()> {
-    todo!();
+pub fn luab_collectgarbage(s: &mut LuaState) -> Result<i32, ()> {
+    const OPTS: [&str; 8] = [
+        "stop",
+        "restart",
+        "collect",
+        "count",
+        "step",
+        "setpause",
+        "setstepmul",
+        "isrunning",
+    ];
+    const OPTSNUM: [GcOption; 8] = [
+        GcOption::Stop,
+        GcOption::Restart,
+        GcOption::Collect,
+        GcOption::Count,
+        GcOption::Step,
+        GcOption::SetPause,
+        GcOption::SetStepMul,
+        GcOption::IsRunning,
+    ];
+    let o = OPTSNUM[luaL::check_option(s, 1, Some("collect"), &OPTS)?];
+    let ex = luaL::opt_integer(s, 2, 0)?;
+    let res = api::gc(s, o, ex.max(0) as usize).map_err(|_| ())?;
+    match o {
+        GcOption::Count => {
+            let b = api::gc(s, GcOption::CountB, 0).map_err(|_| ())?;
+            api::push_number(s, res as LuaFloat + b as LuaFloat / 1024.0);
+        }
+        GcOption::Step | GcOption::IsRunning => api::push_boolean(s, res != 0),
+        _ => api::push_integer(s, res as LuaInteger),
+    }
+    Ok(1)
 }
-pub fn luab_error(_state: &mut LuaState) -> Result<i32, ()> {
-    todo!();
+pub fn luab_dofile(s: &mut LuaState) -> Result<i32, ()> {
+    let fname = if api::is_none_or_nil(s, 1) {
+        None
+    } else {
+        Some(luaL::check_string(s, 1)?)
+    };
+    api::set_top(s, 1);
+    if luaL::loadfile(s, fname.as_deref()).is_err() {
+        api::error(s).map_err(|_| ())?;
+    }
+    api::call(s, 0, LUA_MULTRET).map_err(|_| ())?;
+    Ok(api::get_top(s) as i32 - 1)
 }
-pub fn luab_getmetatable(_state: &mut LuaState) -> Result<i32, ()> {
-    todo!();
+pub fn luab_error(s: &mut LuaState) -> Result<i32, ()> {
+    let level = if api::is_none_or_nil(s, 2) {
+        1
+    } else {
+        luaL::check_integer(s, 2)?
+    };
+    api::set_top(s, 1);
+    if matches!(s.index2adr(1), TValue::String(_)) && level > 0 {
+        luaL::lwhere(s, level as usize); // add extra information
+        api::push_value(s, 1);
+        api::concat(s, 2).map_err(|_| ())?;
+    }
+    api::error(s).map_err(|_| ())?;
+    unreachable!()
 }
-pub fn luab_load(_state: &mut LuaState) -> Result<i32, ()> {
-    todo!();
+pub fn luab_getmetatable(s: &mut LuaState) -> Result<i32, ()> {
+    luaL::check_any(s, 1).map_err(|_| ())?;
+    if !api::get_meta_table(s, 1) {
+        api::push_nil(s);
+        return Ok(1); // no metatable
+    }
+    luaL::get_meta_field(s, 1, "__metatable");
+    Ok(1) // returns either __metatable field (if present) or metatable
 }
-pub fn luab_loadfile(_state: &mut LuaState) -> Result<i32, ()> {
-    todo!();
+fn load_aux(s: &mut LuaState, status: Result<i32, LuaError>, envidx: isize) -> i32 {
+    match status {
+        Ok(_) => {
+            if envidx != 0 {
+                // 'env' parameter?
+                api::push_value(s, envidx); // environment for loaded function
+                if api::set_upvalue(s, -2, 1).is_none() {
+                    // set it as 1st upvalue
+                    api::pop(s, 1); // remove 'env' if not used by previous call
+                }
+            }
+            1
+        }
+        Err(_) => {
+            // error (message is on top of the stack)
+            let msg = s.stack.pop().unwrap();
+            api::push_nil(s);
+            s.stack.push(msg);
+            2 // return nil plus error message
+        }
+    }
 }
-pub fn luab_pcall(_state: &mut LuaState) -> Result<i32, ()> {
-    todo!();
+/// Reader for 'load' calling the function at stack position `func`
+/// to get the pieces of the chunk
+struct FunctionReader {
+    func: StkId,
+    /// error raised by the function or invalid value returned
+    error: RefCell<Option<String>>,
+}
+fn generic_reader(
+    s: &mut LuaState,
+    reader: &&FunctionReader,
+    buff: &mut Vec<char>,
+) -> Result<(), ()> {
+    buff.clear();
+    if reader.error.borrow().is_some() {
+        return Err(());
+    }
+    let func = s.stack[reader.func].clone();
+    s.stack.push(func); // get function
+    let piece = match api::pcall(s, 0, 1, 0) {
+        // call it
+        Ok(_) if api::is_nil(s, -1) => None, // end of chunk
+        Ok(_) if api::is_string(s, -1) => api::to_string(s, -1).map(Ok),
+        Ok(_) => Some(Err("reader function must return a string".to_owned())),
+        Err(_) => Some(Err(api::to_string(s, -1).unwrap_or_default())),
+    };
+    api::pop(s, 1);
+    match piece {
+        Some(Ok(piece)) if !piece.is_empty() => {
+            buff.extend(piece.chars());
+            Ok(())
+        }
+        Some(Err(msg)) => {
+            *reader.error.borrow_mut() = Some(msg);
+            Err(())
+        }
+        _ => Err(()),
+    }
+}
+pub fn luab_load(s: &mut LuaState) -> Result<i32, ()> {
+    let mode = if api::is_none_or_nil(s, 3) {
+        "bt".to_owned()
+    } else {
+        luaL::check_string(s, 3)?
+    };
+    let envidx = if api::is_none(s, 4) { 0 } else { 4 }; // 'env' index or 0 if no 'env'
+    let status = if api::is_string(s, 1) {
+        // loading a string?
+        let chunk = api::to_string(s, 1).unwrap();
+        let chunkname = if api::is_none_or_nil(s, 2) {
+            chunk.clone()
+        } else {
+            luaL::check_string(s, 2)?
+        };
+        luaL::loadbufferx(s, &chunk, &chunkname, Some(&mode))
+    } else {
+        // loading from a reader function
+        let chunkname = if api::is_none_or_nil(s, 2) {
+            "=(load)".to_owned()
+        } else {
+            luaL::check_string(s, 2)?
+        };
+        if !api::is_function(s, 1) {
+            luaL::type_error(s, 1, "function").map_err(|_| ())?;
+        }
+        let reader = FunctionReader {
+            func: s.base_ci[s.ci].func + 1,
+            error: RefCell::new(None),
+        };
+        let status = api::load(s, generic_reader, &reader, Some(&chunkname), Some(&mode));
+        if s.exit_code.is_some() {
+            // os.exit called by the reader
+            return Err(());
+        }
+        match reader.error.into_inner() {
+            Some(msg) => {
+                // discard the chunk or the error built from the partial chunk
+                api::pop(s, 1);
+                api::push_string(s, &msg);
+                Err(LuaError::RuntimeError)
+            }
+            None => status,
+        }
+    };
+    Ok(load_aux(s, status, envidx))
+}
+pub fn luab_loadfile(s: &mut LuaState) -> Result<i32, ()> {
+    let fname = if api::is_none_or_nil(s, 1) {
+        None
+    } else {
+        Some(luaL::check_string(s, 1)?)
+    };
+    let mode = if api::is_none_or_nil(s, 2) {
+        None
+    } else {
+        Some(luaL::check_string(s, 2)?)
+    };
+    let envidx = if api::is_none(s, 3) { 0 } else { 3 }; // 'env' index or 0 if no 'env'
+    let status = luaL::loadfilex(s, fname.as_deref(), mode.as_deref());
+    Ok(load_aux(s, status, envidx))
+}
+/// Continuation of 'pcall' and 'xpcall'. The first result (true) is below
+/// the results of the call, `extra` values below it are not returned.
+fn finish_pcall(s: &mut LuaState, status: Result<i32, LuaError>, extra: i32) -> Result<i32, ()> {
+    match status {
+        Ok(_) => Ok(api::get_top(s) as i32 - extra),
+        // os.exit is not an error
+        Err(_) if s.exit_code.is_some() => Err(()),
+        Err(_) => {
+            // error
+            let msg = s.stack.pop().unwrap();
+            api::push_boolean(s, false); // first result (false)
+            s.stack.push(msg); // error message
+            Ok(2) // return false, msg
+        }
+    }
+}
+pub fn luab_pcall(s: &mut LuaState) -> Result<i32, ()> {
+    luaL::check_any(s, 1).map_err(|_| ())?;
+    // first result if no errors, inserted below the function
+    let func = s.base_ci[s.ci].func;
+    s.stack.insert(func + 1, TValue::Boolean(true));
+    let nargs = api::get_top(s) - 2;
+    let status = api::pcall(s, nargs, LUA_MULTRET, 0);
+    finish_pcall(s, status, 0)
 }
 /// If your system does not support `stdout', you can just remove this function.
 /// If you need, you can define your own `print' function, following this
@@ -140,23 +361,64 @@ pub fn luab_print(s: &mut LuaState) -> Result<i32, ()> {
     _ = writeln!(s.stdout);
     Ok(0)
 }
-pub fn luab_rawequal(_state: &mut LuaState) -> Result<i32, ()> {
-    todo!();
+pub fn luab_rawequal(s: &mut LuaState) -> Result<i32, ()> {
+    luaL::check_any(s, 1).map_err(|_| ())?;
+    luaL::check_any(s, 2).map_err(|_| ())?;
+    let equal = api::raw_equal(s, 1, 2);
+    api::push_boolean(s, equal);
+    Ok(1)
 }
-pub fn luab_rawlen(_state: &mut LuaState) -> Result<i32, ()> {
-    todo!();
+pub fn luab_rawlen(s: &mut LuaState) -> Result<i32, ()> {
+    if !matches!(api::type_of(s, 1), LuaType::Table | LuaType::String) {
+        luaL::arg_error(s, 1, "table or string expected").map_err(|_| ())?;
+    }
+    let len = api::raw_len(s, 1);
+    api::push_integer(s, len as LuaInteger);
+    Ok(1)
 }
-pub fn luab_rawget(_state: &mut LuaState) -> Result<i32, ()> {
-    todo!();
+pub fn luab_rawget(s: &mut LuaState) -> Result<i32, ()> {
+    luaL::check_table(s, 1)?;
+    luaL::check_any(s, 2).map_err(|_| ())?;
+    api::set_top(s, 2);
+    api::raw_get(s, 1);
+    Ok(1)
 }
-pub fn luab_rawset(_state: &mut LuaState) -> Result<i32, ()> {
-    todo!();
+pub fn luab_rawset(s: &mut LuaState) -> Result<i32, ()> {
+    luaL::check_table(s, 1)?;
+    luaL::check_any(s, 2).map_err(|_| ())?;
+    luaL::check_any(s, 3).map_err(|_| ())?;
+    api::set_top(s, 3);
+    api::raw_set(s, 1).map_err(|_| ())?;
+    Ok(1)
 }
-pub fn luab_select(_state: &mut LuaState) -> Result<i32, ()> {
-    todo!();
+pub fn luab_select(s: &mut LuaState) -> Result<i32, ()> {
+    let n = api::get_top(s) as LuaInteger;
+    if api::type_of(s, 1) == LuaType::String && api::to_string(s, 1).unwrap().starts_with('#') {
+        api::push_integer(s, n - 1);
+        return Ok(1);
+    }
+    let mut i = luaL::check_integer(s, 1)?;
+    if i < 0 {
+        i += n;
+    } else if i > n {
+        i = n;
+    }
+    if i < 1 {
+        luaL::arg_error(s, 1, "index out of range").map_err(|_| ())?;
+    }
+    Ok((n - i) as i32)
 }
-pub fn luab_setmetatable(_state: &mut LuaState) -> Result<i32, ()> {
-    todo!();
+pub fn luab_setmetatable(s: &mut LuaState) -> Result<i32, ()> {
+    luaL::check_table(s, 1)?;
+    if !api::is_none_or_nil(s, 2) && !api::is_table(s, 2) {
+        luaL::type_error(s, 2, "nil or table").map_err(|_| ())?;
+    }
+    if luaL::get_meta_field(s, 1, "__metatable") {
+        luaL::error(s, "cannot change a protected metatable").map_err(|_| ())?;
+    }
+    api::set_top(s, 2);
+    api::set_metatable(s, 1);
+    Ok(1)
 }
 pub fn luab_tonumber(state: &mut LuaState) -> Result<i32, ()> {
     let base = if api::get_top(state) == 2 {
@@ -183,18 +445,32 @@ pub fn luab_tonumber(state: &mut LuaState) -> Result<i32, ()> {
     Ok(1)
 }
 pub fn luab_tostring(s: &mut LuaState) -> Result<i32, ()> {
-    // TODO hangle metamethods
-    let value = s.index2adr(1);
-    let svalue = format!("{}", value);
-    api::push_string(s, &svalue);
+    luaL::check_any(s, 1).map_err(|_| ())?;
+    luaL::to_lstring(s, 1).map_err(|_| ())?;
     Ok(1)
 }
-pub fn luab_type(_state: &mut LuaState) -> Result<i32, ()> {
-    todo!();
+pub fn luab_type(s: &mut LuaState) -> Result<i32, ()> {
+    let t = api::type_of(s, 1);
+    if t == LuaType::None {
+        luaL::arg_error(s, 1, "value expected").map_err(|_| ())?;
+    }
+    api::push_string(s, t.name());
+    Ok(1)
 }
 
-pub fn luab_xpcall(_state: &mut LuaState) -> Result<i32, ()> {
-    todo!();
+pub fn luab_xpcall(s: &mut LuaState) -> Result<i32, ()> {
+    let n = api::get_top(s);
+    if !api::is_function(s, 2) {
+        // check error function
+        luaL::type_error(s, 2, "function").map_err(|_| ())?;
+    }
+    // push the first result and the function below the function's arguments
+    let func = s.base_ci[s.ci].func;
+    let f = s.stack[func + 1].clone();
+    s.stack.insert(func + 3, TValue::Boolean(true));
+    s.stack.insert(func + 4, f);
+    let status = api::pcall(s, n - 2, LUA_MULTRET, 2);
+    finish_pcall(s, status, 2)
 }
 
 fn pairs_meta(
@@ -233,7 +509,7 @@ pub fn ipairs_aux(s: &mut LuaState) -> Result<i32, ()> {
     let i = luaL::check_integer(s, 2)? + 1; // next value
     luaL::check_table(s, 1)?;
     api::push_integer(s, i);
-    api::raw_get_i(s, 1, i as usize);
+    api::raw_get_i(s, 1, i);
     if api::is_nil(s, -1) {
         Ok(1)
     } else {
@@ -270,7 +546,11 @@ pub fn lib_open_base(state: &mut LuaState) -> Result<i32, ()> {
 
 #[cfg(test)]
 mod tests {
-    use crate::{api, luaL, object::TValue, LUA_VERSION};
+    use std::rc::Rc;
+
+    use crate::{
+        api, fs::MemoryFileSystem, luaL, object::TValue, state::LuaState, LuaInteger, LUA_VERSION,
+    };
     #[test]
     fn baselib_defines_g() {
         let mut state = luaL::newstate();
@@ -358,4 +638,483 @@ mod tests {
         api::get_global(&mut state, "z");
         assert_eq!(state.stack.last().unwrap(), &TValue::Integer(10));
     }
+    #[test]
+    fn load_string() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "local f = load('return 1 + ...')
+            z = f(41)
+            local _, msg = load('x = = 1', '=chunk')
+            w = msg",
+        )
+        .unwrap();
+        api::get_global(&mut state, "z");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(42));
+        api::get_global(&mut state, "w");
+        assert_eq!(
+            state.stack.last().unwrap(),
+            &TValue::from("chunk:1: unexpected symbol near '='")
+        );
+    }
+    #[test]
+    fn load_reader() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "local pieces = { 'return ', '40', ' + 2' }
+            local i = 0
+            local f = load(function()
+                i = i + 1
+                return pieces[i]
+            end)
+            z = f()
+            local _, msg = load(function() return {} end)
+            w = msg",
+        )
+        .unwrap();
+        api::get_global(&mut state, "z");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(42));
+        api::get_global(&mut state, "w");
+        assert_eq!(
+            state.stack.last().unwrap(),
+            &TValue::from("reader function must return a string")
+        );
+    }
+    #[test]
+    fn load_mode() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "local f, msg = load('return 1', 'chunk', 'b')
+            w = msg
+            z = load('return 1', 'chunk', 't')()",
+        )
+        .unwrap();
+        api::get_global(&mut state, "w");
+        assert_eq!(
+            state.stack.last().unwrap(),
+            &TValue::from("attempt to load a text chunk (mode is 'b')")
+        );
+        api::get_global(&mut state, "z");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(1));
+    }
+    #[test]
+    fn load_env() {
+        let fs = MemoryFileSystem::new();
+        fs.insert("plugin.lua", "y = x * 2");
+        let mut state = luaL::newstate();
+        state.g.fs = Rc::new(fs);
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "local env = { x = 20 }
+            load('x = x + 1', 'plugin', 't', env)()
+            loadfile('plugin.lua', 'bt', env)()
+            z = env.y + (x or 0) + (y or 0)",
+        )
+        .unwrap();
+        api::get_global(&mut state, "z");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(42));
+    }
+    #[test]
+    fn pcall_results() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "local ok, a, b = pcall(function(x, y) return x + y, x * y end, 3, 4)
+            z = tostring(ok) .. a .. b
+            local ok2, e = pcall(error, { code = 42 })
+            w = e.code
+            local ok3, msg = pcall(function() local t = nil; return t.x end)
+            v = msg",
+        )
+        .unwrap();
+        api::get_global(&mut state, "z");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("true712"));
+        api::get_global(&mut state, "w");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(42));
+        api::get_global(&mut state, "v");
+        assert_eq!(
+            state.stack.last().unwrap(),
+            &TValue::from(
+                "[string \"local ok, a, b = pcall(function(x, y) return ...\"]:5: \
+                attempt to index a nil value"
+            )
+        );
+    }
+    #[test]
+    fn pcall_restores_stack() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "local function deep(n)
+                if n == 0 then error('deep', 0) end
+                local a, b, c = n, n, n
+                return deep(n - 1) + a
+            end
+            for i = 1, 1000 do ok, msg = pcall(deep, 50) end",
+        )
+        .unwrap();
+        assert_eq!(state.base_ci.len(), 1);
+        assert_eq!(state.n_rcalls, 0);
+        assert_eq!(api::get_top(&mut state), 0);
+        api::get_global(&mut state, "ok");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Boolean(false));
+        api::get_global(&mut state, "msg");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("deep"));
+    }
+    #[test]
+    fn error_levels() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "local function f(level) error('boom', level) end
+            local function g(level) f(level) end
+            _, a = pcall(g)
+            _, b = pcall(g, 2)
+            _, c = pcall(g, 0)
+            _, d = pcall(error, 12)",
+        )
+        .unwrap();
+        let chunk = "[string \"local function f(level) error('boom', level) ...\"]";
+        for (name, expected) in [
+            ("a", TValue::from(format!("{}:1: boom", chunk))),
+            ("b", TValue::from(format!("{}:2: boom", chunk))),
+            ("c", TValue::from("boom")),
+            ("d", TValue::Integer(12)),
+        ] {
+            api::get_global(&mut state, name);
+            assert_eq!(state.stack.last().unwrap(), &expected);
+        }
+    }
+    #[test]
+    fn assert_values() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "local a, b, c = assert(1, 2, 3)
+            z = a + b + c
+            _, w = pcall(assert, false, 'custom')
+            _, v = pcall(assert, nil)
+            _, u = pcall(assert, false, { 1 })
+            local function check(...) return pcall(assert, ...) end
+            _, t = check(false, 'nested')",
+        )
+        .unwrap();
+        api::get_global(&mut state, "z");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(6));
+        api::get_global(&mut state, "w");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("custom"));
+        api::get_global(&mut state, "v");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("assertion failed!"));
+        api::get_global(&mut state, "u");
+        assert!(matches!(state.stack.last().unwrap(), TValue::Table(_)));
+        api::get_global(&mut state, "t");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("nested"));
+    }
+    #[test]
+    fn xpcall_handler() {
+        fn handler(s: &mut LuaState) -> Result<i32, ()> {
+            // still at the error point
+            api::push_integer(s, s.ci as LuaInteger);
+            api::set_global(s, "depth");
+            let msg = api::to_string(s, 1).unwrap();
+            api::push_string(s, &format!("handled: {}", msg));
+            Ok(1)
+        }
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        api::push_rust_function(&mut state, handler, 0);
+        api::set_global(&mut state, "handler");
+        luaL::dostring(
+            &mut state,
+            "local function deep(n) if n == 0 then error('down', 0) end deep(n - 1) end
+            ok, msg = xpcall(deep, handler, 5)
+            top = xpcall(function() return 1 end, handler)
+            _, again = xpcall(error, function() error('again') end)",
+        )
+        .unwrap();
+        api::get_global(&mut state, "msg");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("handled: down"));
+        // main chunk, xpcall, 6 calls to 'deep', error and the handler
+        api::get_global(&mut state, "depth");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(10));
+        api::get_global(&mut state, "top");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Boolean(true));
+        api::get_global(&mut state, "again");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("error in error handling"));
+        // the state is usable after the errors
+        assert_eq!(state.ci, 0);
+        luaL::dostring(&mut state, "z = pcall(error)").unwrap();
+        api::get_global(&mut state, "z");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Boolean(false));
+    }
+    #[test]
+    fn protected_metatable() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "local t = setmetatable({}, { __index = { x = 1 }, __metatable = 'locked' })
+            a = t.x
+            b = getmetatable(t)
+            local ok, e = pcall(setmetatable, t, {})
+            c = e
+            d = getmetatable(setmetatable({}, nil))",
+        )
+        .unwrap();
+        api::get_global(&mut state, "a");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(1));
+        api::get_global(&mut state, "b");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("locked"));
+        api::get_global(&mut state, "c");
+        assert_eq!(
+            state.stack.last().unwrap(),
+            &TValue::from("cannot change a protected metatable")
+        );
+        api::get_global(&mut state, "d");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Nil);
+    }
+    #[test]
+    fn type_metatable() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        // all numbers share the same metatable
+        api::push_integer(&mut state, 0);
+        luaL::dostring(&mut state, "return { __index = { answer = 42 } }").unwrap();
+        api::set_metatable(&mut state, -2);
+        luaL::dostring(
+            &mut state,
+            "z = (3.5).answer
+            w = getmetatable(1) == getmetatable(2.0)",
+        )
+        .unwrap();
+        api::get_global(&mut state, "z");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(42));
+        api::get_global(&mut state, "w");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Boolean(true));
+    }
+    #[test]
+    fn tostring_name() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "z = tostring(setmetatable({}, { __name = 'Point' }))",
+        )
+        .unwrap();
+        api::get_global(&mut state, "z");
+        let TValue::String(z) = state.stack.last().unwrap() else {
+            panic!("string expected");
+        };
+        assert!(z.starts_with("Point: 0x"));
+    }
+    #[test]
+    fn arg_error_names() {
+        fn point_x(s: &mut LuaState) -> Result<i32, ()> {
+            luaL::check_udata(s, 1, "Point")?;
+            Ok(0)
+        }
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::new_metatable(&mut state, "Point");
+        api::push_rust_function(&mut state, point_x, 0);
+        api::set_global(&mut state, "f");
+        luaL::dostring(
+            &mut state,
+            "local ok
+            ok, a = pcall(function() f({}) end)
+            ok, b = pcall(f, setmetatable({}, { __name = 'Vector' }))
+            local o = { x = f }
+            ok, c = pcall(function() o:x() end)
+            ok, d = pcall(setmetatable, 1, {})",
+        )
+        .unwrap();
+        api::get_global(&mut state, "a");
+        assert_eq!(
+            state.stack.last().unwrap(),
+            &TValue::from(
+                "[string \"local ok...\"]:2: bad argument #1 to 'f' (Point expected, got table)"
+            )
+        );
+        api::get_global(&mut state, "b");
+        assert_eq!(
+            state.stack.last().unwrap(),
+            &TValue::from("bad argument #1 to 'f' (Point expected, got Vector)")
+        );
+        api::get_global(&mut state, "c");
+        assert_eq!(
+            state.stack.last().unwrap(),
+            &TValue::from(
+                "[string \"local ok...\"]:5: calling 'x' on bad self (Point expected, got table)"
+            )
+        );
+        api::get_global(&mut state, "d");
+        assert_eq!(
+            state.stack.last().unwrap(),
+            &TValue::from("bad argument #1 to 'setmetatable' (table expected, got number)")
+        );
+    }
+    #[test]
+    fn raw_access() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "local t = setmetatable({}, { __index = function() return 'meta' end })
+            local r = rawset(t, 'x', 1)
+            a = tostring(r == t) .. rawget(t, 'x') .. tostring(rawget(t, 'y')) .. t.y
+            b = rawlen({ 1, 2, 3 }) + rawlen('hello')
+            c = tostring(rawequal(t, t)) .. tostring(rawequal(t, {})) .. tostring(rawequal(1, 1.0))
+            local ok, e = pcall(rawset, t, nil, 1)
+            d = e",
+        )
+        .unwrap();
+        api::get_global(&mut state, "a");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("true1nilmeta"));
+        api::get_global(&mut state, "b");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(8));
+        api::get_global(&mut state, "c");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("truefalsetrue"));
+        api::get_global(&mut state, "d");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("index is nil"));
+    }
+    #[test]
+    fn select_and_type() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "local function cat(x, y, z) return x .. y .. tostring(z) end
+            a = select('#', 1, nil, 3, nil)
+            b = cat(select(2, 'a', 'b', 'c'))
+            c = cat(select(-2, 'a', 'b', 'c'))
+            d = select(5, 'a', 'b')
+            e = type(1) .. type('') .. type({}) .. type(print) .. type(nil) .. type(true)",
+        )
+        .unwrap();
+        api::get_global(&mut state, "a");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(4));
+        api::get_global(&mut state, "b");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("bcnil"));
+        api::get_global(&mut state, "c");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("bcnil"));
+        api::get_global(&mut state, "d");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Nil);
+        api::get_global(&mut state, "e");
+        assert_eq!(
+            state.stack.last().unwrap(),
+            &TValue::from("numberstringtablefunctionnilboolean")
+        );
+    }
+    #[test]
+    fn raw_access_errors() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "local ok
+            ok, a = pcall(type)
+            ok, b = pcall(rawget)
+            ok, c = pcall(rawget, {})
+            ok, d = pcall(rawlen, 5)
+            ok, e = pcall(select, 0, 'a')
+            ok, f = pcall(select, 'x')",
+        )
+        .unwrap();
+        for (name, msg) in [
+            ("a", "bad argument #1 to 'type' (value expected)"),
+            ("b", "bad argument #1 to 'rawget' (table expected, got no value)"),
+            ("c", "bad argument #2 to 'rawget' (value expected)"),
+            ("d", "bad argument #1 to 'rawlen' (table or string expected)"),
+            ("e", "bad argument #1 to 'select' (index out of range)"),
+            ("f", "bad argument #1 to 'select' (number expected, got string)"),
+        ] {
+            api::get_global(&mut state, name);
+            assert_eq!(state.stack.last().unwrap(), &TValue::from(msg));
+        }
+    }
+    #[test]
+    fn tostring_metamethods() {
+        #[derive(Clone, Default)]
+        struct Output(Rc<std::cell::RefCell<Vec<u8>>>);
+        impl std::io::Write for Output {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.borrow_mut().write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        let output = Output::default();
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        state.stdout = Box::new(output.clone());
+        luaL::dostring(
+            &mut state,
+            "local p = setmetatable({}, { __tostring = function() return 'point(1, 2)' end })
+            print(p, 1.0, 1e100, 2^63, 3, nil, false)
+            a = tostring(p) .. ' ' .. tostring(-(0/0) ~= -(0/0))
+            local ok, e = pcall(tostring, setmetatable({}, { __tostring = function() return {} end }))
+            b = e",
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output.0.borrow().clone()).unwrap(),
+            "point(1, 2)\t1.0\t1e+100\t9.2233720368548e+18\t3\tnil\tfalse\n"
+        );
+        api::get_global(&mut state, "a");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("point(1, 2) true"));
+        api::get_global(&mut state, "b");
+        assert_eq!(
+            state.stack.last().unwrap(),
+            &TValue::from("'__tostring' must return a string")
+        );
+    }
+    #[test]
+    fn pairs_metamethod() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "local t = setmetatable({}, { __pairs = function(t)
+                return function(_, k) if k < 3 then return k + 1, k * 10 end end, t, 0
+            end })
+            z = 0
+            for k, v in pairs(t) do z = z + k + v end",
+        )
+        .unwrap();
+        api::get_global(&mut state, "z");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(36));
+    }
+    #[test]
+    fn collectgarbage() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "local r = collectgarbage('isrunning')
+            collectgarbage('stop')
+            r = tostring(r) .. tostring(collectgarbage('isrunning'))
+            collectgarbage('restart')
+            s = r .. collectgarbage() .. tostring(collectgarbage('step'))
+            _, e = pcall(collectgarbage, 'bogus')",
+        )
+        .unwrap();
+        api::get_global(&mut state, "s");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("truefalse0true"));
+        api::get_global(&mut state, "e");
+        assert_eq!(
+            state.stack.last().unwrap(),
+            &TValue::from("bad argument #1 to 'collectgarbage' (invalid option 'bogus')")
+        );
+    }
 }
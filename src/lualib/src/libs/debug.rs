@@ -0,0 +1,631 @@
+//! Interface from Lua to its debug API
+
+use std::io::BufRead;
+
+use crate::{
+    api::{self, LuaDebug, LuaType},
+    luaL,
+    state::LuaState,
+    LuaHook, LuaInteger, LUA_MASKCALL, LUA_MASKCOUNT, LUA_MASKLINE, LUA_MASKRET,
+    LUA_REGISTRYINDEX,
+};
+
+use super::LibReg;
+
+const DB_FUNCS: [LibReg; 16] = [
+    LibReg {
+        name: "debug",
+        func: db_debug,
+    },
+    LibReg {
+        name: "getuservalue",
+        func: db_getuservalue,
+    },
+    LibReg {
+        name: "gethook",
+        func: db_gethook,
+    },
+    LibReg {
+        name: "getinfo",
+        func: db_getinfo,
+    },
+    LibReg {
+        name: "getlocal",
+        func: db_getlocal,
+    },
+    LibReg {
+        name: "getregistry",
+        func: db_getregistry,
+    },
+    LibReg {
+        name: "getmetatable",
+        func: db_getmetatable,
+    },
+    LibReg {
+        name: "getupvalue",
+        func: db_getupvalue,
+    },
+    LibReg {
+        name: "upvaluejoin",
+        func: db_upvaluejoin,
+    },
+    LibReg {
+        name: "upvalueid",
+        func: db_upvalueid,
+    },
+    LibReg {
+        name: "setuservalue",
+        func: db_setuservalue,
+    },
+    LibReg {
+        name: "sethook",
+        func: db_sethook,
+    },
+    LibReg {
+        name: "setlocal",
+        func: db_setlocal,
+    },
+    LibReg {
+        name: "setmetatable",
+        func: db_setmetatable,
+    },
+    LibReg {
+        name: "setupvalue",
+        func: db_setupvalue,
+    },
+    LibReg {
+        name: "traceback",
+        func: db_traceback,
+    },
+];
+
+/// key, in the registry, for the Lua function set by `debug.sethook`
+const HOOKKEY: &str = "_HOOKKEY";
+
+pub fn db_getregistry(s: &mut LuaState) -> Result<i32, ()> {
+    api::push_value(s, LUA_REGISTRYINDEX);
+    Ok(1)
+}
+
+pub fn db_getmetatable(s: &mut LuaState) -> Result<i32, ()> {
+    luaL::check_any(s, 1).map_err(|_| ())?;
+    if !api::get_meta_table(s, 1) {
+        api::push_nil(s); // no metatable
+    }
+    Ok(1)
+}
+
+pub fn db_setmetatable(s: &mut LuaState) -> Result<i32, ()> {
+    let t = api::type_of(s, 2);
+    if t != LuaType::Nil && t != LuaType::Table {
+        luaL::arg_error(s, 2, "nil or table expected").map_err(|_| ())?;
+    }
+    api::set_top(s, 2);
+    api::set_metatable(s, 1);
+    Ok(1) // return 1st argument
+}
+
+pub fn db_getuservalue(s: &mut LuaState) -> Result<i32, ()> {
+    if api::type_of(s, 1) != LuaType::UserData {
+        api::push_nil(s);
+    } else {
+        api::get_user_value(s, 1);
+    }
+    Ok(1)
+}
+
+pub fn db_setuservalue(s: &mut LuaState) -> Result<i32, ()> {
+    luaL::check_type(s, 1, LuaType::UserData)?;
+    luaL::check_any(s, 2).map_err(|_| ())?;
+    api::set_top(s, 2);
+    api::set_user_value(s, 1);
+    Ok(1)
+}
+
+/// Returns the index of the first argument after the optional thread
+/// argument. There is only one thread per state, so an explicit thread
+/// argument always refers to the running one.
+fn get_thread(s: &mut LuaState) -> isize {
+    if api::type_of(s, 1) == LuaType::Thread {
+        1
+    } else {
+        0
+    }
+}
+
+// Variations of 'set_field', used by 'db_getinfo' to put results
+// from 'get_info' into the result table.
+
+fn set_str_field(s: &mut LuaState, key: &str, value: &str) {
+    api::push_string(s, value);
+    api::set_field(s, -2, key);
+}
+
+fn set_int_field(s: &mut LuaState, key: &str, value: LuaInteger) {
+    api::push_integer(s, value);
+    api::set_field(s, -2, key);
+}
+
+fn set_bool_field(s: &mut LuaState, key: &str, value: bool) {
+    api::push_boolean(s, value);
+    api::set_field(s, -2, key);
+}
+
+/// Calls 'get_info' and collects all results in a new table.
+pub fn db_getinfo(s: &mut LuaState) -> Result<i32, ()> {
+    let arg = get_thread(s);
+    let options = luaL::opt_string(s, arg + 2, "flnStu")?;
+    if options.starts_with('>') {
+        luaL::arg_error(s, arg + 2, "invalid option '>'").map_err(|_| ())?;
+    }
+    let (mut ar, options) = if api::is_function(s, arg + 1) {
+        // info about a function?
+        api::push_value(s, arg + 1); // move function to the top
+        (LuaDebug::default(), format!(">{}", options))
+    } else {
+        // stack level
+        let level = luaL::check_integer(s, arg + 1)?;
+        match usize::try_from(level).ok().and_then(|level| api::get_stack(s, level)) {
+            Some(ar) => (ar, options),
+            None => {
+                api::push_nil(s); // level out of range
+                return Ok(1);
+            }
+        }
+    };
+    if !api::get_info(s, &options, &mut ar) {
+        luaL::arg_error(s, arg + 2, "invalid option").map_err(|_| ())?;
+    }
+    let lines = if options.contains('L') {
+        s.stack.pop()
+    } else {
+        None
+    };
+    let func = if options.contains('f') {
+        s.stack.pop()
+    } else {
+        None
+    };
+    api::create_table(s); // table to collect results
+    if options.contains('S') {
+        set_str_field(s, "source", &ar.source);
+        set_str_field(s, "short_src", &ar.short_src);
+        set_int_field(s, "linedefined", ar.linedefined as LuaInteger);
+        set_int_field(s, "lastlinedefined", ar.lastlinedefined as LuaInteger);
+        set_str_field(s, "what", ar.what);
+    }
+    if options.contains('l') {
+        set_int_field(s, "currentline", ar.currentline as LuaInteger);
+    }
+    if options.contains('u') {
+        set_int_field(s, "nups", ar.nups as LuaInteger);
+        set_int_field(s, "nparams", ar.nparams as LuaInteger);
+        set_bool_field(s, "isvararg", ar.isvararg);
+    }
+    if options.contains('n') {
+        if let Some(name) = &ar.name {
+            set_str_field(s, "name", name);
+        }
+        set_str_field(s, "namewhat", ar.namewhat);
+    }
+    if options.contains('t') {
+        set_bool_field(s, "istailcall", ar.istailcall);
+    }
+    if let Some(lines) = lines {
+        s.stack.push(lines);
+        api::set_field(s, -2, "activelines");
+    }
+    if let Some(func) = func {
+        s.stack.push(func);
+        api::set_field(s, -2, "func");
+    }
+    Ok(1) // return table
+}
+
+pub fn db_getlocal(s: &mut LuaState) -> Result<i32, ()> {
+    let arg = get_thread(s);
+    let nvar = luaL::check_integer(s, arg + 2)? as i32; // local-variable index
+    if api::is_function(s, arg + 1) {
+        // function argument?
+        api::push_value(s, arg + 1); // push function
+        match api::get_local(s, None, nvar) {
+            // push local name
+            Some(name) => api::push_string(s, &name),
+            None => api::push_nil(s),
+        }
+        return Ok(1); // return only name (there is no value)
+    }
+    // stack-level argument
+    let ar = get_level(s, arg + 1)?;
+    match api::get_local(s, Some(&ar), nvar) {
+        Some(name) => {
+            let value = s.stack.pop().unwrap();
+            api::push_string(s, &name); // push name
+            s.stack.push(value); // push value
+            Ok(2)
+        }
+        None => {
+            api::push_nil(s); // no name (nor value)
+            Ok(1)
+        }
+    }
+}
+
+/// Returns the activation record of the stack level given as argument `arg`
+fn get_level(s: &mut LuaState, arg: isize) -> Result<LuaDebug, ()> {
+    let level = luaL::check_integer(s, arg)?;
+    match usize::try_from(level).ok().and_then(|level| api::get_stack(s, level)) {
+        Some(ar) => Ok(ar),
+        None => {
+            // out of range?
+            luaL::arg_error(s, arg, "level out of range").map_err(|_| ())?;
+            unreachable!()
+        }
+    }
+}
+
+pub fn db_setlocal(s: &mut LuaState) -> Result<i32, ()> {
+    let arg = get_thread(s);
+    let ar = get_level(s, arg + 1)?;
+    let nvar = luaL::check_integer(s, arg + 2)? as i32;
+    luaL::check_any(s, arg + 3).map_err(|_| ())?;
+    api::set_top(s, arg as i32 + 3);
+    match api::set_local(s, &ar, nvar) {
+        Some(name) => api::push_string(s, &name),
+        None => {
+            api::pop(s, 1); // pop value (if not popped by 'set_local')
+            api::push_nil(s);
+        }
+    }
+    Ok(1)
+}
+
+/// get (if 'get' is true) or set an upvalue from a closure
+fn aux_upvalue(s: &mut LuaState, get: bool) -> Result<i32, ()> {
+    let n = luaL::check_integer(s, 2)?.max(0) as usize;
+    luaL::check_type(s, 1, LuaType::Function)?; // closure
+    let name = if get {
+        api::get_upvalue(s, 1, n)
+    } else {
+        api::set_upvalue(s, 1, n)
+    };
+    let Some(name) = name else {
+        return Ok(0);
+    };
+    if get {
+        let value = s.stack.pop().unwrap();
+        api::push_string(s, &name);
+        s.stack.push(value);
+        Ok(2)
+    } else {
+        api::push_string(s, &name);
+        Ok(1)
+    }
+}
+
+pub fn db_getupvalue(s: &mut LuaState) -> Result<i32, ()> {
+    aux_upvalue(s, true)
+}
+
+pub fn db_setupvalue(s: &mut LuaState) -> Result<i32, ()> {
+    luaL::check_any(s, 3).map_err(|_| ())?;
+    aux_upvalue(s, false)
+}
+
+/// Checks whether a given upvalue from a given closure exists and
+/// returns its index
+fn check_upval(s: &mut LuaState, argf: isize, argnup: isize) -> Result<usize, ()> {
+    let nup = luaL::check_integer(s, argnup)?.max(0) as usize; // upvalue index
+    luaL::check_type(s, argf, LuaType::Function)?; // closure
+    if api::get_upvalue(s, argf, nup).is_none() {
+        luaL::arg_error(s, argnup, "invalid upvalue index").map_err(|_| ())?;
+    }
+    api::pop(s, 1);
+    Ok(nup)
+}
+
+pub fn db_upvalueid(s: &mut LuaState) -> Result<i32, ()> {
+    let n = check_upval(s, 1, 2)?;
+    let id = api::upvalue_id(s, 1, n).unwrap();
+    api::push_light_userdata(s, id);
+    Ok(1)
+}
+
+pub fn db_upvaluejoin(s: &mut LuaState) -> Result<i32, ()> {
+    let n1 = check_upval(s, 1, 2)?;
+    let n2 = check_upval(s, 3, 4)?;
+    if api::is_rust_function(s, 1) {
+        luaL::arg_error(s, 1, "Lua function expected").map_err(|_| ())?;
+    }
+    if api::is_rust_function(s, 3) {
+        luaL::arg_error(s, 3, "Lua function expected").map_err(|_| ())?;
+    }
+    api::upvalue_join(s, 1, n1, 3, n2);
+    Ok(0)
+}
+
+/// Call hook function registered at hook table for the current
+/// thread (if there is one)
+fn hookf(s: &mut LuaState, ar: &LuaDebug) -> Result<(), ()> {
+    api::get_field(s, LUA_REGISTRYINDEX, HOOKKEY);
+    if api::is_function(s, -1) {
+        // is there a hook function?
+        api::push_string(s, ar.event.name()); // push event name
+        if ar.currentline >= 0 {
+            api::push_integer(s, ar.currentline as LuaInteger); // push current line
+        } else {
+            api::push_nil(s);
+        }
+        api::call(s, 2, 0).map_err(|_| ())?; // call hook function
+    } else {
+        api::pop(s, 1);
+    }
+    Ok(())
+}
+
+/// Converts a string mask (for 'sethook') into a bit mask
+fn make_mask(smask: &str, count: usize) -> usize {
+    let mut mask = 0;
+    if smask.contains('c') {
+        mask |= LUA_MASKCALL;
+    }
+    if smask.contains('r') {
+        mask |= LUA_MASKRET;
+    }
+    if smask.contains('l') {
+        mask |= LUA_MASKLINE;
+    }
+    if count > 0 {
+        mask |= LUA_MASKCOUNT;
+    }
+    mask
+}
+
+/// Converts a bit mask (for 'gethook') into a string mask
+fn unmake_mask(mask: usize) -> String {
+    let mut smask = String::new();
+    if mask & LUA_MASKCALL != 0 {
+        smask.push('c');
+    }
+    if mask & LUA_MASKRET != 0 {
+        smask.push('r');
+    }
+    if mask & LUA_MASKLINE != 0 {
+        smask.push('l');
+    }
+    smask
+}
+
+pub fn db_sethook(s: &mut LuaState) -> Result<i32, ()> {
+    let arg = get_thread(s);
+    let (func, mask, count): (Option<LuaHook>, usize, usize) = if api::is_none_or_nil(s, arg + 1) {
+        // no hook?
+        api::set_top(s, arg as i32 + 1);
+        (None, 0, 0) // turn off hooks
+    } else {
+        let smask = luaL::check_string(s, arg + 2)?;
+        luaL::check_type(s, arg + 1, LuaType::Function)?;
+        let count = luaL::opt_integer(s, arg + 3, 0)?.max(0) as usize;
+        (Some(hookf), make_mask(&smask, count), count)
+    };
+    api::push_value(s, arg + 1); // value (hook function)
+    api::set_field(s, LUA_REGISTRYINDEX, HOOKKEY);
+    api::set_hook(s, func, mask, count);
+    Ok(0)
+}
+
+pub fn db_gethook(s: &mut LuaState) -> Result<i32, ()> {
+    let mask = api::get_hook_mask(s);
+    match api::get_hook(s) {
+        None => api::push_nil(s), // no hook?
+        Some(hook) if !std::ptr::fn_addr_eq(hook, hookf as LuaHook) => {
+            // external hook?
+            api::push_literal(s, "external hook");
+        }
+        Some(_) => api::get_field(s, LUA_REGISTRYINDEX, HOOKKEY), // hook set by 'sethook'
+    }
+    api::push_string(s, &unmake_mask(mask));
+    api::push_integer(s, api::get_hook_count(s) as LuaInteger);
+    Ok(3)
+}
+
+pub fn db_debug(s: &mut LuaState) -> Result<i32, ()> {
+    let stdin = std::io::stdin();
+    loop {
+        _ = write!(s.stderr, "lua_debug> ");
+        _ = s.stderr.flush();
+        let mut buffer = String::new();
+        match stdin.lock().read_line(&mut buffer) {
+            Ok(n) if n > 0 && buffer != "cont\n" => (),
+            _ => return Ok(0),
+        }
+        if luaL::loadbuffer(s, &buffer, "=(debug command)").is_err()
+            || api::pcall(s, 0, 0, 0).is_err()
+        {
+            if s.exit_code.is_some() {
+                // os.exit called by the command
+                return Err(());
+            }
+            let msg = api::to_string(s, -1).unwrap_or_default();
+            _ = writeln!(s.stderr, "{}", msg);
+        }
+        api::set_top(s, 0); // remove eventual returns
+    }
+}
+
+pub fn db_traceback(s: &mut LuaState) -> Result<i32, ()> {
+    let arg = get_thread(s);
+    let msg = if api::is_string(s, arg + 1) {
+        api::to_string(s, arg + 1)
+    } else {
+        None
+    };
+    if msg.is_none() && !api::is_none_or_nil(s, arg + 1) {
+        // non-string 'msg'?
+        api::push_value(s, arg + 1); // return it untouched
+    } else {
+        let level = luaL::opt_integer(s, arg + 2, if arg == 0 { 1 } else { 0 })?.max(0) as usize;
+        luaL::traceback(s, msg.as_deref(), level);
+    }
+    Ok(1)
+}
+
+pub fn lib_open_debug(state: &mut LuaState) -> Result<i32, ()> {
+    luaL::new_lib(state, &DB_FUNCS);
+    Ok(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{api, luaL, object::TValue};
+    #[test]
+    fn getinfo() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "local function f(a, b)
+                local i = debug.getinfo(1, 'Slnu')
+                return i.what .. ',' .. i.currentline .. ',' .. i.linedefined .. ','
+                    .. i.lastlinedefined .. ',' .. i.nparams .. ',' .. tostring(i.isvararg)
+                    .. ',' .. i.name .. ',' .. i.namewhat
+            end
+            s = f(1, 2)
+            local m = debug.getinfo(1, 'S')
+            main = m.what .. ',' .. m.linedefined
+            local p = debug.getinfo(print)
+            c = p.what .. ',' .. p.short_src .. ',' .. p.currentline",
+        )
+        .unwrap();
+        api::get_global(&mut state, "s");
+        assert_eq!(
+            state.stack.last().unwrap(),
+            &TValue::from("Lua,2,1,6,2,false,f,local")
+        );
+        api::get_global(&mut state, "main");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("main,0"));
+        api::get_global(&mut state, "c");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("C,[C],-1"));
+    }
+    #[test]
+    fn locals() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "local function f(a, ...)
+                local x = 10
+                debug.setlocal(1, 2, 42)
+                local n1, v1 = debug.getlocal(1, 1)
+                local n2, v2 = debug.getlocal(1, 2)
+                local n3, v3 = debug.getlocal(1, -1)
+                return n1 .. v1 .. n2 .. x .. n3 .. v3
+            end
+            s = f('p', 'v')
+            param = debug.getlocal(f, 1)",
+        )
+        .unwrap();
+        api::get_global(&mut state, "s");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("apx42(*vararg)v"));
+        api::get_global(&mut state, "param");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("a"));
+    }
+    #[test]
+    fn upvalues() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "local a, b = 1, 2
+            local function f() return a end
+            local function g() return b end
+            local function h() return a end
+            local n = debug.getupvalue(f, 1)
+            debug.setupvalue(f, 1, 5)
+            same = debug.upvalueid(f, 1) == debug.upvalueid(h, 1)
+            diff = debug.upvalueid(f, 1) ~= debug.upvalueid(g, 1)
+            debug.upvaluejoin(f, 1, g, 1)
+            s = n .. a .. h() .. f()",
+        )
+        .unwrap();
+        api::get_global(&mut state, "s");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("a552"));
+        api::get_global(&mut state, "same");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Boolean(true));
+        api::get_global(&mut state, "diff");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Boolean(true));
+    }
+    #[test]
+    fn metatable() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "local t = setmetatable({}, {__metatable = 'locked'})
+            s = getmetatable(t) .. ',' .. type(debug.getmetatable(t))
+            debug.setmetatable(10, {__index = {twice = function(n) return n * 2 end}})
+            n = (21):twice()
+            debug.setmetatable(10, nil)",
+        )
+        .unwrap();
+        api::get_global(&mut state, "s");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("locked,table"));
+        api::get_global(&mut state, "n");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(42));
+    }
+    #[test]
+    fn traceback() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "local function f()
+                return debug.traceback('msg')
+            end
+            s = f()",
+        )
+        .unwrap();
+        api::get_global(&mut state, "s");
+        assert_eq!(
+            state.stack.last().unwrap(),
+            &TValue::from(
+                "msg\nstack traceback:\n\t[string \"local function f()...\"]:2: in local 'f'\n\t[string \"local function f()...\"]:4: in main chunk"
+            )
+        );
+    }
+    #[test]
+    fn sethook() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "local t = {}
+            local function f(x) return x + 1 end
+            local function g(x) return f(x) end
+            debug.sethook(function(ev, line) t[#t + 1] = line or ev end, 'crl')
+            g(1)
+            debug.sethook()
+            s = table.concat(t, ' ')
+            local n = 0
+            debug.sethook(function() n = n + 1 end, '', 1)
+            local mask, count = select(2, debug.gethook())
+            for i = 1, 10 do end
+            debug.sethook()
+            h = mask .. count .. ',' .. tostring(n > 10)
+            ok = pcall(function()
+                debug.sethook(function() debug.sethook() error('stop') end, 'l')
+                local a = 1
+            end)",
+        )
+        .unwrap();
+        api::get_global(&mut state, "s");
+        assert_eq!(
+            state.stack.last().unwrap(),
+            &TValue::from("return 5 call 3 tail call 2 return 6 call")
+        );
+        api::get_global(&mut state, "h");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("1,true"));
+        api::get_global(&mut state, "ok");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Boolean(false));
+    }
+}
@@ -1,10 +1,37 @@
 //! Standard I/O (and system) library
 
-use std::io::{stdout, Write};
+use std::{
+    io::{self, Read, Seek, SeekFrom, Write},
+    process::{Child, ChildStdin, ChildStdout, ExitStatus, Stdio},
+    rc::Rc,
+};
 
-use crate::{api, luaL, state::LuaState};
+use crate::{
+    api,
+    fs::{bad_file_descriptor, FileStream, FileSystem, OpenMode},
+    luaL,
+    luaconf::LUAL_BUFFERSIZE,
+    object::{str2number, TValue, UserDataRef},
+    state::LuaState,
+    LuaInteger, LUA_REGISTRYINDEX,
+};
 
-use super::LibReg;
+use super::{
+    os::shell_command,
+    LibReg,
+};
+
+/// registry key of the default input file
+const IO_INPUT: &str = "_IO_input";
+/// registry key of the default output file
+const IO_OUTPUT: &str = "_IO_output";
+const IO_PREFIX: &str = "_IO_";
+/// name of the metatable of file handles
+const LUA_FILEHANDLE: &str = "FILE*";
+/// maximum number of arguments to 'f:lines'/'io.lines' (it + 3 must fit in a Rust closure)
+const MAXARGLINE: usize = 250;
+/// maximum length of a numeral read by 'f:read("n")'
+const L_MAXLENNUM: usize = 200;
 
 const IO_FUNCS: [LibReg; 11] = [
     LibReg {
@@ -92,89 +119,1163 @@ const FILE_FUNCS: [LibReg; 9] = [
     },
 ];
 
+#[cfg(unix)]
+fn illegal_seek() -> io::Error {
+    io::Error::from_raw_os_error(29) // ESPIPE
+}
+
+#[cfg(not(unix))]
+fn illegal_seek() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "Illegal seek")
+}
+
+/// standard input of the process
+struct StdinStream;
+
+impl Read for StdinStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        io::stdin().read(buf)
+    }
+}
+
+impl Write for StdinStream {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(bad_file_descriptor())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for StdinStream {
+    fn seek(&mut self, _pos: SeekFrom) -> io::Result<u64> {
+        Err(illegal_seek())
+    }
+}
+
+/// pipe connected to the standard input or output of a child process
+struct PipeStream {
+    stdin: Option<ChildStdin>,
+    stdout: Option<ChildStdout>,
+}
+
+impl Read for PipeStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.stdout {
+            Some(ref mut stdout) => stdout.read(buf),
+            None => Err(bad_file_descriptor()),
+        }
+    }
+}
+
+impl Write for PipeStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.stdin {
+            Some(ref mut stdin) => stdin.write(buf),
+            None => Err(bad_file_descriptor()),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match self.stdin {
+            Some(ref mut stdin) => stdin.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Seek for PipeStream {
+    fn seek(&mut self, _pos: SeekFrom) -> io::Result<u64> {
+        Err(illegal_seek())
+    }
+}
+
+enum Stream {
+    /// the state standard output, shared with `print`
+    Stdout,
+    /// the state standard error output
+    Stderr,
+    File(Box<dyn FileStream>),
+}
+
+/// see `file:setvbuf`
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum BufferMode {
+    No,
+    Full,
+    Line,
+}
+
+/// A Lua file handle, stored in a `FILE*` userdata.
+/// Reads and writes are buffered like C streams.
+pub(crate) struct LuaFile {
+    /// None once the file is closed
+    stream: Option<Stream>,
+    /// standard files cannot be closed
+    is_std: bool,
+    /// temporary file to remove when the handle is closed
+    tmp_path: Option<(Rc<dyn FileSystem>, String)>,
+    /// process started by `io.popen`, waited for when the handle is closed
+    child: Option<Child>,
+    /// data read from the stream but not consumed yet
+    rbuf: Vec<u8>,
+    rpos: usize,
+    /// data waiting to be written to the stream
+    wbuf: Vec<u8>,
+    mode: BufferMode,
+    buf_size: usize,
+}
+
+impl LuaFile {
+    fn new(stream: Stream) -> Self {
+        let mode = match stream {
+            Stream::Stdout => BufferMode::Line,
+            Stream::Stderr => BufferMode::No,
+            Stream::File(_) => BufferMode::Full,
+        };
+        Self {
+            stream: Some(stream),
+            is_std: false,
+            tmp_path: None,
+            child: None,
+            rbuf: Vec::new(),
+            rpos: 0,
+            wbuf: Vec::new(),
+            mode,
+            buf_size: LUAL_BUFFERSIZE,
+        }
+    }
+    fn is_closed(&self) -> bool {
+        self.stream.is_none()
+    }
+    fn file_stream(&mut self) -> io::Result<&mut Box<dyn FileStream>> {
+        match self.stream {
+            Some(Stream::File(ref mut f)) => Ok(f),
+            _ => Err(bad_file_descriptor()),
+        }
+    }
+    /// writes the pending output to the stream
+    fn flush_output(&mut self) -> io::Result<()> {
+        if self.wbuf.is_empty() {
+            return Ok(());
+        }
+        let data = std::mem::take(&mut self.wbuf);
+        let f = self.file_stream()?;
+        f.write_all(&data)?;
+        f.flush()
+    }
+    /// drops the pending input, moving the stream position back to the last consumed byte
+    fn discard_input(&mut self) {
+        let pending = self.rbuf.len() - self.rpos;
+        if pending > 0 {
+            if let Ok(f) = self.file_stream() {
+                // not seekable streams just lose their pending input
+                let _ = f.seek(SeekFrom::Current(-(pending as i64)));
+            }
+        }
+        self.rbuf.clear();
+        self.rpos = 0;
+    }
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.discard_input();
+        self.wbuf.extend_from_slice(data);
+        let must_flush = match self.mode {
+            BufferMode::No => true,
+            BufferMode::Full => self.wbuf.len() >= self.buf_size,
+            BufferMode::Line => self.wbuf.len() >= self.buf_size || data.contains(&b'\n'),
+        };
+        if must_flush {
+            self.flush_output()?;
+        }
+        Ok(())
+    }
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.rpos >= self.rbuf.len() {
+            self.flush_output()?;
+            let size = self.buf_size.max(1);
+            let f = self.file_stream()?;
+            let mut buf = vec![0; size];
+            let n = f.read(&mut buf)?;
+            buf.truncate(n);
+            self.rbuf = buf;
+            self.rpos = 0;
+        }
+        Ok(&self.rbuf[self.rpos..])
+    }
+    fn consume(&mut self, n: usize) {
+        self.rpos += n;
+    }
+    fn peek(&mut self) -> io::Result<Option<u8>> {
+        Ok(self.fill_buf()?.first().copied())
+    }
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.flush_output()?;
+        let pending = (self.rbuf.len() - self.rpos) as i64;
+        let pos = match pos {
+            SeekFrom::Current(offset) => SeekFrom::Current(offset - pending),
+            pos => pos,
+        };
+        self.rbuf.clear();
+        self.rpos = 0;
+        match self.stream {
+            Some(Stream::File(ref mut f)) => f.seek(pos),
+            _ => Err(illegal_seek()),
+        }
+    }
+    fn set_buffer_mode(&mut self, mode: BufferMode, size: usize) -> io::Result<()> {
+        if let Some(Stream::File(_)) = self.stream {
+            self.flush_output()?;
+        }
+        self.mode = mode;
+        self.buf_size = size;
+        Ok(())
+    }
+    fn close(&mut self) -> io::Result<()> {
+        let res = match self.stream {
+            Some(Stream::File(_)) => self.flush_output(),
+            _ => Ok(()),
+        };
+        self.stream = None;
+        self.rbuf.clear();
+        self.rpos = 0;
+        if let Some((fs, path)) = self.tmp_path.take() {
+            let _ = fs.remove(&path);
+        }
+        res
+    }
+    /// closes the pipe of a file created by `io.popen` and waits for the process to end
+    fn close_process(&mut self) -> io::Result<ExitStatus> {
+        let _ = self.close();
+        self.child.take().unwrap().wait()
+    }
+}
+
+impl Drop for LuaFile {
+    fn drop(&mut self) {
+        let _ = self.close();
+        if let Some(mut child) = self.child.take() {
+            let _ = child.wait();
+        }
+    }
+}
+
+/// Calls `f` with the `LuaFile` inside a `FILE*` userdata
+fn with_file<R>(udref: &UserDataRef, f: impl FnOnce(&mut LuaFile) -> R) -> R {
+    let mut ud = udref.borrow_mut();
+    let file = ud
+        .data
+        .downcast_mut::<LuaFile>()
+        .expect("FILE* userdata without a file");
+    f(file)
+}
+
+fn write_file(s: &mut LuaState, file: &mut LuaFile, data: &[u8]) -> io::Result<()> {
+    let out = match file.stream {
+        Some(Stream::Stdout) => &mut s.stdout,
+        Some(Stream::Stderr) => &mut s.stderr,
+        _ => return file.write(data),
+    };
+    out.write_all(data)?;
+    if file.mode == BufferMode::No {
+        out.flush()?;
+    }
+    Ok(())
+}
+
+fn flush_file(s: &mut LuaState, file: &mut LuaFile) -> io::Result<()> {
+    match file.stream {
+        Some(Stream::Stdout) => s.stdout.flush(),
+        Some(Stream::Stderr) => s.stderr.flush(),
+        _ => file.flush_output(),
+    }
+}
+
 fn create_metatable(state: &mut LuaState) {
-    luaL::new_metatable(state, "FILE"); // create metatable for file handles
+    luaL::new_metatable(state, LUA_FILEHANDLE); // create metatable for file handles
     api::push_value(state, -1); // push metatable
     api::set_field(state, -2, "__index"); // metatable.__index = metatable
     luaL::set_funcs(state, &FILE_FUNCS, 0); // add file methods to new metatable
     api::pop(state, 1); // pop new metatable
 }
 
-pub fn io_close(_state: &mut LuaState) -> Result<i32, ()> {
-    todo!();
+/// Pushes a new file handle onto the stack
+fn new_file(s: &mut LuaState, file: LuaFile) -> UserDataRef {
+    api::new_userdata(s, file);
+    luaL::set_metatable(s, LUA_FILEHANDLE);
+    api::to_userdata(s, -1).unwrap()
 }
-pub fn io_flush(_state: &mut LuaState) -> Result<i32, ()> {
-    todo!();
+
+/// Checks that argument 1 is an open file handle
+fn to_file(s: &mut LuaState) -> Result<UserDataRef, ()> {
+    let udref = luaL::check_udata(s, 1, LUA_FILEHANDLE)?;
+    if with_file(&udref, |f| f.is_closed()) {
+        luaL::error(s, "attempt to use a closed file").map_err(|_| ())?;
+    }
+    Ok(udref)
 }
-pub fn io_input(_state: &mut LuaState) -> Result<i32, ()> {
-    todo!();
+
+fn check_mode(mode: &str) -> bool {
+    let mut chars = mode.chars();
+    if !matches!(chars.next(), Some('r' | 'w' | 'a')) {
+        return false;
+    }
+    let rest = chars.as_str();
+    let rest = rest.strip_prefix('+').unwrap_or(rest);
+    rest.chars().all(|c| c == 'b')
+}
+
+fn open_file(s: &LuaState, fname: &str, mode: &str) -> io::Result<LuaFile> {
+    let file = s.g.fs.open(fname, OpenMode::from_c_mode(mode))?;
+    Ok(LuaFile::new(Stream::File(file)))
+}
+
+/// Opens a file and pushes it on the stack, raising an error on failure
+fn open_check_file(s: &mut LuaState, fname: &str, mode: &str) -> Result<UserDataRef, ()> {
+    match open_file(s, fname, mode) {
+        Ok(file) => Ok(new_file(s, file)),
+        Err(e) => {
+            let msg = format!(
+                "cannot open file '{}' ({})",
+                fname,
+                luaL::os_error_message(&e)
+            );
+            luaL::error(s, &msg).map_err(|_| ())?;
+            unreachable!()
+        }
+    }
 }
-pub fn io_lines(_state: &mut LuaState) -> Result<i32, ()> {
-    todo!();
+
+fn aux_close(s: &mut LuaState, udref: &UserDataRef) -> i32 {
+    if with_file(udref, |f| f.is_std) {
+        api::push_nil(s);
+        api::push_string(s, "cannot close standard file");
+        return 2;
+    }
+    if with_file(udref, |f| f.child.is_some()) {
+        let res = with_file(udref, |f| f.close_process());
+        return luaL::exec_result(s, res);
+    }
+    let res = with_file(udref, |f| f.close());
+    luaL::file_result(s, res, None)
 }
-pub fn io_open(_state: &mut LuaState) -> Result<i32, ()> {
-    todo!();
+
+pub fn io_close(s: &mut LuaState) -> Result<i32, ()> {
+    if api::is_none(s, 1) {
+        // use standard output
+        api::get_field(s, LUA_REGISTRYINDEX, IO_OUTPUT);
+    }
+    let udref = to_file(s)?;
+    Ok(aux_close(s, &udref))
 }
-pub fn io_output(_state: &mut LuaState) -> Result<i32, ()> {
-    todo!();
+
+/// Pushes the default input or output file, checking that it is open
+fn get_io_file(s: &mut LuaState, findex: &str) -> Result<UserDataRef, ()> {
+    api::get_field(s, LUA_REGISTRYINDEX, findex);
+    let udref = api::to_userdata(s, -1).unwrap();
+    if with_file(&udref, |f| f.is_closed()) {
+        let msg = format!("standard {} file is closed", &findex[IO_PREFIX.len()..]);
+        luaL::error(s, &msg).map_err(|_| ())?;
+    }
+    Ok(udref)
 }
-pub fn io_popen(_state: &mut LuaState) -> Result<i32, ()> {
-    todo!();
+
+pub fn io_flush(s: &mut LuaState) -> Result<i32, ()> {
+    let udref = get_io_file(s, IO_OUTPUT)?;
+    let res = with_file(&udref, |f| flush_file(s, f));
+    Ok(luaL::file_result(s, res, None))
 }
-pub fn io_read(_state: &mut LuaState) -> Result<i32, ()> {
-    todo!();
+
+fn g_io_file(s: &mut LuaState, findex: &str, mode: &str) -> Result<i32, ()> {
+    if !api::is_none_or_nil(s, 1) {
+        match api::to_string(s, 1) {
+            Some(fname) => {
+                open_check_file(s, &fname, mode)?;
+            }
+            None => {
+                to_file(s)?; // check that it's a valid file handle
+                api::push_value(s, 1);
+            }
+        }
+        api::set_field(s, LUA_REGISTRYINDEX, findex);
+    }
+    // return current value
+    api::get_field(s, LUA_REGISTRYINDEX, findex);
+    Ok(1)
 }
-pub fn io_tmpfile(_state: &mut LuaState) -> Result<i32, ()> {
-    todo!();
+
+pub fn io_input(s: &mut LuaState) -> Result<i32, ()> {
+    g_io_file(s, IO_INPUT, "r")
 }
-pub fn io_type(_state: &mut LuaState) -> Result<i32, ()> {
-    todo!();
+
+pub fn io_output(s: &mut LuaState) -> Result<i32, ()> {
+    g_io_file(s, IO_OUTPUT, "w")
 }
 
-fn g_write(state: &mut LuaState, out: &mut dyn Write, arg: isize) -> i32 {
-    let mut nargs = api::get_top(state) as isize; // number of arguments
-                                                  //let mut status = true;
-                                                  // TODO handle formatting errors
-    let mut arg = arg;
-    while nargs > 0 {
-        write!(out, "{}", state.index2adr(arg)).unwrap();
-        arg += 1;
-        nargs -= 1;
+/// Pushes the iterator returned by `lines`. The file is at index `file`
+/// and the `n` read formats start at index 2.
+fn aux_lines(s: &mut LuaState, file: isize, n: usize, to_close: bool) -> Result<i32, ()> {
+    if n > MAXARGLINE {
+        luaL::arg_error(s, MAXARGLINE as isize + 2, "too many arguments").map_err(|_| ())?;
+    }
+    api::push_value(s, file);
+    api::push_integer(s, n as LuaInteger);
+    api::push_boolean(s, to_close);
+    for i in 0..n {
+        api::push_value(s, i as isize + 2);
     }
-    api::push_boolean(state, true);
-    1
+    api::push_rust_function(s, io_readline, 3 + n);
+    Ok(1)
 }
 
-pub fn io_write(state: &mut LuaState) -> Result<i32, ()> {
-    Ok(g_write(state, &mut stdout(), 1))
+pub fn io_lines(s: &mut LuaState) -> Result<i32, ()> {
+    let n = api::get_top(s).saturating_sub(1); // number of read formats
+    if api::is_none_or_nil(s, 1) {
+        // no file name: iterate over default input
+        get_io_file(s, IO_INPUT)?;
+        let file = api::abs_index(s, -1);
+        aux_lines(s, file, n, false)
+    } else {
+        // open a new file
+        let fname = luaL::check_string(s, 1)?;
+        open_check_file(s, &fname, "r")?;
+        let file = api::abs_index(s, -1);
+        aux_lines(s, file, n, true)
+    }
 }
-pub fn f_flush(_state: &mut LuaState) -> Result<i32, ()> {
-    todo!();
+
+pub fn io_open(s: &mut LuaState) -> Result<i32, ()> {
+    let fname = luaL::check_string(s, 1)?;
+    let mode = if api::is_none_or_nil(s, 2) {
+        "r".to_owned()
+    } else {
+        luaL::check_string(s, 2)?
+    };
+    if !check_mode(&mode) {
+        luaL::arg_error(s, 2, "invalid mode").map_err(|_| ())?;
+    }
+    match open_file(s, &fname, &mode) {
+        Ok(file) => {
+            new_file(s, file);
+            Ok(1)
+        }
+        Err(e) => Ok(luaL::file_result(s, Err(e), Some(&fname))),
+    }
 }
-pub fn f_lines(_state: &mut LuaState) -> Result<i32, ()> {
-    todo!();
+
+pub fn io_popen(s: &mut LuaState) -> Result<i32, ()> {
+    let prog = luaL::check_string(s, 1)?;
+    let mode = if api::is_none_or_nil(s, 2) {
+        "r".to_owned()
+    } else {
+        luaL::check_string(s, 2)?
+    };
+    if mode != "r" && mode != "w" {
+        luaL::arg_error(s, 2, "invalid mode").map_err(|_| ())?;
+    }
+    if !s.g.allow_processes {
+        luaL::error(s, "'popen' not supported").map_err(|_| ())?;
+    }
+    let mut command = shell_command(&prog);
+    if mode == "r" {
+        command.stdout(Stdio::piped());
+    } else {
+        command.stdin(Stdio::piped());
+    }
+    let _ = s.stdout.flush();
+    match command.spawn() {
+        Ok(mut child) => {
+            let pipe = PipeStream {
+                stdin: child.stdin.take(),
+                stdout: child.stdout.take(),
+            };
+            let mut file = LuaFile::new(Stream::File(Box::new(pipe)));
+            file.child = Some(child);
+            new_file(s, file);
+            Ok(1)
+        }
+        Err(e) => Ok(luaL::file_result(s, Err(e), Some(&prog))),
+    }
+}
+
+pub fn io_read(s: &mut LuaState) -> Result<i32, ()> {
+    let udref = get_io_file(s, IO_INPUT)?;
+    g_read(s, &udref, 1)
+}
+
+pub fn io_tmpfile(s: &mut LuaState) -> Result<i32, ()> {
+    let fs = Rc::clone(&s.g.fs);
+    match fs.create_temp_file() {
+        Ok((path, file)) => {
+            let mut file = LuaFile::new(Stream::File(file));
+            file.tmp_path = Some((fs, path));
+            new_file(s, file);
+            Ok(1)
+        }
+        Err(e) => Ok(luaL::file_result(s, Err(e), None)),
+    }
+}
+
+pub fn io_type(s: &mut LuaState) -> Result<i32, ()> {
+    luaL::check_any(s, 1).map_err(|_| ())?;
+    match luaL::test_udata(s, 1, LUA_FILEHANDLE) {
+        None => api::push_nil(s), // not a file
+        Some(udref) => {
+            if with_file(&udref, |f| f.is_closed()) {
+                api::push_literal(s, "closed file");
+            } else {
+                api::push_literal(s, "file");
+            }
+        }
+    }
+    Ok(1)
+}
+
+/// a format of `file:read`
+enum ReadFormat {
+    Number,
+    Line { keep_eol: bool },
+    All,
+    Chars(usize),
+}
+
+fn check_read_format(s: &mut LuaState, n: isize) -> Result<ReadFormat, ()> {
+    if let TValue::Integer(_) | TValue::Float(_) = s.index2adr(n) {
+        let l = luaL::check_integer(s, n)?;
+        return Ok(ReadFormat::Chars(l.max(0) as usize));
+    }
+    let p = luaL::check_string(s, n)?;
+    // skip optional '*' (for compatibility)
+    match p.strip_prefix('*').unwrap_or(&p).chars().next() {
+        Some('n') => Ok(ReadFormat::Number),
+        Some('l') => Ok(ReadFormat::Line { keep_eol: false }),
+        Some('L') => Ok(ReadFormat::Line { keep_eol: true }),
+        Some('a') => Ok(ReadFormat::All),
+        _ => {
+            luaL::arg_error(s, n, "invalid format").map_err(|_| ())?;
+            unreachable!()
+        }
+    }
+}
+
+/// Adds the next character to the numeral if it belongs to `set`
+fn test2(f: &mut LuaFile, buff: &mut String, set: &str) -> io::Result<bool> {
+    match f.peek()? {
+        Some(c) if set.contains(c as char) && buff.len() < L_MAXLENNUM => {
+            buff.push(c as char);
+            f.consume(1);
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+/// Reads a sequence of (hex)digits
+fn read_digits(f: &mut LuaFile, buff: &mut String, hex: bool) -> io::Result<usize> {
+    let mut count = 0;
+    while let Some(c) = f.peek()? {
+        let is_digit = if hex {
+            c.is_ascii_hexdigit()
+        } else {
+            c.is_ascii_digit()
+        };
+        if !is_digit || buff.len() >= L_MAXLENNUM {
+            break;
+        }
+        buff.push(c as char);
+        f.consume(1);
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Reads a numeral with the same syntax as the Lua lexer.
+/// Returns None if the numeral is invalid.
+fn read_number(f: &mut LuaFile) -> io::Result<Option<TValue>> {
+    let mut buff = String::new();
+    // skip spaces
+    while let Some(c) = f.peek()? {
+        if !c.is_ascii_whitespace() {
+            break;
+        }
+        f.consume(1);
+    }
+    test2(f, &mut buff, "-+")?; // optional sign
+    let mut count = 0;
+    let mut hex = false;
+    if test2(f, &mut buff, "0")? {
+        if test2(f, &mut buff, "xX")? {
+            hex = true; // numeral is hexadecimal
+        } else {
+            count = 1; // count initial '0' as a valid digit
+        }
+    }
+    count += read_digits(f, &mut buff, hex)?; // integral part
+    if test2(f, &mut buff, ".")? {
+        count += read_digits(f, &mut buff, hex)?; // fractional part
+    }
+    if count > 0 && test2(f, &mut buff, if hex { "pP" } else { "eE" })? {
+        test2(f, &mut buff, "-+")?; // exponent sign
+        read_digits(f, &mut buff, false)?; // exponent digits
+    }
+    if buff.len() >= L_MAXLENNUM {
+        // numeral too long
+        return Ok(None);
+    }
+    Ok(str2number(&buff))
 }
-pub fn f_read(_state: &mut LuaState) -> Result<i32, ()> {
-    todo!();
+
+/// Makes room for `additional` bytes in a read buffer, checking the memory
+/// limit before the buffer grows
+fn reserve(s: &mut LuaState, buf: &mut Vec<u8>, additional: usize) -> io::Result<()> {
+    let extra = s
+        .check_buffer_growth(buf.len(), buf.capacity(), additional)
+        .map_err(|_| io::Error::from(io::ErrorKind::OutOfMemory))?;
+    buf.reserve_exact(extra);
+    Ok(())
 }
-pub fn f_seek(_state: &mut LuaState) -> Result<i32, ()> {
-    todo!();
+
+fn read_line(s: &mut LuaState, f: &mut LuaFile, keep_eol: bool) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    loop {
+        let buf = f.fill_buf()?;
+        if buf.is_empty() {
+            // end of file
+            return Ok(if line.is_empty() { None } else { Some(line) });
+        }
+        match buf.iter().position(|&c| c == b'\n') {
+            Some(i) => {
+                let end = if keep_eol { i + 1 } else { i };
+                reserve(s, &mut line, end)?;
+                line.extend_from_slice(&buf[..end]);
+                f.consume(i + 1);
+                return Ok(Some(line));
+            }
+            None => {
+                let n = buf.len();
+                reserve(s, &mut line, n)?;
+                line.extend_from_slice(buf);
+                f.consume(n);
+            }
+        }
+    }
 }
-pub fn f_setvbuf(_state: &mut LuaState) -> Result<i32, ()> {
-    todo!();
+
+fn read_all(s: &mut LuaState, f: &mut LuaFile) -> io::Result<Vec<u8>> {
+    let mut res = Vec::new();
+    loop {
+        let buf = f.fill_buf()?;
+        if buf.is_empty() {
+            return Ok(res);
+        }
+        let n = buf.len();
+        reserve(s, &mut res, n)?;
+        res.extend_from_slice(buf);
+        f.consume(n);
+    }
 }
-pub fn f_write(_state: &mut LuaState) -> Result<i32, ()> {
-    todo!();
+
+fn read_chars(s: &mut LuaState, f: &mut LuaFile, n: usize) -> io::Result<Option<Vec<u8>>> {
+    if n == 0 {
+        // test eof
+        return Ok(f.peek()?.map(|_| Vec::new()));
+    }
+    let mut res = Vec::new();
+    while res.len() < n {
+        let buf = f.fill_buf()?;
+        if buf.is_empty() {
+            break;
+        }
+        let count = buf.len().min(n - res.len());
+        reserve(s, &mut res, count)?;
+        res.extend_from_slice(&buf[..count]);
+        f.consume(count);
+    }
+    Ok(if res.is_empty() { None } else { Some(res) })
 }
-pub fn io_gc(_state: &mut LuaState) -> Result<i32, ()> {
-    todo!();
+
+/// Converts the bytes read from a file to a string value. Valid UTF-8 is kept
+/// as is; any other byte becomes the character with the same code, as built by
+/// `string.char`, so that binary data is not lost.
+fn to_value(v: Vec<u8>) -> TValue {
+    match String::from_utf8(v) {
+        Ok(s) => TValue::from(s),
+        Err(e) => {
+            let v = e.into_bytes();
+            let mut res = String::with_capacity(v.len());
+            for chunk in v.utf8_chunks() {
+                res.push_str(chunk.valid());
+                res.extend(chunk.invalid().iter().map(|&b| b as char));
+            }
+            TValue::from(res)
+        }
+    }
 }
-pub fn io_tostring(_state: &mut LuaState) -> Result<i32, ()> {
-    todo!();
+
+fn read_one(s: &mut LuaState, f: &mut LuaFile, format: &ReadFormat) -> io::Result<Option<TValue>> {
+    Ok(match format {
+        ReadFormat::Number => read_number(f)?,
+        ReadFormat::Line { keep_eol } => read_line(s, f, *keep_eol)?.map(to_value),
+        ReadFormat::All => Some(to_value(read_all(s, f)?)),
+        ReadFormat::Chars(n) => read_chars(s, f, *n)?.map(to_value),
+    })
+}
+
+/// Reads the file according to the formats starting at index `first`.
+/// Returns the number of values pushed on the stack.
+fn g_read(s: &mut LuaState, udref: &UserDataRef, first: isize) -> Result<i32, ()> {
+    let nargs = api::get_top(s) as isize - 1;
+    let formats = if nargs <= 0 {
+        // no arguments? read a line
+        vec![ReadFormat::Line { keep_eol: false }]
+    } else {
+        let mut formats = Vec::new();
+        for n in first..first + nargs {
+            formats.push(check_read_format(s, n)?);
+        }
+        formats
+    };
+    let mut results = Vec::new();
+    for format in formats.iter() {
+        match with_file(udref, |f| read_one(s, f, format)) {
+            Ok(Some(value)) => {
+                if let TValue::String(v) = &value {
+                    s.g.gc.debt += v.capacity();
+                }
+                results.push(value)
+            }
+            Ok(None) => {
+                // read fails: push nil and stop
+                results.push(TValue::Nil);
+                break;
+            }
+            // not enough memory for the result
+            Err(_) if s.g.gc.memerr => return Err(()),
+            Err(e) => return Ok(luaL::file_result(s, Err(e), None)),
+        }
+    }
+    let n = results.len();
+    s.stack.extend(results);
+    Ok(n as i32)
+}
+
+/// Iteration function for `lines`
+fn io_readline(s: &mut LuaState) -> Result<i32, ()> {
+    let udref = api::to_userdata(s, api::upvalue_index(1)).unwrap();
+    let n = api::to_integer(s, api::upvalue_index(2)).unwrap() as usize;
+    if with_file(&udref, |f| f.is_closed()) {
+        // file is already closed?
+        luaL::error(s, "file is already closed").map_err(|_| ())?;
+    }
+    api::set_top(s, 1);
+    for i in 1..=n {
+        // push arguments to 'g_read'
+        api::push_value(s, api::upvalue_index(3 + i));
+    }
+    let n = g_read(s, &udref, 2)?;
+    if api::to_boolean(s, -n as isize) {
+        // read at least one value?
+        return Ok(n); // return them
+    }
+    // first result is nil: EOF or error
+    if n > 1 {
+        // is there error information?
+        let msg = api::to_string(s, -n as isize + 1).unwrap_or_default();
+        luaL::error(s, &msg).map_err(|_| ())?;
+    }
+    if api::to_boolean(s, api::upvalue_index(3)) {
+        // close file when finished?
+        let _ = with_file(&udref, |f| f.close());
+    }
+    Ok(0)
+}
+
+/// Writes the values starting at index `arg`. The file is on the top of the stack.
+fn g_write(s: &mut LuaState, udref: &UserDataRef, arg: isize) -> Result<i32, ()> {
+    let nargs = api::get_top(s) as isize - arg;
+    let mut status = Ok(());
+    for arg in arg..arg + nargs {
+        let data = match s.index2adr(arg) {
+            n @ (TValue::Integer(_) | TValue::Float(_)) => n.to_string(),
+            _ => luaL::check_string(s, arg)?,
+        };
+        if status.is_ok() {
+            status = with_file(udref, |f| write_file(s, f, data.as_bytes()));
+        }
+    }
+    match status {
+        Ok(()) => Ok(1), // file handle already on stack top
+        Err(e) => Ok(luaL::file_result(s, Err(e), None)),
+    }
+}
+
+pub fn io_write(s: &mut LuaState) -> Result<i32, ()> {
+    let udref = get_io_file(s, IO_OUTPUT)?;
+    g_write(s, &udref, 1)
+}
+
+pub fn f_flush(s: &mut LuaState) -> Result<i32, ()> {
+    let udref = to_file(s)?;
+    let res = with_file(&udref, |f| flush_file(s, f));
+    Ok(luaL::file_result(s, res, None))
+}
+
+pub fn f_lines(s: &mut LuaState) -> Result<i32, ()> {
+    to_file(s)?; // check that it's a valid file handle
+    let n = api::get_top(s) - 1;
+    aux_lines(s, 1, n, false)
+}
+
+pub fn f_read(s: &mut LuaState) -> Result<i32, ()> {
+    let udref = to_file(s)?;
+    g_read(s, &udref, 2)
+}
+
+pub fn f_seek(s: &mut LuaState) -> Result<i32, ()> {
+    let udref = to_file(s)?;
+    let op = luaL::check_option(s, 2, Some("cur"), &["set", "cur", "end"])?;
+    let offset = if api::is_none_or_nil(s, 3) {
+        0
+    } else {
+        luaL::check_integer(s, 3)?
+    };
+    let pos = match op {
+        0 if offset < 0 => {
+            let e = io::Error::new(io::ErrorKind::InvalidInput, "Invalid argument");
+            return Ok(luaL::file_result(s, Err(e), None));
+        }
+        0 => SeekFrom::Start(offset as u64),
+        1 => SeekFrom::Current(offset),
+        _ => SeekFrom::End(offset),
+    };
+    match with_file(&udref, |f| f.seek(pos)) {
+        Ok(pos) => {
+            api::push_integer(s, pos as LuaInteger);
+            Ok(1)
+        }
+        Err(e) => Ok(luaL::file_result(s, Err(e), None)),
+    }
+}
+
+pub fn f_setvbuf(s: &mut LuaState) -> Result<i32, ()> {
+    const MODES: [BufferMode; 3] = [BufferMode::No, BufferMode::Full, BufferMode::Line];
+    let udref = to_file(s)?;
+    let op = luaL::check_option(s, 2, None, &["no", "full", "line"])?;
+    let size = if api::is_none_or_nil(s, 3) {
+        LUAL_BUFFERSIZE
+    } else {
+        luaL::check_integer(s, 3)?.max(1) as usize
+    };
+    let res = with_file(&udref, |f| f.set_buffer_mode(MODES[op], size));
+    Ok(luaL::file_result(s, res, None))
+}
+
+pub fn f_write(s: &mut LuaState) -> Result<i32, ()> {
+    let udref = to_file(s)?;
+    api::push_value(s, 1); // push file at the stack top (to be returned)
+    g_write(s, &udref, 2)
+}
+
+pub fn io_gc(s: &mut LuaState) -> Result<i32, ()> {
+    let udref = luaL::check_udata(s, 1, LUA_FILEHANDLE)?;
+    // ignore closed and standard files
+    if !with_file(&udref, |f| f.is_closed() || f.is_std) {
+        aux_close(s, &udref);
+    }
+    Ok(0)
+}
+
+pub fn io_tostring(s: &mut LuaState) -> Result<i32, ()> {
+    let udref = luaL::check_udata(s, 1, LUA_FILEHANDLE)?;
+    if with_file(&udref, |f| f.is_closed()) {
+        api::push_literal(s, "file (closed)");
+    } else {
+        api::push_string(s, &format!("file ({:p})", Rc::as_ptr(&udref)));
+    }
+    Ok(1)
+}
+
+fn create_std_file(s: &mut LuaState, stream: Stream, k: Option<&str>, fname: &str) {
+    let mut file = LuaFile::new(stream);
+    file.is_std = true;
+    new_file(s, file);
+    if let Some(k) = k {
+        api::push_value(s, -1);
+        api::set_field(s, LUA_REGISTRYINDEX, k); // add file to registry
+    }
+    api::set_field(s, -2, fname); // add file to module
 }
 
 pub fn lib_open_io(state: &mut LuaState) -> Result<i32, ()> {
     luaL::new_lib(state, &IO_FUNCS);
     create_metatable(state);
+    // create (and set) default files
+    let stdin = Stream::File(Box::new(StdinStream));
+    create_std_file(state, stdin, Some(IO_INPUT), "stdin");
+    create_std_file(state, Stream::Stdout, Some(IO_OUTPUT), "stdout");
+    create_std_file(state, Stream::Stderr, None, "stderr");
     Ok(1)
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{api, luaL, object::TValue};
+
+    #[test]
+    fn write_read_formats() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "local f = io.tmpfile()
+            f:write('12 0x1F -3.5e1 rest\\n', 42, ' second line\\n', 'last')
+            f:seek('set')
+            a, b, c = f:read('n', 'n', 'n')
+            d = f:read('l')
+            e = f:read('L')
+            g, h = f:read(2, 'a')
+            i = f:read('a')
+            j = f:read('l')
+            f:close()",
+        )
+        .unwrap();
+        api::get_global(&mut state, "a");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(12));
+        api::get_global(&mut state, "b");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(31));
+        api::get_global(&mut state, "c");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Float(-35.0));
+        api::get_global(&mut state, "d");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from(" rest"));
+        api::get_global(&mut state, "e");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("42 second line\n"));
+        api::get_global(&mut state, "g");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("la"));
+        api::get_global(&mut state, "h");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("st"));
+        api::get_global(&mut state, "i");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from(""));
+        api::get_global(&mut state, "j");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Nil);
+    }
+    #[test]
+    fn read_invalid_number() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "local f = io.tmpfile()
+            f:write('0x hello')
+            f:seek('set')
+            a, b = f:read('n', 'l')
+            f:close()",
+        )
+        .unwrap();
+        api::get_global(&mut state, "a");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Nil);
+        api::get_global(&mut state, "b");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Nil);
+    }
+    #[test]
+    fn read_binary_data() {
+        let name = std::env::temp_dir().join(format!("lua-io-binary-{}", std::process::id()));
+        std::fs::write(&name, b"\xff\x00a\xc3\xa9\n\x80\xfe").unwrap();
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        api::push_string(&mut state, name.to_str().unwrap());
+        api::set_global(&mut state, "name");
+        luaL::dostring(
+            &mut state,
+            "local f = io.open(name, 'rb')
+            a = f:read('l')
+            b = f:read(1)
+            f:seek('set')
+            c = f:read('a')
+            f:close()",
+        )
+        .unwrap();
+        std::fs::remove_file(&name).unwrap();
+        api::get_global(&mut state, "a");
+        assert_eq!(
+            state.stack.last().unwrap(),
+            &TValue::from("\u{ff}\0a\u{e9}")
+        );
+        api::get_global(&mut state, "b");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("\u{80}"));
+        api::get_global(&mut state, "c");
+        assert_eq!(
+            state.stack.last().unwrap(),
+            &TValue::from("\u{ff}\0a\u{e9}\n\u{80}\u{fe}")
+        );
+    }
+    #[test]
+    fn seek_whence() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "local f = io.tmpfile()
+            f:write('0123456789')
+            a = f:seek('end')
+            b = f:seek('set', 2)
+            f:read(3)
+            c = f:seek()
+            d = f:seek('cur', -1)
+            e = f:read(1)
+            f:close()",
+        )
+        .unwrap();
+        api::get_global(&mut state, "a");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(10));
+        api::get_global(&mut state, "b");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(2));
+        api::get_global(&mut state, "c");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(5));
+        api::get_global(&mut state, "d");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(4));
+        api::get_global(&mut state, "e");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("4"));
+    }
+    #[test]
+    fn lines_and_default_files() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "name = os.tmpname()
+            io.output(name)
+            io.write('a\\n', 'b\\n', 3, '\\n')
+            io.close()
+            io.output(io.stdout)
+            z = ''
+            for l in io.lines(name) do z = z .. l end
+            io.input(name)
+            w = io.read('L') .. io.read('l') .. io.read('n')
+            io.input():close()
+            local f = io.open(name)
+            y = 0
+            for a, b in f:lines(1, 'l') do y = y + 1 end
+            t = io.type(f)
+            f:close()
+            u = io.type(f)
+            v = io.type(42)
+            os.remove(name)",
+        )
+        .unwrap();
+        api::get_global(&mut state, "z");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("ab3"));
+        api::get_global(&mut state, "w");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("a\nb3"));
+        api::get_global(&mut state, "y");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(3));
+        api::get_global(&mut state, "t");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("file"));
+        api::get_global(&mut state, "u");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("closed file"));
+        api::get_global(&mut state, "v");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Nil);
+    }
+    #[test]
+    fn closed_file_errors() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        let r = luaL::dostring(
+            &mut state,
+            "local f = io.tmpfile()
+            f:close()
+            f:read()",
+        );
+        assert!(r.is_err());
+        let msg = api::to_string(&mut state, -1).unwrap();
+        assert!(msg.ends_with("attempt to use a closed file"));
+        luaL::dostring(&mut state, "a, b = io.stdout:close()").unwrap();
+        api::get_global(&mut state, "b");
+        assert_eq!(
+            state.stack.last().unwrap(),
+            &TValue::from("cannot close standard file")
+        );
+        luaL::dostring(&mut state, "a, b, c = io.open('/nonexistent/file')").unwrap();
+        api::get_global(&mut state, "b");
+        assert_eq!(
+            state.stack.last().unwrap(),
+            &TValue::from("/nonexistent/file: No such file or directory")
+        );
+    }
+    #[cfg(unix)]
+    #[test]
+    fn popen_read_write() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "local p = io.popen('echo hello; echo world')
+            a = p:read('a')
+            b, c, d = p:close()
+            p = io.popen('exit 3')
+            e, f, g = p:close()
+            local name = os.tmpname()
+            p = io.popen('cat > ' .. name, 'w')
+            p:write('piped')
+            p:close()
+            h = io.open(name):read('a')
+            os.remove(name)",
+        )
+        .unwrap();
+        api::get_global(&mut state, "a");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("hello\nworld\n"));
+        api::get_global(&mut state, "b");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Boolean(true));
+        api::get_global(&mut state, "c");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("exit"));
+        api::get_global(&mut state, "d");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(0));
+        api::get_global(&mut state, "e");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Nil);
+        api::get_global(&mut state, "g");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(3));
+        api::get_global(&mut state, "h");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("piped"));
+    }
+    #[test]
+    fn popen_disabled() {
+        let mut state = luaL::newstate();
+        state.g.allow_processes = false;
+        luaL::open_libs(&mut state).unwrap();
+        let r = luaL::dostring(&mut state, "io.popen('ls')");
+        assert!(r.is_err());
+        let msg = api::to_string(&mut state, -1).unwrap();
+        assert!(msg.ends_with("'popen' not supported"));
+        luaL::dostring(&mut state, "z = os.execute()").unwrap();
+        api::get_global(&mut state, "z");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Boolean(false));
+    }
+    #[test]
+    fn setvbuf_modes() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "local name = os.tmpname()
+            local f = io.open(name, 'w')
+            a = f:setvbuf('no')
+            f:write('x')
+            local g = io.open(name)
+            b = g:read('a')
+            f:setvbuf('full', 1024)
+            f:write('y')
+            c = g:read('a')
+            f:flush()
+            d = g:read('a')
+            f:close()
+            g:close()
+            os.remove(name)",
+        )
+        .unwrap();
+        api::get_global(&mut state, "a");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Boolean(true));
+        api::get_global(&mut state, "b");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("x"));
+        api::get_global(&mut state, "c");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from(""));
+        api::get_global(&mut state, "d");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("y"));
+    }
+}
@@ -1,6 +1,12 @@
 //! Standard mathematical library
 
-use crate::{api, luaL, state::LuaState, LuaInteger, TValue};
+use crate::{
+    api::{self, CompareOp},
+    luaL,
+    object::float_to_integer,
+    state::{make_seed, LuaState, RandomState},
+    LuaFloat, LuaInteger, TValue,
+};
 
 use super::LibReg;
 
@@ -100,10 +106,11 @@ const MATH_FUNCS: [LibReg; 23] = [
 ];
 
 pub fn math_abs(s: &mut LuaState) -> Result<i32, ()> {
-    let value = luaL::check_numeral(s, 1).map_err(|_| ())?;
     if api::is_integer(s, 1) {
-        api::push_integer(s, value.abs() as LuaInteger);
+        let n = luaL::check_integer(s, 1)?;
+        api::push_integer(s, n.wrapping_abs()); // abs(mininteger) is mininteger
     } else {
+        let value = luaL::check_number(s, 1)?;
         api::push_number(s, value.abs());
     }
     Ok(1)
@@ -120,13 +127,17 @@ pub fn math_asin(s: &mut LuaState) -> Result<i32, ()> {
 }
 pub fn math_atan(s: &mut LuaState) -> Result<i32, ()> {
     let y = luaL::check_number(s, 1).map_err(|_| ())?;
-    let x = luaL::opt_number(s, 2).unwrap_or(1.0);
+    let x = luaL::opt_number(s, 2, 1.0)?;
     api::push_number(s, y.atan2(x));
     Ok(1)
 }
 pub fn math_ceil(s: &mut LuaState) -> Result<i32, ()> {
-    let value = luaL::check_number(s, 1).map_err(|_| ())?;
-    api::push_number(s, value.ceil());
+    if api::is_integer(s, 1) {
+        api::set_top(s, 1); // integer is its own ceil
+    } else {
+        let value = luaL::check_number(s, 1)?;
+        push_num_int(s, value.ceil());
+    }
     Ok(1)
 }
 pub fn math_cos(s: &mut LuaState) -> Result<i32, ()> {
@@ -144,33 +155,71 @@ pub fn math_exp(s: &mut LuaState) -> Result<i32, ()> {
     api::push_number(s, value.exp());
     Ok(1)
 }
+/// Pushes a float as an integer if it fits, as a float otherwise
+fn push_num_int(s: &mut LuaState, d: LuaFloat) {
+    match float_to_integer(d) {
+        Some(n) => api::push_integer(s, n), // result is integer
+        None => api::push_number(s, d),     // result is float
+    }
+}
 pub fn math_floor(s: &mut LuaState) -> Result<i32, ()> {
-    let value = luaL::check_number(s, 1).map_err(|_| ())?;
-    api::push_number(s, value.floor());
+    if api::is_integer(s, 1) {
+        api::set_top(s, 1); // integer is its own floor
+    } else {
+        let value = luaL::check_number(s, 1)?;
+        push_num_int(s, value.floor());
+    }
     Ok(1)
 }
 pub fn math_fmod(s: &mut LuaState) -> Result<i32, ()> {
-    let x = luaL::check_number(s, 1).map_err(|_| ())?;
-    let y = luaL::check_number(s, 2).map_err(|_| ())?;
-    api::push_number(s, x % y);
+    if api::is_integer(s, 1) && api::is_integer(s, 2) {
+        let m = luaL::check_integer(s, 1)?;
+        let d = luaL::check_integer(s, 2)?;
+        match d {
+            0 => {
+                luaL::arg_error(s, 2, "zero").map_err(|_| ())?;
+            }
+            // avoid overflow with 0x80000... / -1
+            -1 => api::push_integer(s, 0),
+            _ => api::push_integer(s, m % d), // truncated remainder, as C '%'
+        }
+    } else {
+        let x = luaL::check_number(s, 1)?;
+        let y = luaL::check_number(s, 2)?;
+        api::push_number(s, x % y);
+    }
     Ok(1)
 }
 pub fn math_toint(s: &mut LuaState) -> Result<i32, ()> {
-    match api::to_integer(s, 1) {
-        None => api::push_nil(s),
+    // strings are not converted
+    let n = match s.index2adr(1) {
+        TValue::Integer(_) | TValue::Float(_) => api::to_integer(s, 1),
+        _ => None,
+    };
+    match n {
+        None => {
+            luaL::check_any(s, 1).map_err(|_| ())?;
+            api::push_nil(s) // value is not convertible to integer
+        }
         Some(i) => api::push_integer(s, i),
     }
     Ok(1)
 }
-pub fn math_ult(_state: &mut LuaState) -> Result<i32, ()> {
-    todo!()
+pub fn math_ult(s: &mut LuaState) -> Result<i32, ()> {
+    let a = luaL::check_integer(s, 1)?;
+    let b = luaL::check_integer(s, 2)?;
+    api::push_boolean(s, (a as u64) < (b as u64));
+    Ok(1)
 }
 
 pub fn math_type(s: &mut LuaState) -> Result<i32, ()> {
     match s.index2adr(1) {
         TValue::Float(_) => api::push_literal(s, "float"),
         TValue::Integer(_) => api::push_literal(s, "integer"),
-        _ => api::push_nil(s),
+        _ => {
+            luaL::check_any(s, 1).map_err(|_| ())?;
+            api::push_nil(s)
+        }
     }
     Ok(1)
 }
@@ -192,46 +241,44 @@ pub fn math_log(s: &mut LuaState) -> Result<i32, ()> {
 }
 pub fn math_max(s: &mut LuaState) -> Result<i32, ()> {
     let n = api::get_top(s) as isize; // number of arguments
-    let mut dmax = luaL::check_numeral(s, 1).map_err(|_| ())?;
-    let mut maxi = 1;
+    let mut imax = 1; // index of current maximum value
+    luaL::check_numeral(s, 1)?;
     for i in 2..=n {
-        let d = luaL::check_numeral(s, i).map_err(|_| ())?;
-        if d > dmax {
-            dmax = d;
-            maxi = i;
+        luaL::check_numeral(s, i)?;
+        if api::compare(s, imax, i, CompareOp::Lt).map_err(|_| ())? {
+            imax = i;
         }
     }
-    if api::is_integer(s, maxi) {
-        api::push_integer(s, dmax as LuaInteger);
-    } else {
-        api::push_number(s, dmax);
-    }
+    api::push_value(s, imax);
     Ok(1)
 }
 
 /// Returns the minimum value among its arguments.
 pub fn math_min(s: &mut LuaState) -> Result<i32, ()> {
     let n = api::get_top(s) as isize; // number of arguments
-    let mut dmin = luaL::check_numeral(s, 1).map_err(|_| ())?;
-    let mut imin = 1;
+    let mut imin = 1; // index of current minimum value
+    luaL::check_numeral(s, 1)?;
     for i in 2..=n {
-        let d = luaL::check_numeral(s, i).map_err(|_| ())?;
-        if d < dmin {
-            dmin = d;
+        luaL::check_numeral(s, i)?;
+        if api::compare(s, i, imin, CompareOp::Lt).map_err(|_| ())? {
             imin = i;
         }
     }
-    if api::is_integer(s, imin) {
-        api::push_integer(s, dmin as LuaInteger);
-    } else {
-        api::push_number(s, dmin);
-    }
+    api::push_value(s, imin);
     Ok(1)
 }
 pub fn math_modf(s: &mut LuaState) -> Result<i32, ()> {
-    let value = luaL::check_number(s, 1).map_err(|_| ())?;
-    api::push_number(s, value.floor());
-    api::push_number(s, value.fract());
+    if api::is_integer(s, 1) {
+        api::set_top(s, 1); // number is its own integer part
+        api::push_number(s, 0.0); // no fractional part
+    } else {
+        let n = luaL::check_number(s, 1)?;
+        // integer part (rounds toward zero)
+        let ip = n.trunc();
+        api::push_number(s, ip);
+        // fractional part (test needed for inf/-inf)
+        api::push_number(s, if n == ip { 0.0 } else { n - ip });
+    }
     Ok(2)
 }
 pub fn math_rad(s: &mut LuaState) -> Result<i32, ()> {
@@ -239,11 +286,60 @@ pub fn math_rad(s: &mut LuaState) -> Result<i32, ()> {
     api::push_number(s, value.to_radians());
     Ok(1)
 }
-pub fn math_random(_state: &mut LuaState) -> Result<i32, ()> {
-    todo!();
+pub fn math_random(s: &mut LuaState) -> Result<i32, ()> {
+    let rv = s.g.random.next_u64();
+    let (low, up) = match api::get_top(s) {
+        // check number of arguments
+        0 => {
+            // no arguments
+            api::push_number(s, RandomState::to_float(rv)); // Number between 0 and 1
+            return Ok(1);
+        }
+        1 => {
+            // only upper limit
+            let up = luaL::check_integer(s, 1)?;
+            if up == 0 {
+                // single 0 as argument?
+                api::push_integer(s, rv as LuaInteger); // full random integer
+                return Ok(1);
+            }
+            (1, up)
+        }
+        2 => {
+            // lower and upper limits
+            (luaL::check_integer(s, 1)?, luaL::check_integer(s, 2)?)
+        }
+        _ => {
+            luaL::error(s, "wrong number of arguments").map_err(|_| ())?;
+            unreachable!()
+        }
+    };
+    // random integer in the interval [low, up]
+    if low > up {
+        luaL::arg_error(s, 1, "interval is empty").map_err(|_| ())?;
+    }
+    // project random integer into the interval [0, up - low]
+    let p = s.g.random.project(rv, (up as u64).wrapping_sub(low as u64));
+    api::push_integer(s, p.wrapping_add(low as u64) as LuaInteger);
+    Ok(1)
 }
-pub fn math_randomseed(_state: &mut LuaState) -> Result<i32, ()> {
-    todo!();
+pub fn math_randomseed(s: &mut LuaState) -> Result<i32, ()> {
+    let (n1, n2) = if api::is_none(s, 1) {
+        let local = 0u8;
+        let n1 = s.g.random.next_u64() as LuaInteger ^ make_seed();
+        (n1, &local as *const u8 as LuaInteger)
+    } else {
+        let n1 = if api::is_integer(s, 1) {
+            luaL::check_integer(s, 1)?
+        } else {
+            luaL::check_number(s, 1)? as LuaInteger
+        };
+        (n1, luaL::opt_integer(s, 2, 0)?)
+    };
+    s.g.random = RandomState::from_seed(n1, n2);
+    api::push_integer(s, n1);
+    api::push_integer(s, n2);
+    Ok(2)
 }
 pub fn math_sin(s: &mut LuaState) -> Result<i32, ()> {
     let value = luaL::check_number(s, 1).map_err(|_| ())?;
@@ -276,7 +372,7 @@ pub fn lib_open_math(state: &mut LuaState) -> Result<i32, ()> {
 
 #[cfg(test)]
 mod tests {
-    use crate::{api, luaL, object::TValue};
+    use crate::{api, luaL, object::TValue, state::RandomState};
     #[test]
     fn sqrt() {
         let mut state = luaL::newstate();
@@ -313,4 +409,142 @@ mod tests {
         api::get_global(&mut state, "z");
         assert_eq!(state.stack.last().unwrap(), &TValue::Float(5.2));
     }
+    #[test]
+    fn xoshiro() {
+        // reference values of xoshiro256**
+        let mut rs = RandomState::from_state([1, 2, 3, 4]);
+        assert_eq!(rs.next_u64(), 11520);
+        assert_eq!(rs.next_u64(), 0);
+        assert_eq!(rs.next_u64(), 1509978240);
+        assert_eq!(rs.next_u64(), 1215971899390074240);
+    }
+    #[test]
+    fn random() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "a, b = math.randomseed(42)
+            local t = {}
+            for i = 1, 5 do t[i] = math.random(1, 1000) end
+            math.randomseed(42)
+            same = true
+            for i = 1, 5 do same = same and t[i] == math.random(1, 1000) end
+            ok = true
+            for i = 1, 1000 do
+                local f = math.random()
+                local n = math.random(6)
+                local m = math.random(math.mininteger, math.maxinteger)
+                ok = ok and f >= 0 and f < 1 and n >= 1 and n <= 6
+                    and math.type(n) == 'integer' and math.type(m) == 'integer'
+            end
+            local r
+            r, e = pcall(math.random, 2, 1)",
+        )
+        .unwrap();
+        api::get_global(&mut state, "a");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(42));
+        api::get_global(&mut state, "b");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(0));
+        api::get_global(&mut state, "same");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Boolean(true));
+        api::get_global(&mut state, "ok");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Boolean(true));
+        api::get_global(&mut state, "e");
+        assert_eq!(
+            state.stack.last().unwrap(),
+            &TValue::from("bad argument #1 to 'math.random' (interval is empty)")
+        );
+    }
+    #[test]
+    fn random_state() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        state.g.random = RandomState::from_seed(7, 0);
+        let snapshot = state.g.random.state();
+        luaL::dostring(&mut state, "a = math.random(0)").unwrap();
+        state.g.random = RandomState::from_state(snapshot);
+        luaL::dostring(&mut state, "b = math.random(0) math.randomseed(7) c = math.random(0)")
+            .unwrap();
+        api::get_global(&mut state, "a");
+        let a = state.stack.pop().unwrap();
+        api::get_global(&mut state, "b");
+        assert_eq!(state.stack.last().unwrap(), &a);
+        api::get_global(&mut state, "c");
+        assert_eq!(state.stack.last().unwrap(), &a);
+    }
+    #[test]
+    fn integer_functions() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "a = math.floor(3.7)
+            b = math.ceil(-3.7)
+            c = math.floor(1e100)
+            d = math.abs(math.mininteger)
+            e = math.fmod(-7, 3)
+            f = math.fmod(math.mininteger, -1)
+            g = math.tointeger('16')
+            h = math.tointeger(2^63)
+            i = math.ult(1, -1)
+            j = math.max(math.maxinteger, 2^53)
+            k = math.tointeger(3.0)
+            local ok
+            ok, l = pcall(math.fmod, 1, 0)
+            m = math.fmod(5.5, 2)
+            n = math.modf(-3.5)",
+        )
+        .unwrap();
+        api::get_global(&mut state, "a");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(3));
+        api::get_global(&mut state, "b");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(-3));
+        api::get_global(&mut state, "c");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Float(1e100));
+        api::get_global(&mut state, "d");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(i64::MIN));
+        api::get_global(&mut state, "e");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(-1));
+        api::get_global(&mut state, "f");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(0));
+        api::get_global(&mut state, "g");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Nil);
+        api::get_global(&mut state, "h");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Nil);
+        api::get_global(&mut state, "i");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Boolean(true));
+        api::get_global(&mut state, "j");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(i64::MAX));
+        api::get_global(&mut state, "k");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(3));
+        api::get_global(&mut state, "l");
+        assert_eq!(
+            state.stack.last().unwrap(),
+            &TValue::from("bad argument #2 to 'math.fmod' (zero)")
+        );
+        api::get_global(&mut state, "m");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Float(1.5));
+        api::get_global(&mut state, "n");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Float(-3.0));
+    }
+    #[test]
+    fn integer_float_comparison() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "local big = math.maxinteger
+            a = big < 2^63
+            b = big + 0.0 == 2^63
+            c = big - 1 < big + 0.0
+            d = math.mininteger <= -2^63
+            e = 1 < 0/0",
+        )
+        .unwrap();
+        for (name, value) in [("a", true), ("b", true), ("c", true), ("d", true), ("e", false)] {
+            api::get_global(&mut state, name);
+            assert_eq!(state.stack.last().unwrap(), &TValue::Boolean(value), "{}", name);
+        }
+    }
 }
@@ -1,27 +1,38 @@
 //! Initialization of libraries for lua
 mod base;
+mod coro;
+mod debug;
 mod io;
 mod maths;
+mod os;
+mod package;
 mod string;
 mod table;
-mod coro;
-use crate::{luaL,api,api::LuaError, state::LuaState, LuaRustFunction, LUA_REGISTRYINDEX};
+use crate::{api, api::LuaError, luaL, state::LuaState, LuaRustFunction, LUA_REGISTRYINDEX};
 
 use self::{
-    base::lib_open_base, io::lib_open_io, maths::lib_open_math, string::lib_open_string,
-    table::lib_open_table,coro::lib_open_coro
+    base::lib_open_base, coro::lib_open_coro, debug::lib_open_debug, io::lib_open_io,
+    maths::lib_open_math,
+    os::lib_open_os, package::lib_open_package, string::lib_open_string,
+    table::lib_open_table,
 };
 
+pub use self::package::{add_searcher, preload};
+
 pub struct LibReg<'a> {
     pub name: &'a str,
     pub func: LuaRustFunction,
 }
 
-const LUA_LIBS: [LibReg; 6] = [
+const LUA_LIBS: [LibReg; 9] = [
     LibReg {
-        name: "",
+        name: "_G",
         func: lib_open_base,
     },
+    LibReg {
+        name: "package",
+        func: lib_open_package,
+    },
     LibReg {
         name: "string",
         func: lib_open_string,
@@ -42,15 +53,22 @@ const LUA_LIBS: [LibReg; 6] = [
         name: "coroutine",
         func: lib_open_coro,
     },
-    // TODO os and debug ?
+    LibReg {
+        name: "os",
+        func: lib_open_os,
+    },
+    LibReg {
+        name: "debug",
+        func: lib_open_debug,
+    },
 ];
 
-// Opens all standard Lua libraries into the given state. 
+// Opens all standard Lua libraries into the given state.
 pub fn open_libs(state: &mut LuaState) -> Result<(), LuaError> {
     // call open functions from 'loadedlibs' and set results to global table
     for reg in LUA_LIBS.iter() {
         luaL::requiref(state, reg.name, reg.func, true)?;
-        api::pop(state,1); // remove lib 
+        api::pop(state, 1); // remove lib
     }
     // add open functions from 'preloadedlibs' into 'package.preload' table
     luaL::get_sub_table(state, LUA_REGISTRYINDEX, "_PRELOAD");
@@ -58,6 +76,6 @@ pub fn open_libs(state: &mut LuaState) -> Result<(), LuaError> {
         api::push_rust_function(state, reg.func, 0);
         api::set_field(state, -2, reg.name);
     }
-    api::pop(state,1); // remove _PRELOAD table
+    api::pop(state, 1); // remove _PRELOAD table
     Ok(())
 }
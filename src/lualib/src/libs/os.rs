@@ -0,0 +1,736 @@
+//! Standard Operating System library
+
+use std::process::Command;
+
+use crate::{api, luaL, state::ExitBehavior, state::LuaState, LuaInteger};
+
+use super::LibReg;
+
+const SYS_FUNCS: [LibReg; 11] = [
+    LibReg {
+        name: "clock",
+        func: os_clock,
+    },
+    LibReg {
+        name: "date",
+        func: os_date,
+    },
+    LibReg {
+        name: "difftime",
+        func: os_difftime,
+    },
+    LibReg {
+        name: "execute",
+        func: os_execute,
+    },
+    LibReg {
+        name: "exit",
+        func: os_exit,
+    },
+    LibReg {
+        name: "getenv",
+        func: os_getenv,
+    },
+    LibReg {
+        name: "remove",
+        func: os_remove,
+    },
+    LibReg {
+        name: "rename",
+        func: os_rename,
+    },
+    LibReg {
+        name: "setlocale",
+        func: os_setlocale,
+    },
+    LibReg {
+        name: "time",
+        func: os_time,
+    },
+    LibReg {
+        name: "tmpname",
+        func: os_tmpname,
+    },
+];
+
+/// conversion specifiers accepted by `os.date` (C99 strftime).
+/// Options with a modifier ('E' or 'O') are listed as two characters strings.
+const STRFTIME_OPTIONS: &[&str] = &[
+    "a", "A", "b", "B", "c", "C", "d", "D", "e", "F", "g", "G", "h", "H", "I", "j", "m", "M", "n",
+    "p", "r", "R", "S", "t", "T", "u", "U", "V", "w", "W", "x", "X", "y", "Y", "z", "Z", "%", "Ec",
+    "EC", "Ex", "EX", "Ey", "EY", "Od", "Oe", "OH", "OI", "Om", "OM", "OS", "Ou", "OU", "OV", "Ow",
+    "OW", "Oy",
+];
+
+const WEEKDAYS: [&str; 7] = [
+    "Sunday",
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+    "Thursday",
+    "Friday",
+    "Saturday",
+];
+
+const MONTHS: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+/// maximum absolute value accepted for a field of a date table
+const MAX_DATE_FIELD: LuaInteger = (i32::MAX / 2) as LuaInteger;
+
+/// broken-down time, like C `struct tm`
+#[derive(Debug, Default, PartialEq)]
+struct DateTime {
+    year: LuaInteger,
+    /// 1-12
+    month: LuaInteger,
+    /// 1-31
+    day: LuaInteger,
+    hour: LuaInteger,
+    min: LuaInteger,
+    sec: LuaInteger,
+    /// day of the week, 0-6, Sunday = 0
+    wday: LuaInteger,
+    /// day of the year, 0-365
+    yday: LuaInteger,
+}
+
+/// number of days since 1970-01-01 of a date of the proleptic gregorian calendar
+fn days_from_civil(year: LuaInteger, month: LuaInteger, day: LuaInteger) -> LuaInteger {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400; // [0, 399]
+    let mp = (month + 9) % 12; // [0, 11], March = 0
+    let doy = (153 * mp + 2) / 5 + day - 1; // [0, 365]
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy; // [0, 146096]
+    era * 146097 + doe - 719468
+}
+
+/// (year, month, day) of a number of days since 1970-01-01
+fn civil_from_days(days: LuaInteger) -> (LuaInteger, LuaInteger, LuaInteger) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097; // [0, 146096]
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365; // [0, 399]
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100); // [0, 365]
+    let mp = (5 * doy + 2) / 153; // [0, 11]
+    let day = doy - (153 * mp + 2) / 5 + 1; // [1, 31]
+    let month = if mp < 10 { mp + 3 } else { mp - 9 }; // [1, 12]
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+impl DateTime {
+    fn from_timestamp(t: LuaInteger) -> Self {
+        let days = t.div_euclid(86400);
+        let secs = t.rem_euclid(86400);
+        let (year, month, day) = civil_from_days(days);
+        Self {
+            year,
+            month,
+            day,
+            hour: secs / 3600,
+            min: secs % 3600 / 60,
+            sec: secs % 60,
+            wday: (days + 4).rem_euclid(7), // 1970-01-01 was a Thursday
+            yday: days - days_from_civil(year, 1, 1),
+        }
+    }
+    /// ISO 8601 week-based year and week number
+    fn iso_week(&self) -> (LuaInteger, LuaInteger) {
+        let iso_wday = (self.wday + 6) % 7 + 1; // Monday = 1 .. Sunday = 7
+        let week = (self.yday + 1 - iso_wday + 10) / 7;
+        if week < 1 {
+            (self.year - 1, iso_weeks_in_year(self.year - 1))
+        } else if week > iso_weeks_in_year(self.year) {
+            (self.year + 1, 1)
+        } else {
+            (self.year, week)
+        }
+    }
+    fn hour12(&self) -> LuaInteger {
+        match self.hour % 12 {
+            0 => 12,
+            h => h,
+        }
+    }
+}
+
+fn iso_weeks_in_year(year: LuaInteger) -> LuaInteger {
+    let p = |y: LuaInteger| (y + y.div_euclid(4) - y.div_euclid(100) + y.div_euclid(400)) % 7;
+    if p(year) == 4 || p(year - 1) == 3 {
+        53
+    } else {
+        52
+    }
+}
+
+/// current time in seconds since the epoch
+#[cfg(not(target_arch = "wasm32"))]
+fn now() -> LuaInteger {
+    match std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as LuaInteger,
+        Err(e) => -(e.duration().as_secs() as LuaInteger),
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+fn now() -> LuaInteger {
+    (crate::wasm::now_millis() / 1000.0) as LuaInteger
+}
+
+/// Converts a time to the local time zone.
+/// Pure rust has no portable access to the system time zone database,
+/// so the local time is the UTC time.
+fn local_time(t: LuaInteger) -> DateTime {
+    DateTime::from_timestamp(t)
+}
+
+/// Returns an approximation of the amount in seconds of CPU time used by the program
+pub fn os_clock(s: &mut LuaState) -> Result<i32, ()> {
+    api::push_number(s, cpu_time());
+    Ok(1)
+}
+
+#[cfg(target_os = "linux")]
+fn cpu_time() -> f64 {
+    // user and system time of the process, in clock ticks (always 1/100s in /proc)
+    std::fs::read_to_string("/proc/self/stat")
+        .ok()
+        .and_then(|stat| {
+            let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+            let utime = fields.get(11)?.parse::<u64>().ok()?;
+            let stime = fields.get(12)?.parse::<u64>().ok()?;
+            Some((utime + stime) as f64 / 100.0)
+        })
+        .unwrap_or_else(elapsed_time)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn cpu_time() -> f64 {
+    elapsed_time()
+}
+
+/// fallback when the CPU time is not available: time elapsed since the first call
+#[cfg(not(target_arch = "wasm32"))]
+fn elapsed_time() -> f64 {
+    static START: std::sync::OnceLock<std::time::Instant> = std::sync::OnceLock::new();
+    START
+        .get_or_init(std::time::Instant::now)
+        .elapsed()
+        .as_secs_f64()
+}
+
+#[cfg(target_arch = "wasm32")]
+fn elapsed_time() -> f64 {
+    crate::wasm::now_millis() / 1000.0
+}
+
+fn set_field(s: &mut LuaState, key: &str, value: LuaInteger) {
+    api::push_integer(s, value);
+    api::set_field(s, -2, key);
+}
+
+/// Set all fields from structure 'DateTime' in table on the top of the stack
+fn set_all_fields(s: &mut LuaState, dt: &DateTime) {
+    set_field(s, "sec", dt.sec);
+    set_field(s, "min", dt.min);
+    set_field(s, "hour", dt.hour);
+    set_field(s, "day", dt.day);
+    set_field(s, "month", dt.month);
+    set_field(s, "year", dt.year);
+    set_field(s, "wday", dt.wday + 1);
+    set_field(s, "yday", dt.yday + 1);
+    api::push_boolean(s, false);
+    api::set_field(s, -2, "isdst");
+}
+
+/// Gets an integer field from the date table on the top of the stack.
+/// `def` is the default value of an absent field, None if the field is mandatory.
+fn get_field(s: &mut LuaState, key: &str, def: Option<LuaInteger>) -> Result<LuaInteger, ()> {
+    api::get_field(s, -1, key);
+    let res = match api::to_integer(s, -1) {
+        Some(res) if !(-MAX_DATE_FIELD..=MAX_DATE_FIELD).contains(&res) => {
+            luaL::error(s, &format!("field '{}' is out-of-bound", key)).map_err(|_| ())?;
+            unreachable!()
+        }
+        Some(res) => res,
+        None if !api::is_nil(s, -1) => {
+            luaL::error(s, &format!("field '{}' is not an integer", key)).map_err(|_| ())?;
+            unreachable!()
+        }
+        None => match def {
+            Some(def) => def,
+            None => {
+                luaL::error(s, &format!("field '{}' missing in date table", key))
+                    .map_err(|_| ())?;
+                unreachable!()
+            }
+        },
+    };
+    api::pop(s, 1);
+    Ok(res)
+}
+
+fn check_time(s: &mut LuaState, narg: isize) -> Result<LuaInteger, ()> {
+    luaL::check_integer(s, narg)
+}
+
+/// Returns a string or a table containing date and time, formatted according
+/// to the given string format.
+/// Without a leading `!`, the date is in the local time zone, which is
+/// always UTC (see `local_time`).
+pub fn os_date(s: &mut LuaState) -> Result<i32, ()> {
+    let format = if api::is_none_or_nil(s, 1) {
+        "%c".to_owned()
+    } else {
+        luaL::check_string(s, 1)?
+    };
+    let t = if api::is_none_or_nil(s, 2) {
+        now()
+    } else {
+        check_time(s, 2)?
+    };
+    let (dt, format) = match format.strip_prefix('!') {
+        // UTC?
+        Some(format) => (DateTime::from_timestamp(t), format),
+        None => (local_time(t), &format[..]),
+    };
+    if format.starts_with("*t") {
+        api::create_table(s); // 9 = number of fields
+        set_all_fields(s, &dt);
+    } else {
+        let mut res = String::new();
+        let mut chars = format.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                res.push(c);
+                continue;
+            }
+            let mut conv = String::new();
+            if let Some(&modifier) = chars.peek() {
+                if modifier == 'E' || modifier == 'O' {
+                    conv.push(modifier);
+                    chars.next();
+                }
+            }
+            if let Some(c) = chars.next() {
+                conv.push(c);
+            }
+            if !STRFTIME_OPTIONS.contains(&&conv[..]) {
+                luaL::arg_error(s, 1, &format!("invalid conversion specifier '%{}'", conv))
+                    .map_err(|_| ())?;
+                unreachable!()
+            }
+            // modifiers select alternative representations, which are the
+            // same as the standard ones in the C locale
+            strftime(&mut res, conv.chars().last().unwrap(), &dt);
+        }
+        api::push_string(s, &res);
+    }
+    Ok(1)
+}
+
+/// Appends to `res` the conversion of the date `dt` with the strftime specifier `conv`,
+/// using the "C" locale
+fn strftime(res: &mut String, conv: char, dt: &DateTime) {
+    let wday = WEEKDAYS[dt.wday as usize];
+    let month = MONTHS[(dt.month - 1) as usize];
+    let text = match conv {
+        'a' => wday[..3].to_owned(),
+        'A' => wday.to_owned(),
+        'b' | 'h' => month[..3].to_owned(),
+        'B' => month.to_owned(),
+        'c' => format!(
+            "{} {} {:2} {:02}:{:02}:{:02} {}",
+            &wday[..3],
+            &month[..3],
+            dt.day,
+            dt.hour,
+            dt.min,
+            dt.sec,
+            dt.year
+        ),
+        'C' => format!("{:02}", dt.year.div_euclid(100)),
+        'd' => format!("{:02}", dt.day),
+        'D' | 'x' => format!(
+            "{:02}/{:02}/{:02}",
+            dt.month,
+            dt.day,
+            dt.year.rem_euclid(100)
+        ),
+        'e' => format!("{:2}", dt.day),
+        'F' => format!("{}-{:02}-{:02}", dt.year, dt.month, dt.day),
+        'g' => format!("{:02}", dt.iso_week().0.rem_euclid(100)),
+        'G' => format!("{}", dt.iso_week().0),
+        'H' => format!("{:02}", dt.hour),
+        'I' => format!("{:02}", dt.hour12()),
+        'j' => format!("{:03}", dt.yday + 1),
+        'm' => format!("{:02}", dt.month),
+        'M' => format!("{:02}", dt.min),
+        'n' => "\n".to_owned(),
+        'p' => if dt.hour < 12 { "AM" } else { "PM" }.to_owned(),
+        'r' => format!(
+            "{:02}:{:02}:{:02} {}",
+            dt.hour12(),
+            dt.min,
+            dt.sec,
+            if dt.hour < 12 { "AM" } else { "PM" }
+        ),
+        'R' => format!("{:02}:{:02}", dt.hour, dt.min),
+        'S' => format!("{:02}", dt.sec),
+        't' => "\t".to_owned(),
+        'T' | 'X' => format!("{:02}:{:02}:{:02}", dt.hour, dt.min, dt.sec),
+        'u' => format!("{}", (dt.wday + 6) % 7 + 1),
+        'U' => format!("{:02}", (dt.yday + 7 - dt.wday) / 7),
+        'V' => format!("{:02}", dt.iso_week().1),
+        'w' => format!("{}", dt.wday),
+        'W' => format!("{:02}", (dt.yday + 7 - (dt.wday + 6) % 7) / 7),
+        'y' => format!("{:02}", dt.year.rem_euclid(100)),
+        'Y' => format!("{}", dt.year),
+        'z' => "+0000".to_owned(),
+        'Z' => "UTC".to_owned(),
+        _ => "%".to_owned(),
+    };
+    res.push_str(&text);
+}
+
+/// Returns the current time when called without arguments,
+/// or a time representing the local date and time specified by the given table.
+/// The fields of the table are normalized.
+pub fn os_time(s: &mut LuaState) -> Result<i32, ()> {
+    let t = if api::is_none_or_nil(s, 1) {
+        // called without args?
+        now() // get current time
+    } else {
+        luaL::check_table(s, 1)?;
+        api::set_top(s, 1); // make sure table is at the top
+        let sec = get_field(s, "sec", Some(0))?;
+        let min = get_field(s, "min", Some(0))?;
+        let hour = get_field(s, "hour", Some(12))?;
+        let day = get_field(s, "day", None)?;
+        let month = get_field(s, "month", None)? - 1;
+        let year = get_field(s, "year", None)?;
+        // normalize the month then add the other fields, which may overflow their range
+        let days =
+            days_from_civil(year + month.div_euclid(12), month.rem_euclid(12) + 1, 1) + day - 1;
+        let t = days * 86400 + hour * 3600 + min * 60 + sec;
+        set_all_fields(s, &local_time(t)); // update fields with normalized values
+        t
+    };
+    api::push_integer(s, t);
+    Ok(1)
+}
+
+/// Returns the difference, in seconds, from time t2 to time t1
+pub fn os_difftime(s: &mut LuaState) -> Result<i32, ()> {
+    let t1 = check_time(s, 1)?;
+    let t2 = check_time(s, 2)?;
+    api::push_number(s, (t1 - t2) as f64);
+    Ok(1)
+}
+
+/// Returns a command running `cmd` with the operating system shell
+pub(crate) fn shell_command(cmd: &str) -> Command {
+    if cfg!(windows) {
+        let mut command = Command::new("cmd");
+        command.args(["/C", cmd]);
+        command
+    } else {
+        let mut command = Command::new("/bin/sh");
+        command.args(["-c", cmd]);
+        command
+    }
+}
+
+/// Executes an operating system shell command
+pub fn os_execute(s: &mut LuaState) -> Result<i32, ()> {
+    if api::is_none_or_nil(s, 1) {
+        // is there a shell?
+        api::push_boolean(s, s.g.allow_processes);
+        return Ok(1);
+    }
+    let cmd = luaL::check_string(s, 1)?;
+    if !s.g.allow_processes {
+        luaL::error(s, "'execute' not supported").map_err(|_| ())?;
+    }
+    let _ = s.stdout.flush();
+    let status = shell_command(&cmd).status();
+    Ok(luaL::exec_result(s, status))
+}
+
+/// Terminates the host program, or unwinds back to the host when
+/// the state was not configured with `ExitBehavior::Process`.
+/// With `ExitBehavior::Process`, a true `close` argument closes the state
+/// before exiting. When unwinding, `close` is ignored: the host gets the
+/// state back and closes it by dropping it.
+pub fn os_exit(s: &mut LuaState) -> Result<i32, ()> {
+    let status = if api::is_boolean(s, 1) {
+        if api::to_boolean(s, 1) {
+            0
+        } else {
+            1
+        }
+    } else if api::is_none_or_nil(s, 1) {
+        0
+    } else {
+        luaL::check_integer(s, 1)? as i32
+    };
+    match s.g.exit_behavior {
+        ExitBehavior::Process => {
+            if api::to_boolean(s, 2) {
+                s.close_state();
+            }
+            let _ = s.stdout.flush();
+            let _ = s.stderr.flush();
+            std::process::exit(status);
+        }
+        ExitBehavior::Unwind => {
+            s.exit_code = Some(status);
+            api::push_integer(s, status as LuaInteger); // error object
+            Err(())
+        }
+    }
+}
+
+pub fn os_getenv(s: &mut LuaState) -> Result<i32, ()> {
+    let name = luaL::check_string(s, 1)?;
+    match std::env::var_os(name) {
+        Some(value) => api::push_string(s, &value.to_string_lossy()),
+        None => api::push_nil(s),
+    }
+    Ok(1)
+}
+
+/// Deletes the file (or empty directory, on POSIX systems) with the given name
+pub fn os_remove(s: &mut LuaState) -> Result<i32, ()> {
+    let filename = luaL::check_string(s, 1)?;
+    let res = s.g.fs.remove(&filename);
+    Ok(luaL::file_result(s, res, Some(&filename)))
+}
+
+pub fn os_rename(s: &mut LuaState) -> Result<i32, ()> {
+    let fromname = luaL::check_string(s, 1)?;
+    let toname = luaL::check_string(s, 2)?;
+    let res = s.g.fs.rename(&fromname, &toname);
+    Ok(luaL::file_result(s, res, Some(&fromname)))
+}
+
+/// Only the "C" locale is supported
+pub fn os_setlocale(s: &mut LuaState) -> Result<i32, ()> {
+    const CAT_NAMES: [&str; 6] = ["all", "collate", "ctype", "monetary", "numeric", "time"];
+    let locale = if api::is_none_or_nil(s, 1) {
+        None
+    } else {
+        Some(luaL::check_string(s, 1)?)
+    };
+    luaL::check_option(s, 2, Some("all"), &CAT_NAMES)?;
+    match locale.as_deref() {
+        None | Some("") | Some("C") | Some("POSIX") => api::push_literal(s, "C"),
+        _ => api::push_nil(s), // locale not available
+    }
+    Ok(1)
+}
+
+/// Returns a string with a file name that can be used for a temporary file.
+/// The file is created to avoid security risks.
+pub fn os_tmpname(s: &mut LuaState) -> Result<i32, ()> {
+    match s.g.fs.create_temp_file() {
+        Ok((path, _)) => {
+            api::push_string(s, &path);
+            Ok(1)
+        }
+        Err(_) => {
+            luaL::error(s, "unable to generate a unique filename").map_err(|_| ())?;
+            unreachable!()
+        }
+    }
+}
+
+pub fn lib_open_os(state: &mut LuaState) -> Result<i32, ()> {
+    luaL::new_lib(state, &SYS_FUNCS);
+    Ok(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{days_from_civil, DateTime};
+    use crate::{api, luaL, object::TValue, LuaError, LuaInteger};
+    #[test]
+    fn civil_dates() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 3, 1), 11017);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+        let dt = DateTime::from_timestamp(951782400); // 2000-02-29
+        assert_eq!(
+            (dt.year, dt.month, dt.day, dt.wday, dt.yday),
+            (2000, 2, 29, 2, 59)
+        );
+    }
+    #[test]
+    fn date_format() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "z=os.date('!%Y-%m-%d %H:%M:%S %a %b %j %p', 86399)",
+        )
+        .unwrap();
+        api::get_global(&mut state, "z");
+        assert_eq!(
+            state.stack.last().unwrap(),
+            &TValue::from("1970-01-01 23:59:59 Thu Jan 001 PM")
+        );
+    }
+    #[test]
+    fn date_iso_week() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        // 2021-01-01 is in the 53rd week of 2020
+        luaL::dostring(&mut state, "z=os.date('!%G-W%V-%u %U %W', 1609459200)").unwrap();
+        api::get_global(&mut state, "z");
+        assert_eq!(
+            state.stack.last().unwrap(),
+            &TValue::from("2020-W53-5 00 00")
+        );
+    }
+    #[test]
+    fn date_invalid_specifier() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        let r = luaL::dostring(&mut state, "os.date('%Ez')");
+        assert_eq!(r, Err(LuaError::RuntimeError));
+        let msg = api::to_string(&mut state, -1).unwrap();
+        assert!(msg.contains("invalid conversion specifier '%Ez'"));
+    }
+    #[test]
+    fn date_table() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "local t=os.date('!*t', 1609459200) z=t.year*10000+t.month*100+t.day w=t.wday y=t.yday",
+        )
+        .unwrap();
+        api::get_global(&mut state, "z");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(20210101));
+        api::get_global(&mut state, "w");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(6));
+        api::get_global(&mut state, "y");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(1));
+    }
+    #[test]
+    fn time_normalize() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "t={year=2000, month=14, day=0, hour=0}
+            z=os.time(t)",
+        )
+        .unwrap();
+        api::get_global(&mut state, "z");
+        // 2001-01-31
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(980899200));
+        luaL::dostring(&mut state, "y=t.year*10000+t.month*100+t.day").unwrap();
+        api::get_global(&mut state, "y");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(20010131));
+    }
+    #[test]
+    fn time_missing_field() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        let r = luaL::dostring(&mut state, "os.time({year=2000})");
+        assert_eq!(r, Err(LuaError::RuntimeError));
+        let msg = api::to_string(&mut state, -1).unwrap();
+        assert!(msg.ends_with("field 'day' missing in date table"));
+    }
+    #[test]
+    fn difftime() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(&mut state, "z=os.difftime(10, 4)").unwrap();
+        api::get_global(&mut state, "z");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Float(6.0));
+    }
+    #[test]
+    fn exit_unwinds() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        let r = luaL::dostring(&mut state, "os.exit(3) z=1");
+        assert_eq!(r, Err(LuaError::Exit(3)));
+        let r = luaL::dostring(&mut state, "os.exit(false)");
+        assert_eq!(r, Err(LuaError::Exit(1)));
+    }
+    #[test]
+    fn exit_unwinds_through_rust_functions() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        for (code, script) in [
+            (4, "table.sort({3, 1, 2}, function(a, b) os.exit(4) end)"),
+            (
+                5,
+                "print(setmetatable({}, {__tostring = function() os.exit(5) end}))",
+            ),
+            (
+                6,
+                "for k in pairs(setmetatable({}, {__pairs = function() os.exit(6) end})) do end",
+            ),
+            (
+                7,
+                "table.insert(package.searchers, 1, function() os.exit(7) end) require('x')",
+            ),
+            (8, "xpcall(error, function() os.exit(8) end)"),
+            (9, "pcall(os.exit, 9)"),
+            (10, "load(function() os.exit(10) end)"),
+            (11, "xpcall(os.exit, print, 11)"),
+        ] {
+            let r = luaL::dostring(&mut state, &format!("{} z = 1", script));
+            assert_eq!(r, Err(LuaError::Exit(code)), "{}", script);
+            assert_eq!(
+                state.stack.last().unwrap(),
+                &TValue::Integer(code as LuaInteger)
+            );
+            api::get_global(&mut state, "z");
+            assert!(api::is_nil(&mut state, -1));
+            api::set_top(&mut state, 0);
+        }
+        // the exit does not outlive the call that reached the host
+        luaL::dostring(&mut state, "pcall(error, 'x') z = 1").unwrap();
+        api::get_global(&mut state, "z");
+        assert_eq!(api::to_integer(&mut state, -1), Some(1));
+    }
+    #[test]
+    fn tmpname_rename_remove() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "local name=os.tmpname()
+            a=os.rename(name, name..'.bak')
+            b=os.remove(name..'.bak')
+            local _
+            _, c = os.remove(name..'.bak')",
+        )
+        .unwrap();
+        api::get_global(&mut state, "a");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Boolean(true));
+        api::get_global(&mut state, "b");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Boolean(true));
+        api::get_global(&mut state, "c");
+        let msg = api::to_string(&mut state, -1).unwrap();
+        assert!(msg.ends_with(".bak: No such file or directory"));
+    }
+}
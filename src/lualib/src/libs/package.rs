@@ -0,0 +1,511 @@
+//! Package library
+
+use crate::{
+    api::{self, LuaError},
+    fs::{FileSystem, OpenMode},
+    luaH::TableRef,
+    luaL,
+    luaconf::{LUA_DIRSEP, LUA_PATH_DEFAULT},
+    object::TValue,
+    state::LuaState,
+    LuaInteger, LuaRustFunction, LUA_REGISTRYINDEX,
+};
+
+use super::LibReg;
+
+/// character that separates templates in a path
+const LUA_PATH_SEP: &str = ";";
+/// string that marks the substitution points in a template
+const LUA_PATH_MARK: &str = "?";
+/// in a Windows path, it is replaced by the executable's directory
+const LUA_EXEC_DIR: &str = "!";
+/// for C libraries, marks the end of the module name to be used by `luaopen_`
+const LUA_IGMARK: &str = "-";
+/// environment variable holding the path, checked first
+const LUA_PATH_VERSION_VAR: &str = "LUA_PATH_5_3";
+const LUA_PATH_VAR: &str = "LUA_PATH";
+/// mark for the default path in a path from the environment
+const AUXMARK: &str = "\x01";
+/// registry key of the table of modules being loaded
+const LOADING: &str = "_LOADING";
+
+const PK_FUNCS: [LibReg; 2] = [
+    LibReg {
+        name: "loadlib",
+        func: ll_loadlib,
+    },
+    LibReg {
+        name: "searchpath",
+        func: ll_searchpath,
+    },
+];
+
+const LL_FUNCS: [LibReg; 1] = [LibReg {
+    name: "require",
+    func: ll_require,
+}];
+
+const SEARCHERS: [LuaRustFunction; 2] = [searcher_preload, searcher_lua];
+
+/// Dynamic libraries cannot be loaded by this implementation
+pub fn ll_loadlib(s: &mut LuaState) -> Result<i32, ()> {
+    luaL::check_string(s, 1)?;
+    luaL::check_string(s, 2)?;
+    api::push_nil(s);
+    api::push_literal(s, "dynamic libraries not enabled; check your Lua installation");
+    api::push_literal(s, "absent");
+    Ok(3) // return nil, error message, and where
+}
+
+fn readable(fs: &dyn FileSystem, filename: &str) -> bool {
+    fs.open(filename, OpenMode::from_c_mode("r")).is_ok()
+}
+
+/// Looks for `name` in the templates of `path`. Returns the first readable
+/// file name, or the list of file names tried.
+fn search_path(
+    fs: &dyn FileSystem,
+    name: &str,
+    path: &str,
+    sep: &str,
+    dirsep: &str,
+) -> Result<String, String> {
+    let name = if sep.is_empty() {
+        name.to_owned()
+    } else {
+        name.replace(sep, dirsep) // replace it by 'dirsep'
+    };
+    let mut msg = String::new(); // to build error message
+    for template in path.split(LUA_PATH_SEP).filter(|t| !t.is_empty()) {
+        let filename = template.replace(LUA_PATH_MARK, &name);
+        if readable(fs, &filename) {
+            return Ok(filename); // return that file name
+        }
+        msg.push_str(&format!("\n\tno file '{}'", filename));
+    }
+    Err(msg) // not found
+}
+
+pub fn ll_searchpath(s: &mut LuaState) -> Result<i32, ()> {
+    let name = luaL::check_string(s, 1)?;
+    let path = luaL::check_string(s, 2)?;
+    let sep = if api::is_none_or_nil(s, 3) {
+        ".".to_owned()
+    } else {
+        luaL::check_string(s, 3)?
+    };
+    let dirsep = if api::is_none_or_nil(s, 4) {
+        LUA_DIRSEP.to_owned()
+    } else {
+        luaL::check_string(s, 4)?
+    };
+    let fs = s.g.fs.clone();
+    match search_path(fs.as_ref(), &name, &path, &sep, &dirsep) {
+        Ok(filename) => {
+            api::push_string(s, &filename);
+            Ok(1)
+        }
+        Err(msg) => {
+            // error message is on top of the stack
+            api::push_nil(s);
+            api::push_string(s, &msg);
+            Ok(2) // return nil + error message
+        }
+    }
+}
+
+/// Searches `name` in `package[pname]`. Pushes the list of file names tried
+/// and returns None if the module is not found.
+fn find_file(s: &mut LuaState, name: &str, pname: &str) -> Result<Option<String>, ()> {
+    api::get_field(s, api::upvalue_index(1), pname);
+    let path = if api::is_string(s, -1) {
+        api::to_string(s, -1).unwrap()
+    } else {
+        luaL::error(s, &format!("'package.{}' must be a string", pname)).map_err(|_| ())?;
+        unreachable!()
+    };
+    api::pop(s, 1);
+    let fs = s.g.fs.clone();
+    match search_path(fs.as_ref(), name, &path, ".", LUA_DIRSEP) {
+        Ok(filename) => Ok(Some(filename)),
+        Err(msg) => {
+            api::push_string(s, &msg);
+            Ok(None)
+        }
+    }
+}
+
+fn check_load(s: &mut LuaState, loaded: bool, filename: &str) -> Result<i32, ()> {
+    if loaded {
+        // module loaded successfully?
+        api::push_string(s, filename); // will be 2nd argument to module
+        Ok(2) // return open function and file name
+    } else {
+        let name = api::to_string(s, 1).unwrap_or_default();
+        let msg = api::to_string(s, -1).unwrap_or_default();
+        luaL::error(
+            s,
+            &format!(
+                "error loading module '{}' from file '{}':\n\t{}",
+                name, filename, msg
+            ),
+        )
+        .map_err(|_| ())?;
+        unreachable!()
+    }
+}
+
+pub fn searcher_lua(s: &mut LuaState) -> Result<i32, ()> {
+    let name = luaL::check_string(s, 1)?;
+    match find_file(s, &name, "path")? {
+        None => Ok(1), // module not found in this path
+        Some(filename) => {
+            let loaded = luaL::loadfile(s, Some(&filename)).is_ok();
+            check_load(s, loaded, &filename)
+        }
+    }
+}
+
+pub fn searcher_preload(s: &mut LuaState) -> Result<i32, ()> {
+    let name = luaL::check_string(s, 1)?;
+    api::get_field(s, LUA_REGISTRYINDEX, "_PRELOAD");
+    api::get_field(s, -1, &name);
+    if api::is_nil(s, -1) {
+        // not found?
+        api::push_string(s, &format!("\n\tno field package.preload['{}']", name));
+    }
+    Ok(1)
+}
+
+/// Calls the searchers until one of them returns a loader.
+/// Leaves the loader and its extra value on top of the stack.
+fn find_loader(s: &mut LuaState, name: &str) -> Result<(), ()> {
+    // push 'package.searchers' to index 4 in the stack
+    api::get_field(s, api::upvalue_index(1), "searchers");
+    if !api::is_table(s, 4) {
+        luaL::error(s, "'package.searchers' must be a table").map_err(|_| ())?;
+    }
+    let mut msg = String::new(); // to build error message
+    // iterate over available searchers to find a loader
+    for i in 1.. {
+        api::raw_get_i(s, 4, i); // get a searcher
+        if api::is_nil(s, -1) {
+            // no more searchers?
+            api::pop(s, 1); // remove nil
+            luaL::error(s, &format!("module '{}' not found:{}", name, msg)).map_err(|_| ())?;
+        }
+        api::push_string(s, name);
+        api::call(s, 1, 2).map_err(|_| ())?; // call it
+        if api::is_function(s, -2) {
+            // did it find a loader?
+            return Ok(()); // module loader found
+        } else if api::is_string(s, -2) {
+            // searcher returned error message?
+            msg.push_str(&api::to_string(s, -2).unwrap());
+        }
+        api::pop(s, 2); // remove both returns
+    }
+    unreachable!()
+}
+
+pub fn ll_require(s: &mut LuaState) -> Result<i32, ()> {
+    let name = luaL::check_string(s, 1)?;
+    api::set_top(s, 1); // _LOADED table will be at index 2
+    api::get_field(s, LUA_REGISTRYINDEX, "_LOADED");
+    api::get_field(s, 2, &name); // _LOADED[name]
+    if api::to_boolean(s, -1) {
+        // is it there?
+        return Ok(1); // package is already loaded
+    }
+    api::pop(s, 1);
+    // a module requiring itself (directly or not) before it is loaded
+    // would loop forever
+    luaL::get_sub_table(s, LUA_REGISTRYINDEX, LOADING); // index 3
+    api::get_field(s, 3, &name);
+    if api::to_boolean(s, -1) {
+        luaL::error(s, &format!("loop detected while loading module '{}'", name))
+            .map_err(|_| ())?;
+    }
+    api::pop(s, 1);
+    find_loader(s, &name)?; // loader at index 5, extra value at index 6
+    api::push_boolean(s, true);
+    api::set_field(s, 3, &name); // _LOADING[name] = true
+    // call loader with module name and extra value as arguments
+    let extra = s.stack.pop().unwrap();
+    api::push_string(s, &name);
+    s.stack.push(extra);
+    let status = api::pcall(s, 2, 1, 0);
+    api::push_nil(s);
+    api::set_field(s, 3, &name); // _LOADING[name] = nil
+    match status {
+        Ok(_) => (),
+        // os.exit called by the loader
+        Err(_) if s.exit_code.is_some() => return Err(()),
+        Err(_) => {
+            // propagate the error raised by the loader
+            api::error(s).map_err(|_| ())?;
+        }
+    }
+    if !api::is_nil(s, -1) {
+        // non-nil return?
+        api::set_field(s, 2, &name); // _LOADED[name] = returned value
+    }
+    api::get_field(s, 2, &name);
+    if api::is_nil(s, -1) {
+        // module did not set a value?
+        api::push_boolean(s, true); // use true as result
+        api::push_value(s, -1); // extra copy to be returned
+        api::set_field(s, 2, &name); // _LOADED[name] = true
+    }
+    Ok(1)
+}
+
+/// Builds the path from the value of the environment variable, replacing `;;`
+/// by the default path
+fn make_path(env_path: Option<String>, def: &str) -> String {
+    match env_path {
+        None => def.to_owned(), // use default
+        Some(path) => {
+            // replace ";;" by ";AUXMARK;" and then AUXMARK by default path
+            let path = path.replace(
+                &format!("{}{}", LUA_PATH_SEP, LUA_PATH_SEP),
+                &format!("{}{}{}", LUA_PATH_SEP, AUXMARK, LUA_PATH_SEP),
+            );
+            path.replace(AUXMARK, def)
+        }
+    }
+}
+
+fn set_path(s: &mut LuaState, fieldname: &str, envname1: &str, envname2: &str, def: &str) {
+    let env_path = std::env::var(envname1)
+        .or_else(|_| std::env::var(envname2))
+        .ok();
+    api::push_string(s, &make_path(env_path, def));
+    api::set_field(s, -2, fieldname);
+}
+
+fn create_searchers_table(s: &mut LuaState) {
+    // create 'searchers' table
+    api::create_table(s);
+    let searchers = match s.stack.last() {
+        Some(TValue::Table(t)) => t.clone(),
+        _ => unreachable!(),
+    };
+    // fill it with predefined searchers
+    for (i, &searcher) in SEARCHERS.iter().enumerate() {
+        api::push_value(s, -2); // set 'package' as upvalue for all searchers
+        api::push_rust_function(s, searcher, 1);
+        let searcher = s.stack.pop().unwrap();
+        searcher_table_set(&searchers, i + 1, searcher);
+    }
+    api::set_field(s, -2, "searchers"); // put it in field 'searchers'
+}
+
+fn searcher_table_set(searchers: &TableRef, i: usize, searcher: TValue) {
+    searchers
+        .borrow_mut()
+        .set(TValue::Integer(i as LuaInteger), searcher);
+}
+
+/// Appends a searcher to `package.searchers`. Like the searchers written in Lua,
+/// it is called by `require` with the module name and returns a loader function
+/// (and an extra value passed to the loader) or a string explaining why the module
+/// was not found. The `package` table is its first upvalue.
+pub fn add_searcher(s: &mut LuaState, searcher: LuaRustFunction) -> Result<(), LuaError> {
+    api::get_field(s, LUA_REGISTRYINDEX, "_LOADED");
+    api::get_field(s, -1, "package");
+    api::remove(s, -2); // remove _LOADED table
+    if !api::is_table(s, -1) {
+        api::pop(s, 1);
+        return luaL::error(s, "package library is not loaded");
+    }
+    api::get_field(s, -1, "searchers");
+    let searchers = match s.stack.last() {
+        Some(TValue::Table(t)) => t.clone(),
+        _ => {
+            api::pop(s, 2);
+            return luaL::error(s, "'package.searchers' must be a table");
+        }
+    };
+    api::pop(s, 1); // remove searchers table
+    api::push_rust_function(s, searcher, 1); // with 'package' as upvalue
+    let searcher = s.stack.pop().unwrap();
+    let n = searchers.borrow().len();
+    searcher_table_set(&searchers, n + 1, searcher);
+    Ok(())
+}
+
+/// Makes the module `modname` available to `require`, which will call `openf`
+/// to load it. Equivalent to `package.preload[modname] = openf`.
+pub fn preload(s: &mut LuaState, modname: &str, openf: LuaRustFunction) {
+    luaL::get_sub_table(s, LUA_REGISTRYINDEX, "_PRELOAD");
+    api::push_rust_function(s, openf, 0);
+    api::set_field(s, -2, modname);
+    api::pop(s, 1); // remove _PRELOAD table
+}
+
+pub fn lib_open_package(s: &mut LuaState) -> Result<i32, ()> {
+    luaL::new_lib(s, &PK_FUNCS); // create 'package' table
+    create_searchers_table(s);
+    set_path(s, "path", LUA_PATH_VERSION_VAR, LUA_PATH_VAR, LUA_PATH_DEFAULT);
+    api::push_literal(s, ""); // dynamic libraries are not supported
+    api::set_field(s, -2, "cpath");
+    // store config information
+    api::push_string(
+        s,
+        &[LUA_DIRSEP, LUA_PATH_SEP, LUA_PATH_MARK, LUA_EXEC_DIR, LUA_IGMARK, ""].join("\n"),
+    );
+    api::set_field(s, -2, "config");
+    // set field 'loaded'
+    luaL::get_sub_table(s, LUA_REGISTRYINDEX, "_LOADED");
+    api::set_field(s, -2, "loaded");
+    // set field 'preload'
+    luaL::get_sub_table(s, LUA_REGISTRYINDEX, "_PRELOAD");
+    api::set_field(s, -2, "preload");
+    api::push_global_table(s);
+    api::push_value(s, -2); // set 'package' as upvalue for next lib
+    luaL::set_funcs(s, &LL_FUNCS, 1); // open lib into global table
+    api::pop(s, 1); // pop global table
+    Ok(1) // return 'package' table
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::make_path;
+    use crate::{api, fs::MemoryFileSystem, luaL, object::TValue, state::LuaState, LuaError};
+
+    fn new_state(fs: &MemoryFileSystem) -> LuaState {
+        let mut state = luaL::newstate();
+        state.g.fs = Rc::new(fs.clone());
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(&mut state, "package.path = '?.lua;lib/?/init.lua'").unwrap();
+        state
+    }
+
+    #[test]
+    fn require_files() {
+        let fs = MemoryFileSystem::new();
+        fs.insert("util/strings.lua", "count = (count or 0) + 1 return { name = ... }");
+        fs.insert("lib/net/init.lua", "local _, filename = ... return filename");
+        fs.insert("noreturn.lua", "x = 1");
+        let mut state = new_state(&fs);
+        luaL::dostring(
+            &mut state,
+            "local a = require 'util.strings'
+            local b = require 'util.strings'
+            z = a.name .. ' ' .. tostring(a == b) .. ' ' .. count
+            z = z .. ' ' .. require('net') .. ' ' .. tostring(require('noreturn'))
+            z = z .. ' ' .. tostring(package.loaded.noreturn)",
+        )
+        .unwrap();
+        api::get_global(&mut state, "z");
+        assert_eq!(
+            state.stack.last().unwrap(),
+            &TValue::from("util.strings true 1 lib/net/init.lua true true")
+        );
+    }
+    #[test]
+    fn require_not_found() {
+        let fs = MemoryFileSystem::new();
+        let mut state = new_state(&fs);
+        let r = luaL::dostring(&mut state, "require 'a.b'");
+        assert_eq!(r, Err(LuaError::RuntimeError));
+        let msg = api::to_string(&mut state, -1).unwrap();
+        assert!(msg.ends_with(
+            "module 'a.b' not found:\n\tno field package.preload['a.b']\
+            \n\tno file 'a/b.lua'\n\tno file 'lib/a/b/init.lua'"
+        ));
+    }
+    #[test]
+    fn require_cycles() {
+        let fs = MemoryFileSystem::new();
+        // a module can break a cycle by registering itself before requiring others
+        fs.insert(
+            "a.lua",
+            "local M = {} package.loaded.a = M M.b = require 'b' return M",
+        );
+        fs.insert("b.lua", "return { a = require 'a' }");
+        fs.insert("c.lua", "return require 'd'");
+        fs.insert("d.lua", "return require 'c'");
+        fs.insert("e.lua", "local t return t.x");
+        let mut state = new_state(&fs);
+        luaL::dostring(
+            &mut state,
+            "local a = require 'a'
+            z = tostring(a.b.a == a)",
+        )
+        .unwrap();
+        api::get_global(&mut state, "z");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("true"));
+        let r = luaL::dostring(&mut state, "require 'c'");
+        assert_eq!(r, Err(LuaError::RuntimeError));
+        let msg = api::to_string(&mut state, -1).unwrap();
+        assert!(msg.ends_with("loop detected while loading module 'c'"));
+        // a failed module can be required again
+        for _ in 0..2 {
+            let r = luaL::dostring(&mut state, "require 'e'");
+            assert_eq!(r, Err(LuaError::RuntimeError));
+            let msg = api::to_string(&mut state, -1).unwrap();
+            assert!(msg.ends_with("attempt to index a nil value"));
+        }
+    }
+    #[test]
+    fn preload_and_searchers() {
+        fn open_answer(s: &mut LuaState) -> Result<i32, ()> {
+            api::push_integer(s, 42);
+            Ok(1)
+        }
+        fn searcher_virtual(s: &mut LuaState) -> Result<i32, ()> {
+            let name = luaL::check_string(s, 1)?;
+            if name.starts_with("virtual.") {
+                api::push_rust_function(s, open_answer, 0);
+            } else {
+                api::push_string(s, "\n\tnot virtual");
+            }
+            Ok(1)
+        }
+        let fs = MemoryFileSystem::new();
+        let mut state = new_state(&fs);
+        luaL::preload(&mut state, "answer", open_answer);
+        luaL::add_searcher(&mut state, searcher_virtual).unwrap();
+        luaL::dostring(
+            &mut state,
+            "package.preload.p = function(name) return name .. '!' end
+            package.searchers[#package.searchers + 1] = function(name)
+                return function(n, extra) return extra end, name .. '?'
+            end
+            z = require('answer') + require('virtual.x') .. require('p') .. require('lua')",
+        )
+        .unwrap();
+        api::get_global(&mut state, "z");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("84p!lua?"));
+    }
+    #[test]
+    fn searchpath() {
+        let fs = MemoryFileSystem::new();
+        fs.insert("mods/a/b.lua", "");
+        let mut state = new_state(&fs);
+        luaL::dostring(
+            &mut state,
+            "z = package.searchpath('a.b', 'x/?.lua;mods/?.lua')
+            local _, msg = package.searchpath('a_b', 'x/?.lua;?.txt', '_', '.')
+            w = msg",
+        )
+        .unwrap();
+        api::get_global(&mut state, "z");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("mods/a/b.lua"));
+        api::get_global(&mut state, "w");
+        assert_eq!(
+            state.stack.last().unwrap(),
+            &TValue::from("\n\tno file 'x/a.b.lua'\n\tno file 'a.b.txt'")
+        );
+    }
+    #[test]
+    fn env_path() {
+        assert_eq!(make_path(None, "def"), "def");
+        assert_eq!(make_path(Some("a/?.lua".to_owned()), "def"), "a/?.lua");
+        assert_eq!(make_path(Some("a/?.lua;;".to_owned()), "def"), "a/?.lua;def;");
+    }
+}
@@ -181,8 +181,12 @@ pub fn str_format(s: &mut LuaState) -> Result<i32, ()> {
                             }
                         }
                         's' => {
-                            let s = luaL::check_string(s, arg).map_err(|_| ())?;
-                            res.push_str(&s);
+                            let value = luaL::check_string(s, arg).map_err(|_| ())?;
+                            let extra = s
+                                .check_buffer_growth(res.len(), res.capacity(), value.len())
+                                .map_err(|_| ())?;
+                            res.reserve_exact(extra);
+                            res.push_str(&value);
                         }
                         _ => {
                             luaL::error(s, &format!("invalid option '%{}' to 'format'", c))
@@ -198,7 +202,7 @@ pub fn str_format(s: &mut LuaState) -> Result<i32, ()> {
             }
         }
     }
-    s.push_string(&res);
+    s.push_buffer(res);
     Ok(1)
 }
 pub fn str_gfind(_state: &mut LuaState) -> Result<i32, ()> {
@@ -219,8 +223,34 @@ pub fn str_lower(_state: &mut LuaState) -> Result<i32, ()> {
 pub fn str_match(_state: &mut LuaState) -> Result<i32, ()> {
     todo!();
 }
-pub fn str_rep(_state: &mut LuaState) -> Result<i32, ()> {
-    todo!();
+pub fn str_rep(s: &mut LuaState) -> Result<i32, ()> {
+    let value = luaL::check_string(s, 1)?;
+    let n = luaL::check_integer(s, 2)?;
+    let sep = luaL::opt_string(s, 3, "")?;
+    if n <= 0 {
+        api::push_literal(s, "");
+        return Ok(1);
+    }
+    let n = n as usize;
+    let len = (value.len() + sep.len())
+        .checked_mul(n)
+        .and_then(|l| l.checked_sub(sep.len()))
+        .filter(|&l| l < isize::MAX as usize);
+    let Some(len) = len else {
+        luaL::error(s, "resulting string too large").map_err(|_| ())?;
+        unreachable!()
+    };
+    // check the memory limit before allocating the result
+    s.check_alloc(len).map_err(|_| ())?;
+    let mut res = String::with_capacity(len);
+    for i in 0..n {
+        if i > 0 {
+            res.push_str(&sep);
+        }
+        res.push_str(&value);
+    }
+    s.push_buffer(res);
+    Ok(1)
 }
 pub fn str_reverse(_state: &mut LuaState) -> Result<i32, ()> {
     todo!();
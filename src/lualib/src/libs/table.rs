@@ -1,10 +1,16 @@
 //! Library for Table Manipulation
 
-use crate::{api, luaL, state::LuaState, LuaInteger};
+use crate::{
+    api::{self, CompareOp, LuaType},
+    luaL,
+    object::TValue,
+    state::LuaState,
+    LuaInteger,
+};
 
 use super::LibReg;
 
-const TAB_FUNCS: [LibReg; 6] = [
+const TAB_FUNCS: [LibReg; 7] = [
     LibReg {
         name: "concat",
         func: tconcat,
@@ -25,46 +31,411 @@ const TAB_FUNCS: [LibReg; 6] = [
         name: "remove",
         func: tremove,
     },
+    LibReg {
+        name: "move",
+        func: tmove,
+    },
     LibReg {
         name: "sort",
         func: sort,
     },
 ];
 
-pub fn tunpack(state: &mut LuaState) -> Result<i32, ()> {
-    luaL::check_table(state, 1).map_err(|_| ())?;
-    let mut i = luaL::opt_integer(state, 2).unwrap_or(1);
-    let len = luaL::obj_len(state, 1);
-    let e = luaL::opt_integer(state, 3).unwrap_or(len as LuaInteger);
+// Operations that an object must define to mimic a table
+// (some functions only need some of them)
+/// read
+const TAB_R: u8 = 1;
+/// write
+const TAB_W: u8 = 2;
+/// length
+const TAB_L: u8 = 4;
+/// read/write
+const TAB_RW: u8 = TAB_R | TAB_W;
+
+/// Checks that 'arg' either is a table or can behave like one (that is,
+/// has a metatable with the required metamethods)
+fn check_tab(s: &mut LuaState, arg: isize, what: u8) -> Result<(), ()> {
+    if api::type_of(s, arg) != LuaType::Table {
+        // is it not a table?
+        let has_fields = api::get_meta_table(s, arg as i32) && {
+            // must have metatable
+            let mt = s.stack.pop().unwrap();
+            let has_field = |name: &str| match &mt {
+                TValue::Table(mt) => mt.borrow().get(&TValue::from(name)).is_some(),
+                _ => false,
+            };
+            (what & TAB_R == 0 || has_field("__index"))
+                && (what & TAB_W == 0 || has_field("__newindex"))
+                && (what & TAB_L == 0 || has_field("__len"))
+        };
+        if !has_fields {
+            luaL::check_table(s, arg)?; // force an error
+        }
+    }
+    Ok(())
+}
+
+fn aux_getn(s: &mut LuaState, n: isize, what: u8) -> Result<LuaInteger, ()> {
+    check_tab(s, n, what | TAB_L)?;
+    luaL::len(s, n).map_err(|_| ())
+}
+
+pub fn tinsert(s: &mut LuaState) -> Result<i32, ()> {
+    let e = aux_getn(s, 1, TAB_RW)?.wrapping_add(1); // first empty element
+    let pos = match api::get_top(s) {
+        // called with only 2 arguments
+        2 => e, // insert new element at the end
+        3 => {
+            let pos = luaL::check_integer(s, 2)?; // 2nd argument is the position
+            if pos < 1 || pos > e {
+                luaL::arg_error(s, 2, "position out of bounds").map_err(|_| ())?;
+            }
+            for i in (pos + 1..=e).rev() {
+                // move up elements
+                api::get_i(s, 1, i - 1).map_err(|_| ())?;
+                api::set_i(s, 1, i).map_err(|_| ())?; // t[i] = t[i - 1]
+            }
+            pos
+        }
+        _ => {
+            luaL::error(s, "wrong number of arguments to 'insert'").map_err(|_| ())?;
+            unreachable!()
+        }
+    };
+    api::set_i(s, 1, pos).map_err(|_| ())?; // t[pos] = v
+    Ok(0)
+}
+
+pub fn tremove(s: &mut LuaState) -> Result<i32, ()> {
+    let size = aux_getn(s, 1, TAB_RW)?;
+    let mut pos = luaL::opt_integer(s, 2, size)?;
+    if pos != size && (pos < 1 || pos > size.wrapping_add(1)) {
+        // validate 'pos' if given
+        luaL::arg_error(s, 1, "position out of bounds").map_err(|_| ())?;
+    }
+    api::get_i(s, 1, pos).map_err(|_| ())?; // result = t[pos]
+    while pos < size {
+        api::get_i(s, 1, pos + 1).map_err(|_| ())?;
+        api::set_i(s, 1, pos).map_err(|_| ())?; // t[pos] = t[pos + 1]
+        pos += 1;
+    }
+    api::push_nil(s);
+    api::set_i(s, 1, pos).map_err(|_| ())?; // t[pos] = nil
+    Ok(1)
+}
+
+/// Copy elements (1[f], ..., 1[e]) into (tt[t], tt[t+1], ...). Whenever
+/// possible, copy in increasing order, which is better for rehashing.
+/// "possible" means destination after original range, or smaller
+/// than origin, or copying to another table.
+pub fn tmove(s: &mut LuaState) -> Result<i32, ()> {
+    let f = luaL::check_integer(s, 2)?;
+    let e = luaL::check_integer(s, 3)?;
+    let t = luaL::check_integer(s, 4)?;
+    let tt = if !api::is_none_or_nil(s, 5) { 5 } else { 1 }; // destination table
+    check_tab(s, 1, TAB_R)?;
+    check_tab(s, tt, TAB_W)?;
+    if e >= f {
+        // otherwise, nothing to move
+        if f <= 0 && e >= LuaInteger::MAX + f {
+            luaL::arg_error(s, 3, "too many elements to move").map_err(|_| ())?;
+        }
+        let n = e - f + 1; // number of elements to move
+        if t > LuaInteger::MAX - n + 1 {
+            luaL::arg_error(s, 4, "destination wrap around").map_err(|_| ())?;
+        }
+        if t > e || t <= f || (tt != 1 && !api::compare(s, 1, tt, CompareOp::Eq).unwrap()) {
+            for i in 0..n {
+                api::get_i(s, 1, f + i).map_err(|_| ())?;
+                api::set_i(s, tt, t + i).map_err(|_| ())?;
+            }
+        } else {
+            for i in (0..n).rev() {
+                api::get_i(s, 1, f + i).map_err(|_| ())?;
+                api::set_i(s, tt, t + i).map_err(|_| ())?;
+            }
+        }
+    }
+    api::push_value(s, tt); // return destination table
+    Ok(1)
+}
+
+/// Makes room for `additional` bytes in the buffer, checking the memory limit
+/// before the buffer grows
+fn reserve(s: &mut LuaState, b: &mut String, additional: usize) -> Result<(), ()> {
+    let extra = s
+        .check_buffer_growth(b.len(), b.capacity(), additional)
+        .map_err(|_| ())?;
+    b.reserve_exact(extra);
+    Ok(())
+}
+
+fn add_field(s: &mut LuaState, b: &mut String, i: LuaInteger) -> Result<(), ()> {
+    api::get_i(s, 1, i).map_err(|_| ())?;
+    match api::to_string(s, -1) {
+        Some(value) => {
+            reserve(s, b, value.len())?;
+            b.push_str(&value)
+        }
+        None => {
+            luaL::error(
+                s,
+                &format!("invalid value (at index {}) in table for 'concat'", i),
+            )
+            .map_err(|_| ())?;
+        }
+    }
+    api::pop(s, 1);
+    Ok(())
+}
+
+pub fn tconcat(s: &mut LuaState) -> Result<i32, ()> {
+    let last = aux_getn(s, 1, TAB_R)?;
+    let sep = luaL::opt_string(s, 2, "")?;
+    let mut i = luaL::opt_integer(s, 3, 1)?;
+    let last = luaL::opt_integer(s, 4, last)?;
+    let mut b = String::new();
+    while i < last {
+        add_field(s, &mut b, i)?;
+        reserve(s, &mut b, sep.len())?;
+        b.push_str(&sep);
+        i += 1;
+    }
+    if i == last {
+        // add last value (if interval was not empty)
+        add_field(s, &mut b, i)?;
+    }
+    s.push_buffer(b);
+    Ok(1)
+}
+
+pub fn tpack(s: &mut LuaState) -> Result<i32, ()> {
+    let n = api::get_top(s); // number of elements to pack
+    api::create_table(s); // create result table
+    for i in (1..=n).rev() {
+        // assign elements
+        api::push_value(s, i as isize);
+        api::set_i(s, -2, i as LuaInteger).map_err(|_| ())?;
+    }
+    api::push_integer(s, n as LuaInteger);
+    api::set_field(s, -2, "n"); // t.n = number of elements
+    Ok(1) // return table
+}
+
+pub fn tunpack(s: &mut LuaState) -> Result<i32, ()> {
+    let mut i = luaL::opt_integer(s, 2, 1)?;
+    let e = if api::is_none_or_nil(s, 3) {
+        luaL::len(s, 1).map_err(|_| ())?
+    } else {
+        luaL::check_integer(s, 3)?
+    };
     if i > e {
         return Ok(0); // empty range
     }
-    let n = e - i + 1; // number of elements
-    if n <= 0 {
-        return Ok(0); // empty range
+    // number of elements minus 1 (avoid overflows)
+    let n = (e as u64).wrapping_sub(i as u64);
+    if n >= i32::MAX as u64 {
+        luaL::error(s, "too many results to unpack").map_err(|_| ())?;
     }
-    api::raw_get_i(state, 1, i as usize);
     while i < e {
+        // push arg[i..e - 1] (to avoid overflows)
+        api::get_i(s, 1, i).map_err(|_| ())?;
         i += 1;
-        api::raw_get_i(state, 1, i as usize);
     }
-    Ok(n as i32)
+    api::get_i(s, 1, e).map_err(|_| ())?; // push last element
+    Ok(n as i32 + 1)
 }
 
-pub fn tconcat(_state: &mut LuaState) -> Result<i32, ()> {
-    todo!();
+// Quicksort
+// (based on 'Algorithms in MODULA-3', Robert Sedgewick;
+//  Addison-Wesley, 1993.)
+
+/// size of smaller partitions where it is not worth randomizing the pivot
+const RANLIMIT: LuaInteger = 100;
+
+/// Produces a "random" number to choose the pivot of large partitions
+#[cfg(not(target_arch = "wasm32"))]
+fn randomize_pivot() -> u32 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.subsec_nanos() ^ d.as_secs() as u32)
+        .unwrap_or(0)
 }
-pub fn tpack(_state: &mut LuaState) -> Result<i32, ()> {
-    todo!();
+
+#[cfg(target_arch = "wasm32")]
+fn randomize_pivot() -> u32 {
+    crate::wasm::now_millis() as u64 as u32
 }
-pub fn tinsert(_state: &mut LuaState) -> Result<i32, ()> {
-    todo!();
+
+fn set2(s: &mut LuaState, i: LuaInteger, j: LuaInteger) -> Result<(), ()> {
+    api::set_i(s, 1, i).map_err(|_| ())?;
+    api::set_i(s, 1, j).map_err(|_| ())
 }
-pub fn tremove(_state: &mut LuaState) -> Result<i32, ()> {
-    todo!();
+
+/// Returns true if the value at index `a` is smaller than the value at index `b`,
+/// using the comparison function at index 2 if there is one.
+fn sort_comp(s: &mut LuaState, a: isize, b: isize) -> Result<bool, ()> {
+    if api::is_nil(s, 2) {
+        // no function?
+        api::compare(s, a, b, CompareOp::Lt).map_err(|_| ()) // a < b
+    } else {
+        api::push_value(s, 2); // push function
+        api::push_value(s, a - 1); // -1 to compensate function
+        api::push_value(s, b - 2); // -2 to compensate function and 'a'
+        api::call(s, 2, 1).map_err(|_| ())?; // call function
+        let res = api::to_boolean(s, -1); // get result
+        api::pop(s, 1); // pop result
+        Ok(res)
+    }
 }
-pub fn sort(_state: &mut LuaState) -> Result<i32, ()> {
-    todo!();
+
+fn order_error(s: &mut LuaState) -> Result<(), ()> {
+    luaL::error(s, "invalid order function for sorting").map_err(|_| ())
+}
+
+/// Does the partition: Pivot P is at the top of the stack.
+/// precondition: a[lo] <= P == a[up-1] <= a[up],
+/// so it only needs to do the partition from lo + 1 to up - 2.
+/// Pos-condition: a[lo .. i - 1] <= a[i] == P <= a[i + 1 .. up]
+/// returns 'i'.
+fn partition(s: &mut LuaState, lo: LuaInteger, up: LuaInteger) -> Result<LuaInteger, ()> {
+    let mut i = lo; // will be incremented before first use
+    let mut j = up - 1; // will be decremented before first use
+    // loop invariant: a[lo .. i] <= P <= a[j .. up], a[up - 1] == P
+    loop {
+        // next loop: repeat ++i while a[i] < P
+        loop {
+            i += 1;
+            api::get_i(s, 1, i).map_err(|_| ())?;
+            if !sort_comp(s, -1, -2)? {
+                break;
+            }
+            if i == up - 1 {
+                // a[i] < P  but a[up - 1] == P  ??
+                order_error(s)?;
+            }
+            api::pop(s, 1); // remove a[i]
+        }
+        // after the loop, a[i] >= P and a[lo .. i - 1] < P
+        // next loop: repeat --j while P < a[j]
+        loop {
+            j -= 1;
+            api::get_i(s, 1, j).map_err(|_| ())?;
+            if !sort_comp(s, -3, -1)? {
+                break;
+            }
+            if j < i {
+                // j < i  but  a[j] > P ??
+                order_error(s)?;
+            }
+            api::pop(s, 1); // remove a[j]
+        }
+        // after the loop, a[j] <= P and a[j + 1 .. up] >= P
+        if j < i {
+            // no elements out of place?
+            // a[lo .. i - 1] <= P <= a[j + 1 .. i .. up]
+            api::pop(s, 1); // pop a[j]
+            // swap pivot (a[up - 1]) with a[i] to satisfy pred.: a[i] == P
+            set2(s, up - 1, i)?;
+            return Ok(i);
+        }
+        // otherwise, swap a[i] - a[j] to restore invariant and repeat
+        set2(s, i, j)?;
+    }
+}
+
+/// Chooses an element in the middle (2nd-3th quarters) of [lo,up]
+/// "randomized" by 'rnd'
+fn choose_pivot(lo: LuaInteger, up: LuaInteger, rnd: u32) -> LuaInteger {
+    let r4 = (up - lo) / 4; // range/4
+    let p = rnd as LuaInteger % (r4 * 2) + (lo + r4);
+    debug_assert!(lo + r4 <= p && p <= up - r4);
+    p
+}
+
+/// Quicksort algorithm (recursive function)
+fn aux_sort(s: &mut LuaState, mut lo: LuaInteger, mut up: LuaInteger, mut rnd: u32) -> Result<(), ()> {
+    while lo < up {
+        // loop for tail recursion
+        // sort elements 'lo', 'p', and 'up'
+        api::get_i(s, 1, lo).map_err(|_| ())?;
+        api::get_i(s, 1, up).map_err(|_| ())?;
+        if sort_comp(s, -1, -2)? {
+            // a[up] < a[lo]?
+            set2(s, lo, up)?; // swap a[lo] - a[up]
+        } else {
+            api::pop(s, 2); // remove both values
+        }
+        if up - lo == 1 {
+            // only 2 elements?
+            break; // already sorted
+        }
+        let mut p = if up - lo < RANLIMIT || rnd == 0 {
+            // small interval or no randomize?
+            (lo + up) / 2 // middle element is a good pivot
+        } else {
+            // for larger intervals, it is expensive to solve worst cases
+            choose_pivot(lo, up, rnd)
+        };
+        api::get_i(s, 1, p).map_err(|_| ())?;
+        api::get_i(s, 1, lo).map_err(|_| ())?;
+        if sort_comp(s, -2, -1)? {
+            // a[p] < a[lo]?
+            set2(s, p, lo)?; // swap a[p] - a[lo]
+        } else {
+            api::pop(s, 1); // remove second element
+            api::get_i(s, 1, up).map_err(|_| ())?;
+            if sort_comp(s, -1, -2)? {
+                // a[up] < a[p]?
+                set2(s, p, up)?; // swap up - p
+            } else {
+                api::pop(s, 2); // clean stack
+            }
+        }
+        if up - lo == 2 {
+            // only 3 elements?
+            break; // already sorted
+        }
+        api::get_i(s, 1, p).map_err(|_| ())?; // get median (Pivot)
+        api::push_value(s, -1); // push Pivot
+        api::get_i(s, 1, up - 1).map_err(|_| ())?; // push a[up - 1]
+        set2(s, p, up - 1)?; // a[p] = a[up - 1]; a[up - 1] = a[p]
+        p = partition(s, lo, up)?;
+        let n;
+        // a[lo .. p - 1] <= a[p] == P <= a[p + 1 .. up]
+        if p - lo < up - p {
+            // lower interval is shorter?
+            aux_sort(s, lo, p - 1, rnd)?; // call recursively for lower interval
+            n = p - lo; // size of smaller interval
+            lo = p + 1; // tail call for [p + 1 .. up] (upper interval)
+        } else {
+            aux_sort(s, p + 1, up, rnd)?; // call recursively for upper interval
+            n = up - p; // size of smaller interval
+            up = p - 1; // tail call for [lo .. p - 1]  (lower interval)
+        }
+        if (up - lo) / 128 > n {
+            // partition too imbalanced?
+            rnd = randomize_pivot(); // try a new randomization
+        }
+    }
+    Ok(())
+}
+
+pub fn sort(s: &mut LuaState) -> Result<i32, ()> {
+    let n = aux_getn(s, 1, TAB_RW)?;
+    if n > 1 {
+        // non-trivial interval?
+        if n >= i32::MAX as LuaInteger {
+            luaL::arg_error(s, 1, "array too big").map_err(|_| ())?;
+        }
+        if !api::is_none_or_nil(s, 2) && !api::is_function(s, 2) {
+            // is there a 2nd argument? it must be a function
+            luaL::type_error(s, 2, "function").map_err(|_| ())?;
+        }
+        api::set_top(s, 2); // make sure there are two arguments
+        aux_sort(s, 1, n, 0)?;
+    }
+    Ok(0)
 }
 
 pub fn lib_open_table(state: &mut LuaState) -> Result<i32, ()> {
@@ -85,4 +456,138 @@ mod tests {
         api::get_global(&mut state, "b");
         assert_eq!(state.stack.last().unwrap(), &TValue::Integer(5));
     }
+    #[test]
+    fn insert_remove() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "local t = {1, 2, 3}
+            table.insert(t, 4)
+            table.insert(t, 1, 0)
+            local r = table.remove(t)
+            local r2 = table.remove(t, 1)
+            s = table.concat(t, ',') .. ';' .. r .. ';' .. r2
+            local ok
+            ok, e = pcall(table.insert, t, 7, 1)
+            ok, e2 = pcall(table.insert, t, 1, 2, 3)",
+        )
+        .unwrap();
+        api::get_global(&mut state, "s");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("1,2,3;4;0"));
+        api::get_global(&mut state, "e");
+        assert_eq!(
+            state.stack.last().unwrap(),
+            &TValue::from("bad argument #2 to 'table.insert' (position out of bounds)")
+        );
+        api::get_global(&mut state, "e2");
+        assert_eq!(
+            state.stack.last().unwrap(),
+            &TValue::from("wrong number of arguments to 'insert'")
+        );
+    }
+    #[test]
+    fn concat() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "local t = {1, 'a', 2.5, 'b'}
+            a = table.concat(t)
+            b = table.concat(t, '-', 2, 3)
+            c = table.concat(t, '-', 3, 2)
+            local ok
+            ok, d = pcall(table.concat, {1, {}, 3})",
+        )
+        .unwrap();
+        api::get_global(&mut state, "a");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("1a2.5b"));
+        api::get_global(&mut state, "b");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("a-2.5"));
+        api::get_global(&mut state, "c");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from(""));
+        api::get_global(&mut state, "d");
+        assert_eq!(
+            state.stack.last().unwrap(),
+            &TValue::from("invalid value (at index 2) in table for 'concat'")
+        );
+    }
+    #[test]
+    fn pack_move() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "local p = table.pack(1, nil, 3)
+            n = p.n
+            local t = {1, 2, 3, 4, 5}
+            table.move(t, 1, 3, 2)
+            a = table.concat(t, ',')
+            t = {1, 2, 3, 4, 5}
+            table.move(t, 2, 5, 1)
+            b = table.concat(t, ',')
+            local d = table.move({7, 8}, 1, 2, 3, {1, 2})
+            c = table.concat(d, ',')",
+        )
+        .unwrap();
+        api::get_global(&mut state, "n");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(3));
+        api::get_global(&mut state, "a");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("1,1,2,3,5"));
+        api::get_global(&mut state, "b");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("2,3,4,5,5"));
+        api::get_global(&mut state, "c");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("1,2,7,8"));
+    }
+    #[test]
+    fn sort() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "local t = {}
+            for i = 1, 300 do t[i] = (i * 37) % 101 end
+            table.sort(t)
+            ok = true
+            for i = 2, 300 do ok = ok and t[i - 1] <= t[i] end
+            t = {'b', 'c', 'a'}
+            table.sort(t, function(x, y) return x > y end)
+            a = table.concat(t)
+            local r
+            r, e = pcall(table.sort, {1, 2, 3, 4, 5}, function(x, y) return true end)",
+        )
+        .unwrap();
+        api::get_global(&mut state, "ok");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Boolean(true));
+        api::get_global(&mut state, "a");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("cba"));
+        api::get_global(&mut state, "e");
+        assert_eq!(
+            state.stack.last().unwrap(),
+            &TValue::from("invalid order function for sorting")
+        );
+    }
+    #[test]
+    fn proxy() {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        luaL::dostring(
+            &mut state,
+            "local t = {3, 1, 2}
+            local p = setmetatable({}, {
+                __index = t,
+                __newindex = function(_, k, v) t[k] = v end,
+                __len = function() return #t end,
+            })
+            table.insert(p, 4)
+            table.sort(p)
+            a = table.concat(p, ',')
+            n = #t",
+        )
+        .unwrap();
+        api::get_global(&mut state, "a");
+        assert_eq!(state.stack.last().unwrap(), &TValue::from("1,2,3,4"));
+        api::get_global(&mut state, "n");
+        assert_eq!(state.stack.last().unwrap(), &TValue::Integer(4));
+    }
 }
@@ -1,6 +1,9 @@
 //! Limits, basic types, and some other `installation-dependent' definitions
 
 pub type Instruction=u32;
+
+/// name of the upvalue holding the environment of a chunk
+pub const LUA_ENV: &str = "_ENV";
 /// Instruction offset (=program counter)
 pub type InstId = usize;
 
@@ -13,3 +16,9 @@ pub const MAX_INT: usize = std::i32::MAX as usize - 2;
 /// maximum number of upvalues in a closure (both C and Lua). (Value
 ///  must fit in an unsigned char.)
 pub const MAX_UPVAL: usize = std::u8::MAX as usize;
+
+/// limit for table tag-method chains (to avoid loops)
+pub const MAXTAGLOOP: usize = 2000;
+
+/// maximum size of a number converted to a string
+pub const LUAI_MAXNUMBER2STR: usize = 32;
@@ -0,0 +1,1161 @@
+//! High-level embedding API
+//!
+//! A typed layer on top of the stack based [`api`](crate::api): a [`Lua`]
+//! owns a state, values are exchanged as [`Value`]s or as Rust types through
+//! the [`IntoLua`] and [`FromLua`] traits, and tables, functions, userdata
+//! and threads are handles that keep a reference in the registry, so that
+//! they stay alive as long as the handle exists.
+//!
+//! The state is taken out of the [`Lua`] during each operation. Rust
+//! closures created with [`Lua::create_function`] get it back while they
+//! run, so that they can use handles. Rust functions created with
+//! [`Lua::create_rust_function`] receive the state as a `&mut LuaState` and
+//! must use the stack based api instead.
+//!
+//! Handles do not keep the state alive: once the [`Lua`] and its clones are
+//! dropped, their operations fail with [`LuaError::RuntimeError`].
+
+use std::{
+    any::{type_name, Any},
+    cell::RefCell,
+    collections::HashMap,
+    ffi::c_void,
+    hash::Hash,
+    ops::{Deref, DerefMut},
+    rc::{Rc, Weak},
+};
+
+use crate::{
+    api::{self, LuaError},
+    luaL::{self, RegistryKey},
+    object::{number2str, TValue},
+    state::LuaState,
+    LuaFloat, LuaInteger, LuaRustFunction, LUA_MULTRET,
+};
+
+struct LuaInner {
+    /// None while an operation uses the state
+    state: RefCell<Option<LuaState>>,
+    /// empty state swapped with the state lent to a Rust closure
+    spare: RefCell<Option<LuaState>>,
+    last_error: RefCell<Option<String>>,
+}
+
+/// A Lua state. Cloning it returns another handle to the same state.
+///
+/// Each operation takes the state until it returns. While a script runs,
+/// through [`Function::call`], [`Lua::exec`] or a metamethod, the closures
+/// created with [`Lua::create_function`] it calls can use the handles of the
+/// same state. The Rust functions created with [`Lua::create_rust_function`]
+/// cannot: the operations of the handles fail with "the Lua state is already
+/// in use" and they must use the stack based api on the `&mut LuaState` they
+/// receive.
+#[derive(Clone)]
+pub struct Lua(Rc<LuaInner>);
+
+impl Default for Lua {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Lua {
+    /// Creates a new state with the standard libraries opened
+    pub fn new() -> Self {
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).expect("cannot open the standard libraries");
+        Self::from_state(state)
+    }
+    /// Takes ownership of an existing state
+    pub fn from_state(state: LuaState) -> Self {
+        Self(Rc::new(LuaInner {
+            state: RefCell::new(Some(state)),
+            spare: RefCell::new(None),
+            last_error: RefCell::new(None),
+        }))
+    }
+    /// Gives access to the underlying state, to use the stack based api.
+    /// The stack must be left as it was found. Panics if the state is already
+    /// in use, i.e. when called from a Rust function created with
+    /// [`Lua::create_rust_function`].
+    pub fn with_state<R>(&self, f: impl FnOnce(&mut LuaState) -> R) -> R {
+        let mut state = self
+            .borrow_state()
+            .expect("the Lua state is already in use");
+        f(&mut state)
+    }
+    /// Returns the message of the last error raised by an operation on this
+    /// state or on one of its handles
+    pub fn last_error(&self) -> Option<String> {
+        self.0.last_error.borrow().clone()
+    }
+    /// Returns the table of globals
+    pub fn globals(&self) -> Result<Table, LuaError> {
+        self.protect(|state| {
+            api::push_global_table(state);
+            Ok(Table(self.pop_ref(state)))
+        })
+    }
+    /// Compiles a chunk without running it. `name` is the chunk name used in
+    /// error messages and debug information.
+    pub fn load(&self, source: &str, name: &str) -> Result<Function, LuaError> {
+        self.protect(|state| {
+            luaL::loadbuffer(state, source, name)?;
+            Ok(Function(self.pop_ref(state)))
+        })
+    }
+    /// Compiles and runs a chunk, discarding its results
+    pub fn exec(&self, source: &str) -> Result<(), LuaError> {
+        let chunk = self.protect(|state| {
+            luaL::loadstring(state, source)?;
+            Ok(Function(self.pop_ref(state)))
+        })?;
+        chunk.call(())
+    }
+    /// Creates a new empty table
+    pub fn create_table(&self) -> Result<Table, LuaError> {
+        self.protect(|state| {
+            api::new_table(state);
+            Ok(Table(self.pop_ref(state)))
+        })
+    }
+    /// Creates a function calling the Rust closure `func`. Its arguments are
+    /// converted to `A` and its results from `R`. The closure receives the
+    /// state and can use handles. If it fails, the message of
+    /// [`Lua::last_error`] is raised as a Lua error.
+    pub fn create_function<A, R, F>(&self, func: F) -> Result<Function, LuaError>
+    where
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+        F: Fn(&Lua, A) -> Result<R, LuaError> + 'static,
+    {
+        let func: Callback =
+            Rc::new(move |lua, args| func(lua, A::from_lua_multi(args, lua)?)?.into_lua_multi(lua));
+        self.protect(|state| {
+            api::new_userdata(
+                state,
+                CallbackData {
+                    func,
+                    lua: Rc::downgrade(&self.0),
+                },
+            );
+            api::push_rust_function(state, call_callback, 1);
+            Ok(Function(self.pop_ref(state)))
+        })
+    }
+    /// Creates a function calling the Rust function `func`, which uses the
+    /// stack based api. Handles cannot be used while it runs.
+    pub fn create_rust_function(&self, func: LuaRustFunction) -> Result<Function, LuaError> {
+        self.protect(|state| {
+            api::push_rust_function(state, func, 0);
+            Ok(Function(self.pop_ref(state)))
+        })
+    }
+    /// Creates a full userdata wrapping `data`
+    pub fn create_userdata<T: Any>(&self, data: T) -> Result<AnyUserData, LuaError> {
+        self.protect(|state| {
+            api::new_userdata(state, data);
+            Ok(AnyUserData(self.pop_ref(state)))
+        })
+    }
+    /// Creates a table from key/value pairs. Nil values are skipped.
+    fn create_table_from(&self, pairs: Vec<(Value, Value)>) -> Result<Table, LuaError> {
+        self.protect(|state| {
+            api::new_table(state);
+            for (key, value) in pairs {
+                self.push(state, key)?;
+                self.push(state, value)?;
+                api::raw_set(state, -3)?;
+            }
+            Ok(Table(self.pop_ref(state)))
+        })
+    }
+
+    /// Runs `f` with the state, restoring the stack and keeping the error
+    /// message if it fails
+    fn protect<R>(
+        &self,
+        f: impl FnOnce(&mut LuaState) -> Result<R, LuaError>,
+    ) -> Result<R, LuaError> {
+        let mut state = self.borrow_state()?;
+        let top = api::get_top(&mut state);
+        let res = f(&mut state);
+        if res.is_err() {
+            let msg = if api::get_top(&mut state) > top {
+                match api::to_string(&mut state, -1) {
+                    Some(msg) => msg,
+                    None => format!(
+                        "(error object is a {} value)",
+                        luaL::typename(&mut state, -1)
+                    ),
+                }
+            } else {
+                "unknown error".to_owned()
+            };
+            api::set_top(&mut state, top as i32);
+            // the error does not reach Lua
+            state.g.gc.memerr = false;
+            *self.0.last_error.borrow_mut() = Some(msg);
+        }
+        res
+    }
+    /// Takes the state until the returned guard is dropped
+    fn borrow_state(&self) -> Result<StateGuard<'_>, LuaError> {
+        match self.0.state.borrow_mut().take() {
+            Some(state) => Ok(StateGuard {
+                inner: &self.0,
+                state: Some(state),
+            }),
+            None => Err(self.error("the Lua state is already in use")),
+        }
+    }
+    /// Records an error raised on the Rust side
+    fn error(&self, msg: &str) -> LuaError {
+        *self.0.last_error.borrow_mut() = Some(msg.to_owned());
+        LuaError::RuntimeError
+    }
+    fn conversion_error(&self, from: &str, to: &str) -> LuaError {
+        self.error(&format!("cannot convert a {} to a {}", from, to))
+    }
+    /// Pushes a value onto the stack. Raises an error if the value is a
+    /// handle from another state.
+    fn push(&self, state: &mut LuaState, value: Value) -> Result<(), LuaError> {
+        match value {
+            Value::Nil => api::push_nil(state),
+            Value::Boolean(b) => api::push_boolean(state, b),
+            Value::LightUserData(p) => api::push_light_userdata(state, p),
+            Value::Integer(n) => api::push_integer(state, n),
+            Value::Number(n) => api::push_number(state, n),
+            Value::String(s) => {
+                state.check_alloc(s.len())?;
+                api::push_string(state, &s)
+            }
+            Value::Table(Table(r))
+            | Value::Function(Function(r))
+            | Value::UserData(AnyUserData(r))
+            | Value::Thread(Thread(r)) => r.key.push(state)?,
+        }
+        Ok(())
+    }
+    /// Pops a value from the stack
+    fn pop(&self, state: &mut LuaState) -> Value {
+        match state.stack.last().unwrap() {
+            TValue::Table(_) => Value::Table(Table(self.pop_ref(state))),
+            TValue::Function(_) => Value::Function(Function(self.pop_ref(state))),
+            TValue::UserData(_) => Value::UserData(AnyUserData(self.pop_ref(state))),
+            TValue::Thread() => Value::Thread(Thread(self.pop_ref(state))),
+            _ => match state.stack.pop().unwrap() {
+                TValue::Nil => Value::Nil,
+                TValue::Boolean(b) => Value::Boolean(b),
+                TValue::LightUserData(p) => Value::LightUserData(p),
+                TValue::Integer(n) => Value::Integer(n),
+                TValue::Float(n) => Value::Number(n),
+                TValue::String(s) => Value::String(s.as_ref().clone()),
+                _ => unreachable!(),
+            },
+        }
+    }
+    /// Pops the `n` values at the top of the stack
+    fn pop_n(&self, state: &mut LuaState, n: usize) -> Vec<Value> {
+        let mut values: Vec<Value> = (0..n).map(|_| self.pop(state)).collect();
+        values.reverse();
+        values
+    }
+    /// Pops the value at the top of the stack into the registry
+    fn pop_ref(&self, state: &mut LuaState) -> LuaRef {
+        LuaRef {
+            key: Rc::new(RegistryKey::new(state)),
+            lua: Rc::downgrade(&self.0),
+        }
+    }
+}
+
+/// The state taken out of a [`Lua`] by an operation. It is put back when
+/// the guard is dropped.
+struct StateGuard<'a> {
+    inner: &'a LuaInner,
+    state: Option<LuaState>,
+}
+
+impl Deref for StateGuard<'_> {
+    type Target = LuaState;
+    fn deref(&self) -> &LuaState {
+        self.state.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for StateGuard<'_> {
+    fn deref_mut(&mut self) -> &mut LuaState {
+        self.state.as_mut().unwrap()
+    }
+}
+
+impl Drop for StateGuard<'_> {
+    fn drop(&mut self) {
+        *self.inner.state.borrow_mut() = self.state.take();
+    }
+}
+
+/// A Rust closure created with [`Lua::create_function`], taking and
+/// returning values
+type Callback = Rc<dyn Fn(&Lua, Vec<Value>) -> Result<Vec<Value>, LuaError>>;
+
+/// Userdata kept as the upvalue of the function calling a closure
+struct CallbackData {
+    func: Callback,
+    lua: Weak<LuaInner>,
+}
+
+/// Calls the closure of the first upvalue. The state is lent back to the
+/// [`Lua`] while the closure runs: it is swapped with an empty spare state.
+fn call_callback(s: &mut LuaState) -> Result<i32, ()> {
+    let (func, lua) = {
+        let ud = api::to_userdata(s, api::upvalue_index(1)).unwrap();
+        let ud = ud.borrow();
+        let data = ud.data.downcast_ref::<CallbackData>().unwrap();
+        (Rc::clone(&data.func), data.lua.upgrade().map(Lua))
+    };
+    let lua = match lua {
+        Some(lua) if lua.0.state.borrow().is_none() => lua,
+        _ => {
+            luaL::error(s, "the Lua state is already in use").map_err(|_| ())?;
+            unreachable!()
+        }
+    };
+    let nargs = api::get_top(s);
+    let args = lua.pop_n(s, nargs);
+    let spare = lua.0.spare.borrow_mut().take().unwrap_or_default();
+    *lua.0.state.borrow_mut() = Some(std::mem::replace(s, spare));
+    *lua.0.last_error.borrow_mut() = None;
+    let res = func(&lua, args);
+    let state = lua.0.state.borrow_mut().take().unwrap();
+    *lua.0.spare.borrow_mut() = Some(std::mem::replace(s, state));
+    let Ok(results) = res else {
+        return raise_last_error(s, &lua);
+    };
+    luaL::check_stack(s, results.len(), Some("too many results")).map_err(|_| ())?;
+    let n = results.len();
+    for v in results {
+        if lua.push(s, v).is_err() {
+            return raise_last_error(s, &lua);
+        }
+    }
+    Ok(n as i32)
+}
+
+/// Raises the last error of `lua` as a Lua error
+fn raise_last_error(s: &mut LuaState, lua: &Lua) -> Result<i32, ()> {
+    let msg = lua
+        .last_error()
+        .unwrap_or_else(|| "unknown error".to_owned());
+    luaL::error(s, &msg).map_err(|_| ())?;
+    unreachable!()
+}
+
+/// A value stored in the registry, shared by the clones of a handle.
+/// The state is held weakly, so that a handle stored in a Lua value does not
+/// keep its own state alive.
+#[derive(Clone)]
+struct LuaRef {
+    key: Rc<RegistryKey>,
+    lua: Weak<LuaInner>,
+}
+
+impl LuaRef {
+    /// Returns the state of the handle. Fails with a
+    /// [`LuaError::RuntimeError`] once the state has been dropped.
+    fn lua(&self) -> Result<Lua, LuaError> {
+        self.lua.upgrade().map(Lua).ok_or(LuaError::RuntimeError)
+    }
+}
+
+impl std::fmt::Debug for LuaRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ref {}", self.key.id())
+    }
+}
+
+/// A Lua value
+#[derive(Clone, Debug, Default)]
+pub enum Value {
+    #[default]
+    Nil,
+    Boolean(bool),
+    LightUserData(*const c_void),
+    Integer(LuaInteger),
+    Number(LuaFloat),
+    String(String),
+    Table(Table),
+    Function(Function),
+    UserData(AnyUserData),
+    Thread(Thread),
+}
+
+impl Value {
+    /// Returns the name of the type of the value, as returned by the Lua
+    /// function `type`
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Nil => "nil",
+            Value::Boolean(_) => "boolean",
+            Value::LightUserData(_) | Value::UserData(_) => "userdata",
+            Value::Integer(_) | Value::Number(_) => "number",
+            Value::String(_) => "string",
+            Value::Table(_) => "table",
+            Value::Function(_) => "function",
+            Value::Thread(_) => "thread",
+        }
+    }
+    pub fn is_nil(&self) -> bool {
+        matches!(self, Value::Nil)
+    }
+}
+
+/// Handle to a Lua table
+#[derive(Clone, Debug)]
+pub struct Table(LuaRef);
+
+fn table_get(s: &mut LuaState) -> Result<i32, ()> {
+    api::get_table(s, 1).map_err(|_| ())?;
+    Ok(1)
+}
+
+fn table_set(s: &mut LuaState) -> Result<i32, ()> {
+    api::set_table(s, 1).map_err(|_| ())?;
+    Ok(0)
+}
+
+fn table_len(s: &mut LuaState) -> Result<i32, ()> {
+    let len = luaL::len(s, 1).map_err(|_| ())?;
+    api::push_integer(s, len);
+    Ok(1)
+}
+
+impl Table {
+    /// Returns the value t[key]. May call the __index metamethod.
+    pub fn get<K: IntoLua, V: FromLua>(&self, key: K) -> Result<V, LuaError> {
+        let lua = &self.0.lua()?;
+        let key = key.into_lua(lua)?;
+        let value = lua.protect(|state| {
+            api::push_rust_function(state, table_get, 0);
+            self.0.key.push(state)?;
+            lua.push(state, key)?;
+            api::pcall(state, 2, 1, 0)?;
+            Ok(lua.pop(state))
+        })?;
+        V::from_lua(value, lua)
+    }
+    /// Does t[key] = value. May call the __newindex metamethod.
+    pub fn set<K: IntoLua, V: IntoLua>(&self, key: K, value: V) -> Result<(), LuaError> {
+        let lua = &self.0.lua()?;
+        let key = key.into_lua(lua)?;
+        let value = value.into_lua(lua)?;
+        lua.protect(|state| {
+            api::push_rust_function(state, table_set, 0);
+            self.0.key.push(state)?;
+            lua.push(state, key)?;
+            lua.push(state, value)?;
+            api::pcall(state, 3, 0, 0)?;
+            Ok(())
+        })
+    }
+    /// Returns the value t[key] without calling metamethods
+    pub fn raw_get<K: IntoLua, V: FromLua>(&self, key: K) -> Result<V, LuaError> {
+        let lua = &self.0.lua()?;
+        let key = key.into_lua(lua)?;
+        let value = lua.protect(|state| {
+            self.0.key.push(state)?;
+            lua.push(state, key)?;
+            api::raw_get(state, -2);
+            let value = lua.pop(state);
+            api::pop(state, 1);
+            Ok(value)
+        })?;
+        V::from_lua(value, lua)
+    }
+    /// Does t[key] = value without calling metamethods
+    pub fn raw_set<K: IntoLua, V: IntoLua>(&self, key: K, value: V) -> Result<(), LuaError> {
+        let lua = &self.0.lua()?;
+        let key = key.into_lua(lua)?;
+        let value = value.into_lua(lua)?;
+        lua.protect(|state| {
+            self.0.key.push(state)?;
+            lua.push(state, key)?;
+            lua.push(state, value)?;
+            api::raw_set(state, -3)?;
+            api::pop(state, 1);
+            Ok(())
+        })
+    }
+    /// Returns the length of the table, as the '#' operator. May call the
+    /// __len metamethod.
+    pub fn len(&self) -> Result<LuaInteger, LuaError> {
+        let lua = &self.0.lua()?;
+        let len = lua.protect(|state| {
+            api::push_rust_function(state, table_len, 0);
+            self.0.key.push(state)?;
+            api::pcall(state, 1, 1, 0)?;
+            Ok(lua.pop(state))
+        })?;
+        LuaInteger::from_lua(len, lua)
+    }
+    /// Returns true if the length of the table is 0. May call the __len
+    /// metamethod.
+    pub fn is_empty(&self) -> Result<bool, LuaError> {
+        Ok(self.len()? == 0)
+    }
+    /// Returns the length of the table without calling metamethods
+    pub fn raw_len(&self) -> Result<usize, LuaError> {
+        self.0.lua()?.protect(|state| {
+            self.0.key.push(state)?;
+            let len = api::raw_len(state, -1);
+            api::pop(state, 1);
+            Ok(len)
+        })
+    }
+    /// Returns the metatable of the table, if any
+    pub fn get_metatable(&self) -> Result<Option<Table>, LuaError> {
+        let lua = &self.0.lua()?;
+        lua.protect(|state| {
+            self.0.key.push(state)?;
+            let mt = if api::get_meta_table(state, -1) {
+                Some(Table(lua.pop_ref(state)))
+            } else {
+                None
+            };
+            api::pop(state, 1);
+            Ok(mt)
+        })
+    }
+    /// Sets or removes the metatable of the table
+    pub fn set_metatable(&self, metatable: Option<Table>) -> Result<(), LuaError> {
+        let lua = &self.0.lua()?;
+        let metatable = metatable.map_or(Value::Nil, Value::Table);
+        lua.protect(|state| {
+            self.0.key.push(state)?;
+            lua.push(state, metatable)?;
+            api::set_metatable(state, -2);
+            api::pop(state, 1);
+            Ok(())
+        })
+    }
+    /// Returns the values t[1] to t[n], where n is the raw length of the table
+    pub fn sequence_values<V: FromLua>(&self) -> Result<Vec<V>, LuaError> {
+        let lua = &self.0.lua()?;
+        let values = lua.protect(|state| {
+            self.0.key.push(state)?;
+            let len = api::raw_len(state, -1);
+            let mut values = Vec::with_capacity(len);
+            for i in 1..=len {
+                api::raw_get_i(state, -1, i as LuaInteger);
+                values.push(lua.pop(state));
+            }
+            api::pop(state, 1);
+            Ok(values)
+        })?;
+        values.into_iter().map(|v| V::from_lua(v, lua)).collect()
+    }
+    /// Returns all the key/value pairs of the table, without calling the
+    /// __pairs metamethod. The order is unspecified.
+    pub fn pairs<K: FromLua, V: FromLua>(&self) -> Result<Vec<(K, V)>, LuaError> {
+        let lua = &self.0.lua()?;
+        let pairs = lua.protect(|state| {
+            self.0.key.push(state)?;
+            api::push_nil(state); // first key
+            let mut pairs = Vec::new();
+            while api::next(state, -2) {
+                let value = lua.pop(state);
+                api::push_value(state, -1); // keep the key for next
+                let key = lua.pop(state);
+                pairs.push((key, value));
+            }
+            api::pop(state, 1);
+            Ok(pairs)
+        })?;
+        pairs
+            .into_iter()
+            .map(|(k, v)| Ok((K::from_lua(k, lua)?, V::from_lua(v, lua)?)))
+            .collect()
+    }
+}
+
+/// Handle to a Lua or Rust function
+#[derive(Clone, Debug)]
+pub struct Function(LuaRef);
+
+impl Function {
+    /// Calls the function in protected mode with `args` and converts its
+    /// results. On error, the message is available with [`Lua::last_error`].
+    ///
+    /// A Rust function created with [`Lua::create_rust_function`] called by
+    /// the function cannot use handles (see [`Lua`]).
+    pub fn call<A: IntoLuaMulti, R: FromLuaMulti>(&self, args: A) -> Result<R, LuaError> {
+        let lua = &self.0.lua()?;
+        let args = args.into_lua_multi(lua)?;
+        let results = lua.protect(|state| {
+            let top = api::get_top(state);
+            luaL::check_stack(state, args.len() + 1, Some("too many arguments"))?;
+            self.0.key.push(state)?;
+            let nargs = args.len();
+            for arg in args {
+                lua.push(state, arg)?;
+            }
+            api::pcall(state, nargs, LUA_MULTRET, 0)?;
+            let nresults = api::get_top(state) - top;
+            Ok(lua.pop_n(state, nresults))
+        })?;
+        R::from_lua_multi(results, lua)
+    }
+}
+
+/// Handle to a full userdata
+#[derive(Clone, Debug)]
+pub struct AnyUserData(LuaRef);
+
+impl AnyUserData {
+    fn userdata(&self) -> Result<crate::object::UserDataRef, LuaError> {
+        self.0.lua()?.protect(|state| {
+            self.0.key.push(state)?;
+            let ud = api::to_userdata(state, -1).unwrap();
+            api::pop(state, 1);
+            Ok(ud)
+        })
+    }
+    /// Returns true if the userdata wraps a value of type `T`
+    pub fn is<T: Any>(&self) -> bool {
+        self.userdata()
+            .is_ok_and(|ud| ud.borrow().data.downcast_ref::<T>().is_some())
+    }
+    /// Calls `f` with the wrapped value. Fails if it is not a `T`.
+    pub fn borrow<T: Any, R>(&self, f: impl FnOnce(&T) -> R) -> Result<R, LuaError> {
+        let ud = self.userdata()?;
+        let ud = ud.borrow();
+        match ud.data.downcast_ref::<T>() {
+            Some(data) => Ok(f(data)),
+            None => Err(self.0.lua()?.conversion_error("userdata", type_name::<T>())),
+        }
+    }
+    /// Calls `f` with the wrapped value, mutably. Fails if it is not a `T`.
+    pub fn borrow_mut<T: Any, R>(&self, f: impl FnOnce(&mut T) -> R) -> Result<R, LuaError> {
+        let ud = self.userdata()?;
+        let mut ud = ud.borrow_mut();
+        match ud.data.downcast_mut::<T>() {
+            Some(data) => Ok(f(data)),
+            None => Err(self.0.lua()?.conversion_error("userdata", type_name::<T>())),
+        }
+    }
+}
+
+/// Handle to a Lua thread. Coroutines are not supported yet: the only
+/// thread is the main one.
+#[derive(Clone, Debug)]
+pub struct Thread(LuaRef);
+
+/// Any number of values, passed to or returned by a function
+#[derive(Clone, Debug, Default)]
+pub struct Variadic<T>(pub Vec<T>);
+
+impl<T> Deref for Variadic<T> {
+    type Target = Vec<T>;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for Variadic<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+/// Conversion of a Rust value to a Lua value
+pub trait IntoLua {
+    fn into_lua(self, lua: &Lua) -> Result<Value, LuaError>;
+}
+
+/// Conversion of a Lua value to a Rust value
+pub trait FromLua: Sized {
+    fn from_lua(value: Value, lua: &Lua) -> Result<Self, LuaError>;
+}
+
+/// Conversion of a Rust value to the arguments or results of a function
+pub trait IntoLuaMulti {
+    fn into_lua_multi(self, lua: &Lua) -> Result<Vec<Value>, LuaError>;
+}
+
+/// Conversion of the arguments or results of a function to a Rust value.
+/// Missing values are nil and extra values are ignored.
+pub trait FromLuaMulti: Sized {
+    fn from_lua_multi(values: Vec<Value>, lua: &Lua) -> Result<Self, LuaError>;
+}
+
+impl IntoLua for Value {
+    fn into_lua(self, _lua: &Lua) -> Result<Value, LuaError> {
+        Ok(self)
+    }
+}
+
+impl FromLua for Value {
+    fn from_lua(value: Value, _lua: &Lua) -> Result<Self, LuaError> {
+        Ok(value)
+    }
+}
+
+impl IntoLua for bool {
+    fn into_lua(self, _lua: &Lua) -> Result<Value, LuaError> {
+        Ok(Value::Boolean(self))
+    }
+}
+
+/// Like all tests in Lua, any value different from false and nil is true
+impl FromLua for bool {
+    fn from_lua(value: Value, _lua: &Lua) -> Result<Self, LuaError> {
+        Ok(!matches!(value, Value::Nil | Value::Boolean(false)))
+    }
+}
+
+macro_rules! impl_integer {
+    ($($t:ty)*) => {$(
+        impl IntoLua for $t {
+            fn into_lua(self, lua: &Lua) -> Result<Value, LuaError> {
+                LuaInteger::try_from(self)
+                    .map(Value::Integer)
+                    .map_err(|_| lua.error("integer overflow"))
+            }
+        }
+
+        impl FromLua for $t {
+            fn from_lua(value: Value, lua: &Lua) -> Result<Self, LuaError> {
+                let n = match &value {
+                    Value::Integer(n) => Ok(*n),
+                    Value::Number(n) => TValue::Float(*n).into_integer(),
+                    Value::String(s) => TValue::from(s.as_str()).into_integer(),
+                    _ => Err(()),
+                };
+                n.ok()
+                    .and_then(|n| <$t>::try_from(n).ok())
+                    .ok_or_else(|| lua.conversion_error(value.type_name(), stringify!($t)))
+            }
+        }
+    )*};
+}
+
+impl_integer!(i8 i16 i32 i64 isize u8 u16 u32 u64 usize);
+
+macro_rules! impl_float {
+    ($($t:ty)*) => {$(
+        impl IntoLua for $t {
+            fn into_lua(self, _lua: &Lua) -> Result<Value, LuaError> {
+                Ok(Value::Number(self as LuaFloat))
+            }
+        }
+
+        impl FromLua for $t {
+            fn from_lua(value: Value, lua: &Lua) -> Result<Self, LuaError> {
+                let n = match &value {
+                    Value::Integer(n) => Ok(*n as LuaFloat),
+                    Value::Number(n) => Ok(*n),
+                    Value::String(s) => TValue::from(s.as_str()).into_float(),
+                    _ => Err(()),
+                };
+                n.map(|n| n as $t)
+                    .map_err(|_| lua.conversion_error(value.type_name(), stringify!($t)))
+            }
+        }
+    )*};
+}
+
+impl_float!(f32 f64);
+
+impl IntoLua for &str {
+    fn into_lua(self, _lua: &Lua) -> Result<Value, LuaError> {
+        Ok(Value::String(self.to_owned()))
+    }
+}
+
+impl IntoLua for String {
+    fn into_lua(self, _lua: &Lua) -> Result<Value, LuaError> {
+        Ok(Value::String(self))
+    }
+}
+
+/// Numbers are converted to strings
+impl FromLua for String {
+    fn from_lua(value: Value, lua: &Lua) -> Result<Self, LuaError> {
+        match value {
+            Value::String(s) => Ok(s),
+            Value::Integer(n) => Ok(format!("{}", n)),
+            Value::Number(n) => Ok(number2str(n)),
+            _ => Err(lua.conversion_error(value.type_name(), "string")),
+        }
+    }
+}
+
+macro_rules! impl_handle {
+    ($($t:ident => $variant:ident, $name:literal;)*) => {$(
+        impl IntoLua for $t {
+            fn into_lua(self, _lua: &Lua) -> Result<Value, LuaError> {
+                Ok(Value::$variant(self))
+            }
+        }
+
+        impl FromLua for $t {
+            fn from_lua(value: Value, lua: &Lua) -> Result<Self, LuaError> {
+                match value {
+                    Value::$variant(v) => Ok(v),
+                    _ => Err(lua.conversion_error(value.type_name(), $name)),
+                }
+            }
+        }
+    )*};
+}
+
+impl_handle! {
+    Table => Table, "table";
+    Function => Function, "function";
+    AnyUserData => UserData, "userdata";
+    Thread => Thread, "thread";
+}
+
+/// None is nil
+impl<T: IntoLua> IntoLua for Option<T> {
+    fn into_lua(self, lua: &Lua) -> Result<Value, LuaError> {
+        match self {
+            Some(v) => v.into_lua(lua),
+            None => Ok(Value::Nil),
+        }
+    }
+}
+
+/// Nil is None
+impl<T: FromLua> FromLua for Option<T> {
+    fn from_lua(value: Value, lua: &Lua) -> Result<Self, LuaError> {
+        match value {
+            Value::Nil => Ok(None),
+            v => T::from_lua(v, lua).map(Some),
+        }
+    }
+}
+
+/// A vector is a sequence
+impl<T: IntoLua> IntoLua for Vec<T> {
+    fn into_lua(self, lua: &Lua) -> Result<Value, LuaError> {
+        let pairs = self
+            .into_iter()
+            .enumerate()
+            .map(|(i, v)| Ok((Value::Integer(i as LuaInteger + 1), v.into_lua(lua)?)))
+            .collect::<Result<Vec<_>, LuaError>>()?;
+        lua.create_table_from(pairs).map(Value::Table)
+    }
+}
+
+impl<T: FromLua> FromLua for Vec<T> {
+    fn from_lua(value: Value, lua: &Lua) -> Result<Self, LuaError> {
+        match value {
+            Value::Table(t) => t.sequence_values(),
+            _ => Err(lua.conversion_error(value.type_name(), "Vec")),
+        }
+    }
+}
+
+impl<K: IntoLua, V: IntoLua> IntoLua for HashMap<K, V> {
+    fn into_lua(self, lua: &Lua) -> Result<Value, LuaError> {
+        let pairs = self
+            .into_iter()
+            .map(|(k, v)| Ok((k.into_lua(lua)?, v.into_lua(lua)?)))
+            .collect::<Result<Vec<_>, LuaError>>()?;
+        lua.create_table_from(pairs).map(Value::Table)
+    }
+}
+
+impl<K: FromLua + Eq + Hash, V: FromLua> FromLua for HashMap<K, V> {
+    fn from_lua(value: Value, lua: &Lua) -> Result<Self, LuaError> {
+        match value {
+            Value::Table(t) => Ok(t.pairs()?.into_iter().collect()),
+            _ => Err(lua.conversion_error(value.type_name(), "HashMap")),
+        }
+    }
+}
+
+impl<T: IntoLua> IntoLuaMulti for T {
+    fn into_lua_multi(self, lua: &Lua) -> Result<Vec<Value>, LuaError> {
+        Ok(vec![self.into_lua(lua)?])
+    }
+}
+
+/// Takes the first value
+impl<T: FromLua> FromLuaMulti for T {
+    fn from_lua_multi(values: Vec<Value>, lua: &Lua) -> Result<Self, LuaError> {
+        T::from_lua(values.into_iter().next().unwrap_or_default(), lua)
+    }
+}
+
+impl IntoLuaMulti for () {
+    fn into_lua_multi(self, _lua: &Lua) -> Result<Vec<Value>, LuaError> {
+        Ok(Vec::new())
+    }
+}
+
+impl FromLuaMulti for () {
+    fn from_lua_multi(_values: Vec<Value>, _lua: &Lua) -> Result<Self, LuaError> {
+        Ok(())
+    }
+}
+
+impl<T: IntoLua> IntoLuaMulti for Variadic<T> {
+    fn into_lua_multi(self, lua: &Lua) -> Result<Vec<Value>, LuaError> {
+        self.0.into_iter().map(|v| v.into_lua(lua)).collect()
+    }
+}
+
+/// Takes all the values
+impl<T: FromLua> FromLuaMulti for Variadic<T> {
+    fn from_lua_multi(values: Vec<Value>, lua: &Lua) -> Result<Self, LuaError> {
+        values
+            .into_iter()
+            .map(|v| T::from_lua(v, lua))
+            .collect::<Result<_, _>>()
+            .map(Variadic)
+    }
+}
+
+/// The last element of a tuple can be a [`Variadic`] or another tuple
+macro_rules! impl_tuple {
+    ($($name:ident)* ; $last:ident) => {
+        impl<$($name: IntoLua,)* $last: IntoLuaMulti> IntoLuaMulti for ($($name,)* $last,) {
+            #[allow(non_snake_case)]
+            fn into_lua_multi(self, lua: &Lua) -> Result<Vec<Value>, LuaError> {
+                let ($($name,)* $last,) = self;
+                let mut values = vec![$($name.into_lua(lua)?),*];
+                values.extend($last.into_lua_multi(lua)?);
+                Ok(values)
+            }
+        }
+
+        impl<$($name: FromLua,)* $last: FromLuaMulti> FromLuaMulti for ($($name,)* $last,) {
+            #[allow(non_snake_case, unused_mut)]
+            fn from_lua_multi(values: Vec<Value>, lua: &Lua) -> Result<Self, LuaError> {
+                let mut values = values.into_iter();
+                $(let $name = $name::from_lua(values.next().unwrap_or_default(), lua)?;)*
+                let $last = $last::from_lua_multi(values.collect(), lua)?;
+                Ok(($($name,)* $last,))
+            }
+        }
+    };
+}
+
+impl_tuple!(; A);
+impl_tuple!(A; B);
+impl_tuple!(A B; C);
+impl_tuple!(A B C; D);
+impl_tuple!(A B C D; E);
+impl_tuple!(A B C D E; F);
+impl_tuple!(A B C D E F; G);
+impl_tuple!(A B C D E F G; H);
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+    use crate::{api, LuaError, LuaState};
+
+    use super::{AnyUserData, FromLua, Function, IntoLua, Lua, Table, Value, Variadic};
+    #[test]
+    fn globals() {
+        let lua = Lua::new();
+        let globals = lua.globals().unwrap();
+        globals.set("x", 21).unwrap();
+        lua.exec("y = x * 2 s = 'a' .. y").unwrap();
+        assert_eq!(globals.get::<_, i64>("y").unwrap(), 42);
+        assert_eq!(globals.get::<_, String>("s").unwrap(), "a42");
+        assert_eq!(globals.get::<_, Option<i32>>("none").unwrap(), None);
+        assert!(globals.get::<_, i32>("print").is_err());
+        assert_eq!(
+            lua.last_error().as_deref(),
+            Some("cannot convert a function to a i32")
+        );
+    }
+    #[test]
+    fn call() {
+        let lua = Lua::new();
+        lua.exec("function f(a, b, ...) return a + b, select('#', ...), ... end")
+            .unwrap();
+        let f: Function = lua.globals().unwrap().get("f").unwrap();
+        let (sum, n, rest): (i64, usize, Variadic<String>) =
+            f.call((1, 2, Variadic(vec!["x", "y"]))).unwrap();
+        assert_eq!((sum, n), (3, 2));
+        assert_eq!(rest.0, ["x", "y"]);
+        assert_eq!(f.call::<_, f64>((1, 2.5)).unwrap(), 3.5);
+        assert_eq!(lua.with_state(api::get_top), 0);
+        let g = lua.load("error('boom')", "=g").unwrap();
+        assert_eq!(g.call::<_, ()>(()), Err(LuaError::RuntimeError));
+        assert_eq!(lua.last_error().as_deref(), Some("g:1: boom"));
+        assert_eq!(lua.with_state(api::get_top), 0);
+    }
+    #[test]
+    fn tables() {
+        let lua = Lua::new();
+        let t = Table::from_lua(vec![1, 2, 3].into_lua(&lua).unwrap(), &lua).unwrap();
+        assert_eq!(t.raw_len().unwrap(), 3);
+        assert_eq!(t.sequence_values::<i32>().unwrap(), [1, 2, 3]);
+        let mut map = HashMap::new();
+        map.insert("a".to_owned(), 1);
+        map.insert("b".to_owned(), 2);
+        lua.globals().unwrap().set("map", map.clone()).unwrap();
+        lua.exec("map.c = map.a + map.b").unwrap();
+        map.insert("c".to_owned(), 3);
+        let res: HashMap<String, i32> = lua.globals().unwrap().get("map").unwrap();
+        assert_eq!(res, map);
+        lua.exec("mt = { __index = function(t, k) return k .. '!' end, __len = function() return 10 end }")
+            .unwrap();
+        let mt: Table = lua.globals().unwrap().get("mt").unwrap();
+        t.set_metatable(Some(mt)).unwrap();
+        assert_eq!(t.get::<_, String>("x").unwrap(), "x!");
+        assert!(t.raw_get::<_, Value>("x").unwrap().is_nil());
+        assert_eq!(t.len().unwrap(), 10);
+        assert!(t.get_metatable().unwrap().is_some());
+        assert!(t.raw_set(Value::Nil, 1).is_err());
+        assert_eq!(lua.last_error().as_deref(), Some("index is nil"));
+    }
+    fn add(s: &mut LuaState) -> Result<i32, ()> {
+        let a = api::to_integer(s, 1).unwrap_or(0);
+        let b = api::to_integer(s, 2).unwrap_or(0);
+        api::push_integer(s, a + b);
+        Ok(1)
+    }
+    #[test]
+    fn rust_functions_and_userdata() {
+        let lua = Lua::new();
+        let f = lua.create_rust_function(add).unwrap();
+        lua.globals().unwrap().set("add", f).unwrap();
+        lua.exec("z = add(3, 4)").unwrap();
+        assert_eq!(lua.globals().unwrap().get::<_, i32>("z").unwrap(), 7);
+        let ud = lua.create_userdata(vec![1u8, 2]).unwrap();
+        ud.borrow_mut(|v: &mut Vec<u8>| v.push(3)).unwrap();
+        lua.globals().unwrap().set("ud", ud).unwrap();
+        let ud: AnyUserData = lua.globals().unwrap().get("ud").unwrap();
+        assert!(ud.is::<Vec<u8>>());
+        assert_eq!(ud.borrow(|v: &Vec<u8>| v.len()).unwrap(), 3);
+        assert!(ud.borrow(|_: &String| ()).is_err());
+    }
+    #[test]
+    fn other_state() {
+        let lua = Lua::new();
+        let other = Lua::new();
+        let t = lua.create_table().unwrap();
+        assert!(other.globals().unwrap().set("t", t).is_err());
+        assert_eq!(
+            other.last_error().as_deref(),
+            Some("registry key used with a different state")
+        );
+    }
+    #[test]
+    fn userdata_holding_handles() {
+        struct Holder {
+            _cb: Function,
+        }
+        let lua = Lua::new();
+        let cb = lua.load("return 1", "=cb").unwrap();
+        let ud = lua.create_userdata(Holder { _cb: cb }).unwrap();
+        let f = lua.create_rust_function(add).unwrap();
+        let other = lua.create_userdata(Holder { _cb: f }).unwrap();
+        drop(ud);
+        drop(other);
+        lua.exec("collectgarbage()").unwrap();
+        assert_eq!(lua.with_state(api::get_top), 0);
+    }
+    #[test]
+    fn handles_do_not_keep_the_state() {
+        struct Holder {
+            _cb: Function,
+        }
+        let lua = Lua::new();
+        let f = lua.load("return 1", "=f").unwrap();
+        let holder = lua.create_userdata(Holder { _cb: f.clone() }).unwrap();
+        lua.globals().unwrap().set("holder", holder).unwrap();
+        let t = lua.create_table().unwrap();
+        let captured = t.clone();
+        let g = lua
+            .create_function(move |_, ()| captured.raw_len())
+            .unwrap();
+        lua.globals().unwrap().set("g", g).unwrap();
+        let inner = Rc::downgrade(&lua.0);
+        drop(lua);
+        assert!(inner.upgrade().is_none());
+        assert_eq!(t.raw_len(), Err(LuaError::RuntimeError));
+        assert_eq!(f.call::<_, i32>(()), Err(LuaError::RuntimeError));
+    }
+    thread_local! {
+        static CALLBACK_LUA: RefCell<Option<Lua>> = const { RefCell::new(None) };
+    }
+    /// tries to use a handle from a Rust function called by Lua: pushes the error
+    fn use_handle(s: &mut LuaState) -> Result<i32, ()> {
+        let lua = CALLBACK_LUA.with(|l| l.borrow().clone()).unwrap();
+        match lua.globals() {
+            Ok(_) => api::push_nil(s),
+            Err(_) => api::push_string(s, &lua.last_error().unwrap()),
+        }
+        Ok(1)
+    }
+    #[test]
+    fn handles_in_callbacks() {
+        let lua = Lua::new();
+        CALLBACK_LUA.with(|l| *l.borrow_mut() = Some(lua.clone()));
+        let f = lua.create_rust_function(use_handle).unwrap();
+        let msg: String = f.call(()).unwrap();
+        assert_eq!(msg, "the Lua state is already in use");
+        lua.globals().unwrap().set("use_handle", f).unwrap();
+        lua.exec("msg = use_handle()").unwrap();
+        let msg: String = lua.globals().unwrap().get("msg").unwrap();
+        assert_eq!(msg, "the Lua state is already in use");
+        CALLBACK_LUA.with(|l| l.borrow_mut().take());
+    }
+    #[test]
+    fn closures() {
+        let lua = Lua::new();
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let log = Rc::clone(&calls);
+        let f = lua
+            .create_function(move |lua, (name, n): (String, i64)| {
+                log.borrow_mut().push(name.clone());
+                // handles can be used while the closure runs
+                let globals = lua.globals()?;
+                let scale: i64 = globals.get("scale")?;
+                let twice: Function = globals.get("twice")?;
+                let r: i64 = twice.call(n * scale)?;
+                globals.set(name, r)?;
+                Ok((r, lua.create_table()?))
+            })
+            .unwrap();
+        lua.globals().unwrap().set("f", f.clone()).unwrap();
+        lua.exec(
+            "scale = 10
+            function twice(x) return 2 * x end
+            a, t = f('x', 2)
+            b = f('y', 3) + 1",
+        )
+        .unwrap();
+        let globals = lua.globals().unwrap();
+        assert_eq!(globals.get::<_, i64>("a").unwrap(), 40);
+        assert_eq!(globals.get::<_, i64>("x").unwrap(), 40);
+        assert_eq!(globals.get::<_, i64>("b").unwrap(), 61);
+        assert!(globals.get::<_, Table>("t").is_ok());
+        assert_eq!(f.call::<_, i64>(("z", 1)).unwrap(), 20);
+        assert_eq!(*calls.borrow(), ["x", "y", "z"]);
+        // errors of the closure are raised in Lua
+        lua.exec("ok, msg = pcall(f, 'w', {})").unwrap();
+        assert_eq!(
+            globals.get::<_, String>("msg").unwrap(),
+            "cannot convert a table to a i64"
+        );
+        let fail = lua
+            .create_function(|lua, ()| -> Result<(), LuaError> { lua.exec("error('inner')") })
+            .unwrap();
+        assert!(fail.call::<_, ()>(()).is_err());
+        assert_eq!(
+            lua.last_error().as_deref(),
+            Some("[string \"error('inner')\"]:1: inner")
+        );
+        assert_eq!(lua.with_state(api::get_top), 0);
+    }
+}
@@ -6,6 +6,12 @@
 /// exhausting memory.
 //pub const LUAI_MAXCALLS: usize = 20000;
 
+/// LUAI_MAXSTACK limits the size of the Lua stack.
+/// CHANGE it if you need a different limit. This limit is arbitrary;
+/// its only purpose is to stop Lua from consuming unlimited stack
+/// space (and to reserve some numbers for pseudo-indices).
+pub const LUAI_MAXSTACK: usize = 1_000_000;
+
 /// LUAI_MAXRCALLS is the maximum depth for nested Rust calls (short) and
 // syntactical nested non-terminals in a program.
 pub const LUAI_MAXRCALLS: usize = 8000;
@@ -17,3 +23,28 @@ pub const LUAI_MAXRCALLS: usize = 8000;
 /// LUAI_MAXVARS is the maximum number of local variables per function
 /// (must be smaller than 250).
 pub const LUAI_MAXVARS: usize = 200;
+
+/// LUAL_BUFFERSIZE is the buffer size used by the io library.
+pub const LUAL_BUFFERSIZE: usize = 8192;
+
+/// LUA_IDSIZE gives the maximum size for the description of the source
+/// of a function in debug information.
+pub const LUA_IDSIZE: usize = 60;
+
+/// LUA_PATH_DEFAULT is the default path that Lua uses to look for
+/// Lua libraries.
+#[cfg(not(windows))]
+pub const LUA_PATH_DEFAULT: &str = "/usr/local/share/lua/5.3/?.lua;/usr/local/share/lua/5.3/?/init.lua;\
+/usr/local/lib/lua/5.3/?.lua;/usr/local/lib/lua/5.3/?/init.lua;./?.lua;./?/init.lua";
+#[cfg(windows)]
+pub const LUA_PATH_DEFAULT: &str = ".\\?.lua;.\\?\\init.lua";
+
+/// LUA_DIRSEP is the directory separator (for submodules).
+#[cfg(not(windows))]
+pub const LUA_DIRSEP: &str = "/";
+#[cfg(windows)]
+pub const LUA_DIRSEP: &str = "\\";
+
+/// LUAI_NUMDIGITS is the number of significant digits used to convert
+/// a float to a string (the C format is "%.14g").
+pub const LUAI_NUMDIGITS: usize = 14;
@@ -1,11 +1,12 @@
 //! Some generic functions over Lua objects
 
-use std::{cell::RefCell, fmt::Display, rc::Rc};
+use std::{any::Any, cell::RefCell, ffi::c_void, fmt::Display, rc::Rc, sync::Arc};
 
 use crate::{
     lex::str2d,
     limits::Instruction,
     luaH::{Table, TableRef},
+    luaconf::{LUAI_NUMDIGITS, LUA_IDSIZE},
     parser::UpValDesc,
     LuaFloat, LuaInteger, LuaRustFunction,
 };
@@ -15,7 +16,7 @@ pub type StkId = usize;
 
 pub type UserDataRef = Rc<RefCell<UserData>>;
 pub type ClosureRef = Rc<RefCell<Closure>>;
-/// index in the LuaState.protos vector
+/// index in the LuaState.protos stack of the prototypes being compiled
 pub type ProtoId = usize;
 
 #[derive(Clone, Default)]
@@ -30,7 +31,8 @@ pub enum TValue {
     Boolean(bool),
     UserData(UserDataRef),
     Thread(),
-    LightUserData(),
+    /// a raw pointer, not managed by Lua
+    LightUserData(*const c_void),
 }
 impl From<&str> for TValue {
     fn from(value: &str) -> Self {
@@ -90,7 +92,7 @@ impl TValue {
             TValue::Table(_) => "table",
             TValue::Function(_) => "function",
             TValue::Boolean(_) => "boolean",
-            TValue::UserData(_) | TValue::LightUserData() => "userdata",
+            TValue::UserData(_) | TValue::LightUserData(_) => "userdata",
             TValue::Thread() => "thread",
         }
     }
@@ -153,21 +155,12 @@ impl TValue {
     pub fn into_integer(&self) -> Result<LuaInteger, ()> {
         match self {
             TValue::Integer(n) => Ok(*n),
-            TValue::Float(n) => {
-                if n.fract() == 0.0 {
-                    Ok(*n as LuaInteger)
-                } else {
-                    Err(())
-                }
-            }
-            TValue::String(rcs) => {
-                let v = str2d(&*rcs).ok_or(())?;
-                if v.fract() == 0.0 {
-                    Ok(v as LuaInteger)
-                } else {
-                    Err(())
-                }
-            }
+            TValue::Float(n) => float_to_integer(*n).ok_or(()),
+            TValue::String(rcs) => match str2number(rcs) {
+                Some(TValue::Integer(n)) => Ok(n),
+                Some(TValue::Float(n)) => float_to_integer(n).ok_or(()),
+                _ => Err(()),
+            },
             _ => Err(()),
         }
     }
@@ -191,20 +184,131 @@ impl TValue {
             _ => false,
         }
     }
-    pub(crate) fn to_pointer(&self) -> *const TValue {
-        self as *const TValue
+    /// Returns the address of the object referenced by a collectable value,
+    /// or a null pointer for other values
+    pub(crate) fn to_pointer(&self) -> *const c_void {
+        match self {
+            TValue::Table(tref) => Rc::as_ptr(tref) as *const c_void,
+            TValue::Function(cl) => Rc::as_ptr(cl) as *const c_void,
+            TValue::UserData(udref) => Rc::as_ptr(udref) as *const c_void,
+            TValue::LightUserData(p) => *p,
+            _ => std::ptr::null(),
+        }
     }
 }
 
+/// Converts a float to a string the way Lua does, with 14 significant digits
+/// ("%.14g" in C) and a ".0" suffix when the result looks like an integer.
+pub(crate) fn number2str(n: LuaFloat) -> String {
+    let mut buff = if n.is_nan() {
+        // spelled like the C library, which keeps the sign of a NaN
+        (if n.is_sign_negative() { "-nan" } else { "nan" }).to_owned()
+    } else if n.is_infinite() {
+        (if n < 0.0 { "-inf" } else { "inf" }).to_owned()
+    } else {
+        fmt_g(n, LUAI_NUMDIGITS)
+    };
+    if buff.bytes().all(|c| c == b'-' || c.is_ascii_digit()) {
+        // looks like an int?
+        buff.push_str(".0"); // adds '.0' to result
+    }
+    buff
+}
+
+/// Formats a finite float like the C "%.{precision}g" format
+fn fmt_g(n: LuaFloat, precision: usize) -> String {
+    if n == 0.0 {
+        return (if n.is_sign_negative() { "-0" } else { "0" }).to_owned();
+    }
+    // the exponent of the rounded value decides between the two styles
+    let sci = format!("{:.*e}", precision - 1, n);
+    let (mantissa, exp) = sci.split_once('e').unwrap();
+    let exp: i32 = exp.parse().unwrap();
+    if exp < -4 || exp >= precision as i32 {
+        let mantissa = strip_zeros(mantissa);
+        let sign = if exp < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", mantissa, sign, exp.abs())
+    } else {
+        let fixed = format!("{:.*}", (precision as i32 - 1 - exp) as usize, n);
+        strip_zeros(&fixed).to_owned()
+    }
+}
+
+/// Removes the trailing zeros of the fractional part of a number
+fn strip_zeros(s: &str) -> &str {
+    if s.contains('.') {
+        s.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        s
+    }
+}
+
+/// Converts a float to an integer if it has an exact integer representation
+pub(crate) fn float_to_integer(n: LuaFloat) -> Option<LuaInteger> {
+    // -2^63 is exactly representable, 2^63 is not a valid integer
+    const LIMIT: LuaFloat = -(LuaInteger::MIN as LuaFloat);
+    if n.fract() == 0.0 && (-LIMIT..LIMIT).contains(&n) {
+        Some(n as LuaInteger)
+    } else {
+        None
+    }
+}
+
+/// Converts a string to an integer, accepting decimal and hexadecimal numerals
+/// (hexadecimal integers wrap around on overflow)
+fn str2int(svalue: &str) -> Option<LuaInteger> {
+    let (neg, digits) = match svalue.as_bytes().first() {
+        Some(b'-') => (true, &svalue[1..]),
+        Some(b'+') => (false, &svalue[1..]),
+        _ => (false, svalue),
+    };
+    let value = if let Some(hex) = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        if hex.is_empty() || !hex.bytes().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        hex.bytes().fold(0 as LuaInteger, |a, c| {
+            a.wrapping_mul(16)
+                .wrapping_add((c as char).to_digit(16).unwrap() as LuaInteger)
+        })
+    } else {
+        if digits.is_empty() || !digits.bytes().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        // overflow: the numeral is read as a float
+        let a = digits.parse::<u64>().ok()?;
+        if a > LuaInteger::MAX as u64 + neg as u64 {
+            return None;
+        }
+        a as LuaInteger
+    };
+    Some(if neg { value.wrapping_neg() } else { value })
+}
+
+/// Converts a string to a number (integer or float) following the Lua lexer rules.
+/// Leading and trailing spaces are allowed.
+pub(crate) fn str2number(svalue: &str) -> Option<TValue> {
+    let svalue = svalue.trim_matches(|c: char| c.is_ascii_whitespace());
+    if let Some(i) = str2int(svalue) {
+        return Some(TValue::Integer(i));
+    }
+    if svalue.is_empty() || svalue.contains(|c: char| c.is_ascii_whitespace()) {
+        return None;
+    }
+    str2d(svalue).map(TValue::Float)
+}
+
 impl Display for TValue {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             TValue::Nil => write!(f, "nil"),
-            TValue::Float(n) => write!(f, "{}", n),
+            TValue::Float(n) => write!(f, "{}", number2str(*n)),
             TValue::Integer(i) => write!(f, "{}", i),
             TValue::Boolean(b) => write!(f, "{}", b),
             TValue::String(s) => write!(f, "{}", s),
-            _ => write!(f, "{} : {:?}", self.get_type_name(), self.to_pointer()),
+            _ => write!(f, "{}: {:?}", self.get_type_name(), self.to_pointer()),
         }
     }
 }
@@ -225,6 +329,10 @@ impl PartialEq for TValue {
             (Self::Integer(l0), Self::Integer(r0)) => l0 == r0,
             (Self::String(l0), Self::String(r0)) => l0 == r0,
             (Self::Boolean(l0), Self::Boolean(r0)) => l0 == r0,
+            (Self::Table(l0), Self::Table(r0)) => Rc::ptr_eq(l0, r0),
+            (Self::Function(l0), Self::Function(r0)) => Rc::ptr_eq(l0, r0),
+            (Self::UserData(l0), Self::UserData(r0)) => Rc::ptr_eq(l0, r0),
+            (Self::LightUserData(l0), Self::LightUserData(r0)) => l0 == r0,
             _ => core::mem::discriminant(self) == core::mem::discriminant(other),
         }
     }
@@ -234,17 +342,44 @@ impl Eq for TValue {}
 
 impl std::hash::Hash for TValue {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        core::mem::discriminant(self).hash(state);
         match self {
             TValue::String(s) => s.hash(state),
-            _ => core::mem::discriminant(self).hash(state),
+            TValue::Integer(i) => i.hash(state),
+            // 0.0 and -0.0 are equal and must have the same hash
+            TValue::Float(f) => (if *f == 0.0 { 0.0 } else { *f }).to_bits().hash(state),
+            TValue::Boolean(b) => b.hash(state),
+            TValue::Table(t) => Rc::as_ptr(t).hash(state),
+            TValue::Function(f) => Rc::as_ptr(f).hash(state),
+            TValue::UserData(u) => Rc::as_ptr(u).hash(state),
+            TValue::LightUserData(p) => p.hash(state),
+            _ => (),
         }
     }
 }
 
-#[derive(Clone, Default)]
 pub struct UserData {
     pub metatable: Option<TableRef>,
-    pub env: Option<TableRef>,
+    /// the Lua value associated with this userdata
+    pub uservalue: TValue,
+    /// the Rust value wrapped by this userdata
+    pub data: Box<dyn Any>,
+}
+
+impl UserData {
+    pub fn new(data: Box<dyn Any>) -> Self {
+        Self {
+            metatable: None,
+            uservalue: TValue::Nil,
+            data,
+        }
+    }
+}
+
+impl Default for UserData {
+    fn default() -> Self {
+        Self::new(Box::new(()))
+    }
 }
 
 #[derive(Clone, Default)]
@@ -260,16 +395,18 @@ pub struct LocVar {
 pub struct Proto {
     /// constants used by the function
     pub k: Vec<TValue>,
-    /// the bytecode
-    pub code: Vec<Instruction>,
+    /// the bytecode. The bytecode, the line information, the local variables
+    /// and the upvalues are shared with the compiled chunks made from the
+    /// function and with the functions loaded from them
+    pub code: Arc<Vec<Instruction>>,
     /// functions defined inside the function
-    pub p: Vec<ProtoId>,
+    pub p: Vec<Rc<Proto>>,
     /// map from opcodes to source lines
-    pub lineinfo: Vec<usize>,
+    pub lineinfo: Arc<Vec<usize>>,
     /// information about local variables
-    pub locvars: Vec<LocVar>,
+    pub locvars: Arc<Vec<LocVar>>,
     /// upvalues information
-    pub upvalues: Vec<UpValDesc>,
+    pub upvalues: Arc<Vec<UpValDesc>>,
     /// file name
     pub source: String,
     pub linedefined: usize,
@@ -296,17 +433,29 @@ impl Proto {
     }
 }
 
-#[derive(Clone, Default)]
-pub struct UpVal {
-    pub v: StkId,
-    pub value: TValue,
+pub type UpValRef = Rc<RefCell<UpVal>>;
+
+/// Upvalue of a closure. It is shared by all the closures that capture the
+/// same variable. Upvalues of Rust closures start closed.
+#[derive(Clone)]
+pub enum UpVal {
+    /// the variable is still alive: the upvalue points to its stack slot
+    Open(StkId),
+    /// the variable went out of scope: the upvalue holds its value
+    Closed(TValue),
+}
+
+impl Default for UpVal {
+    fn default() -> Self {
+        UpVal::Closed(TValue::Nil)
+    }
 }
 
 /// native rust closure
 #[derive(Clone)]
 pub struct RClosure {
     pub f: LuaRustFunction,
-    pub upvalues: Vec<TValue>,
+    pub upvalues: Vec<UpValRef>,
 }
 
 impl RClosure {
@@ -316,7 +465,7 @@ impl RClosure {
             upvalues: Vec::new(),
         }
     }
-    pub fn borrow_upvalue(&self, index: usize) -> &TValue {
+    pub fn borrow_upvalue(&self, index: usize) -> &UpValRef {
         &self.upvalues[index]
     }
 }
@@ -324,15 +473,15 @@ impl RClosure {
 /// Lua closure
 #[derive(Clone)]
 pub struct LClosure {
-    pub proto: ProtoId,
-    pub upvalues: Vec<UpVal>,
+    pub proto: Rc<Proto>,
+    pub upvalues: Vec<UpValRef>,
 }
 
 impl LClosure {
-    pub fn new(proto: ProtoId, nupval: usize) -> Self {
+    pub fn new(proto: Rc<Proto>, nupval: usize) -> Self {
         let mut upvalues = Vec::new();
         for _ in 0..nupval {
-            upvalues.push(UpVal::default())
+            upvalues.push(UpValRef::default())
         }
         Self { proto, upvalues }
     }
@@ -346,15 +495,7 @@ pub enum Closure {
 
 impl Closure {
     #[inline]
-    pub fn add_lua_upvalue(&mut self, upval: UpVal) {
-        if let Closure::Lua(cl) = self {
-            cl.upvalues.push(upval);
-            return;
-        }
-        unreachable!()
-    }
-    #[inline]
-    pub fn set_lua_upvalue(&mut self, id: usize, upval: UpVal) {
+    pub fn set_lua_upvalue(&mut self, id: usize, upval: UpValRef) {
         if let Closure::Lua(cl) = self {
             cl.upvalues[id] = upval;
             return;
@@ -362,46 +503,24 @@ impl Closure {
         unreachable!()
     }
     #[inline]
-    pub fn borrow_lua_upval(&self, id: usize) -> &UpVal {
+    pub fn borrow_lua_upval(&self, id: usize) -> &UpValRef {
         if let Closure::Lua(cl) = self {
             return &cl.upvalues[id];
         }
         unreachable!()
     }
     #[inline]
-    pub fn get_lua_upvalue(&self, id: usize) -> TValue {
-        if let Closure::Lua(cl) = self {
-            return cl.upvalues[id].value.clone();
-        }
-        unreachable!()
-    }
-    #[inline]
-    pub fn get_rust_upvalue(&self, id: usize) -> TValue {
-        if let Closure::Rust(cl) = self {
-            return cl.upvalues[id].clone();
-        }
-        unreachable!()
-    }
-    #[inline]
-    pub fn set_lua_upval_value(&mut self, id: usize, value: TValue) {
-        if let Closure::Lua(cl) = self {
-            cl.upvalues[id].value = value;
-            return;
-        }
-        unreachable!()
-    }
-    #[inline]
-    pub fn get_lua_upval_desc(&self, id: usize) -> UpVal {
-        if let Closure::Lua(cl) = self {
-            return cl.upvalues[id].clone();
+    pub fn borrow_upval(&self, id: usize) -> &UpValRef {
+        match self {
+            Closure::Rust(cl) => &cl.upvalues[id],
+            Closure::Lua(cl) => &cl.upvalues[id],
         }
-        unreachable!()
     }
     #[inline]
-    pub fn get_proto_id(&self) -> usize {
+    pub fn get_proto(&self) -> &Rc<Proto> {
         match self {
             Closure::Rust(_cl) => unreachable!(),
-            Closure::Lua(cl) => cl.proto,
+            Closure::Lua(cl) => &cl.proto,
         }
     }
     pub fn get_nupvalues(&self) -> usize {
@@ -421,14 +540,39 @@ impl Closure {
 
 /// identify current chunkid (file name or source code)
 pub fn chunk_id(source_name: &str) -> String {
+    // available characters, without the final '\0' of the C version
+    let bufflen = LUA_IDSIZE - 1;
+    let len = source_name.chars().count();
     if let Some(stripped) = source_name.strip_prefix('=') {
-        stripped.to_owned()
+        // 'literal' source: truncate it if needed
+        stripped.chars().take(bufflen).collect()
     } else if let Some(stripped) = source_name.strip_prefix('@') {
-        format!("{}...", stripped)
+        // file name
+        if len <= bufflen {
+            // small enough?
+            stripped.to_owned()
+        } else {
+            // add '...' before rest of name
+            let skip = len - 1 - (bufflen - 3);
+            format!("...{}", stripped.chars().skip(skip).collect::<String>())
+        }
     } else {
-        // get first line of source code
-        let len = source_name.find(['\r', '\n']).unwrap_or(source_name.len());
-        format!("[string \"{}...\"]", &source_name[0..len])
+        // string; format as [string "source"]
+        const PRE: &str = "[string \"";
+        const POS: &str = "\"]";
+        const RETS: &str = "...";
+        // save space for prefix+suffix
+        let bufflen = bufflen - (PRE.len() + RETS.len() + POS.len());
+        let nl = source_name.find(['\r', '\n']);
+        if len < bufflen && nl.is_none() {
+            // small one-line source?
+            format!("{}{}{}", PRE, source_name, POS) // keep it
+        } else {
+            // stop at first newline
+            let first_line = &source_name[..nl.unwrap_or(source_name.len())];
+            let first_line: String = first_line.chars().take(bufflen).collect();
+            format!("{}{}{}{}", PRE, first_line, RETS, POS)
+        }
     }
 }
 
@@ -454,14 +598,14 @@ mod tests {
     use std::collections::HashMap;
 
     use super::TValue;
-    use crate::{luaL, state::LuaState};
+    use crate::luaL;
     #[test]
     /// check if the TValue::Table works
     fn table() {
         let mut state = luaL::newstate();
         let t = TValue::new_table();
         state.set_tablev(&t, TValue::from("key"), TValue::from("value"));
-        LuaState::get_tablev2(&mut state.stack, &t, &TValue::from("key"), None);
+        state.get_tablev2(&t, &TValue::from("key"), None);
         let v = &state.stack[state.stack.len() - 1];
 
         assert!(if let TValue::String(s) = v {
@@ -485,4 +629,42 @@ mod tests {
 
         assert_eq!(v, Some(&123));
     }
+
+    #[test]
+    /// check that objects are compared and hashed by identity
+    fn identity() {
+        let (a, b) = (TValue::new_table(), TValue::new_table());
+        assert_eq!(a, a.clone());
+        assert_ne!(a, b);
+        let mut h = HashMap::new();
+        h.insert(a.clone(), 1);
+        h.insert(b.clone(), 2);
+        h.insert(TValue::Float(0.0), 3);
+        assert_eq!(h.get(&a), Some(&1));
+        assert_eq!(h.get(&b), Some(&2));
+        assert_eq!(h.get(&TValue::Float(-0.0)), Some(&3));
+    }
+
+    #[test]
+    /// check that floats are converted like Lua does
+    fn number_format() {
+        for (n, expected) in [
+            (1.0, "1.0"),
+            (-0.0, "-0.0"),
+            (0.1, "0.1"),
+            (1e100, "1e+100"),
+            (1e15, "1e+15"),
+            (123456789012345.0, "1.2345678901234e+14"),
+            (12345678901234.0, "12345678901234.0"),
+            (1.0 / 3.0, "0.33333333333333"),
+            (2.5e-5, "2.5e-05"),
+            (0.0001, "0.0001"),
+            (f64::INFINITY, "inf"),
+            (f64::NEG_INFINITY, "-inf"),
+            (f64::NAN, "nan"),
+            (-f64::NAN, "-nan"),
+        ] {
+            assert_eq!(super::number2str(n), expected);
+        }
+    }
 }
@@ -191,6 +191,24 @@ pub enum OpCode {
 pub use unformatted::*;
 
 impl OpCode {
+    /// whether the instruction sets register A
+    pub(crate) fn test_a_mode(&self) -> bool {
+        !matches!(
+            self,
+            OpCode::SetTabUp
+                | OpCode::SetupVal
+                | OpCode::SetTable
+                | OpCode::Jmp
+                | OpCode::Eq
+                | OpCode::Lt
+                | OpCode::Le
+                | OpCode::Test
+                | OpCode::Return
+                | OpCode::TForCall
+                | OpCode::SetList
+                | OpCode::ExtraArg
+        )
+    }
     pub(crate) fn is_test(&self) -> bool {
         matches!(
             self,
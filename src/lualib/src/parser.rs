@@ -1,6 +1,6 @@
 //! Lua Parser
 
-use std::{cell::RefCell, rc::Rc};
+use std::{cell::RefCell, rc::Rc, sync::Arc};
 
 use crate::{
     api::LuaError,
@@ -109,7 +109,7 @@ const UNARY_PRIORITY: usize = 12;
 #[derive(Default, Clone)]
 pub struct UpValDesc {
     ///  upvalue name (for debug information)
-    name: String,
+    pub name: String,
     /// whether it is in stack
     pub in_stack: bool,
     /// index of upvalue (in stack or in outer function's list)
@@ -218,9 +218,12 @@ impl FuncState {
         key: TValue,
         value: TValue,
     ) -> usize {
-        let val = self.h.borrow_mut().get(&key).cloned();
+        let val = self.h.borrow().get(&key).cloned();
         match val {
-            Some(TValue::Integer(n)) => n as usize,
+            // correct value? (warning: must distinguish floats from integers!)
+            Some(TValue::Integer(n)) if state.protos[self.f].k.get(n as usize) == Some(&value) => {
+                n as usize
+            }
             _ => {
                 let kid = state.protos[self.f].k.len();
                 self.h
@@ -251,12 +254,18 @@ impl FuncState {
 pub fn parser<T>(state: &mut LuaState, parser: &mut SParser<T>) -> Result<LClosure, LuaError> {
     let mut lex = LexState::new(parser.z.take().unwrap(), &parser.name);
     let mut new_fs = FuncState::new();
-    new_fs.f = state.add_prototype(&lex, &parser.name, 1);
+    let main_proto = state.add_prototype(&parser.name, 0);
+    new_fs.f = main_proto;
     lex.vfs.push(new_fs);
     // read the first character in the stream
     lex.next_char(state);
-    main_func(&mut lex, state)?;
-    let cl = LClosure::new(0, 1); //create main closure
+    if let Err(e) = main_func(&mut lex, state) {
+        // discard the prototypes being compiled
+        state.protos.truncate(main_proto);
+        return Err(e);
+    }
+    let main_proto = Rc::new(state.protos.pop().unwrap());
+    let cl = LClosure::new(main_proto, 1); //create main closure
     Ok(cl)
 }
 
@@ -283,6 +292,12 @@ fn close_func<T>(lex: &mut LexState<T>, state: &mut LuaState) -> Result<(), LuaE
     luaK::ret(lex, state, 0, 0)?; // final return
     leave_block(lex, state)?;
     lex.vfs.pop();
+    // the main function of a chunk has no enclosing function
+    if lex.vfs.len() > 1 {
+        // move the compiled function to its enclosing function
+        let proto = Rc::new(state.protos.pop().unwrap());
+        lex.borrow_mut_proto(state, None).p.push(proto);
+    }
     Ok(())
 }
 
@@ -569,6 +584,11 @@ fn suffixed_expr<T>(
     primary_expr(lex, state, v)?;
     loop {
         match lex.t.clone() {
+            Some(t) if t.token == Reserved::String as u32 || t.token == '{' as u32 => {
+                // funcargs
+                luaK::exp2nextreg(lex, state, v)?;
+                func_args(lex, state, v, line)?;
+            }
             Some(t) => match t.token as u8 as char {
                 '.' => {
                     // fieldsel
@@ -615,14 +635,13 @@ fn assignment<T>(
         return lex.syntax_error(state, "syntax error");
     }
     if test_next(lex, state, ',' as u32)? {
-        // assignment -> `,' primaryexp assignment
+        // assignment -> `,' suffixedexp assignment
         let mut nv = LHSAssignment::default();
-        primary_expr(lex, state, &mut nv.v)?;
-        let nvk = nv.v.k;
-        lhs.push(nv);
-        if nvk == ExpressionKind::LocalRegister {
-            check_conflict(lex, state, &exp, lhs)?;
+        suffixed_expr(lex, state, &mut nv.v)?;
+        if nv.v.k != ExpressionKind::Indexed {
+            check_conflict(lex, state, &nv.v, lhs)?;
         }
+        lhs.push(nv);
         if nvars > LUAI_MAXRCALLS - state.n_rcalls {
             return lex.error_limit(state, LUAI_MAXRCALLS, "variables in assignment");
         }
@@ -1037,7 +1056,7 @@ fn single_var_aux<T>(
             let prev_fsid = fsid - 1;
             // not found ?
             // try upper levels
-            single_var_aux(lex, state, prev_fsid, name, exp, base)?;
+            single_var_aux(lex, state, prev_fsid, name, exp, false)?;
             if exp.k == ExpressionKind::Void {
                 // not found; is a global
                 return Ok(());
@@ -1064,7 +1083,7 @@ fn new_upvalue<T>(
         lex.error_limit(state, MAX_UPVAL, "upvalues")?
     }
     let proto = lex.borrow_mut_proto(state, fsid);
-    proto.upvalues.push(UpValDesc {
+    Arc::make_mut(&mut proto.upvalues).push(UpValDesc {
         name: name.to_owned(),
         in_stack: exp.k == ExpressionKind::LocalRegister,
         idx: exp.info as usize,
@@ -1232,7 +1251,7 @@ fn body<T>(
     line: usize,
 ) -> Result<(), LuaError> {
     let mut new_fs = FuncState::new();
-    new_fs.f = state.add_prototype(&lex, &lex.source, line);
+    new_fs.f = state.add_prototype(&lex.source, line);
     lex.vfs.push(new_fs);
     open_func(lex, state);
     check_next(lex, state, '(' as u32)?;
@@ -1263,7 +1282,8 @@ fn code_closure<T>(
     exp: &mut ExpressionDesc,
 ) -> Result<(), LuaError> {
     let fs = lex.vfs.pop().unwrap();
-    let funcnum = lex.borrow_proto(state, None).p.len() as u32 - 1;
+    // the function is added to its enclosing function when closed
+    let funcnum = lex.borrow_proto(state, None).p.len() as u32;
     exp.init(
         ExpressionKind::Relocable,
         code_abx(lex, state, OpCode::Closure as u32, 0, funcnum)? as i32,
@@ -1484,7 +1504,7 @@ fn new_localvar<T>(
 
 fn register_local_var<T>(lex: &mut LexState<T>, state: &mut LuaState, name: String) -> usize {
     let proto = lex.borrow_mut_proto(state, None);
-    proto.locvars.push(LocVar {
+    Arc::make_mut(&mut proto.locvars).push(LocVar {
         name,
         start_pc: 0,
         end_pc: 0,
@@ -1,17 +1,21 @@
 //! Global State
 
-use std::{collections::HashMap, rc::Rc};
+use std::{cell::RefCell, collections::HashMap, rc::Rc, sync::Arc};
 
 use crate::{
     api::LuaError,
+    fs::{FileSystem, RealFileSystem},
+    debug::HookEvent,
     ldo::CallId,
-    lex::{str2d, LexState},
-    limits::{InstId, MAX_UPVAL},
+    lex::str2d,
+    limits::{InstId, MAXTAGLOOP, MAX_UPVAL},
     luaH::TableRef,
-    object::{Closure, ClosureRef, Proto, ProtoId, RClosure, StkId, TValue, UpVal},
+    luaC::{self, GcState},
+    luaG,
+    object::{ClosureRef, Proto, ProtoId, RClosure, StkId, TValue, UpVal, UpValRef},
     opcodes::{get_arg_b, get_arg_c, rk_is_k, BIT_RK},
-    LuaFloat, LuaInteger, LuaRustFunction, LUA_MINSTACK, LUA_MULTRET, LUA_REGISTRYINDEX,
-    LUA_RIDX_GLOBALS,
+    LuaFloat, LuaHook, LuaInteger, LuaRustFunction, LUA_MASKLINE, LUA_MASKRET, LUA_MINSTACK,
+    LUA_MULTRET, LUA_REGISTRYINDEX, LUA_RIDX_GLOBALS, LUA_RIDX_MAINTHREAD,
 };
 
 #[cfg(target_arch = "wasm32")]
@@ -76,13 +80,124 @@ impl CallInfo {
     }
 }
 
+/// what `os.exit` does when called from a script
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExitBehavior {
+    /// unwind back to the host, which receives `LuaError::Exit(code)`
+    #[default]
+    Unwind,
+    /// terminate the whole process with `std::process::exit`
+    Process,
+}
+
+/// state of the xoshiro256** pseudo-random generator used by `math.random`.
+/// Set or snapshot it through `LuaState::g.random` to get reproducible sequences.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RandomState([u64; 4]);
+
+impl RandomState {
+    /// Initializes the generator the same way `math.randomseed(n1, n2)` does.
+    pub fn from_seed(n1: LuaInteger, n2: LuaInteger) -> Self {
+        // avoid a zero state
+        let mut rs = Self([n1 as u64, 0xff, n2 as u64, 0]);
+        for _ in 0..16 {
+            // discard initial values to "spread" seed
+            rs.next_u64();
+        }
+        rs
+    }
+    /// Restores a snapshot taken with `RandomState::state`.
+    /// The state must not be all zeros.
+    pub fn from_state(state: [u64; 4]) -> Self {
+        Self(state)
+    }
+    /// the raw generator state
+    pub fn state(&self) -> [u64; 4] {
+        self.0
+    }
+    /// Returns the next 64 bits random value.
+    pub fn next_u64(&mut self) -> u64 {
+        let s = &mut self.0;
+        let state0 = s[0];
+        let state1 = s[1];
+        let state2 = s[2] ^ state0;
+        let state3 = s[3] ^ state1;
+        let res = state1.wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        s[0] = state0 ^ state3;
+        s[1] = state1 ^ state2;
+        s[2] = state2 ^ (state1 << 17);
+        s[3] = state3.rotate_left(45);
+        res
+    }
+    /// Returns a float uniformly distributed in [0,1).
+    pub fn next_float(&mut self) -> LuaFloat {
+        Self::to_float(self.next_u64())
+    }
+    /// Converts a random value to a float in [0,1) using its 53 higher bits.
+    pub(crate) fn to_float(rv: u64) -> LuaFloat {
+        (rv >> 11) as LuaFloat * (0.5 / (1u64 << 52) as LuaFloat)
+    }
+    /// Returns an integer uniformly distributed in [0, n].
+    pub fn project(&mut self, mut ran: u64, n: u64) -> u64 {
+        if n & n.wrapping_add(1) == 0 {
+            // is 'n + 1' a power of 2?
+            return ran & n;
+        }
+        // compute the smallest (2^b - 1) not smaller than n
+        let lim = u64::MAX >> n.leading_zeros();
+        loop {
+            // project 'ran' into [0, lim]
+            ran &= lim;
+            if ran <= n {
+                return ran;
+            }
+            // not inside [0, n]? Try again
+            ran = self.next_u64();
+        }
+    }
+}
+
+impl Default for RandomState {
+    /// a seed that changes at each run
+    fn default() -> Self {
+        let local = 0u8;
+        let addr = &local as *const u8 as LuaInteger;
+        Self::from_seed(make_seed(), addr)
+    }
+}
+
+/// some unpredictable value to seed the generator
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn make_seed() -> LuaInteger {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos() as LuaInteger)
+        .unwrap_or(0)
+}
+
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn make_seed() -> LuaInteger {
+    crate::wasm::now_millis() as LuaInteger
+}
+
 /// 'global state', shared by all threads of this state
 pub struct GlobalState {
     /// to be called in unprotected errors
     pub panic: Option<PanicFunction>,
     /// metatables for basic types
     pub mt: HashMap<String, Option<TableRef>>,
-    registry: TValue,
+    /// behavior of `os.exit`
+    pub exit_behavior: ExitBehavior,
+    /// whether scripts can spawn processes with `io.popen` and `os.execute`.
+    /// Set it to false for sandboxed states.
+    pub allow_processes: bool,
+    /// files seen by the `io`, `os` and `package` libraries and by `loadfile`
+    pub fs: Rc<dyn FileSystem>,
+    /// pseudo-random generator of `math.random`
+    pub random: RandomState,
+    pub(crate) registry: TValue,
+    /// cycle collector
+    pub(crate) gc: GcState,
 }
 
 impl Default for GlobalState {
@@ -90,11 +205,26 @@ impl Default for GlobalState {
         Self {
             panic: None,
             mt: HashMap::new(),
+            exit_behavior: ExitBehavior::default(),
+            allow_processes: !cfg!(target_arch = "wasm32"),
+            fs: Rc::new(RealFileSystem),
+            random: RandomState::default(),
             registry: TValue::new_table(),
+            gc: GcState::default(),
         }
     }
 }
 
+/// outcome of a table access through the `__index` chain
+enum IndexResult {
+    /// the value was found
+    Value(TValue),
+    /// the `__index` function must be called with the indexed object
+    Call(TValue, TValue),
+    /// this value cannot be indexed
+    NotIndexable(TValue),
+}
+
 /// 'per thread' state
 pub struct LuaState {
     pub g: GlobalState,
@@ -110,19 +240,31 @@ pub struct LuaState {
     pub ci: CallId,
     /// list of nested CallInfo
     pub base_ci: Vec<CallInfo>,
+    /// debug hook, see `api::set_hook`
+    pub hook: Option<LuaHook>,
     pub allowhook: bool,
+    /// events that trigger the hook (LUA_MASK*)
     pub hookmask: usize,
+    /// instructions between two count hooks
+    pub basehookcount: usize,
+    /// instructions left before the next count hook
+    pub hookcount: usize,
+    /// last pc traced by the line hook
+    pub(crate) oldpc: usize,
     /// temporary place for environments
     pub env: TableRef,
     pub envvalue: TValue,
     /// list of open upvalues
-    pub open_upval: Vec<UpVal>,
-    /// all closures prototypes
-    pub protos: Vec<Proto>,
+    pub open_upval: Vec<UpValRef>,
+    /// prototypes of the functions being compiled, enclosing function first.
+    /// A prototype is moved to its enclosing function once compiled.
+    pub(crate) protos: Vec<Proto>,
     /// io default output
     pub stdout: Box<dyn std::io::Write>,
     /// io default error output
     pub stderr: Box<dyn std::io::Write>,
+    /// exit code requested by `os.exit`, waiting to unwind to the host
+    pub(crate) exit_code: Option<i32>,
 }
 
 #[cfg(target_arch = "wasm32")]
@@ -138,12 +280,17 @@ impl Default for LuaState {
             n_rcalls: Default::default(),
             ci: Default::default(),
             base_ci: Default::default(),
+            hook: Default::default(),
             allowhook: Default::default(),
             hookmask: Default::default(),
+            basehookcount: Default::default(),
+            hookcount: Default::default(),
+            oldpc: Default::default(),
             env: Default::default(),
             envvalue: Default::default(),
             open_upval: Default::default(),
             protos: Default::default(),
+            exit_code: Default::default(),
         }
     }
 }
@@ -161,24 +308,37 @@ impl Default for LuaState {
             n_rcalls: Default::default(),
             ci: Default::default(),
             base_ci: Default::default(),
+            hook: Default::default(),
             allowhook: Default::default(),
             hookmask: Default::default(),
+            basehookcount: Default::default(),
+            hookcount: Default::default(),
+            oldpc: Default::default(),
             env: Default::default(),
             envvalue: Default::default(),
             open_upval: Default::default(),
             protos: Default::default(),
+            exit_code: Default::default(),
         }
     }
 }
 
+impl Drop for LuaState {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            return;
+        }
+        self.close_state();
+    }
+}
+
 impl LuaState {
     pub(crate) fn init_stack(&mut self) {
         // initialize first ci
         let mut ci = CallInfo::new();
         // `function' entry for this `ci'
-        //self.stack.push(TValue::Nil);
+        self.stack.push(TValue::Nil);
         ci.top = 1 + LUA_MINSTACK;
-        ci.call_status = CIST_LUA;
         self.base_ci.push(ci);
     }
     #[inline]
@@ -186,18 +346,28 @@ impl LuaState {
         self.protos[protoid].code[pc]
     }
     pub(crate) fn borrow_mut_instruction(&mut self, protoid: usize, pc: usize) -> &mut u32 {
-        &mut self.protos[protoid].code[pc]
+        &mut Arc::make_mut(&mut self.protos[protoid].code)[pc]
     }
-    #[inline]
-    pub fn get_lua_constant(&self, protoid: usize, kid: usize) -> TValue {
-        self.protos[protoid].k[kid].clone()
+    /// Closes the state: calls the `__gc` finalizers of all the objects
+    /// still registered for finalization, then frees all the objects
+    pub(crate) fn close_state(&mut self) {
+        self.close_func(0);
+        self.call_all_finalizers();
+        self.free_all_objects();
     }
     pub(crate) fn push_rust_function(&mut self, func: LuaRustFunction) {
         self.push_rust_closure(func, 0);
     }
     pub(crate) fn push_string(&mut self, value: &str) {
+        self.g.gc.debt += value.len();
         self.stack.push(TValue::String(Rc::new(value.to_owned())));
     }
+    /// Pushes a string built in a buffer whose growth was checked against
+    /// the memory limit, without copying it
+    pub(crate) fn push_buffer(&mut self, value: String) {
+        self.g.gc.debt += value.capacity();
+        self.stack.push(TValue::from(value));
+    }
     pub(crate) fn push_number(&mut self, value: LuaFloat) {
         self.stack.push(TValue::Float(value));
     }
@@ -250,10 +420,16 @@ impl LuaState {
     pub(crate) fn push_rust_closure(&mut self, func: LuaRustFunction, nup_values: usize) {
         self.api_check_nelems(nup_values);
         let mut cl = RClosure::new(func);
-        for _ in 0..nup_values {
-            cl.upvalues.push(self.stack.pop().unwrap());
-        }
-        self.stack.push(TValue::from(cl));
+        // first upvalue is the deepest in the stack
+        cl.upvalues = self
+            .stack
+            .split_off(self.stack.len() - nup_values)
+            .into_iter()
+            .map(|v| Rc::new(RefCell::new(UpVal::Closed(v))))
+            .collect();
+        let cl = TValue::from(cl);
+        self.link_object(&cl);
+        self.stack.push(cl);
     }
     pub(crate) fn get_closure_ref(&self, func: usize) -> ClosureRef {
         if let TValue::Function(cl) = &self.stack[func] {
@@ -263,17 +439,9 @@ impl LuaState {
         }
     }
     pub(crate) fn run_error(&mut self, msg: &str) -> Result<(), LuaError> {
-        let fullmsg = {
-            let ci = &self.base_ci[self.ci];
-            let rcl = self.get_closure_ref(ci.func);
-            let pc = self.base_ci[self.ci].saved_pc;
-            let proto = &self.protos[rcl.borrow().get_proto_id()];
-            let line = proto.lineinfo[pc];
-            let chunk_id = &proto.source;
-            format!("{}:{} {}", chunk_id, line, msg)
-        };
+        let fullmsg = luaG::add_info(self, msg);
         self.stack.push(TValue::from(&fullmsg[..]));
-        Err(LuaError::RuntimeError)
+        luaG::error_msg(self)
     }
 
     pub(crate) fn adjust_results(&mut self, nresults: i32) {
@@ -291,7 +459,9 @@ impl LuaState {
     }
 
     pub(crate) fn create_table(&mut self) {
-        self.stack.push(TValue::new_table());
+        let t = TValue::new_table();
+        self.link_object(&t);
+        self.stack.push(t);
     }
 
     pub(crate) fn set_tablev(&self, tvalue: &TValue, key: TValue, value: TValue) {
@@ -303,117 +473,172 @@ impl LuaState {
         }
     }
 
-    pub(crate) fn get_tablev2(
-        stack: &mut Vec<TValue>,
+    /// Main function for table assignment (invoking metamethods if necessary).
+    /// Does `t[key] = value`, following the `__newindex` chain.
+    pub(crate) fn set_table(
+        &mut self,
         t: &TValue,
-        key: &TValue,
-        val: Option<StkId>,
-    ) {
-        // TODO INDEX metamethods
-        if let TValue::Table(rt) = t {
-            let mut rt = rt.clone();
-            loop {
-                let newrt;
-                {
-                    let mut rtmut = rt.borrow_mut();
-                    match rtmut.get(key) {
-                        Some(value) => {
-                            // found a value, put it on stack
-                            match val {
-                                Some(idx) => {
-                                    if idx == stack.len() {
-                                        stack.push(value.clone());
-                                    } else {
-                                        stack[idx] = value.clone();
-                                    }
-                                }
-                                None => return stack.push(value.clone()),
-                            }
-                            return;
-                        }
-                        None => {
-                            if let Some(ref mt) = rtmut.metatable {
-                                // not found. try with the metatable
-                                newrt = mt.clone();
-                            } else {
-                                // no metatable, put Nil on stack
-                                match val {
-                                    Some(idx) => {
-                                        if idx == stack.len() {
-                                            stack.push(TValue::Nil);
-                                        } else {
-                                            stack[idx] = TValue::Nil;
-                                        }
-                                    }
-                                    None => stack.push(TValue::Nil),
-                                }
-                                return;
-                            }
-                        }
+        key: TValue,
+        value: TValue,
+    ) -> Result<(), LuaError> {
+        let mut t = t.clone();
+        for _ in 0..MAXTAGLOOP {
+            let tm = if let TValue::Table(tref) = &t {
+                // is there a previous entry in the table or no metamethod?
+                let tm = if tref.borrow().get(&key).is_none_or(|v| v.is_nil()) {
+                    self.get_tm_by_obj(&t, "__newindex")
+                } else {
+                    TValue::Nil
+                };
+                if tm.is_nil() {
+                    match &key {
+                        TValue::Nil => return self.run_error("index is nil"),
+                        TValue::Float(f) if f.is_nan() => return self.run_error("index is NaN"),
+                        _ => (),
                     }
+                    let growth = tref.borrow().set_growth(&key, &value);
+                    self.check_alloc(growth)?;
+                    let mut t = tref.borrow_mut();
+                    let size = luaC::table_size(&t);
+                    t.set(key, value);
+                    self.g.gc.debt += luaC::table_size(&t).saturating_sub(size);
+                    return Ok(());
+                }
+                tm
+            } else {
+                match self.get_tm_by_obj(&t, "__newindex") {
+                    TValue::Nil => {
+                        self.stack.push(t);
+                        return luaG::type_error(self, self.stack.len() - 1, "index");
+                    }
+                    tm => tm,
+                }
+            };
+            if let TValue::Function(_) = tm {
+                // call the metamethod
+                let top = self.stack.len();
+                self.stack.push(tm);
+                self.stack.push(t);
+                self.stack.push(key);
+                self.stack.push(value);
+                self.dcall(top, 0)?;
+                self.stack.truncate(top);
+                return Ok(());
+            }
+            // else repeat assignment over 'tm'
+            t = tm;
+        }
+        self.run_error("'__newindex' chain too long; possible loop")
+    }
+
+    /// Returns the metatable of any value: tables and userdata have their own,
+    /// other types share a metatable per type
+    pub(crate) fn get_metatable(&self, o: &TValue) -> Option<TableRef> {
+        match o {
+            TValue::Table(tref) => tref.borrow().metatable.clone(),
+            TValue::UserData(udref) => udref.borrow().metatable.clone(),
+            _ => self.g.mt.get(o.get_type_name()).cloned().flatten(),
+        }
+    }
+
+    /// Returns the metamethod `event` of a value, or nil
+    pub(crate) fn get_tm_by_obj(&self, o: &TValue, event: &str) -> TValue {
+        match self.get_metatable(o) {
+            Some(mt) => mt
+                .borrow()
+                .get(&TValue::from(event))
+                .cloned()
+                .unwrap_or_default(),
+            None => TValue::Nil,
+        }
+    }
+
+    /// Follows the `__index` chain of `t` to find `t[key]`.
+    /// Stops when a value is found or a function metamethod must be called.
+    fn index_value(&self, t: &TValue, key: &TValue) -> Result<IndexResult, LuaError> {
+        let mut t = t.clone();
+        for _ in 0..MAXTAGLOOP {
+            let tm = if let TValue::Table(tref) = &t {
+                if let Some(value) = tref.borrow().get(key) {
+                    return Ok(IndexResult::Value(value.clone()));
+                }
+                match self.get_tm_by_obj(&t, "__index") {
+                    // no metamethod: result is nil
+                    TValue::Nil => return Ok(IndexResult::Value(TValue::Nil)),
+                    tm => tm,
+                }
+            } else {
+                match self.get_tm_by_obj(&t, "__index") {
+                    TValue::Nil => return Ok(IndexResult::NotIndexable(t)),
+                    tm => tm,
                 }
-                rt = newrt;
+            };
+            if let TValue::Function(_) = tm {
+                return Ok(IndexResult::Call(tm, t));
             }
+            // else repeat access over 'tm'
+            t = tm;
         }
+        Err(LuaError::RuntimeError)
     }
-    /// put field value `key` from table `t` on stack
+
+    /// Main function for table access (invoking metamethods if necessary).
+    /// Puts `t[key]` in stack slot `val` or pushes it on the stack if `val` is None.
     pub(crate) fn get_tablev(
-        stack: &mut Vec<TValue>,
-        tableid: usize,
+        &mut self,
+        t: &TValue,
         key: &TValue,
         val: Option<StkId>,
-    ) {
-        // TODO INDEX metamethods
-        if let TValue::Table(rt) = &stack[tableid] {
-            let mut rt = rt.clone();
-            loop {
-                let newrt;
-                {
-                    let mut rtmut = rt.borrow_mut();
-                    match rtmut.get(key) {
-                        Some(value) => {
-                            // found a value, put it on stack
-                            match val {
-                                Some(idx) => {
-                                    if idx == stack.len() {
-                                        stack.push(value.clone());
-                                    } else {
-                                        stack[idx] = value.clone();
-                                    }
-                                }
-                                None => return stack.push(value.clone()),
-                            }
-                            return;
-                        }
-                        None => {
-                            if let Some(ref mt) = rtmut.metatable {
-                                // not found. try with the metatable
-                                newrt = mt.clone();
-                            } else {
-                                // no metatable, put Nil on stack
-                                match val {
-                                    Some(idx) => {
-                                        if idx == stack.len() {
-                                            stack.push(TValue::Nil);
-                                        } else {
-                                            stack[idx] = TValue::Nil;
-                                        }
-                                    }
-                                    None => stack.push(TValue::Nil),
-                                }
-                                return;
-                            }
-                        }
-                    }
-                }
-                rt = newrt;
+    ) -> Result<(), LuaError> {
+        let value = match self.index_value(t, key) {
+            Ok(IndexResult::Value(value)) => value,
+            Ok(IndexResult::Call(tm, t)) => {
+                let top = self.stack.len();
+                self.stack.push(tm);
+                self.stack.push(t);
+                self.stack.push(key.clone());
+                self.dcall(top, 1)?;
+                let value = self.stack.pop().unwrap();
+                self.stack.truncate(top);
+                value
+            }
+            Ok(IndexResult::NotIndexable(t)) => {
+                self.stack.push(t);
+                return luaG::type_error(self, self.stack.len() - 1, "index");
             }
+            Err(e) => {
+                self.run_error("'__index' chain too long; possible loop")?;
+                return Err(e);
+            }
+        };
+        match val {
+            Some(idx) => self.set_or_push(idx, value),
+            None => self.stack.push(value),
+        }
+        Ok(())
+    }
+
+    /// Table access for the API. Follows `__index` tables but does not call
+    /// `__index` functions, which would need a protected call.
+    pub(crate) fn get_tablev2(&mut self, t: &TValue, key: &TValue, val: Option<StkId>) {
+        let value = match self.index_value(t, key) {
+            Ok(IndexResult::Value(value)) => value,
+            // TODO call __index functions
+            _ => TValue::Nil,
+        };
+        match val {
+            Some(idx) => self.set_or_push(idx, value),
+            None => self.stack.push(value),
         }
     }
 
     pub(crate) fn is_index_valid(&self, index: isize) -> bool {
-        let len = self.stack.len() as isize;
-        (index >= 0 && index < len) || (index < 0 && index >= -len) || index <= LUA_REGISTRYINDEX
+        // number of values in the current function's stack frame
+        let len = self.stack.len().saturating_sub(self.base_ci[self.ci].func + 1) as isize;
+        (index > 0 && index <= len)
+            || (index < 0 && index >= -len)
+            || index == LUA_REGISTRYINDEX
+            || (index < LUA_REGISTRYINDEX && self.upvalue_index2adr(index).is_some())
     }
     pub(crate) fn index2adr(&self, index: isize) -> TValue {
         let func = self.base_ci[self.ci].func;
@@ -433,19 +658,52 @@ impl LuaState {
         } else {
             match index {
                 LUA_REGISTRYINDEX => self.g.registry.clone(),
-                _ => {
-                    // upvalues
-                    let index = (LUA_REGISTRYINDEX - index) as usize;
-                    debug_assert!(index <= MAX_UPVAL + 1);
-                    // TODO light rust function
-                    let stkid = self.base_ci[self.ci].func;
-                    if index <= self.get_closure_nupvalues(stkid) {
-                        return self.get_rust_closure_upvalue(stkid, index - 1).clone();
-                    }
-                    TValue::Nil
-                }
+                _ => match self.upvalue_index2adr(index) {
+                    Some(uv) => self.get_upval_value(&uv),
+                    None => TValue::Nil,
+                },
+            }
+        }
+    }
+
+    /// Returns the upvalue of the running function designated by the
+    /// pseudo-index `index`, or None if the function has no such upvalue
+    fn upvalue_index2adr(&self, index: isize) -> Option<UpValRef> {
+        let index = (LUA_REGISTRYINDEX - index) as usize;
+        debug_assert!(index <= MAX_UPVAL + 1);
+        let stkid = self.base_ci[self.ci].func;
+        if self.ci == 0 || !self.stack[stkid].is_function() {
+            // no function at the host level
+            return None;
+        }
+        if index <= self.get_closure_nupvalues(stkid) {
+            return Some(self.get_closure_upval(stkid, index - 1));
+        }
+        None
+    }
+
+    /// Replaces the value at the given valid index with `value`
+    pub(crate) fn set_index(&mut self, index: isize, value: TValue) {
+        if index < LUA_REGISTRYINDEX {
+            // upvalues
+            let uv = self.upvalue_index2adr(index);
+            debug_assert!(uv.is_some(), "invalid upvalue index");
+            if let Some(uv) = uv {
+                self.set_upval_value(&uv, value);
             }
+            return;
         }
+        let func = self.base_ci[self.ci].func;
+        let index = if index > 0 {
+            // positive index in the stack
+            index as usize + func
+        } else {
+            // negative index in the stack (count from top)
+            debug_assert!(index != 0 && index > LUA_REGISTRYINDEX);
+            self.stack.len() - (-index) as usize
+        };
+        debug_assert!(index < self.stack.len());
+        self.stack[index] = value;
     }
 
     pub(crate) fn pop_stack(&mut self, count: usize) {
@@ -456,8 +714,13 @@ impl LuaState {
     /// Finishes a function call: calls hook if necessary, removes CallInfo,
     /// moves current number of results to proper place; returns 0 iff call
     /// wanted multiple (variable number of) results.
-    pub(crate) fn poscall(&mut self, first_result: StkId, nres: usize) -> bool {
-        // TODO hooks
+    pub(crate) fn poscall(&mut self, first_result: StkId, nres: usize) -> Result<bool, LuaError> {
+        if self.hookmask & (LUA_MASKRET | LUA_MASKLINE) != 0 {
+            if self.hookmask & LUA_MASKRET != 0 {
+                self.dhook(HookEvent::Return, -1)?;
+            }
+            self.oldpc = self.base_ci[self.ci - 1].saved_pc; // 'oldpc' for caller function
+        }
         let ci = &self.base_ci[self.ci];
         // res == final position of 1st result
         let res = ci.func;
@@ -466,7 +729,7 @@ impl LuaState {
         self.base_ci.pop(); // back to caller
         self.ci -= 1;
         // move results to proper place
-        self.move_results(first_result, res, nres, wanted)
+        Ok(self.move_results(first_result, res, nres, wanted))
     }
 
     /// Given 'nres' results at 'firstResult', move 'wanted' of them to 'res'.
@@ -517,29 +780,42 @@ impl LuaState {
         true
     }
 
-    pub(crate) fn find_upval(&mut self, func: usize, level: usize) -> UpVal {
-        let cl = self.get_closure_ref(func);
-        let mut rcl = cl.borrow_mut();
-        if let Closure::Lua(cl) = &mut *rcl {
-            let mut index = 0;
-            for (i, val) in cl.upvalues.iter().enumerate().rev() {
-                if val.v < level as StkId {
+    /// Returns the open upvalue pointing to the stack slot `level`,
+    /// creating it if needed
+    pub(crate) fn find_upval(&mut self, level: StkId) -> UpValRef {
+        // the list of open upvalues is sorted by stack level
+        let mut index = 0;
+        for (i, uv) in self.open_upval.iter().enumerate().rev() {
+            match *uv.borrow() {
+                UpVal::Open(v) if v == level => return uv.clone(), // found a corresponding upvalue
+                UpVal::Open(v) if v < level => {
                     index = i + 1;
                     break;
                 }
-                if val.v == level as StkId {
-                    // found a corresponding value
-                    return val.clone();
-                }
+                _ => (),
             }
-            let uv = UpVal {
-                v: level as StkId,
-                value: self.stack[level as usize].clone(),
-            };
-            cl.upvalues.insert(index, uv.clone());
-            return uv;
         }
-        unreachable!()
+        // not found: create a new upvalue
+        let uv = Rc::new(RefCell::new(UpVal::Open(level)));
+        self.open_upval.insert(index, uv.clone());
+        uv
+    }
+
+    /// Returns the current value of an upvalue
+    pub(crate) fn get_upval_value(&self, uv: &UpValRef) -> TValue {
+        match &*uv.borrow() {
+            UpVal::Open(v) => self.stack.get(*v).cloned().unwrap_or_default(),
+            UpVal::Closed(value) => value.clone(),
+        }
+    }
+
+    /// Changes the value of an upvalue
+    pub(crate) fn set_upval_value(&mut self, uv: &UpValRef, value: TValue) {
+        let mut uv = uv.borrow_mut();
+        match &mut *uv {
+            UpVal::Open(v) => self.set_or_push(*v, value),
+            UpVal::Closed(v) => *v = value,
+        }
     }
 
     /// convert stack[obj] to a number into stack[dst], return the number value
@@ -564,64 +840,60 @@ impl LuaState {
         }
     }
 
+    /// Closes all the upvalues pointing to stack slots at or above `level`:
+    /// they take a copy of the variable value
     pub(crate) fn close_func(&mut self, level: StkId) {
         while let Some(uv) = self.open_upval.last() {
-            if uv.v < level {
+            let v = match *uv.borrow() {
+                UpVal::Open(v) => v,
+                UpVal::Closed(_) => unreachable!(),
+            };
+            if v < level {
                 break;
             }
-            // if uv.v < self.stack.len() {
-            //     //uv.value = self.stack[uv.v].clone();
-            //     //self.stack[uv.v] = uv.value.clone();
-            // }
-            // TODO save upvals somewhere
+            let value = self.stack.get(v).cloned().unwrap_or_default();
+            *uv.borrow_mut() = UpVal::Closed(value);
             self.open_upval.pop();
         }
     }
 
-    pub(crate) fn get_rkb(&self, i: u32, base: u32, protoid: usize) -> TValue {
+    pub(crate) fn get_rkb(&self, i: u32, base: u32, proto: &Proto) -> TValue {
         let b = get_arg_b(i);
         let rbi = (base + b) as usize;
         if rk_is_k(b) {
-            self.get_lua_constant(protoid, (b & !BIT_RK) as usize)
+            proto.k[(b & !BIT_RK) as usize].clone()
         } else {
             self.stack[rbi].clone()
         }
     }
 
-    pub(crate) fn get_rkc(&self, i: u32, base: u32, protoid: usize) -> TValue {
+    pub(crate) fn get_rkc(&self, i: u32, base: u32, proto: &Proto) -> TValue {
         let c = get_arg_c(i);
         let rci = (base + c) as usize;
         if rk_is_k(c) {
-            self.get_lua_constant(protoid, (c & !BIT_RK) as usize)
+            proto.k[(c & !BIT_RK) as usize].clone()
         } else {
             self.stack[rci].clone()
         }
     }
 
-    pub(crate) fn _get_lua_closure_upval_desc(&self, func: usize, upval_id: usize) -> UpVal {
+    pub(crate) fn get_lua_closure_proto(&self, func: usize) -> Rc<Proto> {
         let cl = self.get_closure_ref(func);
         let cl = cl.borrow();
-        cl.get_lua_upval_desc(upval_id)
-    }
-    pub(crate) fn get_lua_closure_protoid(&self, func: usize) -> usize {
-        let cl = self.get_closure_ref(func);
-        let cl = cl.borrow();
-        cl.get_proto_id()
+        cl.get_proto().clone()
     }
     pub(crate) fn get_lua_closure_upvalue(&self, func: usize, upval_id: usize) -> TValue {
-        let cl = self.get_closure_ref(func);
-        let cl = cl.borrow();
-        cl.get_lua_upvalue(upval_id)
+        let uv = self.get_lua_closure_upval(func, upval_id);
+        self.get_upval_value(&uv)
     }
-    pub(crate) fn get_lua_closure_upval(&self, func: usize, upval_id: usize) -> UpVal {
+    pub(crate) fn get_lua_closure_upval(&self, func: usize, upval_id: usize) -> UpValRef {
         let cl = self.get_closure_ref(func);
         let cl = cl.borrow();
         cl.borrow_lua_upval(upval_id).clone()
     }
     pub(crate) fn set_lua_closure_upvalue(&mut self, func: usize, upval_id: usize, value: TValue) {
-        let cl = self.get_closure_ref(func);
-        let mut cl = cl.borrow_mut();
-        cl.set_lua_upval_value(upval_id, value);
+        let uv = self.get_lua_closure_upval(func, upval_id);
+        self.set_upval_value(&uv, value);
     }
 
     fn get_closure_nupvalues(&self, func: usize) -> usize {
@@ -630,15 +902,16 @@ impl LuaState {
         cl.get_nupvalues()
     }
 
-    fn get_rust_closure_upvalue(&self, func: usize, upval_id: usize) -> TValue {
+    fn get_closure_upval(&self, func: usize, upval_id: usize) -> UpValRef {
         let cl = self.get_closure_ref(func);
         let cl = cl.borrow();
-        cl.get_rust_upvalue(upval_id)
+        cl.borrow_upval(upval_id).clone()
     }
 
     #[inline]
     pub(crate) fn set_or_push(&mut self, index: usize, val: TValue) {
-        if index == self.stack.len() {
+        if index >= self.stack.len() {
+            self.stack.resize(index, TValue::Nil);
             self.stack.push(val);
         } else {
             self.stack[index] = val;
@@ -648,6 +921,8 @@ impl LuaState {
     fn init_registry(&mut self) {
         if let TValue::Table(tref) = &self.g.registry {
             let mut t = tref.borrow_mut();
+            // registry[LUA_RIDX_MAINTHREAD] = L
+            t.set_num(LUA_RIDX_MAINTHREAD, TValue::Thread());
             // registry[LUA_RIDX_GLOBALS] = table of globals
             t.set_num(LUA_RIDX_GLOBALS, TValue::new_table());
         }
@@ -655,7 +930,7 @@ impl LuaState {
 
     pub(crate) fn get_global_table(&self) -> TValue {
         if let TValue::Table(tref) = &self.g.registry {
-            let mut t = tref.borrow_mut();
+            let t = tref.borrow();
             t.get_num(LUA_RIDX_GLOBALS).clone()
         } else {
             unreachable!()
@@ -670,25 +945,15 @@ impl LuaState {
 
     #[inline]
     pub(crate) fn set_stack_from_value(&mut self, dest: usize, value: TValue) {
-        if dest == self.stack.len() {
-            self.stack.push(value);
-        } else {
-            self.stack[dest] = value;
-        }
+        self.set_or_push(dest, value);
     }
 
-    pub(crate) fn add_prototype<T>(
-        &mut self,
-        lex: &LexState<T>,
-        source: &str,
-        line: usize,
-    ) -> ProtoId {
+    /// Pushes a new prototype on the stack of the prototypes being compiled
+    pub(crate) fn add_prototype(&mut self, source: &str, line: usize) -> ProtoId {
         let mut proto = Proto::new(source);
-        let cur_proto = lex.borrow_fs(None).f;
         proto.linedefined = line;
         let id = self.protos.len();
         self.protos.push(proto);
-        self.protos[cur_proto].p.push(id);
         id
     }
 }
@@ -700,10 +965,8 @@ fn f_luaopen(state: &mut LuaState, _: ()) -> Result<i32, LuaError> {
 }
 
 pub(crate) fn newstate() -> LuaState {
-    let mut state = LuaState {
-        allowhook: true,
-        ..Default::default()
-    };
+    let mut state = LuaState::default();
+    state.allowhook = true;
     f_luaopen(&mut state, ()).ok();
     state
 }
@@ -4,8 +4,19 @@
 //! Tables keep its elements in two parts: an array part and a hash part.
 //! Non-negative integer keys are all candidates to be kept in the array
 //! part.
+//!
+//! The hash part keeps its entries in a vector, in insertion order, so that
+//! `next` resumes a traversal from the slot of the previous key. A key whose
+//! value is set to nil stays in its slot (it is dead) so that a traversal can
+//! continue after it. Dead keys are purged when the vector is full, before it
+//! grows, as a traversal cannot add new keys.
 
-use std::{cell::RefCell, collections::HashMap, rc::Rc};
+use std::{
+    cell::RefCell,
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    rc::Rc,
+};
 
 use crate::{object::TValue, LuaFloat, LuaInteger};
 
@@ -16,22 +27,147 @@ pub struct Table {
     pub flags: u8,
     pub metatable: Option<TableRef>,
     pub array: Vec<TValue>,
-    pub node: HashMap<TValue, TValue>,
+    pub node: Node,
+}
+
+/// An entry of the hash part
+#[derive(Clone)]
+struct Entry {
+    key: TValue,
+    /// nil if the key is dead
+    value: TValue,
+    hash: u64,
+    /// previous entry with the same hash
+    prev: Option<usize>,
+}
+
+/// Hash part of a table. `index` maps the hash of a key to the last entry
+/// with this hash, the entries with the same hash being chained by `prev`.
+#[derive(Clone, Default)]
+pub struct Node {
+    entries: Vec<Entry>,
+    index: HashMap<u64, usize>,
+}
+
+impl Node {
+    fn hash(key: &TValue) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        hasher.finish()
+    }
+    /// Returns the slot of a key, dead or alive
+    fn find(&self, key: &TValue) -> Option<usize> {
+        let mut slot = self.index.get(&Self::hash(key)).copied();
+        while let Some(i) = slot {
+            if self.entries[i].key == *key {
+                return Some(i);
+            }
+            slot = self.entries[i].prev;
+        }
+        None
+    }
+    /// Returns the value of a key. Dead keys have a nil value.
+    pub fn get(&self, key: &TValue) -> Option<&TValue> {
+        self.find(key).map(|i| &self.entries[i].value)
+    }
+    pub fn insert(&mut self, key: TValue, value: TValue) {
+        if let Some(i) = self.find(&key) {
+            self.entries[i].value = value;
+            return;
+        }
+        if value.is_nil() {
+            return;
+        }
+        if self.entries.len() == self.entries.capacity() {
+            self.purge_dead_keys();
+        }
+        let hash = Self::hash(&key);
+        let prev = self.index.insert(hash, self.entries.len());
+        self.entries.push(Entry {
+            key,
+            value,
+            hash,
+            prev,
+        });
+    }
+    /// Kills a key. Returns its value if it was alive.
+    pub fn remove(&mut self, key: &TValue) -> Option<TValue> {
+        let i = self.find(key)?;
+        let value = std::mem::take(&mut self.entries[i].value);
+        (!value.is_nil()).then_some(value)
+    }
+    /// Removes the entries, dead keys included, for which `f` returns false
+    pub fn retain(&mut self, mut f: impl FnMut(&TValue, &TValue) -> bool) {
+        let len = self.entries.len();
+        self.entries.retain(|e| f(&e.key, &e.value));
+        if self.entries.len() != len {
+            self.rebuild_index();
+        }
+    }
+    /// Removes the dead keys, leaving room for at least as many new keys as
+    /// there are keys alive
+    fn purge_dead_keys(&mut self) {
+        let len = self.entries.len();
+        self.entries.retain(|e| !e.value.is_nil());
+        let size = (self.entries.len() * 2).max(4);
+        self.entries.shrink_to(size);
+        self.entries.reserve_exact(size - self.entries.len());
+        if self.entries.len() != len {
+            self.rebuild_index();
+        }
+    }
+    fn rebuild_index(&mut self) {
+        self.index.clear();
+        for (i, e) in self.entries.iter_mut().enumerate() {
+            e.prev = self.index.insert(e.hash, i);
+        }
+    }
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.index.clear();
+    }
+    /// Whether there is no entry, dead keys included
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+    /// Returns the number of entries, dead keys included
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    pub fn capacity(&self) -> usize {
+        self.entries.capacity()
+    }
+    /// Iterates over the entries in insertion order, dead keys included
+    pub fn iter(&self) -> impl Iterator<Item = (&TValue, &TValue)> {
+        self.entries.iter().map(|e| (&e.key, &e.value))
+    }
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut TValue> {
+        self.entries.iter_mut().map(|e| &mut e.value)
+    }
+    /// Returns the first live entry at or after `slot`
+    fn next_from(&self, slot: usize) -> (TValue, TValue) {
+        self.entries[slot.min(self.entries.len())..]
+            .iter()
+            .find(|e| !e.value.is_nil())
+            .map(|e| (e.key.clone(), e.value.clone()))
+            .unwrap_or((TValue::Nil, TValue::Nil))
+    }
 }
 
 impl Table {
     pub fn iter(&self) -> std::slice::Iter<'_, TValue> {
         self.array.iter()
     }
-    pub fn pairs(&self) -> std::collections::hash_map::Iter<TValue, TValue> {
-        self.node.iter()
+    /// Iterates over the live entries of the hash part
+    pub fn pairs(&self) -> impl Iterator<Item = (&TValue, &TValue)> {
+        self.node.iter().filter(|(_, v)| !v.is_nil())
     }
     pub fn new() -> Self {
         Self {
             flags: !0,
             metatable: None,
             array: Vec::new(),
-            node: HashMap::new(),
+            node: Node::default(),
         }
     }
 
@@ -55,163 +191,141 @@ impl Table {
             return j;
         }
         // else must find a boundary in hash part
-        self.node.len()
+        while self
+            .node
+            .get(&TValue::Integer(j as LuaInteger + 1))
+            .is_some_and(|v| !v.is_nil())
+        {
+            j += 1;
+        }
+        j
     }
-    pub fn set(&mut self, key: TValue, value: TValue) {
+    /// Float keys with an exact integer value are stored as integer keys
+    fn normalize_key(key: TValue) -> TValue {
         match key {
-            TValue::Integer(n) if n >= 1 => {
-                let n = n as usize;
-                if n > self.array.len() {
-                    let new_size = n.max(self.array.len() * 2);
-                    self.array.resize(new_size, TValue::Nil);
-                }
-                self.array[n - 1] = value;
+            TValue::Float(n)
+                if n.fract() == 0.0
+                    && n >= LuaInteger::MIN as LuaFloat
+                    && n < -(LuaInteger::MIN as LuaFloat) =>
+            {
+                TValue::Integer(n as LuaInteger)
             }
-            TValue::Float(n) if n >= 1.0 => {
-                let n = n as usize;
-                if n > self.array.len() {
-                    let new_size = n.max(self.array.len() * 2);
-                    self.array.resize(new_size, TValue::Nil);
-                }
-                self.array[n - 1] = value;
+            _ => key,
+        }
+    }
+    /// Returns the position in the array part of an integer key, if any
+    fn array_index(&self, key: &TValue) -> Option<usize> {
+        match *key {
+            TValue::Integer(n) if n >= 1 && n as u64 <= self.array.len() as u64 => {
+                Some(n as usize - 1)
             }
-            _ => {
-                self.node.insert(key, value);
+            TValue::Float(n)
+                if n.fract() == 0.0 && n >= 1.0 && n <= self.array.len() as LuaFloat =>
+            {
+                Some(n as usize - 1)
             }
+            _ => None,
         }
     }
-    pub fn get_num(&mut self, key: usize) -> &TValue {
-        if key > self.array.len() {
-            &TValue::Nil
+    /// Returns the size of the buffer allocated if the array part grows to
+    /// `n` elements, 0 if it already has room for them
+    pub(crate) fn array_growth(&self, n: usize) -> usize {
+        let capacity = self.array.capacity();
+        if n <= capacity {
+            return 0;
+        }
+        n.max(capacity * 2).max(4) * size_of::<TValue>()
+    }
+    /// Returns an upper bound of the memory that `set` allocates for `t[key] = value`
+    pub(crate) fn set_growth(&self, key: &TValue, value: &TValue) -> usize {
+        if value.is_nil() || self.array_index(key).is_some() {
+            return 0;
+        }
+        let key = Self::normalize_key(key.clone());
+        if key == TValue::Integer(self.array.len() as LuaInteger + 1) {
+            self.array_growth(self.array.len() + 1)
+        } else if self.node.len() == self.node.capacity() && self.node.find(&key).is_none() {
+            // dead keys may be purged instead, but the hash part can double
+            self.node.capacity().max(2) * 2 * 2 * size_of::<TValue>()
         } else {
-            &self.array[key - 1]
+            0
         }
     }
-    pub fn set_num(&mut self, key: usize, value: TValue) {
-        if key > self.array.len() {
-            let new_size = key.max(self.array.len() * 2);
-            self.array.resize(new_size, TValue::Nil);
+    pub fn set(&mut self, key: TValue, value: TValue) {
+        if let Some(i) = self.array_index(&key) {
+            self.array[i] = value;
+            return;
+        }
+        let key = Self::normalize_key(key);
+        if key == TValue::Integer(self.array.len() as LuaInteger + 1) {
+            if value.is_nil() {
+                self.node.remove(&key);
+                return;
+            }
+            // append to the array part, then migrate following keys from the hash part
+            self.array.push(value);
+            self.node.remove(&key);
+            while let Some(v) = self
+                .node
+                .remove(&TValue::Integer(self.array.len() as LuaInteger + 1))
+            {
+                self.array.push(v);
+            }
+        } else {
+            // a nil value keeps the dead key so that a traversal can continue after it
+            self.node.insert(key, value);
         }
-        self.array[key - 1] = value;
+    }
+    pub fn get_num(&self, key: usize) -> &TValue {
+        self.get(&TValue::Integer(key as LuaInteger))
+            .unwrap_or(&TValue::Nil)
+    }
+    pub fn set_num(&mut self, key: usize, value: TValue) {
+        self.set(TValue::Integer(key as LuaInteger), value);
     }
     /// iterator over both the array and hashmap
     /// returns (next_key, value)
     /// start with key = TValue::Nil then call until it returns (nil,nil)
     pub fn next(&self, key: &TValue) -> (TValue, TValue) {
-        match key {
-            TValue::Nil => {
-                if !self.array.is_empty() {
-                    // return first non nil value
-                    for (i, v) in self.array.iter().enumerate() {
-                        if !v.is_nil() {
-                            return (TValue::Float(i as LuaFloat + 1.0), v.clone());
-                        }
-                    }
-                }
-                // return first entry from hashmap or (nil,nil) if empty
-                return self
-                    .node
-                    .iter()
-                    .next()
-                    .map(|(k, v)| (k.clone(), v.clone()))
-                    .unwrap_or((TValue::Nil, TValue::Nil));
-            }
-            TValue::Integer(idx) => {
-                if *idx >= 0 {
-                    if (*idx as usize) < self.array.len() {
-                        // key is an integer. get next non nil value
-                        for (i, v) in self.array.iter().enumerate().skip(*idx as usize) {
-                            if !v.is_nil() {
-                                return (TValue::Integer(i as LuaInteger + 1), v.clone());
-                            }
-                        }
-                    } else {
-                        return self
-                            .node
-                            .iter()
-                            .next()
-                            .map(|(k, v)| (k.clone(), v.clone()))
-                            .unwrap_or((TValue::Nil, TValue::Nil));
-                    }
-                }
-                // key is not an array idx
-                let mut found = false;
-                for k in self.node.keys() {
-                    if k == key {
-                        found = true;
-                    } else if found {
-                        return (k.clone(), self.node.get(k).unwrap().clone());
-                    }
-                }
-                return (TValue::Nil, TValue::Nil);
-            }
-            TValue::Float(idx) => {
-                if idx.fract() == 0.0 && *idx >= 0.0 {
-                    if (*idx as usize) < self.array.len() {
-                        // key is an integer. get next non nil value
-                        for (i, v) in self.array.iter().enumerate().skip(*idx as usize) {
-                            if !v.is_nil() {
-                                return (TValue::Float(i as LuaFloat + 1.0), v.clone());
-                            }
-                        }
-                    } else {
-                        return self
-                            .node
-                            .iter()
-                            .next()
-                            .map(|(k, v)| (k.clone(), v.clone()))
-                            .unwrap_or((TValue::Nil, TValue::Nil));
-                    }
-                }
-                // key is not an array idx
-                let mut found = false;
-                for k in self.node.keys() {
-                    if k == key {
-                        found = true;
-                    } else if found {
-                        return (k.clone(), self.node.get(k).unwrap().clone());
-                    }
-                }
-                return (TValue::Nil, TValue::Nil);
-            }
-            _ => {
-                let mut found = false;
-                for k in self.node.keys() {
-                    if k == key {
-                        found = true;
-                    } else if found {
-                        return (k.clone(), self.node.get(k).unwrap().clone());
-                    }
+        let start = match key {
+            TValue::Nil => 0,
+            _ => match self.array_index(key) {
+                Some(i) => i + 1,
+                None => {
+                    // key is in the hash part: continue after its slot
+                    return match self.node.find(&Self::normalize_key(key.clone())) {
+                        Some(slot) => self.node.next_from(slot + 1),
+                        None => (TValue::Nil, TValue::Nil),
+                    };
                 }
-                return (TValue::Nil, TValue::Nil);
+            },
+        };
+        // return next non nil value from the array part
+        for (i, v) in self.array.iter().enumerate().skip(start) {
+            if !v.is_nil() {
+                return (TValue::Integer(i as LuaInteger + 1), v.clone());
             }
         }
+        // then first entry from hashmap or (nil,nil) if empty
+        self.node.next_from(0)
     }
-    pub fn get(&mut self, key: &TValue) -> Option<&TValue> {
-        match *key {
-            TValue::Nil => Some(&TValue::Nil),
-            TValue::Integer(n) if n >= 1 => {
-                let n = n as usize;
-                if n > self.array.len() {
-                    self.array.resize(n, TValue::Nil);
-                }
-                Some(&self.array[n - 1])
-            }
-            TValue::Float(n) if n >= 1.0 => {
-                let n = n as usize;
-                if n > self.array.len() {
-                    self.array.resize(n, TValue::Nil);
-                }
-                Some(&self.array[n - 1])
-            }
-            _ => self.node.get(key),
-        }
+    /// Returns the value associated with `key`, or None if the key is absent or its value is nil
+    pub fn get(&self, key: &TValue) -> Option<&TValue> {
+        let value = match self.array_index(key) {
+            Some(i) => Some(&self.array[i]),
+            None => match key {
+                TValue::Nil => None,
+                TValue::Float(_) => self.node.get(&Self::normalize_key(key.clone())),
+                _ => self.node.get(key),
+            },
+        };
+        value.filter(|v| !v.is_nil())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{luaH, object::TValue};
+    use crate::{api, luaH, luaL, object::TValue, LuaInteger};
     #[test]
     fn array() {
         let mut t = luaH::Table::new();
@@ -242,4 +356,73 @@ mod tests {
             }
         }
     }
+    #[test]
+    fn array_part() {
+        let mut t = luaH::Table::new();
+        t.set(TValue::Integer(3), TValue::from("c"));
+        t.set(TValue::Float(2.0), TValue::from("b"));
+        t.set(TValue::Integer(1000), TValue::from("far"));
+        t.set(TValue::Integer(1), TValue::from("a"));
+        // keys 2 and 3 migrate from the hash part, 1000 stays in it
+        assert_eq!(t.array.len(), 3);
+        assert_eq!(t.len(), 3);
+        assert_eq!(t.get(&TValue::Float(2.0)), Some(&TValue::from("b")));
+        assert_eq!(
+            t.next(&TValue::Nil),
+            (TValue::Integer(1), TValue::from("a"))
+        );
+        assert_eq!(
+            t.next(&TValue::Float(3.0)),
+            (TValue::Integer(1000), TValue::from("far"))
+        );
+        assert_eq!(t.next(&TValue::Integer(1000)), (TValue::Nil, TValue::Nil));
+        t.set(TValue::Integer(4), TValue::from("d"));
+        t.set(TValue::Integer(5), TValue::from("e"));
+        assert_eq!(t.len(), 5);
+        t.set(TValue::Integer(5), TValue::Nil);
+        assert_eq!(t.len(), 4);
+    }
+    #[test]
+    fn dead_keys() {
+        let mut t = luaH::Table::new();
+        for i in 0..200_000 {
+            let key = TValue::from(format!("k{}", i).as_str());
+            t.set(key.clone(), TValue::Boolean(true));
+            t.set(key, TValue::Nil);
+        }
+        assert!(t.node.capacity() <= 4);
+        assert_eq!(t.next(&TValue::Nil), (TValue::Nil, TValue::Nil));
+        let mut state = luaL::newstate();
+        luaL::open_libs(&mut state).unwrap();
+        let limit = api::memory_used(&state) + (4 << 20);
+        api::set_memory_limit(&mut state, Some(limit));
+        luaL::dostring(
+            &mut state,
+            "local t = {} for i = 1, 1e5 do t['k' .. i] = true t['k' .. i] = nil end",
+        )
+        .unwrap();
+    }
+    #[test]
+    fn traversal() {
+        let mut t = luaH::Table::new();
+        for i in 0..100 {
+            t.set(TValue::Integer(i * 2), TValue::Integer(i));
+        }
+        // clear every entry during the traversal
+        let mut key = TValue::Nil;
+        let mut sum = 0;
+        loop {
+            let (k, v) = t.next(&key);
+            match v {
+                TValue::Integer(v) => sum += v,
+                _ => break,
+            }
+            t.set(k.clone(), TValue::Nil);
+            key = k;
+        }
+        assert_eq!(sum, (0..100).sum::<LuaInteger>());
+        assert_eq!(t.next(&TValue::Nil), (TValue::Nil, TValue::Nil));
+        t.set(TValue::from("new"), TValue::Boolean(true));
+        assert_eq!(t.pairs().count(), 1);
+    }
 }
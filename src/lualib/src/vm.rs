@@ -3,18 +3,19 @@
 use std::{cell::RefCell, rc::Rc};
 
 use crate::{
-    api,
     api::LuaError,
+    limits::LUAI_MAXNUMBER2STR,
+    luaC,
     luaD::PrecallStatus,
     luaG,
-    luaK::arith,
-    object::{Closure, LClosure, StkId, TValue},
+    luaK::{arith, to_numeral},
+    object::{Closure, LClosure, Proto, StkId, TValue},
     opcodes::{
         get_arg_a, get_arg_ax, get_arg_b, get_arg_bx, get_arg_c, get_arg_sbx, get_opcode, OpCode,
         LFIELDS_PER_FLUSH,
     },
     state::{LuaState, CIST_FRESH, CIST_LUA, CIST_TAIL},
-    LuaInteger, LUA_MULTRET,
+    LuaFloat, LuaInteger, LUA_MASKCOUNT, LUA_MASKLINE, LUA_MULTRET,
 };
 
 #[cfg(feature = "debug_logs")]
@@ -34,52 +35,54 @@ impl LuaState {
         self.base_ci[self.ci].call_status |= CIST_FRESH;
         'new_frame: loop {
             let func = self.base_ci[self.ci].func;
-            let protoid = self.get_lua_closure_protoid(func);
+            let proto = self.get_lua_closure_proto(func);
             let mut base = self.base_ci[self.ci].base as u32;
             #[cfg(feature = "debug_logs")]
             let mut first = true;
             // main loop of interpreter
             loop {
                 let pc = self.base_ci[self.ci].saved_pc;
-                let i = self.get_instruction(protoid, pc);
+                let i = proto.code[pc];
                 #[cfg(feature = "debug_logs")]
                 {
                     self.dump_debug_log(func, first, pc, i);
                     first = false;
                 }
                 self.base_ci[self.ci].saved_pc += 1;
-                // TODO handle hooks
+                if self.hookmask & (LUA_MASKLINE | LUA_MASKCOUNT) != 0 {
+                    luaG::trace_exec(self)?;
+                }
                 let ra = base + get_arg_a(i);
                 debug_assert!(base == self.base_ci[self.ci].base as u32);
                 match get_opcode(i) {
                     OpCode::BinaryAnd => {
-                        let rb = self.get_rkb(i, base, protoid);
-                        let rc = self.get_rkc(i, base, protoid);
+                        let rb = self.get_rkb(i, base, &proto);
+                        let rc = self.get_rkc(i, base, &proto);
                         self.set_stack_from_value(ra as usize, arith(OpCode::BinaryAnd, &rb, &rc));
                     }
                     OpCode::BinaryOr => {
-                        let rb = self.get_rkb(i, base, protoid);
-                        let rc = self.get_rkc(i, base, protoid);
+                        let rb = self.get_rkb(i, base, &proto);
+                        let rc = self.get_rkc(i, base, &proto);
                         self.set_stack_from_value(ra as usize, arith(OpCode::BinaryOr, &rb, &rc));
                     }
                     OpCode::BinaryXor => {
-                        let rb = self.get_rkb(i, base, protoid);
-                        let rc = self.get_rkc(i, base, protoid);
+                        let rb = self.get_rkb(i, base, &proto);
+                        let rc = self.get_rkc(i, base, &proto);
                         self.set_stack_from_value(ra as usize, arith(OpCode::BinaryXor, &rb, &rc));
                     }
                     OpCode::Shl => {
-                        let rb = self.get_rkb(i, base, protoid);
-                        let rc = self.get_rkc(i, base, protoid);
+                        let rb = self.get_rkb(i, base, &proto);
+                        let rc = self.get_rkc(i, base, &proto);
                         self.set_stack_from_value(ra as usize, arith(OpCode::Shl, &rb, &rc));
                     }
                     OpCode::Shr => {
-                        let rb = self.get_rkb(i, base, protoid);
-                        let rc = self.get_rkc(i, base, protoid);
+                        let rb = self.get_rkb(i, base, &proto);
+                        let rc = self.get_rkc(i, base, &proto);
                         self.set_stack_from_value(ra as usize, arith(OpCode::Shr, &rb, &rc));
                     }
                     OpCode::IntegerDiv => {
-                        let rb = self.get_rkb(i, base, protoid);
-                        let rc = self.get_rkc(i, base, protoid);
+                        let rb = self.get_rkb(i, base, &proto);
+                        let rc = self.get_rkc(i, base, &proto);
                         self.set_stack_from_value(ra as usize, arith(OpCode::IntegerDiv, &rb, &rc));
                     }
                     OpCode::BinaryNot => {
@@ -94,17 +97,17 @@ impl LuaState {
                     }
                     OpCode::LoadK => {
                         let kid = get_arg_bx(i);
-                        let kval = self.get_lua_constant(protoid, kid as usize);
+                        let kval = proto.k[kid as usize].clone();
                         let rai = ra as usize;
                         self.set_or_push(rai, kval.clone());
                     }
                     OpCode::LoadKx => {
                         let ci_pc = self.base_ci[self.ci].saved_pc;
-                        let ci_inst = self.get_instruction(protoid, ci_pc);
+                        let ci_inst = proto.code[ci_pc];
                         debug_assert!(get_opcode(ci_inst) == OpCode::ExtraArg);
                         let kid = get_arg_ax(ci_inst);
                         self.base_ci[self.ci].saved_pc += 1;
-                        let kval = self.get_lua_constant(protoid, kid as usize);
+                        let kval = proto.k[kid as usize].clone();
                         let rai = ra as usize;
                         self.set_or_push(rai, kval.clone());
                     }
@@ -117,12 +120,10 @@ impl LuaState {
                         }
                     }
                     OpCode::LoadNil => {
-                        let mut b = get_arg_b(i);
-                        let mut ra = ra;
-                        while b > 0 {
+                        // set registers from 'a' to 'a+b'
+                        let b = get_arg_b(i);
+                        for ra in ra..=ra + b {
                             self.set_stack_from_value(ra as usize, TValue::Nil);
-                            ra += 1;
-                            b -= 1;
                         }
                     }
                     OpCode::GetUpVal => {
@@ -134,23 +135,24 @@ impl LuaState {
                     }
                     OpCode::GetTabUp => {
                         let b = get_arg_b(i);
-                        let key = self.get_rkc(i, base, protoid);
+                        let key = self.get_rkc(i, base, &proto);
                         let table = self.get_lua_closure_upvalue(func, b as usize);
-                        Self::get_tablev2(&mut self.stack, &table, &key, Some(ra as usize));
+                        self.get_tablev(&table, &key, Some(ra as usize))?;
                         base = self.base_ci[self.ci].base as u32;
                     }
                     OpCode::GetTable => {
                         let tableid = get_rb(base, i);
-                        let key = self.get_rkc(i, base, protoid);
-                        Self::get_tablev(&mut self.stack, tableid, &key, Some(ra as usize));
+                        let key = self.get_rkc(i, base, &proto);
+                        let table = self.stack[tableid].clone();
+                        self.get_tablev(&table, &key, Some(ra as usize))?;
                         base = self.base_ci[self.ci].base as u32;
                     }
                     OpCode::SetTabUp => {
                         let a = get_arg_a(i);
-                        let key = self.get_rkb(i, base, protoid);
-                        let val = self.get_rkc(i, base, protoid);
+                        let key = self.get_rkb(i, base, &proto);
+                        let val = self.get_rkc(i, base, &proto);
                         let table = self.get_lua_closure_upvalue(func, a as usize);
-                        self.set_tablev(&table, key, val);
+                        self.set_table(&table, key, val)?;
                         base = self.base_ci[self.ci].base as u32;
                     }
                     OpCode::SetupVal => {
@@ -162,49 +164,54 @@ impl LuaState {
                         );
                     }
                     OpCode::SetTable => {
-                        let key = self.get_rkb(i, base, protoid);
-                        let value = self.get_rkc(i, base, protoid);
-                        self.set_tablev(&self.stack[ra as usize], key, value);
+                        let key = self.get_rkb(i, base, &proto);
+                        let value = self.get_rkc(i, base, &proto);
+                        let table = self.stack[ra as usize].clone();
+                        self.set_table(&table, key, value)?;
                         base = self.base_ci[self.ci].base as u32;
                     }
                     OpCode::NewTable => {
-                        self.set_stack_from_value(ra as usize, TValue::new_table());
+                        let t = TValue::new_table();
+                        self.link_object(&t);
+                        self.set_stack_from_value(ra as usize, t);
+                        self.check_gc()?;
                     }
                     OpCode::OpSelf => {
                         let rb = get_rb(base, i);
                         self.set_stack_from_idx(ra as usize + 1, rb as usize);
-                        let key = self.get_rkc(i, base, protoid);
-                        Self::get_tablev(&mut self.stack, rb, &key, Some(ra as usize));
+                        let key = self.get_rkc(i, base, &proto);
+                        let table = self.stack[rb].clone();
+                        self.get_tablev(&table, &key, Some(ra as usize))?;
                         base = self.base_ci[self.ci].base as u32;
                     }
                     OpCode::Add => {
-                        let rb = self.get_rkb(i, base, protoid);
-                        let rc = self.get_rkc(i, base, protoid);
+                        let rb = self.get_rkb(i, base, &proto);
+                        let rc = self.get_rkc(i, base, &proto);
                         self.set_stack_from_value(ra as usize, arith(OpCode::Add, &rb, &rc));
                     }
                     OpCode::Sub => {
-                        let rb = self.get_rkb(i, base, protoid);
-                        let rc = self.get_rkc(i, base, protoid);
+                        let rb = self.get_rkb(i, base, &proto);
+                        let rc = self.get_rkc(i, base, &proto);
                         self.set_stack_from_value(ra as usize, arith(OpCode::Sub, &rb, &rc));
                     }
                     OpCode::Mul => {
-                        let rb = self.get_rkb(i, base, protoid);
-                        let rc = self.get_rkc(i, base, protoid);
+                        let rb = self.get_rkb(i, base, &proto);
+                        let rc = self.get_rkc(i, base, &proto);
                         self.set_stack_from_value(ra as usize, arith(OpCode::Mul, &rb, &rc));
                     }
                     OpCode::Div => {
-                        let rb = self.get_rkb(i, base, protoid);
-                        let rc = self.get_rkc(i, base, protoid);
+                        let rb = self.get_rkb(i, base, &proto);
+                        let rc = self.get_rkc(i, base, &proto);
                         self.set_stack_from_value(ra as usize, arith(OpCode::Div, &rb, &rc));
                     }
                     OpCode::Mod => {
-                        let rb = self.get_rkb(i, base, protoid);
-                        let rc = self.get_rkc(i, base, protoid);
+                        let rb = self.get_rkb(i, base, &proto);
+                        let rc = self.get_rkc(i, base, &proto);
                         self.set_stack_from_value(ra as usize, arith(OpCode::Mod, &rb, &rc));
                     }
                     OpCode::Pow => {
-                        let rb = self.get_rkb(i, base, protoid);
-                        let rc = self.get_rkc(i, base, protoid);
+                        let rb = self.get_rkb(i, base, &proto);
+                        let rc = self.get_rkc(i, base, &proto);
                         self.set_stack_from_value(ra as usize, arith(OpCode::Pow, &rb, &rc));
                     }
                     OpCode::UnaryMinus => {
@@ -218,25 +225,9 @@ impl LuaState {
                         self.set_stack_from_value(ra as usize, TValue::Boolean(res));
                     }
                     OpCode::Len => {
-                        let rb = get_rb(base, i);
-                        match &self.stack[rb] {
-                            TValue::Table(tref) => {
-                                let len = tref.borrow().len() as LuaInteger;
-                                self.set_stack_from_value(ra as usize, TValue::Integer(len));
-                            }
-                            TValue::String(s) => {
-                                self.set_stack_from_value(
-                                    ra as usize,
-                                    TValue::Integer(s.len() as LuaInteger),
-                                );
-                            }
-                            _ => {
-                                // try metamethod
-                                if !call_bin_tm(self, rb, 0, ra, OpCode::Len)? {
-                                    return luaG::type_error(self, rb, "get length of");
-                                }
-                            }
-                        }
+                        let rb = self.stack[get_rb(base, i)].clone();
+                        let len = obj_len(self, &rb)?;
+                        self.set_stack_from_value(ra as usize, len);
                         base = self.base_ci[self.ci].base as u32;
                     }
                     OpCode::Concat => {
@@ -255,35 +246,35 @@ impl LuaState {
                         self.do_jump(i, 0);
                     }
                     OpCode::Eq => {
-                        let rkb = self.get_rkb(i, base, protoid);
-                        let rkc = self.get_rkc(i, base, protoid);
+                        let rkb = self.get_rkb(i, base, &proto);
+                        let rkc = self.get_rkc(i, base, &proto);
                         let a = get_arg_a(i) > 0;
                         if equal_obj(self, rkb, rkc) != a {
                             self.base_ci[self.ci].saved_pc += 1;
                         } else {
-                            self.do_next_jump(protoid);
+                            self.do_next_jump(&proto);
                         }
                         base = self.base_ci[self.ci].base as u32;
                     }
                     OpCode::Lt => {
-                        let rkb = self.get_rkb(i, base, protoid);
-                        let rkc = self.get_rkc(i, base, protoid);
+                        let rkb = self.get_rkb(i, base, &proto);
+                        let rkc = self.get_rkc(i, base, &proto);
                         let a = get_arg_a(i) > 0;
                         if less_than(self, rkb, rkc)? != a {
                             self.base_ci[self.ci].saved_pc += 1;
                         } else {
-                            self.do_next_jump(protoid);
+                            self.do_next_jump(&proto);
                         }
                         base = self.base_ci[self.ci].base as u32;
                     }
                     OpCode::Le => {
-                        let rkb = self.get_rkb(i, base, protoid);
-                        let rkc = self.get_rkc(i, base, protoid);
+                        let rkb = self.get_rkb(i, base, &proto);
+                        let rkc = self.get_rkc(i, base, &proto);
                         let a = get_arg_a(i) > 0;
                         if less_equal(self, rkb, rkc)? != a {
                             self.base_ci[self.ci].saved_pc += 1;
                         } else {
-                            self.do_next_jump(protoid);
+                            self.do_next_jump(&proto);
                         }
                         base = self.base_ci[self.ci].base as u32;
                     }
@@ -292,7 +283,7 @@ impl LuaState {
                         if self.stack[ra as usize].is_false() == is_false {
                             self.base_ci[self.ci].saved_pc += 1;
                         } else {
-                            self.do_next_jump(protoid);
+                            self.do_next_jump(&proto);
                         }
                     }
                     OpCode::TestSet => {
@@ -302,7 +293,7 @@ impl LuaState {
                             self.base_ci[self.ci].saved_pc += 1;
                         } else {
                             self.stack[ra as usize] = self.stack[rb].clone();
-                            self.do_next_jump(protoid);
+                            self.do_next_jump(&proto);
                         }
                     }
                     OpCode::Call => {
@@ -337,7 +328,7 @@ impl LuaState {
                             Ok(PrecallStatus::Lua) => {
                                 // tail call: put new frame in place of previous one
                                 let nbase = self.base_ci[self.ci].base; // called base
-                                let nfunc = func; // called function
+                                let nfunc = self.base_ci[self.ci].func; // called function
                                 let obase = self.base_ci[self.ci - 1].base; // caller base
                                 if !self.open_upval.is_empty() {
                                     // close all upvalues from previous call
@@ -345,7 +336,7 @@ impl LuaState {
                                 }
                                 let nsaved_pc = self.base_ci[self.ci].saved_pc;
                                 // caller function
-                                let mut oci = &mut self.base_ci[self.ci - 1];
+                                let oci = &mut self.base_ci[self.ci - 1];
                                 let ofunc = oci.func;
                                 oci.base = ofunc + nbase - nfunc;
                                 let mut aux = 0;
@@ -374,7 +365,7 @@ impl LuaState {
                     }
                     OpCode::Return => {
                         let b = get_arg_b(i);
-                        if !self.protos[protoid].p.is_empty() {
+                        if !proto.p.is_empty() {
                             self.close_func(base as StkId);
                         }
                         let was_fresh = self.base_ci[self.ci].call_status & CIST_FRESH != 0;
@@ -385,7 +376,7 @@ impl LuaState {
                             } else {
                                 self.stack.len() - ra as usize
                             },
-                        );
+                        )?;
                         if was_fresh {
                             // 'ci' is still the called one
                             return Ok(()); // external invocation : return
@@ -396,10 +387,8 @@ impl LuaState {
                         }
                         debug_assert!(self.base_ci[self.ci].call_status & CIST_LUA != 0);
                         debug_assert!({
-                            let protoid = self.get_lua_closure_protoid(self.base_ci[self.ci].func);
-                            get_opcode(
-                                self.get_instruction(protoid, self.base_ci[self.ci].saved_pc - 1),
-                            ) == OpCode::Call
+                            let proto = self.get_lua_closure_proto(self.base_ci[self.ci].func);
+                            get_opcode(proto.code[self.base_ci[self.ci].saved_pc - 1]) == OpCode::Call
                         });
                         continue 'new_frame; // restart luaV_execute over new Lua function
                     }
@@ -495,7 +484,7 @@ impl LuaState {
                         self.dcall(cb, nresults as i32)?;
                         self.stack.resize(self.base_ci[self.ci].top, TValue::Nil);
                         let ci_pc = self.base_ci[self.ci].saved_pc;
-                        let i = self.get_instruction(protoid, ci_pc);
+                        let i = proto.code[ci_pc];
                         self.base_ci[self.ci].saved_pc += 1;
                         let ra = get_ra(base, i) as u32;
                         debug_assert!(get_opcode(i) == OpCode::TForLoop);
@@ -526,15 +515,20 @@ impl LuaState {
                         if c == 0 {
                             let ci_pc = self.base_ci[self.ci].saved_pc;
                             debug_assert!(
-                                get_opcode(self.get_instruction(protoid, ci_pc))
+                                get_opcode(proto.code[ci_pc])
                                     == OpCode::ExtraArg
                             );
-                            c = get_arg_ax(self.get_instruction(protoid, ci_pc));
+                            c = get_arg_ax(proto.code[ci_pc]);
                             self.base_ci[self.ci].saved_pc += 1;
                         }
                         let mut last = (c - 1) * LFIELDS_PER_FLUSH + n;
-                        if let TValue::Table(tref) = &self.stack[ra as usize] {
+                        let mut grown = 0;
+                        if let TValue::Table(tref) = self.stack[ra as usize].clone() {
+                            // needs more space?
+                            let growth = tref.borrow().array_growth(last as usize);
+                            self.check_alloc(growth)?;
                             let mut t = tref.borrow_mut();
+                            let size = luaC::table_size(&t);
                             while n > 0 {
                                 t.set(
                                     TValue::Integer(last as LuaInteger),
@@ -543,35 +537,43 @@ impl LuaState {
                                 last -= 1;
                                 n -= 1;
                             }
+                            grown = luaC::table_size(&t).saturating_sub(size);
                         }
+                        self.g.gc.debt += grown;
                         self.stack.resize(self.base_ci[self.ci].top, TValue::Nil);
+                        self.check_gc()?;
                     }
                     OpCode::Closure => {
                         let pid = get_arg_bx(i);
-                        let new_protoid = self.protos[protoid].p[pid as usize];
-                        let nup = self.protos[new_protoid].upvalues.len();
-                        let ncl =
-                            Rc::new(RefCell::new(Closure::Lua(LClosure::new(new_protoid, nup))));
-                        self.set_stack_from_value(ra as usize, TValue::Function(ncl.clone()));
+                        let new_proto = proto.p[pid as usize].clone();
+                        let nup = new_proto.upvalues.len();
+                        let ncl = Rc::new(RefCell::new(Closure::Lua(LClosure::new(
+                            new_proto.clone(),
+                            nup,
+                        ))));
+                        let cl = TValue::Function(ncl.clone());
+                        self.link_object(&cl);
+                        self.set_stack_from_value(ra as usize, cl);
                         let mut ncl = ncl.borrow_mut();
                         for i in 0..nup {
-                            let upvaldesc = &self.protos[new_protoid].upvalues[i];
+                            let upvaldesc = &new_proto.upvalues[i];
                             if upvaldesc.in_stack {
-                                let upval = self.find_upval(func, base as usize + upvaldesc.idx);
+                                // upvalue refers to local variable?
+                                let upval = self.find_upval(base as usize + upvaldesc.idx);
                                 ncl.set_lua_upvalue(i, upval);
                             } else {
-                                ncl.set_lua_upvalue(
-                                    i,
-                                    self.get_lua_closure_upval(func, upvaldesc.idx).clone(),
-                                );
+                                // get upvalue from enclosing function
+                                ncl.set_lua_upvalue(i, self.get_lua_closure_upval(func, upvaldesc.idx));
                             }
                         }
+                        drop(ncl);
+                        self.check_gc()?;
                     }
                     OpCode::VarArg => {
                         let ra = ra as usize;
                         let mut b = get_arg_b(i) as i32 - 1;
                         let cbase = self.base_ci[self.ci].base as i32;
-                        let n = (cbase - func as i32 - self.protos[protoid].numparams as i32 - 1)
+                        let n = (cbase - func as i32 - proto.numparams as i32 - 1)
                             .max(0);
                         if b < 0 {
                             b = n;
@@ -599,52 +601,223 @@ impl LuaState {
         self.base_ci[self.ci].saved_pc =
             (self.base_ci[self.ci].saved_pc as i32 + get_arg_sbx(i) + e) as usize;
     }
-    pub(crate) fn do_next_jump(&mut self, protoid: usize) {
+    pub(crate) fn do_next_jump(&mut self, proto: &Proto) {
         let ci_pc = self.base_ci[self.ci].saved_pc;
-        let inst = self.get_instruction(protoid, ci_pc);
+        let inst = proto.code[ci_pc];
         self.do_jump(inst, 1)
     }
 }
 
 fn equal_obj(_state: &mut LuaState, rkb: TValue, rkc: TValue) -> bool {
-    rkb == rkc
+    raw_equal_obj(&rkb, &rkc)
     // TODO metamethod
 }
 
-fn less_than(state: &mut LuaState, rkb: TValue, rkc: TValue) -> Result<bool, LuaError> {
-    if rkb.get_type_name() != rkc.get_type_name() {
+/// Compares two values without metamethods. An integer and a float are
+/// equal if they denote the same mathematical value.
+pub(crate) fn raw_equal_obj(t1: &TValue, t2: &TValue) -> bool {
+    match (t1, t2) {
+        (TValue::Integer(i), f @ TValue::Float(_)) | (f @ TValue::Float(_), TValue::Integer(i)) => {
+            f.into_integer() == Ok(*i)
+        }
+        _ => t1 == t2,
+    }
+}
+
+/// Main operation less than; return 'rkb < rkc'
+pub(crate) fn less_than(state: &mut LuaState, rkb: TValue, rkc: TValue) -> Result<bool, LuaError> {
+    if rkb.is_number() && rkc.is_number() {
+        Ok(lt_num(&rkb, &rkc))
+    } else if rkb.is_string() && rkc.is_string() {
+        Ok(rkb.borrow_string_value() < rkc.borrow_string_value())
+    } else if let Some(res) = call_order_tm(state, &rkb, &rkc, "__lt")? {
+        Ok(res)
+    } else {
         luaG::order_error(state, &rkb, &rkc)?;
-    } else if rkb.is_number() {
-        return Ok(rkb.into_float().unwrap() < rkc.into_float().unwrap());
-    } else if rkb.is_string() {
-        return Ok(rkb.borrow_string_value() < rkc.borrow_string_value());
+        unreachable!()
     }
-    // TODO metamethods
-    luaG::order_error(state, &rkb, &rkc)?;
-    unreachable!()
 }
 
-fn less_equal(state: &mut LuaState, rkb: TValue, rkc: TValue) -> Result<bool, LuaError> {
-    if rkb.get_type_name() != rkc.get_type_name() {
+/// Main operation less than or equal to; return 'rkb <= rkc'. Without a
+/// `__le` metamethod, tries 'not (rkc < rkb)' using `__lt`.
+pub(crate) fn less_equal(state: &mut LuaState, rkb: TValue, rkc: TValue) -> Result<bool, LuaError> {
+    if rkb.is_number() && rkc.is_number() {
+        Ok(le_num(&rkb, &rkc))
+    } else if rkb.is_string() && rkc.is_string() {
+        Ok(rkb.borrow_string_value() <= rkc.borrow_string_value())
+    } else if let Some(res) = call_order_tm(state, &rkb, &rkc, "__le")? {
+        Ok(res)
+    } else if let Some(res) = call_order_tm(state, &rkc, &rkb, "__lt")? {
+        Ok(!res)
+    } else {
         luaG::order_error(state, &rkb, &rkc)?;
-    } else if rkb.is_number() {
-        return Ok(rkb.into_float().unwrap() <= rkc.into_float().unwrap());
-    } else if rkb.is_string() {
-        return Ok(rkb.borrow_string_value() <= rkc.borrow_string_value());
+        unreachable!()
     }
-    // TODO metamethods
-    luaG::order_error(state, &rkb, &rkc)?;
-    unreachable!()
 }
 
-fn call_bin_tm(
-    _state: &mut LuaState,
-    _rb: usize,
-    _rc: usize,
-    _ra: u32,
-    _op: OpCode,
-) -> Result<bool, LuaError> {
-    todo!()
+/// 2^63, the first float above the integer range
+const INT_LIMIT: LuaFloat = -(LuaInteger::MIN as LuaFloat);
+
+/// Returns 'a < b' for two numbers, comparing integers and floats exactly
+fn lt_num(a: &TValue, b: &TValue) -> bool {
+    match (a, b) {
+        (TValue::Integer(i), TValue::Integer(j)) => i < j,
+        (TValue::Integer(i), TValue::Float(f)) => {
+            // i < f <=> i < ceil(f)
+            if f.is_nan() || *f < -INT_LIMIT {
+                false
+            } else {
+                *f >= INT_LIMIT || *i < f.ceil() as LuaInteger
+            }
+        }
+        (TValue::Float(f), TValue::Integer(i)) => {
+            // f < i <=> floor(f) < i
+            if f.is_nan() || *f >= INT_LIMIT {
+                false
+            } else {
+                *f < -INT_LIMIT || (f.floor() as LuaInteger) < *i
+            }
+        }
+        _ => a.get_float_value() < b.get_float_value(),
+    }
+}
+
+/// Returns 'a <= b' for two numbers, comparing integers and floats exactly
+fn le_num(a: &TValue, b: &TValue) -> bool {
+    match (a, b) {
+        (TValue::Integer(i), TValue::Integer(j)) => i <= j,
+        (TValue::Integer(i), TValue::Float(f)) => {
+            // i <= f <=> i <= floor(f)
+            if f.is_nan() || *f < -INT_LIMIT {
+                false
+            } else {
+                *f >= INT_LIMIT || *i <= f.floor() as LuaInteger
+            }
+        }
+        (TValue::Float(f), TValue::Integer(i)) => {
+            // f <= i <=> ceil(f) <= i
+            if f.is_nan() || *f >= INT_LIMIT {
+                false
+            } else {
+                *f < -INT_LIMIT || f.ceil() as LuaInteger <= *i
+            }
+        }
+        _ => a.get_float_value() <= b.get_float_value(),
+    }
+}
+
+/// Calls the order metamethod `event` of `p1` or `p2`.
+/// Returns None if neither operand has this metamethod.
+fn call_order_tm(
+    state: &mut LuaState,
+    p1: &TValue,
+    p2: &TValue,
+    event: &str,
+) -> Result<Option<bool>, LuaError> {
+    let tm = match state.get_tm_by_obj(p1, event) {
+        TValue::Nil => state.get_tm_by_obj(p2, event), // try second operand
+        tm => tm,
+    };
+    if tm.is_nil() {
+        return Ok(None);
+    }
+    Ok(Some(!call_tm_res(state, tm, p1, p2)?.is_false()))
+}
+
+/// Calls the metamethod `f` with arguments `p1` and `p2` and returns its result
+pub(crate) fn call_tm_res(
+    state: &mut LuaState,
+    f: TValue,
+    p1: &TValue,
+    p2: &TValue,
+) -> Result<TValue, LuaError> {
+    let top = state.stack.len();
+    state.stack.push(f);
+    state.stack.push(p1.clone());
+    state.stack.push(p2.clone());
+    state.dcall(top, 1)?;
+    let res = state.stack.pop().unwrap();
+    state.stack.truncate(top);
+    Ok(res)
+}
+
+/// Main operation 'length of': returns '#rb', using the `__len` metamethod
+pub(crate) fn obj_len(state: &mut LuaState, rb: &TValue) -> Result<TValue, LuaError> {
+    let tm = match rb {
+        TValue::Table(tref) => match state.get_tm_by_obj(rb, "__len") {
+            // no metamethod: primitive len
+            TValue::Nil => return Ok(TValue::Integer(tref.borrow().len() as LuaInteger)),
+            tm => tm,
+        },
+        TValue::String(s) => return Ok(TValue::Integer(s.len() as LuaInteger)),
+        _ => match state.get_tm_by_obj(rb, "__len") {
+            TValue::Nil => {
+                state.stack.push(rb.clone());
+                luaG::type_error(state, state.stack.len() - 1, "get length of")?;
+                unreachable!()
+            }
+            tm => tm,
+        },
+    };
+    call_tm_res(state, tm, rb, rb)
+}
+
+/// Main operation for arithmetic and bitwise operators: returns 'p1 op p2',
+/// using the metamethod `event` when the operands are not numbers.
+/// For unary operators, `p2` is a copy of `p1`.
+pub(crate) fn obj_arith(
+    state: &mut LuaState,
+    op: OpCode,
+    event: &str,
+    p1: &TValue,
+    p2: &TValue,
+) -> Result<TValue, LuaError> {
+    let bitwise = matches!(
+        op,
+        OpCode::BinaryAnd
+            | OpCode::BinaryOr
+            | OpCode::BinaryXor
+            | OpCode::Shl
+            | OpCode::Shr
+            | OpCode::BinaryNot
+    );
+    let is_num = |v: &TValue| v.into_float().is_ok();
+    let valid = if bitwise {
+        p1.into_integer().is_ok() && p2.into_integer().is_ok()
+    } else {
+        is_num(p1) && is_num(p2)
+    };
+    if valid {
+        let (p1, p2) = (&to_numeral(p1), &to_numeral(p2));
+        if p1.is_integer() && p2.is_integer() && p2.get_integer_value() == 0 {
+            match op {
+                OpCode::Mod => state.run_error("attempt to perform 'n%0'")?,
+                OpCode::IntegerDiv => state.run_error("attempt to perform 'n//0'")?,
+                _ => (),
+            }
+        }
+        return Ok(arith(op, p1, p2));
+    }
+    let tm = match state.get_tm_by_obj(p1, event) {
+        TValue::Nil => state.get_tm_by_obj(p2, event), // try second operand
+        tm => tm,
+    };
+    if !tm.is_nil() {
+        return call_tm_res(state, tm, p1, p2);
+    }
+    if bitwise && is_num(p1) && is_num(p2) {
+        state.run_error("number has no integer representation")?;
+    }
+    // blame the operand that is not a number
+    let culprit = if is_num(p1) { p2 } else { p1 };
+    state.stack.push(culprit.clone());
+    let operation = if bitwise {
+        "perform bitwise operation on"
+    } else {
+        "perform arithmetic on"
+    };
+    luaG::type_error(state, state.stack.len() - 1, operation)?;
+    unreachable!()
 }
 
 #[cfg(feature = "debug_logs")]
@@ -653,7 +826,7 @@ fn dump_function_header(state: &mut LuaState, func: usize) {
     let cl = cl.borrow();
     let cl = cl.borrow_lua_closure();
     let nup = cl.upvalues.len();
-    let proto = &state.protos[cl.proto];
+    let proto = &cl.proto;
     let nk = proto.k.len();
     if proto.linedefined == proto.lastlinedefined {
         _ = writeln!(state.stdout, "; function [{}] ", proto.linedefined);
@@ -713,7 +886,7 @@ fn disassemble(state: &LuaState, i: Instruction, func: usize) -> String {
     let cl = state.get_closure_ref(func);
     let cl = cl.borrow();
     let cl = cl.borrow_lua_closure();
-    let proto = &state.protos[cl.proto];
+    let proto = &cl.proto;
     let mut res = if o.is_asbx() {
         format!("{:10} {:>5} {:>5}", OPCODE_NAME[o as usize], a, sbx)
     } else if o.is_ax() {
@@ -742,20 +915,27 @@ fn disassemble(state: &LuaState, i: Instruction, func: usize) -> String {
 
 pub(crate) fn concat(state: &mut LuaState, total: usize) -> Result<(), LuaError> {
     let top = state.stack.len();
-    if !(api::is_string(state, top as isize - 2) || api::is_number(state, top as isize - 2))
-        || api::to_string(state, top as isize - 1).is_none()
+    let first = top - total;
+    if let Some(bad) =
+        (first..top).find(|&i| !state.stack[i].is_string() && !state.stack[i].is_number())
     {
         // TODO metamethods
-        return luaG::concat_error(state, top as isize - 2, top as isize - 1);
-    } else {
-        let mut res = String::new();
-        let first = top - total;
-        for i in first..top {
-            res.push_str(&state.stack[i as usize].to_string());
-        }
-        state.stack[first] = TValue::from(res);
-        Ok(())
+        return luaG::type_error(state, bad, "concatenate");
+    }
+    let len = (first..top)
+        .map(|i| match &state.stack[i] {
+            TValue::String(s) => s.len(),
+            _ => LUAI_MAXNUMBER2STR,
+        })
+        .sum();
+    state.check_alloc(len)?;
+    let mut res = String::with_capacity(len);
+    for i in first..top {
+        res.push_str(&state.stack[i].to_string());
     }
+    state.g.gc.debt += res.capacity();
+    state.stack[first] = TValue::from(res);
+    Ok(())
 }
 
 #[inline]
@@ -10,6 +10,13 @@ use wasm_bindgen::prelude::*;
 extern "C" {
     #[wasm_bindgen(js_namespace = console)]
     fn log(s: &str);
+    #[wasm_bindgen(js_namespace = Date, js_name = now)]
+    fn date_now() -> f64;
+}
+
+/// milliseconds elapsed since the epoch, from the javascript clock
+pub(crate) fn now_millis() -> f64 {
+    date_now()
 }
 
 pub(crate) struct ConsoleWriter {